@@ -1,9 +1,11 @@
+#[cfg(feature = "graphics")]
 use graphics::types::Rectangle;
+use serde::{Deserialize, Serialize};
 
 /// Center = 0, 0
 /// Left = -240, right = +240
 /// Top = +180, bottom = -180
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct SpriteCoordinate {
     pub x: f64,
     pub y: f64,
@@ -72,7 +74,7 @@ impl Size {
     }
 }
 
-#[derive(Copy, Clone, Debug, PartialEq)]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Scale {
     pub x: f64,
     pub y: f64,
@@ -133,10 +135,16 @@ impl SpriteRectangle {
     }
 }
 
-impl Into<Rectangle> for SpriteRectangle {
-    fn into(self) -> Rectangle {
-        let top_left: CanvasCoordinate = self.top_left().into();
-        [top_left.x, top_left.y, self.size.width, self.size.height]
+#[cfg(feature = "graphics")]
+impl From<SpriteRectangle> for Rectangle {
+    fn from(rectangle: SpriteRectangle) -> Self {
+        let top_left: CanvasCoordinate = rectangle.top_left().into();
+        [
+            top_left.x,
+            top_left.y,
+            rectangle.size.width,
+            rectangle.size.height,
+        ]
     }
 }
 
@@ -303,7 +311,7 @@ mod tests {
                 expected: bool,
             }
 
-            let tests = vec![
+            let tests = [
                 Test {
                     a: SpriteRectangle {
                         center: SpriteCoordinate { x: 0.0, y: 0.0 },
@@ -0,0 +1,84 @@
+use anyhow::{Error, Result};
+use image::{ImageBuffer, ImageFormat, RgbaImage};
+use std::io::Cursor;
+
+/// Largest width or height of a decoded image in pixels. SVGs are rasterized at twice their size,
+/// so they can be at most half of this.
+pub const MAX_DIMENSION: u32 = 8192;
+
+/// Renders an SVG at twice its size, the resolution that bitmap costumes are drawn at.
+pub fn rasterize_svg(data: &[u8]) -> Result<RgbaImage> {
+    let mut options = usvg::Options::default();
+    options.fontdb.load_system_fonts();
+
+    let tree = usvg::Tree::from_data(data, &options)?;
+    let size = tree.svg_node().size;
+    check_dimensions(size.width() * 2.0, size.height() * 2.0)?;
+    let size = size.to_screen_size();
+    let mut pixmap = tiny_skia::Pixmap::new(size.width() * 2, size.height() * 2)
+        .ok_or_else(|| Error::msg("svg has no size"))?;
+
+    let width = pixmap.width();
+    let height = pixmap.height();
+
+    resvg::render(&tree, usvg::FitTo::Zoom(2.0), pixmap.as_mut())
+        .ok_or_else(|| Error::msg("svg error"))?;
+    ImageBuffer::from_raw(width, height, pixmap.take()).ok_or_else(|| Error::msg("svg error"))
+}
+
+/// Decodes a PNG, JPEG, GIF or BMP image. GIFs are decoded to their first frame.
+pub fn decode_bitmap(data: &[u8], format: ImageFormat) -> Result<RgbaImage> {
+    let (width, height) = bitmap_size(data, format)?;
+    check_dimensions(width as f64, height as f64)?;
+    Ok(image::load_from_memory_with_format(data, format)?.into_rgba8())
+}
+
+/// Width and height of an SVG in pixels, read from the parsed document.
+pub fn svg_size(data: &[u8]) -> Result<(f64, f64)> {
+    let tree = usvg::Tree::from_data(data, &usvg::Options::default())?;
+    let size = tree.svg_node().size;
+    Ok((size.width(), size.height()))
+}
+
+/// Width and height of a bitmap in pixels, read from its header without decoding the pixels.
+pub fn bitmap_size(data: &[u8], format: ImageFormat) -> Result<(u32, u32)> {
+    Ok(image::io::Reader::with_format(Cursor::new(data), format).into_dimensions()?)
+}
+
+fn check_dimensions(width: f64, height: f64) -> Result<()> {
+    let max = MAX_DIMENSION as f64;
+    if width > max || height > max {
+        return Err(Error::msg(format!(
+            "image is {}x{} which exceeds limit of {}",
+            width, height, MAX_DIMENSION
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_max_dimension() {
+        let svg = |size: u32| {
+            format!(
+                r#"<svg xmlns="http://www.w3.org/2000/svg" width="{0}" height="{0}"></svg>"#,
+                size
+            )
+        };
+        assert_eq!(svg_size(svg(10).as_bytes()).unwrap(), (10.0, 10.0));
+        assert!(rasterize_svg(svg(MAX_DIMENSION / 2 + 1).as_bytes()).is_err());
+
+        let mut png: Vec<u8> = Vec::new();
+        image::DynamicImage::new_rgba8(1, MAX_DIMENSION + 1)
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        assert_eq!(
+            bitmap_size(&png, ImageFormat::Png).unwrap(),
+            (1, MAX_DIMENSION + 1)
+        );
+        assert!(decode_bitmap(&png, ImageFormat::Png).is_err());
+    }
+}
@@ -0,0 +1,22 @@
+//! Runtime for projects exported with `scratch export-rust`, and the parts of the VM that do not
+//! depend on it.
+//!
+//! Exported scripts are async functions that take the sprite running them. They are polled once
+//! per frame and yield at the end of each loop iteration. With the default `window` feature the
+//! project is drawn in a window; without it the project runs headless as fast as it can, which
+//! suits simulations and tests.
+
+pub mod coordinate;
+#[cfg(feature = "images")]
+pub mod image;
+pub mod pen;
+mod project;
+mod sprite;
+pub mod value;
+#[cfg(feature = "window")]
+mod window;
+
+pub use anyhow::Result;
+pub use project::{run, Costume, Hat, Project, Script, Target};
+pub use sprite::Sprite;
+pub use value::*;
@@ -0,0 +1,388 @@
+#[cfg(feature = "graphics")]
+use crate::coordinate::CanvasCoordinate;
+use crate::coordinate::SpriteCoordinate;
+use crate::pen::PenStatus::PenUp;
+use anyhow::{Error, Result};
+#[cfg(feature = "graphics")]
+use graphics::{line, Context, Graphics};
+use palette::{Hsv, IntoColor, Mix, Srgb};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Pen {
+    lines: Vec<Line>,
+    pen_status: PenStatus,
+    /// Number of times the pen was cleared
+    clears: usize,
+    /// Lines removed by the last clear
+    #[serde(skip)]
+    cleared_lines: Vec<Line>,
+}
+
+/// Position in the drawing of a pen, which it can be rewound to. Lines are only added or
+/// extended until the pen is cleared.
+#[derive(Debug, Clone)]
+pub struct PenMark {
+    lines: usize,
+    points: usize,
+    color: Srgb<u8>,
+    size: f64,
+    pen_status: PenStatus,
+    clears: usize,
+    /// Lines before the pen was cleared after the mark
+    cleared_lines: Option<Vec<Line>>,
+}
+
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
+enum PenStatus {
+    PenUp,
+    PenDown,
+}
+
+impl Default for Pen {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pen {
+    pub fn new() -> Self {
+        let mut result = Self {
+            lines: Vec::new(),
+            pen_status: PenUp,
+            clears: 0,
+            cleared_lines: Vec::new(),
+        };
+        result.clear();
+        result
+    }
+
+    pub fn color(&self) -> &Srgb<u8> {
+        self.lines.last().unwrap().color()
+    }
+
+    pub fn set_color(&mut self, color: Srgb<u8>) {
+        self.new_line();
+        self.lines.last_mut().unwrap().set_color(color);
+    }
+
+    pub fn size(&self) -> f64 {
+        self.lines.last().unwrap().size()
+    }
+
+    pub fn set_size(&mut self, size: f64) {
+        self.new_line();
+        self.lines.last_mut().unwrap().set_size(size);
+    }
+
+    pub fn set_position(&mut self, position: &SpriteCoordinate) {
+        match self.pen_status {
+            PenStatus::PenDown => self.lines.last_mut().unwrap().add_point(position),
+            PenStatus::PenUp => {}
+        }
+    }
+
+    pub fn pen_down(&mut self, position: &SpriteCoordinate) {
+        self.new_line();
+        self.pen_status = PenStatus::PenDown;
+        self.set_position(position);
+    }
+
+    pub fn pen_up(&mut self) {
+        self.new_line();
+        self.pen_status = PenStatus::PenUp;
+    }
+
+    pub fn clear(&mut self) {
+        self.cleared_lines = std::mem::take(&mut self.lines);
+        self.clears += 1;
+        self.lines.push(Line::new(Srgb::new(255, 0, 0), 1.0));
+    }
+
+    /// Pens always have a line to draw with, unless they were deserialized from invalid data.
+    pub fn has_lines(&self) -> bool {
+        !self.lines.is_empty()
+    }
+
+    pub fn mark(&self) -> PenMark {
+        let last = self.lines.last().unwrap();
+        PenMark {
+            lines: self.lines.len(),
+            points: last.points.len(),
+            color: last.color,
+            size: last.size,
+            pen_status: self.pen_status,
+            clears: self.clears,
+            cleared_lines: None,
+        }
+    }
+
+    /// Keeps the lines that the pen had at the mark if it was cleared once since then.
+    pub fn complete_mark(&self, mark: &mut PenMark) {
+        if self.clears == mark.clears + 1 {
+            mark.cleared_lines = Some(self.cleared_lines.clone());
+        }
+    }
+
+    /// Removes the lines drawn after the mark.
+    pub fn rewind(&mut self, mark: &PenMark) -> Result<()> {
+        if self.clears != mark.clears {
+            match &mark.cleared_lines {
+                Some(lines) if self.clears == mark.clears + 1 => self.lines = lines.clone(),
+                _ => return Err(Error::msg("pen was cleared after the mark")),
+            }
+            self.clears = mark.clears;
+        }
+
+        self.lines.truncate(mark.lines);
+        let last = self
+            .lines
+            .last_mut()
+            .ok_or_else(|| Error::msg("pen has fewer lines than the mark"))?;
+        last.points.truncate(mark.points);
+        last.color = mark.color;
+        last.size = mark.size;
+        self.pen_status = mark.pen_status;
+        Ok(())
+    }
+
+    #[cfg(feature = "graphics")]
+    pub fn draw<G: Graphics>(&self, context: &Context, graphics: &mut G) {
+        for line in &self.lines {
+            line.draw(context, graphics);
+        }
+    }
+
+    fn new_line(&mut self) {
+        let last_point = self.lines.last().unwrap().last_point();
+        if let Some(point) = last_point {
+            let mut line = Line::new(*self.color(), self.size());
+            match self.pen_status {
+                PenStatus::PenDown => line.add_point(point),
+                PenStatus::PenUp => {}
+            }
+            self.lines.push(line);
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Line {
+    points: Vec<SpriteCoordinate>,
+    #[serde(with = "rgb")]
+    color: Srgb<u8>,
+    size: f64,
+}
+
+impl Line {
+    fn new(color: Srgb<u8>, size: f64) -> Self {
+        Self {
+            points: Vec::new(),
+            color,
+            size,
+        }
+    }
+
+    fn color(&self) -> &Srgb<u8> {
+        &self.color
+    }
+
+    fn set_color(&mut self, color: Srgb<u8>) {
+        self.color = color;
+    }
+
+    fn size(&self) -> f64 {
+        self.size
+    }
+
+    fn set_size(&mut self, size: f64) {
+        self.size = size;
+    }
+
+    fn last_point(&self) -> Option<&SpriteCoordinate> {
+        self.points.last()
+    }
+
+    fn add_point(&mut self, position: &SpriteCoordinate) {
+        self.points.push(*position);
+    }
+
+    #[cfg(feature = "graphics")]
+    fn draw<G: Graphics>(&self, context: &Context, graphics: &mut G) {
+        let line = line::Line {
+            color: [
+                self.color.red as f32 / 255.0,
+                self.color.green as f32 / 255.0,
+                self.color.blue as f32 / 255.0,
+                1.0,
+            ],
+            radius: self.size / 2.0,
+            shape: line::Shape::Round,
+        };
+
+        let mut iter = self.points.iter();
+        if let Some(point0) = iter.next() {
+            if self.points.len() == 1 {
+                let position: CanvasCoordinate = (*point0).into();
+                line.draw(
+                    [position.x, position.y, position.x, position.y],
+                    &context.draw_state,
+                    context.transform,
+                    graphics,
+                );
+            } else {
+                let mut last_position: CanvasCoordinate = (*point0).into();
+                for point in iter {
+                    let position: CanvasCoordinate = (*point).into();
+                    line.draw(
+                        [position.x, position.y, last_position.x, last_position.y],
+                        &context.draw_state,
+                        context.transform,
+                        graphics,
+                    );
+
+                    last_position = position;
+                }
+            }
+        }
+    }
+}
+
+pub fn rgb_to_hsv(rgb: &Srgb<u8>) -> Hsv {
+    Srgb::<f32>::new(
+        rgb.red as f32 / 255.0,
+        rgb.green as f32 / 255.0,
+        rgb.blue as f32 / 255.0,
+    )
+    .into_hsv()
+}
+
+pub fn hsv_to_rgb(hsv: &Hsv) -> Srgb<u8> {
+    let rgb_float: Srgb = hsv.into_rgb().into_encoding();
+    Srgb::new(
+        (rgb_float.red * 255.0) as u8,
+        (rgb_float.green * 255.0) as u8,
+        (rgb_float.blue * 255.0) as u8,
+    )
+}
+
+/// Hue is in [0, 200].
+pub fn set_hue(color: &Hsv, hue: f32) -> Hsv {
+    #[allow(clippy::float_cmp)]
+    if hue == 200.0 {
+        Hsv::new(360.0, 0.0, 0.0)
+    } else {
+        Hsv::new(hue / 200.0 * 360.0, color.saturation, color.value)
+    }
+}
+
+pub fn set_shade(color: &Hsv, shade: f32) -> Hsv {
+    // https://github.com/LLK/scratch-vm/blob/c6962cb390ba2835d64eb21c0456707b51642084/src/extensions/scratch3_pen/index.js#L718
+    let mut new_shade = shade % 200.0;
+    if new_shade < 0.0 {
+        new_shade += 200.0
+    }
+
+    // https://github.com/LLK/scratch-vm/blob/c6962cb390ba2835d64eb21c0456707b51642084/src/extensions/scratch3_pen/index.js#L750
+    let constrained_shade = if new_shade > 100.0 {
+        200.0 - new_shade
+    } else {
+        new_shade
+    };
+
+    let bright = Hsv::new(color.hue, 1.0, 1.0);
+    if constrained_shade < 50.0 {
+        Hsv::new(0.0, 0.0, 0.0).mix(&bright, (10.0 + shade) / 60.0)
+    } else {
+        bright.mix(&Hsv::new(0.0, 0.0, 1.0), (shade - 50.0) / 60.0)
+    }
+}
+
+/// Serializes colors as [red, green, blue].
+mod rgb {
+    use palette::Srgb;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S>(color: &Srgb<u8>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        [color.red, color.green, color.blue].serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Srgb<u8>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let [red, green, blue] = <[u8; 3]>::deserialize(deserializer)?;
+        Ok(Srgb::new(red, green, blue))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest(
+        color,
+        shade,
+        expected,
+        case(Hsv::new(0.0, 0.0, 0.0), 0.0, Hsv::new(0.0, 0.16666667, 0.16666667),),
+        case(Hsv::new(0.0, 0.0, 1.0), 0.0, Hsv::new(0.0, 0.16666667, 0.16666667),),
+        case(Hsv::new(0.0, 0.0, 0.0), 100.0, Hsv::new(0.0, 0.16666669, 1.0),),
+        case(Hsv::new(0.0, 0.0, 1.0), 100.0, Hsv::new(0.0, 0.16666669, 1.0),),
+        case(Hsv::new(0.0, 0.0, 0.0), 50.0, Hsv::new(0.0, 1.0, 1.0),),
+        case(Hsv::new(240.0, 1.0, 1.0), 50.0, Hsv::new(240.0, 1.0, 1.0),)
+    )]
+    fn test_set_shade(color: Hsv, shade: f32, expected: Hsv) {
+        assert_eq!(set_shade(&color, shade), expected);
+    }
+
+    #[rstest(
+        color,
+        hue,
+        expected,
+        case(Hsv::new(0.0, 0.0, 0.0), 0.0, Hsv::new(0.0, 0.0, 0.0),),
+        case(Hsv::new(0.0, 1.0, 1.0), 0.0, Hsv::new(0.0, 1.0, 1.0),),
+        case(Hsv::new(0.0, 0.0, 0.0), 50.0, Hsv::new(90.0, 0.0, 0.0),),
+        case(Hsv::new(0.0, 0.0, 0.0), 100.0, Hsv::new(180.0, 0.0, 0.0),),
+        case(Hsv::new(0.0, 0.0, 0.0), 200.0, Hsv::new(360.0, 0.0, 0.0),)
+    )]
+    fn test_set_hue(color: Hsv, hue: f32, expected: Hsv) {
+        assert_eq!(set_hue(&color, hue), expected);
+    }
+
+    #[test]
+    fn test_rewind() {
+        let point = |x: f64| SpriteCoordinate { x, y: 0.0 };
+
+        let mut pen = Pen::new();
+        pen.pen_down(&point(0.0));
+        pen.set_position(&point(1.0));
+        let mut mark = pen.mark();
+        let lines = pen.lines.clone();
+
+        pen.set_position(&point(2.0));
+        pen.set_color(Srgb::new(0, 0, 255));
+        pen.set_position(&point(3.0));
+        pen.pen_up();
+        pen.complete_mark(&mut mark);
+        pen.rewind(&mark).unwrap();
+        assert_eq!(pen.lines, lines);
+        assert!(matches!(pen.pen_status, PenStatus::PenDown));
+
+        let mut mark = pen.mark();
+        pen.clear();
+        pen.set_position(&point(4.0));
+        pen.complete_mark(&mut mark);
+        pen.rewind(&mark).unwrap();
+        assert_eq!(pen.lines, lines);
+
+        // Lines are only kept for the last clear
+        let mark = pen.mark();
+        pen.clear();
+        pen.clear();
+        assert!(pen.rewind(&mark).is_err());
+    }
+}
@@ -0,0 +1,313 @@
+use crate::sprite::{Running, ScriptID, Sprite, Stage};
+use crate::value::Value;
+use anyhow::Result;
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+/// Future of a running script.
+pub type Script<'a> = Pin<Box<dyn Future<Output = Result<()>> + 'a>>;
+
+#[derive(Debug)]
+pub struct Project {
+    /// Names and initial values of the stage variables, in the order of their slots
+    pub variables: Vec<(&'static str, Value)>,
+    /// The stage is first
+    pub targets: Vec<Target>,
+}
+
+#[derive(Debug)]
+pub struct Target {
+    pub name: &'static str,
+    pub is_stage: bool,
+    pub x: f64,
+    pub y: f64,
+    pub size: f64,
+    pub visible: bool,
+    pub layer_order: usize,
+    pub costumes: Vec<Costume>,
+    pub scripts: Vec<(Hat, fn(&Sprite) -> Script<'_>)>,
+}
+
+#[derive(Debug)]
+pub struct Costume {
+    pub name: &'static str,
+    /// SVG, PNG, JPEG, GIF or BMP file
+    pub image: &'static [u8],
+    pub rotation_center: (f64, f64),
+    pub bitmap_resolution: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Hat {
+    FlagClicked,
+    BroadcastReceived(&'static str),
+}
+
+/// Runs the project until every script finishes, or until the window is closed.
+pub fn run(project: Project) -> Result<()> {
+    let stage = Rc::new(Stage {
+        variables: RefCell::new(
+            project
+                .variables
+                .iter()
+                .map(|(_, value)| value.clone())
+                .collect(),
+        ),
+        ..Stage::default()
+    });
+    let sprites: Vec<Sprite> = project
+        .targets
+        .iter()
+        .map(|target| Sprite::new(target, stage.clone()))
+        .collect();
+    let mut executor = Executor {
+        project: &project,
+        sprites: &sprites,
+        stage,
+        threads: Vec::new(),
+    };
+    executor.start(|hat| *hat == Hat::FlagClicked, None);
+
+    #[cfg(feature = "window")]
+    return crate::window::run(&project, &sprites, executor);
+
+    #[cfg(not(feature = "window"))]
+    {
+        while executor.frame()? {
+            executor.sleep();
+        }
+        Ok(())
+    }
+}
+
+struct Thread<'a> {
+    id: ScriptID,
+    future: Script<'a>,
+    /// Broadcast that started this script
+    broadcast: Option<Running>,
+    stopped: bool,
+}
+
+impl Drop for Thread<'_> {
+    fn drop(&mut self) {
+        if let Some(running) = &self.broadcast {
+            running.set(running.get().map(|n| n - 1));
+        }
+    }
+}
+
+/// Runs each script until it yields once per frame, like the VM does with threads.
+pub(crate) struct Executor<'a> {
+    project: &'a Project,
+    sprites: &'a [Sprite],
+    stage: Rc<Stage>,
+    threads: Vec<Thread<'a>>,
+}
+
+impl<'a> Executor<'a> {
+    /// Starts the scripts with matching hats, restarting them if they are running. Returns the
+    /// number of scripts started.
+    fn start<F>(&mut self, matches: F, broadcast: Option<Running>) -> usize
+    where
+        F: Fn(&Hat) -> bool,
+    {
+        let (project, sprites) = (self.project, self.sprites);
+        let mut started: usize = 0;
+        for (sprite, target) in project.targets.iter().enumerate() {
+            for (script, (hat, function)) in target.scripts.iter().enumerate() {
+                if !matches(hat) {
+                    continue;
+                }
+                let id = (sprite, script);
+                self.threads.retain(|thread| thread.id != id);
+                if let Some(running) = &broadcast {
+                    running.set(Some(running.get().unwrap_or(0) + 1));
+                }
+                self.threads.push(Thread {
+                    id,
+                    future: function(&sprites[sprite]),
+                    broadcast: broadcast.clone(),
+                    stopped: false,
+                });
+                started += 1;
+            }
+        }
+        started
+    }
+
+    /// Runs every script once. Returns false if no scripts are left.
+    pub fn frame(&mut self) -> Result<bool> {
+        let broadcasts = std::mem::take(&mut *self.stage.broadcasts.borrow_mut());
+        for (name, running) in broadcasts {
+            running.set(Some(0));
+            self.start(
+                |hat| matches!(hat, Hat::BroadcastReceived(b) if b.eq_ignore_ascii_case(&name)),
+                Some(running),
+            );
+        }
+
+        self.stage.busy.set(false);
+        self.stage.wake_at.set(None);
+        let waker = noop_waker();
+        let mut context = Context::from_waker(&waker);
+        let mut index: usize = 0;
+        while index < self.threads.len() {
+            let thread = &mut self.threads[index];
+            if thread.stopped {
+                index += 1;
+                continue;
+            }
+            self.stage.current.set(thread.id);
+            match thread.future.as_mut().poll(&mut context) {
+                Poll::Pending => index += 1,
+                Poll::Ready(result) => {
+                    self.threads.remove(index);
+                    result?;
+                }
+            }
+
+            if self.stage.stop_all.get() {
+                self.threads.clear();
+                return Ok(false);
+            }
+            for (sprite, script) in self.stage.stop_others.take() {
+                for thread in &mut self.threads {
+                    if thread.id.0 == sprite && thread.id.1 != script {
+                        thread.stopped = true;
+                    }
+                }
+            }
+        }
+        self.threads.retain(|thread| !thread.stopped);
+        Ok(!self.threads.is_empty() || !self.stage.broadcasts.borrow().is_empty())
+    }
+
+    /// Sleeps until the first wait ends if every script is waiting.
+    #[cfg_attr(feature = "window", allow(dead_code))]
+    fn sleep(&self) {
+        if self.stage.busy.get() {
+            return;
+        }
+        if let Some(wake_at) = self.stage.wake_at.get() {
+            std::thread::sleep(wake_at.saturating_duration_since(std::time::Instant::now()));
+        }
+    }
+}
+
+/// Scripts are polled once per frame, so they never need to be woken.
+fn noop_waker() -> Waker {
+    fn clone(_: *const ()) -> RawWaker {
+        RawWaker::new(std::ptr::null(), &VTABLE)
+    }
+    fn noop(_: *const ()) {}
+    static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+    // The functions ignore the data pointer, so any pointer is valid
+    unsafe { Waker::from_raw(RawWaker::new(std::ptr::null(), &VTABLE)) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn count(s: &Sprite) -> Script<'_> {
+        Box::pin(async move {
+            for _ in 0..3 {
+                s.change_variable(0, &Value::from(1.0)).await?;
+                s.yield_now().await;
+            }
+            s.broadcast_and_wait(&Value::from("double")).await?;
+            s.say(&s.variable(0).await).await?;
+            Ok(())
+        })
+    }
+
+    fn double(s: &Sprite) -> Script<'_> {
+        Box::pin(async move {
+            let n = s.variable(0).await;
+            s.set_variable(0, crate::value::multiply(&n, &Value::from(2.0))?)
+                .await;
+            s.wait(&Value::from(0.01)).await?;
+            Ok(())
+        })
+    }
+
+    fn forever(s: &Sprite) -> Script<'_> {
+        Box::pin(async move {
+            loop {
+                s.yield_now().await;
+            }
+        })
+    }
+
+    fn stop(s: &Sprite) -> Script<'_> {
+        Box::pin(async move {
+            s.yield_now().await;
+            s.stop_other_scripts().await;
+            Ok(())
+        })
+    }
+
+    fn target(name: &'static str, scripts: Vec<(Hat, fn(&Sprite) -> Script<'_>)>) -> Target {
+        Target {
+            name,
+            is_stage: name == "Stage",
+            x: 0.0,
+            y: 0.0,
+            size: 100.0,
+            visible: true,
+            layer_order: 0,
+            costumes: Vec::new(),
+            scripts,
+        }
+    }
+
+    #[test]
+    fn test_frame() {
+        let project = Project {
+            variables: vec![("n", Value::from(0.0))],
+            targets: vec![
+                target(
+                    "Stage",
+                    vec![
+                        (Hat::FlagClicked, count),
+                        (Hat::BroadcastReceived("Double"), double),
+                    ],
+                ),
+                target(
+                    "Sprite1",
+                    vec![(Hat::FlagClicked, forever), (Hat::FlagClicked, stop)],
+                ),
+            ],
+        };
+        let stage = Rc::new(Stage {
+            variables: RefCell::new(vec![Value::from(0.0)]),
+            ..Stage::default()
+        });
+        let sprites: Vec<Sprite> = project
+            .targets
+            .iter()
+            .map(|target| Sprite::new(target, stage.clone()))
+            .collect();
+        let mut executor = Executor {
+            project: &project,
+            sprites: &sprites,
+            stage: stage.clone(),
+            threads: Vec::new(),
+        };
+        assert_eq!(executor.start(|hat| *hat == Hat::FlagClicked, None), 3);
+
+        let mut frames: usize = 0;
+        while executor.frame().unwrap() {
+            executor.sleep();
+            frames += 1;
+        }
+        assert_eq!(stage.variables.borrow()[0], Value::from(6.0));
+        // The stop script ends the forever script in the second frame, and the broadcast
+        // script waits for at least one frame
+        assert!(frames >= 5);
+        assert_eq!(sprites[0].state.borrow().text.as_deref(), Some("6"));
+    }
+}
@@ -0,0 +1,353 @@
+use crate::coordinate::SpriteCoordinate;
+use crate::pen::{hsv_to_rgb, rgb_to_hsv, set_hue, set_shade, Pen};
+use crate::project::Target;
+use crate::value::Value;
+use anyhow::Result;
+use std::cell::{Cell, RefCell};
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// Script of a sprite, identified by the index of the sprite and the index of the script.
+pub(crate) type ScriptID = (usize, usize);
+
+/// Number of running scripts that a broadcast started, which is None until they are started
+pub(crate) type Running = Rc<Cell<Option<usize>>>;
+
+/// State shared by the sprites of a running project.
+#[derive(Debug, Default)]
+pub(crate) struct Stage {
+    pub variables: RefCell<Vec<Value>>,
+    /// Broadcasts sent during this frame
+    pub broadcasts: RefCell<Vec<(String, Running)>>,
+    pub stop_all: Cell<bool>,
+    /// Scripts that stopped the other scripts of their sprite during this frame
+    pub stop_others: RefCell<Vec<ScriptID>>,
+    /// Script that is running
+    pub current: Cell<ScriptID>,
+    /// Whether a script yielded without waiting for a timer during this frame
+    pub busy: Cell<bool>,
+    /// Earliest end of the waits during this frame
+    pub wake_at: Cell<Option<Instant>>,
+}
+
+/// A target of the project. Scripts get a reference to the sprite running them and call its
+/// methods for the blocks that they contain.
+#[derive(Debug)]
+pub struct Sprite {
+    name: String,
+    stage: Rc<Stage>,
+    pub(crate) state: RefCell<SpriteState>,
+}
+
+#[derive(Debug)]
+pub(crate) struct SpriteState {
+    pub position: SpriteCoordinate,
+    /// Degrees clockwise from up, in (-180, 180]
+    pub direction: f64,
+    /// Percent
+    pub size: f64,
+    pub visible: bool,
+    pub costume: usize,
+    pub costume_count: usize,
+    pub text: Option<String>,
+    pub pen: Pen,
+}
+
+impl Sprite {
+    pub(crate) fn new(target: &Target, stage: Rc<Stage>) -> Self {
+        Self {
+            name: target.name.to_string(),
+            stage,
+            state: RefCell::new(SpriteState {
+                position: SpriteCoordinate {
+                    x: target.x,
+                    y: target.y,
+                },
+                direction: 90.0,
+                size: if target.is_stage { 100.0 } else { target.size },
+                visible: target.is_stage || target.visible,
+                costume: 0,
+                costume_count: target.costumes.len(),
+                text: None,
+                pen: Pen::new(),
+            }),
+        }
+    }
+
+    pub async fn variable(&self, slot: usize) -> Value {
+        self.stage.variables.borrow()[slot].clone()
+    }
+
+    pub async fn set_variable(&self, slot: usize, value: Value) {
+        self.stage.variables.borrow_mut()[slot] = value;
+    }
+
+    /// Variables that are not numbers are changed from 0.
+    pub async fn change_variable(&self, slot: usize, by: &Value) -> Result<()> {
+        let by = by.to_number()?;
+        let mut variables = self.stage.variables.borrow_mut();
+        let n = variables[slot].to_number().unwrap_or(0.0) + by;
+        variables[slot] = n.into();
+        Ok(())
+    }
+
+    fn set_position(&self, position: SpriteCoordinate) {
+        let mut state = self.state.borrow_mut();
+        state.position = position;
+        state.pen.set_position(&position);
+    }
+
+    fn position(&self) -> SpriteCoordinate {
+        self.state.borrow().position
+    }
+
+    pub async fn move_steps(&self, steps: &Value) -> Result<()> {
+        let steps = steps.to_number()?;
+        let radians = (90.0 - self.state.borrow().direction).to_radians();
+        let position = self.position();
+        self.set_position(SpriteCoordinate {
+            x: position.x + steps * radians.cos(),
+            y: position.y + steps * radians.sin(),
+        });
+        Ok(())
+    }
+
+    pub async fn go_to_xy(&self, x: &Value, y: &Value) -> Result<()> {
+        self.set_position(SpriteCoordinate {
+            x: x.to_number()?,
+            y: y.to_number()?,
+        });
+        Ok(())
+    }
+
+    pub async fn change_x_by(&self, dx: &Value) -> Result<()> {
+        let position = self.position();
+        self.set_position(SpriteCoordinate {
+            x: position.x + dx.to_number()?,
+            ..position
+        });
+        Ok(())
+    }
+
+    pub async fn change_y_by(&self, dy: &Value) -> Result<()> {
+        let position = self.position();
+        self.set_position(SpriteCoordinate {
+            y: position.y + dy.to_number()?,
+            ..position
+        });
+        Ok(())
+    }
+
+    pub async fn set_x(&self, x: &Value) -> Result<()> {
+        let position = self.position();
+        self.set_position(SpriteCoordinate {
+            x: x.to_number()?,
+            ..position
+        });
+        Ok(())
+    }
+
+    pub async fn set_y(&self, y: &Value) -> Result<()> {
+        let position = self.position();
+        self.set_position(SpriteCoordinate {
+            y: y.to_number()?,
+            ..position
+        });
+        Ok(())
+    }
+
+    pub async fn point_in_direction(&self, direction: &Value) -> Result<()> {
+        let mut direction = direction.to_number()? % 360.0;
+        if direction > 180.0 {
+            direction -= 360.0;
+        } else if direction <= -180.0 {
+            direction += 360.0;
+        }
+        self.state.borrow_mut().direction = direction;
+        Ok(())
+    }
+
+    pub async fn x_position(&self) -> Result<Value> {
+        Ok(self.position().x.into())
+    }
+
+    pub async fn y_position(&self) -> Result<Value> {
+        Ok(self.position().y.into())
+    }
+
+    pub async fn direction(&self) -> Result<Value> {
+        Ok(self.state.borrow().direction.into())
+    }
+
+    /// Speech is printed to standard output. Saying an empty message removes the bubble.
+    pub async fn say(&self, message: &Value) -> Result<()> {
+        let text = message.to_string();
+        if text.is_empty() {
+            self.state.borrow_mut().text = None;
+        } else {
+            println!("{}: {}", self.name, text);
+            self.state.borrow_mut().text = Some(text);
+        }
+        Ok(())
+    }
+
+    pub async fn say_for_secs(&self, message: &Value, secs: &Value) -> Result<()> {
+        self.say(message).await?;
+        self.wait(secs).await?;
+        self.state.borrow_mut().text = None;
+        Ok(())
+    }
+
+    pub async fn show(&self) -> Result<()> {
+        self.state.borrow_mut().visible = true;
+        Ok(())
+    }
+
+    pub async fn hide(&self) -> Result<()> {
+        self.state.borrow_mut().visible = false;
+        Ok(())
+    }
+
+    pub async fn next_costume(&self) -> Result<()> {
+        let mut state = self.state.borrow_mut();
+        if state.costume_count > 0 {
+            state.costume = (state.costume + 1) % state.costume_count;
+        }
+        Ok(())
+    }
+
+    pub async fn set_size_to(&self, size: &Value) -> Result<()> {
+        self.state.borrow_mut().size = size.to_number()?.max(0.0);
+        Ok(())
+    }
+
+    pub async fn pen_down(&self) -> Result<()> {
+        let mut state = self.state.borrow_mut();
+        let position = state.position;
+        state.pen.pen_down(&position);
+        Ok(())
+    }
+
+    pub async fn pen_up(&self) -> Result<()> {
+        self.state.borrow_mut().pen.pen_up();
+        Ok(())
+    }
+
+    pub async fn pen_clear(&self) -> Result<()> {
+        self.state.borrow_mut().pen.clear();
+        Ok(())
+    }
+
+    pub async fn set_pen_size_to(&self, size: &Value) -> Result<()> {
+        self.state.borrow_mut().pen.set_size(size.to_number()?);
+        Ok(())
+    }
+
+    pub async fn set_pen_color_to_color(&self, color: &Value) -> Result<()> {
+        self.state.borrow_mut().pen.set_color(color.to_color()?);
+        Ok(())
+    }
+
+    pub async fn set_pen_hue_to_number(&self, hue: &Value) -> Result<()> {
+        let hue = hue.to_number()?;
+        let mut state = self.state.borrow_mut();
+        let color = set_hue(&rgb_to_hsv(state.pen.color()), hue as f32);
+        state.pen.set_color(hsv_to_rgb(&color));
+        Ok(())
+    }
+
+    pub async fn set_pen_shade_to_number(&self, shade: &Value) -> Result<()> {
+        let shade = shade.to_number()?;
+        let mut state = self.state.borrow_mut();
+        let color = set_shade(&rgb_to_hsv(state.pen.color()), shade as f32);
+        state.pen.set_color(hsv_to_rgb(&color));
+        Ok(())
+    }
+
+    pub async fn wait(&self, secs: &Value) -> Result<()> {
+        let duration = Duration::from_secs_f64(secs.to_number()?.max(0.0));
+        Wait {
+            stage: &self.stage,
+            until: Instant::now() + duration,
+            yielded: false,
+        }
+        .await;
+        Ok(())
+    }
+
+    pub async fn broadcast(&self, name: &Value) -> Result<()> {
+        self.send(name);
+        Ok(())
+    }
+
+    pub async fn broadcast_and_wait(&self, name: &Value) -> Result<()> {
+        let running = self.send(name);
+        loop {
+            self.yield_now().await;
+            if running.get() == Some(0) {
+                return Ok(());
+            }
+        }
+    }
+
+    fn send(&self, name: &Value) -> Running {
+        let running = Rc::new(Cell::new(None));
+        self.stage
+            .broadcasts
+            .borrow_mut()
+            .push((name.to_string(), running.clone()));
+        running
+    }
+
+    pub async fn stop_all(&self) {
+        self.stage.stop_all.set(true);
+    }
+
+    pub async fn stop_other_scripts(&self) {
+        self.stage
+            .stop_others
+            .borrow_mut()
+            .push(self.stage.current.get());
+    }
+
+    /// Lets the other scripts run until the next frame.
+    pub async fn yield_now(&self) {
+        Wait {
+            stage: &self.stage,
+            until: Instant::now(),
+            yielded: false,
+        }
+        .await
+    }
+}
+
+/// Yields at least once, until the time has passed.
+struct Wait<'a> {
+    stage: &'a Stage,
+    until: Instant,
+    yielded: bool,
+}
+
+impl Future for Wait<'_> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<()> {
+        if self.yielded && Instant::now() >= self.until {
+            return Poll::Ready(());
+        }
+        if self.yielded {
+            let wake_at = match self.stage.wake_at.get() {
+                Some(wake_at) => wake_at.min(self.until),
+                None => self.until,
+            };
+            self.stage.wake_at.set(Some(wake_at));
+        } else {
+            self.stage.busy.set(true);
+        }
+        self.yielded = true;
+        Poll::Pending
+    }
+}
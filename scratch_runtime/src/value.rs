@@ -0,0 +1,311 @@
+use anyhow::{Error, Result};
+use palette::Srgb;
+use std::cell::Cell;
+use std::convert::{TryFrom, TryInto};
+use std::fmt::{Display, Formatter};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Value of a variable, input or reporter. The VM and exported projects share the conversions
+/// and operators, so that both run a project the same way.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Color(Srgb<u8>),
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Self::Bool(b)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(f: f64) -> Self {
+        Self::Number(f)
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Self {
+        Self::String(s)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Self {
+        Self::String(s.to_string())
+    }
+}
+
+impl From<Srgb<u8>> for Value {
+    fn from(c: Srgb<u8>) -> Self {
+        Self::Color(c)
+    }
+}
+
+impl TryFrom<Value> for bool {
+    type Error = Error;
+
+    fn try_from(value: Value) -> Result<bool> {
+        if let Value::Bool(b) = value {
+            Ok(b)
+        } else {
+            Err(Error::msg(format!("value is not bool: {}", value)))
+        }
+    }
+}
+
+impl TryFrom<Value> for f64 {
+    type Error = Error;
+
+    fn try_from(value: Value) -> Result<f64> {
+        (&value).try_into()
+    }
+}
+
+/// NaN is 0. Strings are parsed as they are, so empty strings are not numbers.
+impl TryFrom<&Value> for f64 {
+    type Error = Error;
+
+    fn try_from(value: &Value) -> Result<f64> {
+        Ok(match value {
+            Value::Number(f) => {
+                if f.is_nan() {
+                    0.0
+                } else {
+                    *f
+                }
+            }
+            Value::String(s) => s.parse()?,
+            _ => {
+                return Err(Error::msg(format!(
+                    "expected String or Number but got: {:?}",
+                    value
+                )))
+            }
+        })
+    }
+}
+
+impl TryFrom<Value> for Srgb<u8> {
+    type Error = Error;
+
+    fn try_from(value: Value) -> Result<Srgb<u8>> {
+        Ok(match value {
+            Value::String(s) => str_to_color(&s)?,
+            Value::Color(c) => c,
+            _ => return Err(Error::msg(format!("cannot convert {} into color", value))),
+        })
+    }
+}
+
+impl Value {
+    pub fn to_number(&self) -> Result<f64> {
+        self.try_into()
+    }
+
+    pub fn to_color(&self) -> Result<Srgb<u8>> {
+        self.clone().try_into()
+    }
+}
+
+impl Display for Value {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Bool(b) => write!(f, "{}", b),
+            Self::Number(n) => write!(f, "{}", n),
+            Self::String(s) => write!(f, "{}", s),
+            Self::Color(c) => write!(f, "{:?}", c),
+        }
+    }
+}
+
+/// Parses #rrggbb.
+pub fn str_to_color(s: &str) -> Result<Srgb<u8>> {
+    if s.len() != 7 || s.bytes().next() != Some(b'#') {
+        return Err(Error::msg(format!("string is invalid: {}", s)));
+    }
+
+    Ok(Srgb::new(
+        u8::from_str_radix(&s[1..3], 16)?,
+        u8::from_str_radix(&s[3..5], 16)?,
+        u8::from_str_radix(&s[5..7], 16)?,
+    ))
+}
+
+/// Whether an if or repeat until condition is met. Only booleans are conditions.
+pub fn truthy(value: &Value) -> Result<bool> {
+    value.clone().try_into()
+}
+
+/// Number of iterations of a repeat block. Fractions are truncated.
+pub fn repeat_count(times: &Value) -> Result<usize> {
+    Ok(times.to_number()? as usize)
+}
+
+pub fn add(a: &Value, b: &Value) -> Result<Value> {
+    Ok((a.to_number()? + b.to_number()?).into())
+}
+
+pub fn subtract(a: &Value, b: &Value) -> Result<Value> {
+    Ok((a.to_number()? - b.to_number()?).into())
+}
+
+pub fn multiply(a: &Value, b: &Value) -> Result<Value> {
+    Ok((a.to_number()? * b.to_number()?).into())
+}
+
+pub fn divide(a: &Value, b: &Value) -> Result<Value> {
+    Ok((a.to_number()? / b.to_number()?).into())
+}
+
+pub fn lt(a: &Value, b: &Value) -> Result<Value> {
+    Ok((a.to_number()? < b.to_number()?).into())
+}
+
+pub fn gt(a: &Value, b: &Value) -> Result<Value> {
+    Ok((a.to_number()? > b.to_number()?).into())
+}
+
+/// Values of different types are never equal.
+pub fn equals(a: &Value, b: &Value) -> Result<Value> {
+    Ok((a == b).into())
+}
+
+pub fn and(a: &Value, b: &Value) -> Result<Value> {
+    Ok((truthy(a)? && truthy(b)?).into())
+}
+
+pub fn or(a: &Value, b: &Value) -> Result<Value> {
+    Ok((truthy(a)? || truthy(b)?).into())
+}
+
+pub fn not(a: &Value) -> Result<Value> {
+    Ok((!truthy(a)?).into())
+}
+
+pub fn join(a: &Value, b: &Value) -> Result<Value> {
+    Ok(format!("{}{}", a, b).into())
+}
+
+thread_local! {
+    static RANDOM_STATE: Cell<u64> = Cell::new(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0)
+            | 1,
+    );
+}
+
+/// Returns an integer if both bounds are integers, otherwise a fraction.
+pub fn random(from: &Value, to: &Value) -> Result<Value> {
+    let (from, to) = (from.to_number()?, to.to_number()?);
+    let (low, high) = if from <= to { (from, to) } else { (to, from) };
+    // xorshift64
+    let bits = RANDOM_STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.set(x);
+        x
+    });
+    let fraction = (bits >> 11) as f64 / (1u64 << 53) as f64;
+    Ok(if low.fract() == 0.0 && high.fract() == 0.0 {
+        (low + (fraction * (high - low + 1.0)).floor()).into()
+    } else {
+        (low + fraction * (high - low)).into()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rstest::rstest;
+
+    #[rstest(
+        s,
+        expected,
+        expect_err,
+        case("", Srgb::new(0, 0, 0), true),
+        case("#", Srgb::new(0, 0, 0), true),
+        case("#000000", Srgb::new(0, 0, 0), false),
+        case("#ffffff", Srgb::new(255, 255, 255), false),
+        case("#ffffffa", Srgb::new(0, 0, 0), true)
+    )]
+    fn test_str_to_color(s: &'static str, expected: Srgb<u8>, expect_err: bool) {
+        let result = str_to_color(s);
+        assert_eq!(result.is_err(), expect_err);
+        if !expect_err {
+            assert_eq!(result.unwrap(), expected);
+        }
+    }
+
+    #[rstest(
+        value,
+        expected,
+        expect_err,
+        case(Value::from(1.5), 1.5, false),
+        case(Value::from("1.5"), 1.5, false),
+        case(Value::from(f64::NAN), 0.0, false),
+        case(Value::from(" 2 "), 0.0, true),
+        case(Value::from(""), 0.0, true),
+        case(Value::from("a"), 0.0, true),
+        case(Value::from(true), 0.0, true)
+    )]
+    fn test_to_number(value: Value, expected: f64, expect_err: bool) {
+        let result = value.to_number();
+        assert_eq!(result.is_err(), expect_err);
+        if !expect_err {
+            assert_eq!(result.unwrap(), expected);
+        }
+    }
+
+    #[rstest(
+        value,
+        expected,
+        case (Value::String("a".into()), "a"),
+        case (Value::Number(1.0), "1"),
+        case (Value::Number(1.1), "1.1"),
+        case (Value::Bool(false), "false"),
+    )]
+    fn test_to_string(value: Value, expected: &'static str) {
+        assert_eq!(value.to_string(), expected);
+    }
+
+    #[rstest(
+        a,
+        b,
+        expected,
+        case(Value::from(1.0), Value::from(1.0), true),
+        case(Value::from(1.0), Value::from("1"), false),
+        case(Value::from("abc"), Value::from("ABC"), false)
+    )]
+    fn test_equals(a: Value, b: Value, expected: bool) {
+        assert_eq!(equals(&a, &b).unwrap(), Value::from(expected));
+    }
+
+    #[test]
+    fn test_conditions() {
+        assert!(truthy(&Value::from(true)).unwrap());
+        assert!(truthy(&Value::from(1.0)).is_err());
+        assert!(truthy(&Value::from("true")).is_err());
+        assert_eq!(repeat_count(&Value::from(2.9)).unwrap(), 2);
+        assert_eq!(repeat_count(&Value::from(-1.0)).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_random() {
+        for _ in 0..100 {
+            let n = random(&Value::from(3.0), &Value::from(1.0))
+                .unwrap()
+                .to_number()
+                .unwrap();
+            assert!((1.0..=3.0).contains(&n));
+            assert_eq!(n.fract(), 0.0);
+        }
+    }
+}
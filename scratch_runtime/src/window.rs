@@ -0,0 +1,110 @@
+use crate::coordinate::{canvas_const, CanvasCoordinate};
+use crate::image::{decode_bitmap, rasterize_svg};
+use crate::project::{Costume, Executor, Project};
+use crate::sprite::Sprite;
+use anyhow::{Error, Result};
+use piston_window::*;
+
+/// Texture of a costume and its size on the stage at 100%
+struct CostumeTexture {
+    texture: G2dTexture,
+    width: f64,
+    height: f64,
+    /// Rotation center on the stage at 100%
+    center: (f64, f64),
+}
+
+/// Runs a frame on each update and draws the pen lines and costumes of visible sprites. Speech
+/// is not drawn.
+pub(crate) fn run(project: &Project, sprites: &[Sprite], mut executor: Executor) -> Result<()> {
+    let title = project
+        .targets
+        .iter()
+        .find(|target| !target.is_stage)
+        .map_or("Scratch", |target| target.name);
+    let mut window: PistonWindow =
+        WindowSettings::new(title, [canvas_const::X_MAX, canvas_const::Y_MAX])
+            .exit_on_esc(true)
+            .resizable(false)
+            .build()
+            .map_err(|e| Error::msg(e.to_string()))?;
+    window.set_ups(30);
+
+    let mut texture_context = window.create_texture_context();
+    let mut textures: Vec<Vec<CostumeTexture>> = Vec::new();
+    for target in &project.targets {
+        let mut target_textures: Vec<CostumeTexture> = Vec::new();
+        for costume in &target.costumes {
+            target_textures.push(costume_texture(&mut texture_context, costume)?);
+        }
+        textures.push(target_textures);
+    }
+    let mut layers: Vec<usize> = (0..project.targets.len()).collect();
+    layers.sort_by_key(|&index| project.targets[index].layer_order);
+
+    let mut running = true;
+    while let Some(event) = window.next() {
+        if event.update_args().is_some() && running {
+            running = executor.frame()?;
+        }
+        window.draw_2d(&event, |context, graphics, _| {
+            clear([1.0; 4], graphics);
+            for &index in &layers {
+                let state = sprites[index].state.borrow();
+                state.pen.draw(&context, graphics);
+            }
+            for &index in &layers {
+                let state = sprites[index].state.borrow();
+                if !state.visible {
+                    continue;
+                }
+                let costume = match textures[index].get(state.costume) {
+                    Some(costume) => costume,
+                    None => continue,
+                };
+                let scale = state.size / 100.0;
+                let position: CanvasCoordinate = state.position.into();
+                Image::new()
+                    .rect([
+                        position.x - costume.center.0 * scale,
+                        position.y - costume.center.1 * scale,
+                        costume.width * scale,
+                        costume.height * scale,
+                    ])
+                    .draw(
+                        &costume.texture,
+                        &context.draw_state,
+                        context.transform,
+                        graphics,
+                    );
+            }
+        });
+    }
+    Ok(())
+}
+
+fn costume_texture(
+    texture_context: &mut G2dTextureContext,
+    costume: &Costume,
+) -> Result<CostumeTexture> {
+    let bitmap_resolution = if costume.bitmap_resolution == 0.0 {
+        1.0
+    } else {
+        costume.bitmap_resolution
+    };
+    let (image, resolution) = match ::image::guess_format(costume.image) {
+        Ok(format) => (decode_bitmap(costume.image, format)?, bitmap_resolution),
+        // SVGs are rasterized at twice their size
+        Err(_) => (rasterize_svg(costume.image)?, 2.0),
+    };
+    Ok(CostumeTexture {
+        width: image.width() as f64 / resolution,
+        height: image.height() as f64 / resolution,
+        texture: Texture::from_image(texture_context, &image, &TextureSettings::new())
+            .map_err(|e| Error::msg(format!("{:?}", e)))?,
+        center: (
+            costume.rotation_center.0 / bitmap_resolution,
+            costume.rotation_center.1 / bitmap_resolution,
+        ),
+    })
+}
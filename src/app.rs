@@ -1,5 +1,6 @@
 use super::*;
-use crate::file::ScratchFile;
+use crate::debugger::DebugOptions;
+use crate::file::{LoadOptions, ScratchFile};
 use crate::interface::Interface;
 use conrod_core::text::GlyphCache;
 use conrod_core::Theme;
@@ -20,7 +21,11 @@ pub const WINDOW_SIZE: Size = Size {
     height: 480.0,
 };
 
-pub async fn app(file_path: &Path) -> Result<()> {
+pub async fn app(
+    file_path: &Path,
+    load_options: &LoadOptions,
+    debug_options: &DebugOptions,
+) -> Result<()> {
     let mut window: PistonWindow = WindowSettings::new("Scratch", WINDOW_SIZE)
         .graphics_api(OpenGL::V3_2)
         .samples(8)
@@ -56,7 +61,8 @@ pub async fn app(file_path: &Path) -> Result<()> {
 
     let mut image_map = conrod_core::image::Map::new();
 
-    let scratch_file = ScratchFile::parse(BufReader::new(File::open(file_path)?))?;
+    let scratch_file =
+        ScratchFile::parse_with_options(BufReader::new(File::open(file_path)?), load_options)?;
 
     let green_flag_id = image_map.insert(image_texture(
         &mut texture_context,
@@ -75,6 +81,8 @@ pub async fn app(file_path: &Path) -> Result<()> {
         interface::Ids::new(id_generator),
         green_flag_id,
         stop_image_id,
+        load_options.mode,
+        debug_options,
     )
     .await?;
 
@@ -95,7 +103,7 @@ pub async fn app(file_path: &Path) -> Result<()> {
             }
             Event::Input(input, _) => {
                 if matches!(input, Input::Close(_)) {
-                    return Ok(());
+                    break;
                 }
 
                 interface.input(input).await?
@@ -158,7 +166,7 @@ pub async fn app(file_path: &Path) -> Result<()> {
         }
     }
 
-    Ok(())
+    interface.close().await
 }
 
 fn image_texture(
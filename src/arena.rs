@@ -0,0 +1,232 @@
+/// Stores values in reusable slots. Handles stay valid until their value is removed and do not
+/// refer to a later value in the same slot.
+#[derive(Debug)]
+pub struct Arena<T> {
+    slots: Vec<Slot<T>>,
+    /// Indexes of empty slots
+    free: Vec<usize>,
+}
+
+#[derive(Debug)]
+struct Slot<T> {
+    generation: u32,
+    value: Option<T>,
+    /// Whether the empty slot is kept for a value that is filled in later
+    reserved: bool,
+}
+
+/// Index and generation of a slot, which fit in 64 bits.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Handle {
+    index: u32,
+    generation: u32,
+}
+
+impl Handle {
+    pub fn from_bits(bits: u64) -> Self {
+        Self {
+            index: bits as u32,
+            generation: (bits >> 32) as u32,
+        }
+    }
+
+    pub fn to_bits(self) -> u64 {
+        (self.generation as u64) << 32 | self.index as u64
+    }
+}
+
+impl<T> Default for Arena<T> {
+    fn default() -> Self {
+        Self {
+            slots: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+}
+
+impl<T> Arena<T> {
+    /// Keeps an empty slot for a value that needs its handle before it is created.
+    pub fn reserve(&mut self) -> Handle {
+        match self.free.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index];
+                slot.reserved = true;
+                Handle {
+                    index: index as u32,
+                    generation: slot.generation,
+                }
+            }
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    value: None,
+                    reserved: true,
+                });
+                Handle {
+                    index: (self.slots.len() - 1) as u32,
+                    generation: 0,
+                }
+            }
+        }
+    }
+
+    /// Reserves the slot of the handle if it is empty and has not been used by a later
+    /// generation. Returns whether the slot is reserved.
+    pub fn reserve_at(&mut self, handle: Handle) -> bool {
+        let index = handle.index as usize;
+        while self.slots.len() <= index {
+            self.free.push(self.slots.len());
+            self.slots.push(Slot {
+                generation: 0,
+                value: None,
+                reserved: false,
+            });
+        }
+        let slot = &mut self.slots[index];
+        if slot.value.is_some() || slot.generation > handle.generation {
+            return false;
+        }
+        if slot.reserved {
+            return slot.generation == handle.generation;
+        }
+        slot.generation = handle.generation;
+        slot.reserved = true;
+        self.free.retain(|free| *free != index);
+        true
+    }
+
+    /// Puts the value in the reserved slot of the handle. The value is returned if the slot is not
+    /// reserved.
+    pub fn fill(&mut self, handle: Handle, value: T) -> Result<(), T> {
+        match self.slots.get_mut(handle.index as usize) {
+            Some(slot) if slot.generation == handle.generation && slot.reserved => {
+                slot.value = Some(value);
+                slot.reserved = false;
+                Ok(())
+            }
+            _ => Err(value),
+        }
+    }
+
+    pub fn get(&self, handle: Handle) -> Option<&T> {
+        match self.slots.get(handle.index as usize) {
+            Some(slot) if slot.generation == handle.generation => slot.value.as_ref(),
+            _ => None,
+        }
+    }
+
+    pub fn remove(&mut self, handle: Handle) -> Option<T> {
+        let value = self.take(handle)?;
+        self.release(handle);
+        Some(value)
+    }
+
+    /// Removes the value but keeps its slot reserved, so that it can be filled again with the
+    /// same handle.
+    pub fn take(&mut self, handle: Handle) -> Option<T> {
+        match self.slots.get_mut(handle.index as usize) {
+            Some(slot) if slot.generation == handle.generation => {
+                let value = slot.value.take()?;
+                slot.reserved = true;
+                Some(value)
+            }
+            _ => None,
+        }
+    }
+
+    /// Frees the reserved slot of the handle. Later values in the slot have new handles.
+    pub fn release(&mut self, handle: Handle) {
+        if let Some(slot) = self.slots.get_mut(handle.index as usize) {
+            if slot.generation == handle.generation && slot.reserved {
+                slot.reserved = false;
+                slot.generation = slot.generation.wrapping_add(1);
+                self.free.push(handle.index as usize);
+            }
+        }
+    }
+
+    /// Whether the handle referred to a value that is no longer in the arena.
+    pub fn is_removed(&self, handle: Handle) -> bool {
+        match self.slots.get(handle.index as usize) {
+            Some(slot) if slot.generation == handle.generation => slot.value.is_none(),
+            Some(slot) => slot.generation > handle.generation,
+            None => false,
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Handle, &T)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            slot.value.as_ref().map(|value| {
+                (
+                    Handle {
+                        index: index as u32,
+                        generation: slot.generation,
+                    },
+                    value,
+                )
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert<T: std::fmt::Debug>(arena: &mut Arena<T>, value: T) -> Handle {
+        let handle = arena.reserve();
+        arena.fill(handle, value).unwrap();
+        handle
+    }
+
+    #[test]
+    fn test_arena() {
+        let mut arena: Arena<&str> = Arena::default();
+        let a = insert(&mut arena, "a");
+        let b = insert(&mut arena, "b");
+        assert_eq!(arena.get(a), Some(&"a"));
+
+        assert_eq!(arena.remove(a), Some("a"));
+        assert_eq!(arena.remove(a), None);
+        assert_eq!(arena.get(a), None);
+
+        // The slot of a is reused but the old handle does not refer to c
+        let c = insert(&mut arena, "c");
+        assert_eq!(arena.get(a), None);
+        assert_eq!(arena.get(c), Some(&"c"));
+        assert_eq!(arena.slots.len(), 2);
+        assert_eq!(
+            arena.iter().map(|(_, v)| *v).collect::<Vec<&str>>(),
+            vec!["c", "b"]
+        );
+        assert_eq!(arena.get(b), Some(&"b"));
+        assert!(arena.is_removed(a));
+        assert!(!arena.is_removed(b));
+    }
+
+    #[test]
+    fn test_reserve() {
+        let mut arena: Arena<&str> = Arena::default();
+        let a = arena.reserve();
+        assert_eq!(arena.get(a), None);
+        assert_eq!(arena.fill(a, "a"), Ok(()));
+        assert_eq!(arena.fill(a, "b"), Err("b"));
+
+        // A taken value can be put back with the same handle until its slot is released
+        assert_eq!(arena.take(a), Some("a"));
+        assert_eq!(insert(&mut arena, "b"), Handle::from_bits(1));
+        assert_eq!(arena.fill(a, "a"), Ok(()));
+        assert_eq!(arena.take(a), Some("a"));
+        arena.release(a);
+        assert_eq!(arena.fill(a, "a"), Err("a"));
+
+        // Slots of handles from another arena are reserved unless a later generation used them
+        let c = Handle::from_bits(1 << 32 | 3);
+        assert!(arena.reserve_at(c));
+        assert_eq!(arena.fill(c, "c"), Ok(()));
+        assert!(!arena.reserve_at(Handle::from_bits(3)));
+        assert!(!arena.reserve_at(a));
+        assert_eq!(insert(&mut arena, "d").to_bits(), 2);
+        assert_eq!(insert(&mut arena, "e").to_bits(), 1 << 32);
+    }
+}
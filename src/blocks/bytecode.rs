@@ -0,0 +1,957 @@
+//! Compiles stack blocks and their reporters to instructions that run on a value stack, so that
+//! a step runs one loop instead of awaiting a boxed future for every reporter. Blocks that have
+//! no instructions are kept as trait objects and called from the instructions.
+
+use super::*;
+use crate::vm::DebugInfo;
+
+/// Jump offsets are relative to the next instruction.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instruction {
+    Push(Value),
+    /// Pushes the value of the variable or list in the slot
+    Variable(usize),
+    /// Pushes the value of a reporter that has no instruction
+    Reporter(usize),
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    LessThan,
+    GreaterThan,
+    Equals,
+    And,
+    Or,
+    Not,
+    /// Pops the value
+    SetVariable(usize),
+    /// Pops the number that is added
+    ChangeVariable(usize),
+    Jump(isize),
+    /// Pops a boolean and jumps if it is false
+    JumpIfFalse(isize),
+    /// Jumps if the block executes again after its substack and clears the flag
+    JumpIfDone(isize),
+    /// Marks that the block continues after its substack when it executes again
+    SetDone,
+    /// Pops the number of iterations. Counts an iteration or, when all of them are counted,
+    /// resets the count and jumps.
+    Count(isize),
+    Continue(Option<BlockID>),
+    Loop(Option<BlockID>),
+}
+
+/// Runs a block as instructions. Inspection goes to the block that was compiled.
+#[derive(Debug)]
+pub struct Compiled {
+    block: Box<dyn Block + Send + Sync>,
+    instructions: Vec<Instruction>,
+    reporters: Vec<Box<dyn Block + Send + Sync>>,
+    runtime: Runtime,
+    state: BlockState,
+}
+
+impl Compiled {
+    async fn run(&self, state: &mut BlockState, stack: &mut Vec<Value>) -> Result<Next> {
+        let mut pc = 0;
+        while let Some(instruction) = self.instructions.get(pc) {
+            pc += 1;
+            match instruction {
+                Instruction::Push(value) => stack.push(value.clone()),
+                Instruction::Variable(slot) => {
+                    stack.push(self.runtime.global.variables.get_slot(*slot).await?)
+                }
+                Instruction::Reporter(index) => stack.push(self.reporters[*index].value().await?),
+                Instruction::Add => {
+                    let (a, b) = pop_numbers(stack)?;
+                    stack.push((a + b).into());
+                }
+                Instruction::Subtract => {
+                    let (a, b) = pop_numbers(stack)?;
+                    stack.push((a - b).into());
+                }
+                Instruction::Multiply => {
+                    let (a, b) = pop_numbers(stack)?;
+                    stack.push((a * b).into());
+                }
+                Instruction::Divide => {
+                    let (a, b) = pop_numbers(stack)?;
+                    stack.push((a / b).into());
+                }
+                Instruction::LessThan => {
+                    let (a, b) = pop_numbers(stack)?;
+                    stack.push((a < b).into());
+                }
+                Instruction::GreaterThan => {
+                    let (a, b) = pop_numbers(stack)?;
+                    stack.push((a > b).into());
+                }
+                Instruction::Equals => {
+                    let b = pop(stack)?;
+                    let a = pop(stack)?;
+                    stack.push(value::equals(&a, &b)?);
+                }
+                Instruction::And => {
+                    let (a, b) = pop_booleans(stack)?;
+                    stack.push((a && b).into());
+                }
+                Instruction::Or => {
+                    let (a, b) = pop_booleans(stack)?;
+                    stack.push((a || b).into());
+                }
+                Instruction::Not => {
+                    let a: bool = pop(stack)?.try_into()?;
+                    stack.push((!a).into());
+                }
+                Instruction::SetVariable(slot) => {
+                    let value = pop(stack)?;
+                    self.runtime
+                        .global
+                        .variables
+                        .set_slot_with(*slot, |_| value, self.debug_info())
+                        .await?;
+                }
+                Instruction::ChangeVariable(slot) => {
+                    let value: f64 = pop(stack)?.try_into()?;
+                    self.runtime
+                        .global
+                        .variables
+                        .set_slot_with(
+                            *slot,
+                            |v| {
+                                let previous_float: f64 = v.try_into().unwrap_or(0.0);
+                                (previous_float + value).into()
+                            },
+                            self.debug_info(),
+                        )
+                        .await?;
+                }
+                Instruction::Jump(offset) => pc = jump(pc, *offset),
+                Instruction::JumpIfFalse(offset) => {
+                    let condition: bool = pop(stack)?.try_into()?;
+                    if !condition {
+                        pc = jump(pc, *offset);
+                    }
+                }
+                Instruction::JumpIfDone(offset) => {
+                    if state.done {
+                        state.done = false;
+                        pc = jump(pc, *offset);
+                    }
+                }
+                Instruction::SetDone => state.done = true,
+                Instruction::Count(offset) => {
+                    let times = value::repeat_count(&pop(stack)?)?;
+                    if state.count < times {
+                        state.count += 1;
+                    } else {
+                        state.count = 0;
+                        pc = jump(pc, *offset);
+                    }
+                }
+                Instruction::Continue(block) => return Next::continue_(*block),
+                Instruction::Loop(block) => return Next::loop_(*block),
+            }
+        }
+        Ok(Next::None)
+    }
+
+    fn debug_info(&self) -> DebugInfo {
+        DebugInfo {
+            thread_id: self.runtime.thread_id(),
+            block_info: self.block.block_info(),
+        }
+    }
+}
+
+#[async_trait]
+impl Block for Compiled {
+    fn block_info(&self) -> BlockInfo {
+        self.block.block_info()
+    }
+
+    fn block_inputs(&self) -> BlockInputsPartial {
+        self.block.block_inputs()
+    }
+
+    async fn value(&self) -> Result<Value> {
+        let mut stack: Vec<Value> = Vec::new();
+        self.run(&mut BlockState::default(), &mut stack).await?;
+        pop(&mut stack)
+    }
+
+    fn state(&self) -> BlockState {
+        self.state
+    }
+
+    fn set_state(&mut self, state: BlockState) {
+        self.state = state;
+    }
+
+    async fn execute(&mut self) -> Result<Next> {
+        let mut state = self.state;
+        let result = self.run(&mut state, &mut Vec::new()).await;
+        self.state = state;
+        result
+    }
+}
+
+fn jump(pc: usize, offset: isize) -> usize {
+    (pc as isize + offset) as usize
+}
+
+fn pop(stack: &mut Vec<Value>) -> Result<Value> {
+    stack
+        .pop()
+        .ok_or_else(|| Error::msg("value stack is empty"))
+}
+
+fn pop_numbers(stack: &mut Vec<Value>) -> Result<(f64, f64)> {
+    let b: f64 = pop(stack)?.try_into()?;
+    let a: f64 = pop(stack)?.try_into()?;
+    Ok((a, b))
+}
+
+fn pop_booleans(stack: &mut Vec<Value>) -> Result<(bool, bool)> {
+    let b: bool = pop(stack)?.try_into()?;
+    let a: bool = pop(stack)?.try_into()?;
+    Ok((a, b))
+}
+
+/// Profiling and tracing wrap the reporters of the trait objects, so blocks are not compiled when
+/// either is enabled.
+fn enabled(runtime: &Runtime) -> bool {
+    runtime.global.profiler.is_none() && runtime.global.tracer.is_none()
+}
+
+/// Compiles a stack block. Returns the block if its opcode or one of its inputs cannot be
+/// compiled.
+pub fn compile_stack(
+    block: Box<dyn Block + Send + Sync>,
+    hat: BlockID,
+    runtime: &Runtime,
+    infos: &HashMap<BlockID, file::Block>,
+) -> Box<dyn Block + Send + Sync> {
+    let info = match infos.get(&block.block_info().id) {
+        Some(info) if enabled(runtime) => info,
+        _ => return block,
+    };
+    let mut compiler = Compiler::new(hat, runtime, infos, true);
+    match compiler.stack_block(info, true, End::Thread) {
+        Some(()) => Box::new(compiler.finish(block)),
+        None => block,
+    }
+}
+
+/// Compiles a reporter if it and all of its inputs have instructions.
+pub fn compile_reporter(
+    block: Box<dyn Block + Send + Sync>,
+    runtime: &Runtime,
+    infos: &HashMap<BlockID, file::Block>,
+) -> Box<dyn Block + Send + Sync> {
+    let id = block.block_info().id;
+    if !enabled(runtime) || !infos.contains_key(&id) {
+        return block;
+    }
+    let mut compiler = Compiler::new(id, runtime, infos, false);
+    match compiler.reporter(id) {
+        Some(()) => Box::new(compiler.finish(block)),
+        None => block,
+    }
+}
+
+/// Most blocks that are inlined after a stack block
+const MAX_INLINED_BLOCKS: usize = 64;
+
+struct Compiler<'a> {
+    hat: BlockID,
+    runtime: &'a Runtime,
+    infos: &'a HashMap<BlockID, file::Block>,
+    /// Whether reporters without instructions are built as trait objects
+    fallback: bool,
+    /// Whether the blocks after a stack block and the substacks of if blocks are compiled into
+    /// it, so that the thread runs them in one step. Breakpoints on inlined blocks are not hit,
+    /// so this is only done for optimized scripts.
+    inline: bool,
+    inlined: usize,
+    instructions: Vec<Instruction>,
+    reporters: Vec<Box<dyn Block + Send + Sync>>,
+    /// Jumps to each label that are patched when the label is bound
+    labels: Vec<Vec<usize>>,
+}
+
+/// Where the instructions go after the last block of a stack.
+#[derive(Debug, Copy, Clone)]
+enum End {
+    /// Returns to the thread, which continues with the loop block on its loop stack
+    Thread,
+    /// Jumps to the label after an inlined if block
+    Label(usize),
+}
+
+/// Lengths to roll back to when a block cannot be inlined.
+struct Checkpoint {
+    instructions: usize,
+    reporters: usize,
+    labels: usize,
+    inlined: usize,
+}
+
+impl<'a> Compiler<'a> {
+    fn new(
+        hat: BlockID,
+        runtime: &'a Runtime,
+        infos: &'a HashMap<BlockID, file::Block>,
+        fallback: bool,
+    ) -> Self {
+        Self {
+            hat,
+            runtime,
+            infos,
+            fallback,
+            // Inlined blocks never become the current block of the thread, so coverage would not
+            // record them
+            inline: runtime.global.optimize && runtime.global.coverage.is_none(),
+            inlined: 0,
+            instructions: Vec::new(),
+            reporters: Vec::new(),
+            labels: Vec::new(),
+        }
+    }
+
+    fn finish(self, block: Box<dyn Block + Send + Sync>) -> Compiled {
+        Compiled {
+            block,
+            instructions: self.instructions,
+            reporters: self.reporters,
+            runtime: self.runtime.clone(),
+            state: BlockState::default(),
+        }
+    }
+
+    fn emit(&mut self, instruction: Instruction) -> usize {
+        self.instructions.push(instruction);
+        self.instructions.len() - 1
+    }
+
+    /// Makes the jump at index go to the next instruction that is emitted.
+    fn patch(&mut self, index: usize) {
+        let target = (self.instructions.len() - index - 1) as isize;
+        match &mut self.instructions[index] {
+            Instruction::Jump(offset)
+            | Instruction::JumpIfFalse(offset)
+            | Instruction::JumpIfDone(offset)
+            | Instruction::Count(offset) => *offset = target,
+            instruction => unreachable!("not a jump: {:?}", instruction),
+        }
+    }
+
+    fn label(&mut self) -> usize {
+        self.labels.push(Vec::new());
+        self.labels.len() - 1
+    }
+
+    fn jump_to(&mut self, label: usize) {
+        let index = self.emit(Instruction::Jump(0));
+        self.labels[label].push(index);
+    }
+
+    /// Makes the jumps to the label go to the next instruction that is emitted.
+    fn bind(&mut self, label: usize) {
+        for index in std::mem::take(&mut self.labels[label]) {
+            self.patch(index);
+        }
+    }
+
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            instructions: self.instructions.len(),
+            reporters: self.reporters.len(),
+            labels: self.labels.len(),
+            inlined: self.inlined,
+        }
+    }
+
+    fn rollback(&mut self, checkpoint: Checkpoint) {
+        self.instructions.truncate(checkpoint.instructions);
+        self.reporters.truncate(checkpoint.reporters);
+        self.labels.truncate(checkpoint.labels);
+        for jumps in &mut self.labels {
+            jumps.retain(|&index| index < checkpoint.instructions);
+        }
+        self.inlined = checkpoint.inlined;
+    }
+
+    /// Follows the execute methods of the blocks in the control and data modules. Only the block
+    /// that the thread executes (the head) can loop, because the thread returns to it.
+    fn stack_block(&mut self, info: &'a file::Block, head: bool, end: End) -> Option<()> {
+        let next = info.next;
+        match info.opcode.as_str() {
+            "data_setvariableto" => {
+                self.input(info, "VALUE")?;
+                let slot = self.slot(&field_id(info, "VARIABLE")?)?;
+                self.emit(Instruction::SetVariable(slot));
+                self.continue_to(next, end)?;
+            }
+            "data_changevariableby" => {
+                self.input(info, "VALUE")?;
+                let slot = self.slot(&field_id(info, "VARIABLE")?)?;
+                self.emit(Instruction::ChangeVariable(slot));
+                self.continue_to(next, end)?;
+            }
+            "control_if" | "control_if_else" if self.inline_if(info, end).is_some() => {}
+            _ if !head => return None,
+            "control_if" => {
+                let done = self.emit(Instruction::JumpIfDone(0));
+                self.input(info, "CONDITION")?;
+                let condition = self.emit(Instruction::JumpIfFalse(0));
+                self.branch(substack(info, "SUBSTACK")?, next);
+                self.patch(done);
+                self.patch(condition);
+                self.continue_to(next, end)?;
+            }
+            "control_if_else" => {
+                let done = self.emit(Instruction::JumpIfDone(0));
+                self.input(info, "CONDITION")?;
+                let condition = self.emit(Instruction::JumpIfFalse(0));
+                self.branch(substack(info, "SUBSTACK")?, next);
+                self.patch(condition);
+                self.branch(substack(info, "SUBSTACK2")?, next);
+                self.patch(done);
+                self.continue_to(next, end)?;
+            }
+            "control_repeat" => {
+                self.input(info, "TIMES")?;
+                let count = self.emit(Instruction::Count(0));
+                self.emit(Instruction::Loop(substack(info, "SUBSTACK")?));
+                self.patch(count);
+                self.continue_to(next, end)?;
+            }
+            "control_repeat_until" => {
+                self.input(info, "CONDITION")?;
+                let condition = self.emit(Instruction::JumpIfFalse(0));
+                self.continue_to(next, end)?;
+                self.patch(condition);
+                self.emit(Instruction::Loop(substack(info, "SUBSTACK")?));
+            }
+            "control_forever" => {
+                self.emit(Instruction::Loop(substack(info, "SUBSTACK")?));
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+
+    /// Compiles the substacks of an if or if-else block into it, which needs every block in them
+    /// to be inlined.
+    fn inline_if(&mut self, info: &'a file::Block, end: End) -> Option<()> {
+        if !self.inline {
+            return None;
+        }
+        let checkpoint = self.checkpoint();
+        let result = self.branches(info, end);
+        if result.is_none() {
+            self.rollback(checkpoint);
+        }
+        result
+    }
+
+    fn branches(&mut self, info: &'a file::Block, end: End) -> Option<()> {
+        let after = self.label();
+        self.input(info, "CONDITION")?;
+        let condition = self.emit(Instruction::JumpIfFalse(0));
+        self.continue_to(substack(info, "SUBSTACK")?, End::Label(after))?;
+        self.patch(condition);
+        if info.opcode == "control_if_else" {
+            self.continue_to(substack(info, "SUBSTACK2")?, End::Label(after))?;
+        }
+        self.bind(after);
+        self.continue_to(info.next, end)
+    }
+
+    /// Continues with the next block, which is inlined if it can be so that the thread does not
+    /// return to the scheduler between the blocks.
+    fn continue_to(&mut self, next: Option<BlockID>, end: End) -> Option<()> {
+        let next = match (next, end) {
+            (Some(next), _) => next,
+            (None, End::Thread) => {
+                self.emit(Instruction::Continue(None));
+                return Some(());
+            }
+            (None, End::Label(label)) => {
+                self.jump_to(label);
+                return Some(());
+            }
+        };
+
+        if self.inline && self.inlined < MAX_INLINED_BLOCKS {
+            if let Some(info) = self.infos.get(&next) {
+                let checkpoint = self.checkpoint();
+                self.inlined += 1;
+                if self.stack_block(info, false, end).is_some() {
+                    return Some(());
+                }
+                self.rollback(checkpoint);
+            }
+        }
+        match end {
+            End::Thread => {
+                self.emit(Instruction::Continue(Some(next)));
+                Some(())
+            }
+            // The thread cannot return to the block after an inlined if
+            End::Label(_) => None,
+        }
+    }
+
+    /// Runs the substack and continues after it, or continues with next if it is empty.
+    fn branch(&mut self, substack: Option<BlockID>, next: Option<BlockID>) {
+        match substack {
+            Some(substack) => {
+                self.emit(Instruction::SetDone);
+                self.emit(Instruction::Loop(Some(substack)));
+            }
+            None => {
+                self.emit(Instruction::Continue(next));
+            }
+        }
+    }
+
+    /// Emits the instructions that push the value of the input.
+    fn input(&mut self, info: &'a file::Block, key: &str) -> Option<()> {
+        self.inputs(vec![Task::Input(info.inputs.get(key)?)])
+    }
+
+    fn reporter(&mut self, id: BlockID) -> Option<()> {
+        self.inputs(vec![Task::Reporter(id)])
+    }
+
+    /// Emits the inputs in postfix order. Does not recurse, so nesting is only limited by memory.
+    fn inputs(&mut self, mut tasks: Vec<Task<'a>>) -> Option<()> {
+        let infos = self.infos;
+        while let Some(task) = tasks.pop() {
+            let id = match task {
+                Task::Emit(instruction) => {
+                    self.emit(instruction);
+                    continue;
+                }
+                Task::Input(input) => {
+                    let input_arr = input.as_array()?;
+                    match input_arr.get(1)? {
+                        serde_json::Value::String(block_id) => block_id.as_str().try_into().ok()?,
+                        serde_json::Value::Array(arr) => {
+                            self.primitive(arr)?;
+                            continue;
+                        }
+                        _ => return None,
+                    }
+                }
+                Task::Reporter(id) => id,
+            };
+
+            let info = infos.get(&id)?;
+            if let Some((instruction, keys)) = operator(&info.opcode) {
+                tasks.push(Task::Emit(instruction));
+                for key in keys.iter().rev() {
+                    tasks.push(Task::Input(info.inputs.get(*key)?));
+                }
+                continue;
+            }
+            match info.opcode.as_str() {
+                "data_variable" => {
+                    let slot = self.slot(&field_id(info, "VARIABLE")?)?;
+                    self.emit(Instruction::Variable(slot));
+                }
+                "data_listcontents" => {
+                    let slot = self.slot(&field_id(info, "LIST")?)?;
+                    self.emit(Instruction::Variable(slot));
+                }
+                _ if self.fallback => {
+                    let block = build_block(
+                        id,
+                        self.hat,
+                        self.runtime,
+                        infos,
+                        &mut HashSet::new(),
+                        &mut Vec::new(),
+                    )
+                    .ok()?;
+                    self.emit_reporter(block);
+                }
+                _ => return None,
+            }
+        }
+        Some(())
+    }
+
+    /// Follows value_block_from_input_arr.
+    fn primitive(&mut self, arr: &[serde_json::Value]) -> Option<()> {
+        let value_type = arr.first()?.as_i64()?;
+        let value = arr.get(1)?;
+        match value_type {
+            4..=8 => {
+                let number = value::parse_number(value).ok()?;
+                self.emit(Instruction::Push(Value::Number(number)));
+            }
+            10 | 11 => {
+                let string = match value {
+                    serde_json::Value::String(s) => s.clone(),
+                    _ => value.to_string(),
+                };
+                self.emit(Instruction::Push(Value::String(string)));
+            }
+            12 | 13 => {
+                let slot = self.slot(arr.get(2)?.as_str()?)?;
+                self.emit(Instruction::Variable(slot));
+            }
+            _ => {
+                let block = value_block_from_input_arr(arr, self.runtime.clone()).ok()?;
+                self.emit_reporter(block);
+            }
+        }
+        Some(())
+    }
+
+    /// Variables that do not exist are left to the blocks, which return an error when they run.
+    fn slot(&self, id: &str) -> Option<usize> {
+        self.runtime.global.variables.slot(id)
+    }
+
+    fn emit_reporter(&mut self, block: Box<dyn Block + Send + Sync>) {
+        self.reporters.push(block);
+        self.emit(Instruction::Reporter(self.reporters.len() - 1));
+    }
+}
+
+enum Task<'a> {
+    Input(&'a serde_json::Value),
+    Reporter(BlockID),
+    Emit(Instruction),
+}
+
+/// Instruction and input names of an operator
+fn operator(opcode: &str) -> Option<(Instruction, &'static [&'static str])> {
+    Some(match opcode {
+        "operator_add" => (Instruction::Add, &["NUM1", "NUM2"]),
+        "operator_subtract" => (Instruction::Subtract, &["NUM1", "NUM2"]),
+        "operator_multiply" => (Instruction::Multiply, &["NUM1", "NUM2"]),
+        "operator_divide" => (Instruction::Divide, &["NUM1", "NUM2"]),
+        "operator_lt" => (Instruction::LessThan, &["OPERAND1", "OPERAND2"]),
+        "operator_gt" => (Instruction::GreaterThan, &["OPERAND1", "OPERAND2"]),
+        "operator_equals" => (Instruction::Equals, &["OPERAND1", "OPERAND2"]),
+        "operator_and" => (Instruction::And, &["OPERAND1", "OPERAND2"]),
+        "operator_or" => (Instruction::Or, &["OPERAND1", "OPERAND2"]),
+        "operator_not" => (Instruction::Not, &["OPERAND"]),
+        _ => return None,
+    })
+}
+
+fn field_id(info: &file::Block, key: &str) -> Option<String> {
+    Some(get_field_value(info.fields.get(key)?, 1).ok()?.to_string())
+}
+
+/// Returns None if the input is invalid and Some(None) if the substack is empty.
+fn substack(info: &file::Block, key: &str) -> Option<Option<BlockID>> {
+    let input = match info.inputs.get(key) {
+        Some(input) => input,
+        None => return Some(None),
+    };
+    match input.as_array()?.get(1)? {
+        serde_json::Value::String(id) => Some(Some(id.as_str().try_into().ok()?)),
+        serde_json::Value::Null => Some(None),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate test;
+
+    use super::*;
+    use crate::coverage::Coverage;
+    use crate::profiler::Profiler;
+    use crate::testing::{self, file_block, global, id};
+    use crate::thread::{Thread, ThreadState};
+
+    fn set_variable(opcode: &str, next: Option<&str>, value: serde_json::Value) -> file::Block {
+        let mut block = file_block(opcode, next, serde_json::json!({ "VALUE": value }));
+        block.fields.insert(
+            "VARIABLE".to_string(),
+            vec![Some("n".to_string()), Some("n_id".to_string())],
+        );
+        block
+    }
+
+    fn runtime(profiler: bool, optimize: bool) -> Runtime {
+        let mut global = global(&["n"]);
+        if profiler {
+            global.profiler = Some(Arc::new(Profiler::default()));
+        }
+        global.optimize = optimize;
+        testing::runtime(Arc::new(global))
+    }
+
+    /// n = 0; repeat 4 { if n > 1 { n += 10 } else { n += 1 }; if n = 2 { n = 20 } }
+    fn script() -> HashMap<BlockID, file::Block> {
+        let mut infos: HashMap<BlockID, file::Block> = HashMap::new();
+        infos.insert(
+            id("set"),
+            set_variable(
+                "data_setvariableto",
+                Some("repeat"),
+                serde_json::json!([1, [10, "0"]]),
+            ),
+        );
+        infos.insert(
+            id("repeat"),
+            file_block(
+                "control_repeat",
+                None,
+                serde_json::json!({"TIMES": [1, [6, "4"]], "SUBSTACK": [2, "if_else"]}),
+            ),
+        );
+        infos.insert(
+            id("if_else"),
+            file_block(
+                "control_if_else",
+                Some("if"),
+                serde_json::json!({
+                    "CONDITION": [2, "gt"],
+                    "SUBSTACK": [2, "add_ten"],
+                    "SUBSTACK2": [2, "add_one"],
+                }),
+            ),
+        );
+        infos.insert(
+            id("gt"),
+            file_block(
+                "operator_gt",
+                None,
+                serde_json::json!({"OPERAND1": [3, [12, "n", "n_id"], [10, ""]], "OPERAND2": [1, [10, "1"]]}),
+            ),
+        );
+        infos.insert(
+            id("add_ten"),
+            set_variable(
+                "data_changevariableby",
+                None,
+                serde_json::json!([1, [4, "10"]]),
+            ),
+        );
+        infos.insert(
+            id("add_one"),
+            set_variable(
+                "data_changevariableby",
+                None,
+                serde_json::json!([1, [4, "1"]]),
+            ),
+        );
+        infos.insert(
+            id("if"),
+            file_block(
+                "control_if",
+                None,
+                serde_json::json!({"CONDITION": [2, "equals"], "SUBSTACK": [2, "set_twenty"]}),
+            ),
+        );
+        infos.insert(
+            id("equals"),
+            file_block(
+                "operator_equals",
+                None,
+                serde_json::json!({"OPERAND1": [3, [12, "n", "n_id"], [10, ""]], "OPERAND2": [1, [4, "2"]]}),
+            ),
+        );
+        infos.insert(
+            id("set_twenty"),
+            set_variable(
+                "data_setvariableto",
+                None,
+                serde_json::json!([1, [10, "20"]]),
+            ),
+        );
+        infos
+    }
+
+    #[test]
+    fn test_compile() {
+        let infos = script();
+        let runtime = runtime(false, false);
+        let mut compiler = Compiler::new(id("set"), &runtime, &infos, true);
+        compiler
+            .stack_block(&infos[&id("if_else")], true, End::Thread)
+            .unwrap();
+        assert_eq!(
+            compiler.instructions,
+            vec![
+                Instruction::JumpIfDone(8),
+                Instruction::Variable(0),
+                Instruction::Push(Value::String("1".to_string())),
+                Instruction::GreaterThan,
+                Instruction::JumpIfFalse(2),
+                Instruction::SetDone,
+                Instruction::Loop(Some(id("add_ten"))),
+                Instruction::SetDone,
+                Instruction::Loop(Some(id("add_one"))),
+                Instruction::Continue(Some(id("if"))),
+            ]
+        );
+
+        let mut compiler = Compiler::new(id("set"), &runtime, &infos, true);
+        compiler
+            .stack_block(&infos[&id("repeat")], true, End::Thread)
+            .unwrap();
+        assert_eq!(
+            compiler.instructions,
+            vec![
+                Instruction::Push(Value::Number(4.0)),
+                Instruction::Count(1),
+                Instruction::Loop(Some(id("if_else"))),
+                Instruction::Continue(None),
+            ]
+        );
+
+        // Reporters without instructions are only called from stack blocks
+        let mut infos = infos;
+        infos.insert(
+            id("random"),
+            file_block("operator_random", None, serde_json::json!({})),
+        );
+        infos.get_mut(&id("gt")).unwrap().inputs.insert(
+            "OPERAND2".to_string(),
+            serde_json::json!([3, "random", [10, "1"]]),
+        );
+        let mut compiler = Compiler::new(id("set"), &runtime, &infos, true);
+        compiler.reporter(id("gt")).unwrap();
+        assert_eq!(compiler.instructions[1], Instruction::Reporter(0));
+        let mut compiler = Compiler::new(id("set"), &runtime, &infos, false);
+        assert!(compiler.reporter(id("gt")).is_none());
+    }
+
+    /// Runs the script to the end. Returns the value of n and the number of steps.
+    async fn run(runtime: Runtime, infos: &HashMap<BlockID, file::Block>) -> (Value, usize) {
+        let global = runtime.global.clone();
+        let mut thread = Thread::start(id("set"), runtime, infos).unwrap();
+        let mut steps: usize = 0;
+        while thread.state() == ThreadState::Running {
+            thread.step().await.unwrap();
+            steps += 1;
+        }
+        (global.variables.get("n_id").await.unwrap(), steps)
+    }
+
+    #[test]
+    fn test_inline() {
+        let infos = script();
+        let runtime = runtime(false, true);
+        let mut compiler = Compiler::new(id("set"), &runtime, &infos, true);
+        compiler
+            .stack_block(&infos[&id("if_else")], true, End::Thread)
+            .unwrap();
+        assert_eq!(
+            compiler.instructions,
+            vec![
+                Instruction::Variable(0),
+                Instruction::Push(Value::String("1".to_string())),
+                Instruction::GreaterThan,
+                Instruction::JumpIfFalse(3),
+                Instruction::Push(Value::Number(10.0)),
+                Instruction::ChangeVariable(0),
+                Instruction::Jump(3),
+                Instruction::Push(Value::Number(1.0)),
+                Instruction::ChangeVariable(0),
+                Instruction::Jump(0),
+                Instruction::Variable(0),
+                Instruction::Push(Value::Number(2.0)),
+                Instruction::Equals,
+                Instruction::JumpIfFalse(3),
+                Instruction::Push(Value::String("20".to_string())),
+                Instruction::SetVariable(0),
+                Instruction::Jump(0),
+                Instruction::Continue(None),
+            ]
+        );
+
+        // Loops are not inlined
+        let mut compiler = Compiler::new(id("set"), &runtime, &infos, true);
+        compiler
+            .stack_block(&infos[&id("set")], true, End::Thread)
+            .unwrap();
+        assert_eq!(
+            compiler.instructions,
+            vec![
+                Instruction::Push(Value::String("0".to_string())),
+                Instruction::SetVariable(0),
+                Instruction::Continue(Some(id("repeat"))),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compiled_thread() {
+        let mut results: Vec<Value> = Vec::new();
+        for &profiler in &[false, true] {
+            results.push(run(runtime(profiler, false), &script()).await.0);
+        }
+        // 1, 2, 20, 30, 40. The second if is checked in every iteration.
+        assert_eq!(results, vec![Value::Number(40.0), Value::Number(40.0)]);
+
+        let (compiled, compiled_steps) = run(runtime(false, false), &script()).await;
+        let (inlined, inlined_steps) = run(runtime(false, true), &script()).await;
+        assert_eq!(inlined, compiled);
+        // set, then the repeat block and the inlined substack in each iteration, then the repeat
+        // block once more
+        assert_eq!(inlined_steps, 10);
+        assert!(inlined_steps < compiled_steps);
+    }
+
+    #[tokio::test]
+    async fn test_coverage() {
+        let mut global = global(&["n"]);
+        global.optimize = true;
+        global.coverage = Some(Arc::new(Coverage::default()));
+        let infos = script();
+        let mut thread =
+            Thread::start(id("set"), testing::runtime(Arc::new(global)), &infos).unwrap();
+        let mut executed: HashSet<BlockID> = HashSet::new();
+        while thread.state() == ThreadState::Running {
+            executed.insert(thread.curr_block());
+            thread.step().await.unwrap();
+        }
+        for block_id in &[
+            "set",
+            "repeat",
+            "if_else",
+            "add_ten",
+            "add_one",
+            "if",
+            "set_twenty",
+        ] {
+            assert!(executed.contains(&id(block_id)), "{}", block_id);
+        }
+    }
+
+    /// Runs the script with 1000 iterations, so that starting the thread takes little time.
+    fn bench_run(b: &mut test::Bencher, optimize: bool) {
+        let mut infos = script();
+        infos
+            .get_mut(&id("repeat"))
+            .unwrap()
+            .inputs
+            .insert("TIMES".to_string(), serde_json::json!([1, [6, "1000"]]));
+        let executor = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        b.iter(|| executor.block_on(run(runtime(false, optimize), &infos)));
+    }
+
+    #[bench]
+    fn bench_compiled(b: &mut test::Bencher) {
+        bench_run(b, false);
+    }
+
+    #[bench]
+    fn bench_inlined(b: &mut test::Bencher) {
+        bench_run(b, true);
+    }
+}
@@ -81,16 +81,28 @@ impl Block for If {
         }
     }
 
+    fn state(&self) -> BlockState {
+        BlockState {
+            done: self.done,
+            ..BlockState::default()
+        }
+    }
+
+    fn set_state(&mut self, state: BlockState) {
+        self.done = state.done;
+    }
+
     async fn execute(&mut self) -> Result<Next> {
         if self.done {
             self.done = false;
             return Next::continue_(self.next);
         }
 
-        self.done = true;
-
-        if self.condition.value().await?.try_into()? {
-            return Next::loop_(self.substack);
+        if value::truthy(&self.condition.value().await?)? {
+            if let Some(substack) = self.substack {
+                self.done = true;
+                return Ok(Next::Loop(substack));
+            }
         }
 
         Next::continue_(self.next)
@@ -250,9 +262,20 @@ impl Block for Repeat {
         }
     }
 
+    fn state(&self) -> BlockState {
+        BlockState {
+            count: self.count,
+            ..BlockState::default()
+        }
+    }
+
+    fn set_state(&mut self, state: BlockState) {
+        self.count = state.count;
+    }
+
     async fn execute(&mut self) -> Result<Next> {
-        let times: f64 = self.times.value().await?.try_into()?;
-        if self.count < times as usize {
+        let times = value::repeat_count(&self.times.value().await?)?;
+        if self.count < times {
             // Loop until count equals times
             self.count += 1;
             return Next::loop_(self.substack);
@@ -315,7 +338,7 @@ impl Block for RepeatUntil {
     }
 
     async fn execute(&mut self) -> Result<Next> {
-        let condition: bool = self.condition.value().await?.try_into()?;
+        let condition = value::truthy(&self.condition.value().await?)?;
 
         if condition {
             return Next::continue_(self.next);
@@ -385,19 +408,35 @@ impl Block for IfElse {
         }
     }
 
+    fn state(&self) -> BlockState {
+        BlockState {
+            done: self.done,
+            ..BlockState::default()
+        }
+    }
+
+    fn set_state(&mut self, state: BlockState) {
+        self.done = state.done;
+    }
+
     async fn execute(&mut self) -> Result<Next> {
         if self.done {
             self.done = false;
             return Next::continue_(self.next);
         }
 
-        self.done = true;
-
-        if self.condition.value().await?.try_into()? {
-            return Next::loop_(self.substack_true);
+        let substack = if value::truthy(&self.condition.value().await?)? {
+            self.substack_true
+        } else {
+            self.substack_false
+        };
+        match substack {
+            Some(substack) => {
+                self.done = true;
+                Ok(Next::Loop(substack))
+            }
+            None => Next::continue_(self.next),
         }
-
-        Next::loop_(self.substack_false)
     }
 }
 
@@ -452,7 +491,7 @@ impl Block for WaitUntil {
         let mut interval = interval(Duration::from_millis(100));
         loop {
             interval.tick().await;
-            if self.condition.value().await?.try_into()? {
+            if value::truthy(&self.condition.value().await?)? {
                 return Next::continue_(self.next);
             }
         }
@@ -673,7 +712,15 @@ impl Block for CreateCloneOf {
     }
 
     async fn execute(&mut self) -> Result<Next> {
-        let sprite_id = self.runtime.thread_id().sprite_id;
+        let option = self.clone_option.value().await?.to_string();
+        let sprite_id = if option == "_myself_" {
+            self.runtime.thread_id().sprite_id
+        } else {
+            match self.runtime.global.sprite_id(&option) {
+                Some(sprite_id) => sprite_id,
+                None => return Next::continue_(self.next),
+            }
+        };
         self.runtime
             .global
             .broadcaster
@@ -685,11 +732,16 @@ impl Block for CreateCloneOf {
 #[derive(Debug)]
 pub struct CreateCloneOfMenu {
     id: BlockID,
+    /// "_myself_" or sprite name
+    option: String,
 }
 
 impl CreateCloneOfMenu {
     pub fn new(id: BlockID) -> Self {
-        Self { id }
+        Self {
+            id,
+            option: "_myself_".to_string(),
+        }
     }
 }
 
@@ -703,6 +755,144 @@ impl Block for CreateCloneOfMenu {
     }
 
     fn block_inputs(&self) -> BlockInputsPartial {
-        BlockInputsPartial::new(self.block_info(), vec![], vec![], vec![])
+        BlockInputsPartial::new(
+            self.block_info(),
+            vec![("CLONE_OPTION", self.option.clone())],
+            vec![],
+            vec![],
+        )
+    }
+
+    fn set_field(&mut self, key: &str, field: &[Option<String>]) -> Result<()> {
+        if key == "CLONE_OPTION" {
+            self.option = get_field_value(field, 0)?.to_string();
+        }
+        Ok(())
+    }
+
+    async fn value(&self) -> Result<Value> {
+        Ok(Value::String(self.option.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sprite::SpriteID;
+    use crate::testing::{self, id};
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    /// Reports the value of a flag that the test changes
+    #[derive(Debug)]
+    struct Flag(Arc<AtomicBool>);
+
+    #[async_trait]
+    impl Block for Flag {
+        fn block_info(&self) -> BlockInfo {
+            BlockInfo {
+                name: "Flag",
+                id: BlockID::pseudo_id(),
+            }
+        }
+
+        fn block_inputs(&self) -> BlockInputsPartial {
+            BlockInputsPartial::new(self.block_info(), vec![], vec![], vec![])
+        }
+
+        async fn value(&self) -> Result<Value> {
+            Ok(self.0.load(Ordering::SeqCst).into())
+        }
+    }
+
+    fn next_block(next: Next) -> (&'static str, Option<BlockID>) {
+        match next {
+            Next::None => ("none", None),
+            Next::Continue(b) => ("continue", Some(b)),
+            Next::Loop(b) => ("loop", Some(b)),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_if() {
+        let condition = Arc::new(AtomicBool::new(false));
+        let mut block = If::new(id("if"));
+        block.set_input("CONDITION", Box::new(Flag(condition.clone())));
+        block.set_substack("SUBSTACK", id("substack"));
+        block.set_substack("next", id("next"));
+
+        // The condition is checked again after it was false
+        let next = block.execute().await.unwrap();
+        assert_eq!(next_block(next), ("continue", Some(id("next"))));
+        condition.store(true, Ordering::SeqCst);
+        let next = block.execute().await.unwrap();
+        assert_eq!(next_block(next), ("loop", Some(id("substack"))));
+        let next = block.execute().await.unwrap();
+        assert_eq!(next_block(next), ("continue", Some(id("next"))));
+
+        // An empty substack continues with the next block
+        let mut block = If::new(id("if"));
+        block.set_input("CONDITION", Box::new(Flag(condition.clone())));
+        block.set_substack("next", id("next"));
+        let next = block.execute().await.unwrap();
+        assert_eq!(next_block(next), ("continue", Some(id("next"))));
+        assert_eq!(block.state(), BlockState::default());
+    }
+
+    #[tokio::test]
+    async fn test_if_else() {
+        let condition = Arc::new(AtomicBool::new(false));
+        let mut block = IfElse::new(id("if_else"));
+        block.set_input("CONDITION", Box::new(Flag(condition.clone())));
+        block.set_substack("SUBSTACK", id("true"));
+        block.set_substack("next", id("next"));
+
+        // The empty else branch continues with the next block
+        let next = block.execute().await.unwrap();
+        assert_eq!(next_block(next), ("continue", Some(id("next"))));
+        condition.store(true, Ordering::SeqCst);
+        let next = block.execute().await.unwrap();
+        assert_eq!(next_block(next), ("loop", Some(id("true"))));
+        let next = block.execute().await.unwrap();
+        assert_eq!(next_block(next), ("continue", Some(id("next"))));
+    }
+
+    #[tokio::test]
+    async fn test_create_clone_of() {
+        let targets = [
+            file::Target {
+                is_stage: true,
+                ..file::Target::default()
+            },
+            file::Target {
+                name: "Sprite2".to_string(),
+                ..file::Target::default()
+            },
+        ];
+        let runtime = testing::runtime(Arc::new(testing::global_with_sprites(&targets)));
+        let mut channel = runtime.global.broadcaster.subscribe();
+        let menu = |name: &str| {
+            let mut menu = CreateCloneOfMenu::new(id("menu"));
+            menu.set_field("CLONE_OPTION", &[Some(name.to_string())])
+                .unwrap();
+            Box::new(menu)
+        };
+
+        let mut block = CreateCloneOf::new(id("clone"), runtime.clone());
+        block.set_input("CLONE_OPTION", menu("Sprite2"));
+        block.set_substack("next", id("next"));
+        let next = block.execute().await.unwrap();
+        assert_eq!(next_block(next), ("continue", Some(id("next"))));
+        match channel.try_recv().unwrap() {
+            BroadcastMsg::Clone(sprite_id) => assert_eq!(sprite_id, SpriteID::new(1)),
+            msg => panic!("{:?}", msg),
+        }
+
+        // A menu can name a sprite that does not exist
+        let mut block = CreateCloneOf::new(id("clone"), runtime);
+        block.set_input("CLONE_OPTION", menu("Sprite3"));
+        block.set_substack("next", id("next"));
+        let next = block.execute().await.unwrap();
+        assert_eq!(next_block(next), ("continue", Some(id("next"))));
+        assert!(channel.try_recv().is_err());
     }
 }
@@ -1,4 +1,5 @@
 use super::*;
+use crate::vm::DebugInfo;
 
 pub fn get_block(
     name: &str,
@@ -77,7 +78,14 @@ impl Block for SetVariable {
         self.runtime
             .global
             .variables
-            .set(&self.variable_id, value)
+            .set(
+                &self.variable_id,
+                value,
+                DebugInfo {
+                    thread_id: self.runtime.thread_id(),
+                    block_info: self.block_info(),
+                },
+            )
             .await?;
         Next::continue_(self.next)
     }
@@ -146,10 +154,17 @@ impl Block for ChangeVariable {
         self.runtime
             .global
             .variables
-            .set_with(&self.variable_id, |v| {
-                let previous_float: f64 = v.try_into().unwrap_or(0.0);
-                (previous_float + value).into()
-            })
+            .set_with(
+                &self.variable_id,
+                |v| {
+                    let previous_float: f64 = v.try_into().unwrap_or(0.0);
+                    (previous_float + value).into()
+                },
+                DebugInfo {
+                    thread_id: self.runtime.thread_id(),
+                    block_info: self.block_info(),
+                },
+            )
             .await?;
         Next::continue_(self.next)
     }
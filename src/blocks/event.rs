@@ -119,6 +119,17 @@ impl Block for WhenBroadcastReceived {
         Ok(())
     }
 
+    fn state(&self) -> BlockState {
+        BlockState {
+            done: self.started,
+            ..BlockState::default()
+        }
+    }
+
+    fn set_state(&mut self, state: BlockState) {
+        self.started = state.done;
+    }
+
     async fn execute(&mut self) -> Result<Next> {
         if self.started {
             self.runtime
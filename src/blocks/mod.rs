@@ -1,9 +1,11 @@
+mod bytecode;
 mod control;
 mod data;
 mod event;
 mod looks;
 mod motion;
 mod operator;
+pub mod optimizer;
 mod pen;
 mod sensing;
 mod sound;
@@ -11,9 +13,13 @@ pub mod value;
 
 use super::*;
 use crate::blocks::value::value_block_from_input_arr;
-use crate::file::BlockID;
+use crate::file::{BlockID, LoadMode};
+use crate::profiler::{ProfiledReporter, Stack};
 use crate::runtime::Runtime;
+use crate::sprite::is_hat;
+use crate::tracer::TracedInput;
 use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 use std::convert::TryInto;
 use std::time::Duration;
 use tokio::time::sleep;
@@ -24,6 +30,10 @@ fn get_block(
     runtime: Runtime,
     info: &file::Block,
 ) -> Result<Box<dyn Block + Send + Sync>> {
+    if let Some(block) = value::get_block(&info.opcode, runtime.clone()) {
+        return Ok(block);
+    }
+
     let (category, name) = info.opcode.split_once('_').ok_or_else(|| {
         Error::msg(format!(
             "block \"{}\": opcode {} does not exist",
@@ -61,6 +71,11 @@ fn get_block(
     }
 }
 
+/// Returns true if the VM can run blocks with the opcode of info.
+pub fn is_supported(runtime: Runtime, info: &file::Block) -> bool {
+    get_block(BlockID::pseudo_id(), runtime, info).is_ok()
+}
+
 fn add_error_context(id: BlockID, category: &str, error: Error) -> Error {
     ScratchError::BlockInitialization {
         id,
@@ -87,6 +102,14 @@ pub trait Block: std::fmt::Debug + Sync + Send {
         Ok(())
     }
 
+    /// State that the block keeps between executions, which is saved in snapshots.
+    fn state(&self) -> BlockState {
+        BlockState::default()
+    }
+
+    #[allow(unused_variables)]
+    fn set_state(&mut self, state: BlockState) {}
+
     async fn value(&self) -> Result<Value> {
         Err(Error::msg("this block does not return a value"))
     }
@@ -96,6 +119,15 @@ pub trait Block: std::fmt::Debug + Sync + Send {
     }
 }
 
+/// Kept between executions of if, repeat and hat blocks
+#[derive(Debug, Copy, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BlockState {
+    /// Whether the block continues with its next block when it runs again
+    pub done: bool,
+    /// Iterations of a repeat block
+    pub count: usize,
+}
+
 #[derive(Debug)]
 pub enum Next {
     None,
@@ -161,34 +193,154 @@ impl BlockInputsPartial {
     }
 }
 
+/// Reporters are evaluated and dropped recursively, so they cannot be nested deeper than this.
+pub const MAX_REPORTER_DEPTH: usize = 1000;
+
+/// Builds every block that can be reached from top_block_id. Blocks in stacks (next and substacks)
+/// are returned in the map and reporters are set as inputs of the block that uses them.
+///
+/// Construction does not recurse, so the length of scripts and the nesting of substacks are only
+/// limited by memory. Reporters nested deeper than MAX_REPORTER_DEPTH are an error.
 pub fn block_tree(
     top_block_id: BlockID,
     runtime: Runtime,
     infos: &HashMap<BlockID, file::Block>,
 ) -> Result<(BlockID, HashMap<BlockID, Box<dyn Block + Send + Sync>>)> {
-    let info = match infos.get(&top_block_id) {
-        Some(b) => b,
-        None => {
-            return Err(Error::msg(format!(
-                "could not find block: {}",
-                top_block_id
-            )))
+    let mut result: HashMap<BlockID, Box<dyn Block + Send + Sync>> = HashMap::new();
+    let mut visited: HashSet<BlockID> = HashSet::new();
+    // Blocks in stacks that have yet to be built
+    let mut stack_blocks: Vec<BlockID> = vec![top_block_id];
+
+    while let Some(stack_block_id) = stack_blocks.pop() {
+        let block = build_block(
+            stack_block_id,
+            top_block_id,
+            &runtime,
+            infos,
+            &mut visited,
+            &mut stack_blocks,
+        )?;
+        result.insert(block.block_info().id, block);
+    }
+
+    Ok((top_block_id, result))
+}
+
+/// A block that is waiting for its reporter inputs to be built.
+struct PartialBlock {
+    block: Box<dyn Block + Send + Sync>,
+    /// Input name and block ID of reporters that have yet to be built
+    inputs: Vec<(String, BlockID)>,
+    /// Input name of this block in the parent block
+    parent_input: String,
+}
+
+/// Builds the block and its reporter inputs. Blocks in its stacks are added to stack_blocks.
+fn build_block(
+    id: BlockID,
+    hat: BlockID,
+    runtime: &Runtime,
+    infos: &HashMap<BlockID, file::Block>,
+    visited: &mut HashSet<BlockID>,
+    stack_blocks: &mut Vec<BlockID>,
+) -> Result<Box<dyn Block + Send + Sync>> {
+    let mut partial_blocks: Vec<PartialBlock> = vec![partial_block(
+        id,
+        String::new(),
+        runtime,
+        infos,
+        visited,
+        stack_blocks,
+    )?];
+
+    loop {
+        let last = partial_blocks.last_mut().unwrap();
+        match last.inputs.pop() {
+            Some((input_name, input_id)) => {
+                if partial_blocks.len() > MAX_REPORTER_DEPTH {
+                    return Err(Error::msg(format!(
+                        "reporters are nested deeper than {}: {}",
+                        MAX_REPORTER_DEPTH, input_id
+                    )));
+                }
+                let partial =
+                    partial_block(input_id, input_name, runtime, infos, visited, stack_blocks)?;
+                partial_blocks.push(partial);
+            }
+            None => {
+                let finished = partial_blocks.pop().unwrap();
+                if partial_blocks.is_empty() {
+                    return Ok(bytecode::compile_stack(finished.block, hat, runtime, infos));
+                }
+
+                let mut input = finished.block;
+                if let Some(profiler) = &runtime.global.profiler {
+                    let mut blocks: Vec<BlockID> = partial_blocks
+                        .iter()
+                        .map(|p| p.block.block_info().id)
+                        .collect();
+                    blocks.push(input.block_info().id);
+                    let stack = Stack {
+                        sprite_name: runtime.sprite_name().clone(),
+                        sprite_id: runtime.original_id(),
+                        hat,
+                        blocks,
+                    };
+                    input = Box::new(ProfiledReporter::new(input, profiler.clone(), stack));
+                }
+                if partial_blocks.len() == 1 {
+                    input = traced_input(runtime, &finished.parent_input, input);
+                    input = bytecode::compile_reporter(input, runtime, infos);
+                }
+                partial_blocks
+                    .last_mut()
+                    .unwrap()
+                    .block
+                    .set_input(&finished.parent_input, input);
+            }
         }
+    }
+}
+
+fn partial_block(
+    id: BlockID,
+    parent_input: String,
+    runtime: &Runtime,
+    infos: &HashMap<BlockID, file::Block>,
+    visited: &mut HashSet<BlockID>,
+    stack_blocks: &mut Vec<BlockID>,
+) -> Result<PartialBlock> {
+    let info = match infos.get(&id) {
+        Some(b) => b,
+        None => return Err(Error::msg(format!("could not find block: {}", id))),
     };
 
-    let mut result: HashMap<BlockID, Box<dyn Block + Send + Sync>> = HashMap::new();
-    let mut block = get_block(top_block_id, runtime.clone(), &info)?;
+    // Also prevents infinite loops in malformed files
+    if !visited.insert(id) {
+        return Err(Error::msg(format!("block is used more than once: {}", id)));
+    }
 
-    if let Some(next_id) = &info.next {
-        let (id, input_blocks) = block_tree(*next_id, runtime.clone(), infos)?;
-        block.set_substack("next", id);
-        result.extend(input_blocks);
+    let mut block = match get_block(id, runtime.clone(), info) {
+        Ok(block) => block,
+        Err(e) => match runtime.global.load_mode {
+            LoadMode::Strict => return Err(e),
+            LoadMode::Lenient => {
+                log::warn!("replacing block with placeholder: {}", e);
+                Box::new(Unsupported::new(id, info))
+            }
+        },
+    };
+    let mut inputs: Vec<(String, BlockID)> = Vec::new();
+
+    if let Some(next_id) = info.next {
+        block.set_substack("next", next_id);
+        stack_blocks.push(next_id);
     }
 
     for (k, input) in &info.inputs {
         let wrap_err = |error: Error| -> Error {
             ScratchError::BlockInput {
-                block_id: top_block_id,
+                block_id: id,
                 input_id: k.clone(),
                 error,
             }
@@ -196,46 +348,31 @@ pub fn block_tree(
         };
         let input_err = || wrap_err(Error::msg("invalid type"));
 
+        // https://en.scratch-wiki.info/wiki/Scratch_File_Format#Blocks
+        // The first element is the shadow type, 1 (shadow), 2 (no shadow) or 3 (obscured
+        // shadow). The second element is the block ID or primitive that is shown, or null if the
+        // input is empty.
         let input_arr = input.as_array().ok_or_else(input_err)?;
         match input_arr.get(1).ok_or_else(input_err)? {
             serde_json::Value::String(block_id) => {
-                let (id, mut blocks) = block_tree(
-                    block_id.as_str().try_into().map_err(wrap_err)?,
-                    runtime.clone(),
-                    infos,
-                )?;
-
+                let input_id: BlockID = block_id.as_str().try_into().map_err(wrap_err)?;
                 if k.starts_with("SUBSTACK") {
-                    block.set_substack(k, id);
-                    result.extend(blocks);
-                } else if let Some(b) = blocks.drain().next() {
-                    block.set_input(k, b.1);
+                    block.set_substack(k, input_id);
+                    stack_blocks.push(input_id);
+                } else {
+                    inputs.push((k.clone(), input_id));
                 }
             }
             serde_json::Value::Array(arr) => {
-                let input_type = input_arr
-                    .get(0)
-                    .ok_or_else(input_err)?
-                    .as_i64()
-                    .ok_or_else(input_err)?;
-
-                let value = match input_type {
-                    // Value
-                    1 => value_block_from_input_arr(arr).map_err(wrap_err)?,
-                    // Variable
-                    2 | 3 => {
-                        let id = arr
-                            .get(2)
-                            .ok_or_else(input_err)?
-                            .as_str()
-                            .ok_or_else(input_err)?;
-                        Box::new(value::Variable::new(id.to_string(), runtime.clone()))
-                            as Box<dyn Block + Send + Sync>
-                    }
-                    _ => return Err(input_err()),
-                };
+                let mut value =
+                    value_block_from_input_arr(arr, runtime.clone()).map_err(wrap_err)?;
+                // Only the stack block has no parent
+                if parent_input.is_empty() {
+                    value = traced_input(runtime, k, value);
+                }
                 block.set_input(k, value);
             }
+            serde_json::Value::Null => {}
             _ => return Err(input_err()),
         };
     }
@@ -245,7 +382,7 @@ pub fn block_tree(
             Ok(_) => {}
             Err(error) => {
                 return Err(ScratchError::BlockField {
-                    block_id: top_block_id,
+                    block_id: id,
                     field_id: k.clone(),
                     error,
                 }
@@ -254,9 +391,28 @@ pub fn block_tree(
         }
     }
 
-    let id = block.block_info().id;
-    result.insert(id, block);
-    Ok((id, result))
+    Ok(PartialBlock {
+        block,
+        inputs,
+        parent_input,
+    })
+}
+
+/// Wraps an input of a stack block so that its values are traced if tracing is enabled.
+fn traced_input(
+    runtime: &Runtime,
+    input_name: &str,
+    block: Box<dyn Block + Send + Sync>,
+) -> Box<dyn Block + Send + Sync> {
+    match &runtime.global.tracer {
+        Some(tracer) => Box::new(TracedInput::new(
+            block,
+            input_name,
+            tracer.clone(),
+            runtime.thread_id(),
+        )),
+        None => block,
+    }
 }
 
 #[derive(Debug)]
@@ -311,6 +467,69 @@ impl Block for EmptyFalse {
     }
 }
 
+/// Placeholder for a block with an unsupported opcode. Its inputs are ignored and execution
+/// falls through to next. Scripts under an unsupported hat never start.
+#[derive(Debug)]
+pub struct Unsupported {
+    id: BlockID,
+    opcode: String,
+    hat: bool,
+    next: Option<BlockID>,
+}
+
+impl Unsupported {
+    pub fn new(id: BlockID, info: &file::Block) -> Self {
+        Self {
+            id,
+            opcode: info.opcode.clone(),
+            hat: is_hat(info),
+            next: None,
+        }
+    }
+}
+
+#[async_trait]
+impl Block for Unsupported {
+    fn block_info(&self) -> BlockInfo {
+        BlockInfo {
+            name: "Unsupported",
+            id: self.id,
+        }
+    }
+
+    fn block_inputs(&self) -> BlockInputsPartial {
+        BlockInputsPartial::new(
+            self.block_info(),
+            vec![("opcode", self.opcode.clone())],
+            vec![],
+            vec![("next", &self.next)],
+        )
+    }
+
+    fn set_substack(&mut self, key: &str, block: BlockID) {
+        if key == "next" {
+            self.next = Some(block);
+        }
+    }
+
+    async fn value(&self) -> Result<Value> {
+        log::debug!(
+            "unsupported block {} ({}) returned empty",
+            self.opcode,
+            self.id
+        );
+        Ok(Value::String(String::new()))
+    }
+
+    async fn execute(&mut self) -> Result<Next> {
+        if self.hat {
+            return Ok(Next::None);
+        }
+        log::debug!("unsupported block {} ({}) skipped", self.opcode, self.id);
+        Next::continue_(self.next)
+    }
+}
+
 pub fn get_field_value(field: &[Option<String>], index: usize) -> Result<&str> {
     if let Some(Some(s)) = field.get(index) {
         Ok(s)
@@ -318,3 +537,294 @@ pub fn get_field_value(field: &[Option<String>], index: usize) -> Result<&str> {
         Err(Error::msg("invalid field"))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::profiler::Profiler;
+    use crate::sprite::SpriteID;
+    use crate::testing::{self, file_block, global, id};
+    use crate::thread::{Thread, ThreadState};
+
+    fn runtime_with_mode(load_mode: LoadMode) -> Runtime {
+        let mut global = global(&[]);
+        global.load_mode = load_mode;
+        testing::runtime(Arc::new(global))
+    }
+
+    fn runtime() -> Runtime {
+        runtime_with_mode(LoadMode::Strict)
+    }
+
+    /// ID of the nth block of a generated script
+    fn nth(n: usize) -> String {
+        format!("block{}", n)
+    }
+
+    const LENGTH: usize = 100_000;
+
+    #[tokio::test]
+    async fn test_long_script() {
+        let mut infos: HashMap<BlockID, file::Block> = HashMap::new();
+        infos.insert(
+            id("block0"),
+            file_block(
+                "event_whenflagclicked",
+                Some("block1"),
+                serde_json::json!({}),
+            ),
+        );
+        for n in 1..=LENGTH {
+            let next = if n < LENGTH { Some(nth(n + 1)) } else { None };
+            infos.insert(
+                id(&nth(n)),
+                file_block(
+                    "motion_changexby",
+                    next.as_deref(),
+                    serde_json::json!({"DX": [1, [4, "1"]]}),
+                ),
+            );
+        }
+
+        let (top, blocks) = block_tree(id("block0"), runtime(), &infos).unwrap();
+        assert_eq!(top, id("block0"));
+        assert_eq!(blocks.len(), LENGTH + 1);
+
+        assert!(Thread::start(id("block0"), runtime(), &infos).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_nested_substacks() {
+        let mut infos: HashMap<BlockID, file::Block> = HashMap::new();
+        infos.insert(
+            id("block0"),
+            file_block(
+                "event_whenflagclicked",
+                Some("block1"),
+                serde_json::json!({}),
+            ),
+        );
+        for n in 1..=LENGTH {
+            let inputs = if n < LENGTH {
+                serde_json::json!({"SUBSTACK": [2, nth(n + 1)]})
+            } else {
+                serde_json::json!({})
+            };
+            infos.insert(id(&nth(n)), file_block("control_forever", None, inputs));
+        }
+
+        let (_, blocks) = block_tree(id("block0"), runtime(), &infos).unwrap();
+        assert_eq!(blocks.len(), LENGTH + 1);
+    }
+
+    /// change x by (1 + (1 + ... (1 + 1))) with depth additions
+    fn nested_reporters(depth: usize) -> HashMap<BlockID, file::Block> {
+        let mut infos: HashMap<BlockID, file::Block> = HashMap::new();
+        infos.insert(
+            id("block0"),
+            file_block(
+                "motion_changexby",
+                None,
+                serde_json::json!({"DX": [3, "block1", [4, "1"]]}),
+            ),
+        );
+        for n in 1..=depth {
+            let num2 = if n < depth {
+                serde_json::json!([3, nth(n + 1), [4, "1"]])
+            } else {
+                serde_json::json!([1, [4, "1"]])
+            };
+            infos.insert(
+                id(&nth(n)),
+                file_block(
+                    "operator_add",
+                    None,
+                    serde_json::json!({"NUM1": [1, [4, "1"]], "NUM2": num2}),
+                ),
+            );
+        }
+        infos
+    }
+
+    #[tokio::test]
+    async fn test_nested_reporters() {
+        let runtime = runtime();
+        let infos = nested_reporters(MAX_REPORTER_DEPTH);
+        let (_, mut blocks) = block_tree(id("block0"), runtime.clone(), &infos).unwrap();
+        // Reporters are inputs of the block that uses them
+        assert_eq!(blocks.len(), 1);
+
+        blocks
+            .get_mut(&id("block0"))
+            .unwrap()
+            .execute()
+            .await
+            .unwrap();
+        assert_eq!(
+            runtime.sprite.read().await.center().x,
+            (MAX_REPORTER_DEPTH + 1) as f64
+        );
+
+        let infos = nested_reporters(MAX_REPORTER_DEPTH + 1);
+        assert!(block_tree(id("block0"), runtime, &infos).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_invalid() {
+        {
+            let infos: HashMap<BlockID, file::Block> = HashMap::new();
+            assert!(block_tree(id("block0"), runtime(), &infos).is_err());
+        }
+        {
+            let mut infos: HashMap<BlockID, file::Block> = HashMap::new();
+            infos.insert(
+                id("block0"),
+                file_block("motion_changexby", Some("block1"), serde_json::json!({})),
+            );
+            infos.insert(
+                id("block1"),
+                file_block("motion_changexby", Some("block0"), serde_json::json!({})),
+            );
+            assert!(block_tree(id("block0"), runtime(), &infos).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_unsupported() {
+        let mut infos: HashMap<BlockID, file::Block> = HashMap::new();
+        infos.insert(
+            id("block0"),
+            file_block("motion_changexby", Some("block1"), serde_json::json!({})),
+        );
+        infos.insert(
+            id("block1"),
+            file_block(
+                "music_playDrumForBeats",
+                Some("block2"),
+                serde_json::json!({"BEATS": [1, [4, "1"]]}),
+            ),
+        );
+        infos.insert(
+            id("block2"),
+            file_block("motion_changexby", None, serde_json::json!({})),
+        );
+
+        assert!(block_tree(id("block0"), runtime(), &infos).is_err());
+
+        let (_, mut blocks) =
+            block_tree(id("block0"), runtime_with_mode(LoadMode::Lenient), &infos).unwrap();
+        assert_eq!(blocks.len(), 3);
+        let placeholder = blocks.get_mut(&id("block1")).unwrap();
+        assert_eq!(placeholder.block_info().name, "Unsupported");
+        assert!(
+            matches!(placeholder.execute().await.unwrap(), Next::Continue(b) if b == id("block2"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_hat() {
+        let mut infos: HashMap<BlockID, file::Block> = HashMap::new();
+        let hat = file_block(
+            "videoSensing_whenMotionGreaterThan",
+            Some("block1"),
+            serde_json::json!({}),
+        );
+        infos.insert(id("block0"), hat);
+        infos.insert(
+            id("block1"),
+            file_block("motion_changexby", None, serde_json::json!({})),
+        );
+
+        let (_, mut blocks) =
+            block_tree(id("block0"), runtime_with_mode(LoadMode::Lenient), &infos).unwrap();
+        let placeholder = blocks.get_mut(&id("block0")).unwrap();
+        assert!(matches!(placeholder.execute().await.unwrap(), Next::None));
+    }
+
+    #[tokio::test]
+    async fn test_thread_error() {
+        let mut infos: HashMap<BlockID, file::Block> = HashMap::new();
+        // DX is unconnected so the block returns an error
+        infos.insert(
+            id("block0"),
+            file_block("motion_changexby", Some("block1"), serde_json::json!({})),
+        );
+        infos.insert(
+            id("block1"),
+            file_block("motion_changexby", None, serde_json::json!({})),
+        );
+
+        let mut thread = Thread::start(id("block0"), runtime(), &infos).unwrap();
+        let error = thread.step().await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ScratchError>(),
+            Some(ScratchError::Block { id: block_id, .. }) if *block_id == id("block0")
+        ));
+
+        // The thread stays stopped on the failing block
+        assert_eq!(thread.state(), ThreadState::Error);
+        assert!(thread.step().await.is_ok());
+        assert_eq!(thread.block_info().unwrap().id, id("block0"));
+
+        thread.skip_block().unwrap();
+        assert_eq!(thread.state(), ThreadState::Running);
+        assert_eq!(thread.block_info().unwrap().id, id("block1"));
+        assert!(thread.step().await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_profiler() {
+        let mut infos: HashMap<BlockID, file::Block> = HashMap::new();
+        infos.insert(
+            id("block0"),
+            file_block(
+                "motion_changexby",
+                None,
+                serde_json::json!({"DX": [3, "block1", [4, "1"]]}),
+            ),
+        );
+        infos.insert(
+            id("block1"),
+            file_block(
+                "operator_add",
+                None,
+                serde_json::json!({"NUM1": [3, "block2", [4, "1"]], "NUM2": [1, [4, "1"]]}),
+            ),
+        );
+        infos.insert(
+            id("block2"),
+            file_block(
+                "operator_add",
+                None,
+                serde_json::json!({"NUM1": [1, [4, "1"]], "NUM2": [1, [4, "1"]]}),
+            ),
+        );
+
+        let mut global = global(&[]);
+        let profiler = Arc::new(Profiler::default());
+        global.profiler = Some(profiler.clone());
+        let runtime = testing::runtime(Arc::new(global)).with_original_id(SpriteID::new(3));
+
+        let mut thread = Thread::start(id("block0"), runtime, &infos).unwrap();
+        thread.step().await.unwrap();
+
+        let mut samples = profiler.samples();
+        samples.sort_unstable_by_key(|(stack, _)| stack.blocks.len());
+        let stacks: Vec<Vec<BlockID>> = samples.iter().map(|(s, _)| s.blocks.clone()).collect();
+        assert_eq!(
+            stacks,
+            vec![
+                vec![id("block0")],
+                vec![id("block0"), id("block1")],
+                vec![id("block0"), id("block1"), id("block2")]
+            ]
+        );
+        for (stack, sample) in &samples {
+            assert_eq!(stack.hat, id("block0"));
+            assert_eq!(&*stack.sprite_name, "Sprite1");
+            assert_eq!(stack.sprite_id, SpriteID::new(3));
+            assert_eq!(sample.count, 1);
+        }
+        assert!(samples[0].1.time >= samples[1].1.time);
+    }
+}
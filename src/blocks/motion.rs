@@ -1,7 +1,6 @@
 use super::*;
 use crate::broadcaster::BroadcastMsg;
 use crate::coordinate::{canvas_const, SpriteCoordinate};
-use crate::sprite::SpriteID;
 use rand::distributions::{DistIter, Uniform};
 use rand::prelude::*;
 use std::fmt::{Display, Formatter};
@@ -585,7 +584,11 @@ impl Block for GoTo {
                     }
                 }
             }
-            GoToOption::Sprite(id) => {
+            GoToOption::Sprite(name) => {
+                let id = match self.runtime.global.sprite_id(&name) {
+                    Some(id) => id,
+                    None => return Next::continue_(self.next),
+                };
                 self.runtime
                     .global
                     .broadcaster
@@ -681,15 +684,16 @@ impl Block for GoToMenu {
     }
 
     async fn value(&self) -> Result<Value> {
-        Ok(Value::GoToOption(self.option))
+        Ok(self.option.to_string().into())
     }
 }
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum GoToOption {
     RandomPosition,
     MousePointer,
-    Sprite(SpriteID),
+    /// Sprite name
+    Sprite(String),
 }
 
 impl FromStr for GoToOption {
@@ -699,7 +703,7 @@ impl FromStr for GoToOption {
         Ok(match s {
             "_random_" => Self::RandomPosition,
             "_mouse_" => Self::MousePointer,
-            _ => Self::Sprite(SpriteID::from_sprite_name(s)),
+            _ => Self::Sprite(s.to_string()),
         })
     }
 }
@@ -709,7 +713,7 @@ impl Display for GoToOption {
         f.write_str(match self {
             Self::RandomPosition => "_random_",
             Self::MousePointer => "_mouse_",
-            Self::Sprite(id) => return Display::fmt(id, f),
+            Self::Sprite(name) => name,
         })
     }
 }
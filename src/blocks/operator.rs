@@ -71,7 +71,7 @@ impl Block for Equals {
     async fn value(&self) -> Result<Value> {
         let a = self.operand1.value().await?;
         let b = self.operand2.value().await?;
-        Ok((a == b).into())
+        value::equals(&a, &b)
     }
 }
 
@@ -119,9 +119,9 @@ impl Block for Add {
     }
 
     async fn value(&self) -> Result<Value> {
-        let a: f64 = self.num1.value().await?.try_into()?;
-        let b: f64 = self.num2.value().await?.try_into()?;
-        Ok((a + b).into())
+        let a = self.num1.value().await?;
+        let b = self.num2.value().await?;
+        value::add(&a, &b)
     }
 }
 
@@ -169,9 +169,9 @@ impl Block for Subtract {
     }
 
     async fn value(&self) -> Result<Value> {
-        let a: f64 = self.num1.value().await?.try_into()?;
-        let b: f64 = self.num2.value().await?.try_into()?;
-        Ok((a - b).into())
+        let a = self.num1.value().await?;
+        let b = self.num2.value().await?;
+        value::subtract(&a, &b)
     }
 }
 
@@ -219,9 +219,9 @@ impl Block for Multiply {
     }
 
     async fn value(&self) -> Result<Value> {
-        let a: f64 = self.num1.value().await?.try_into()?;
-        let b: f64 = self.num2.value().await?.try_into()?;
-        Ok((a * b).into())
+        let a = self.num1.value().await?;
+        let b = self.num2.value().await?;
+        value::multiply(&a, &b)
     }
 }
 
@@ -269,9 +269,9 @@ impl Block for Divide {
     }
 
     async fn value(&self) -> Result<Value> {
-        let a: f64 = self.num1.value().await?.try_into()?;
-        let b: f64 = self.num2.value().await?.try_into()?;
-        Ok((a / b).into())
+        let a = self.num1.value().await?;
+        let b = self.num2.value().await?;
+        value::divide(&a, &b)
     }
 }
 
@@ -322,9 +322,9 @@ impl Block for And {
     }
 
     async fn value(&self) -> Result<Value> {
-        let left: bool = self.operand1.value().await?.try_into()?;
-        let right = self.operand2.value().await?.try_into()?;
-        Ok((left && right).into())
+        let left = self.operand1.value().await?;
+        let right = self.operand2.value().await?;
+        value::and(&left, &right)
     }
 }
 
@@ -375,9 +375,9 @@ impl Block for Or {
     }
 
     async fn value(&self) -> Result<Value> {
-        let left: bool = self.operand1.value().await?.try_into()?;
-        let right: bool = self.operand2.value().await?.try_into()?;
-        Ok((left || right).into())
+        let left = self.operand1.value().await?;
+        let right = self.operand2.value().await?;
+        value::or(&left, &right)
     }
 }
 
@@ -421,8 +421,7 @@ impl Block for Not {
     }
 
     async fn value(&self) -> Result<Value> {
-        let operand: bool = self.operand.value().await?.try_into()?;
-        Ok((!operand).into())
+        value::not(&self.operand.value().await?)
     }
 }
 
@@ -473,9 +472,9 @@ impl Block for LessThan {
     }
 
     async fn value(&self) -> Result<Value> {
-        let left: f64 = self.operand1.value().await?.try_into()?;
-        let right: f64 = self.operand2.value().await?.try_into()?;
-        Ok((left < right).into())
+        let left = self.operand1.value().await?;
+        let right = self.operand2.value().await?;
+        value::lt(&left, &right)
     }
 }
 
@@ -526,9 +525,9 @@ impl Block for GreaterThan {
     }
 
     async fn value(&self) -> Result<Value> {
-        let left: f64 = self.operand1.value().await?.try_into()?;
-        let right: f64 = self.operand2.value().await?.try_into()?;
-        Ok((left > right).into())
+        let left = self.operand1.value().await?;
+        let right = self.operand2.value().await?;
+        value::gt(&left, &right)
     }
 }
 
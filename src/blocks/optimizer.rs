@@ -0,0 +1,387 @@
+//! Rewrites the blocks of a script before its thread starts. Blocks that are replaced or detached
+//! stay in the map, so they can still be inspected and used as conditions.
+
+use super::*;
+use crate::runtime::Variables;
+use std::ops::AddAssign;
+
+/// Changes that optimize made.
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Optimizations {
+    /// Operators whose inputs are literals, replaced by their value
+    pub folded: usize,
+    /// Text literals replaced by numbers in inputs that are converted to numbers
+    pub coerced: usize,
+    /// Variable and list references with unknown IDs, changed to the variable with the same name
+    /// so that compiled blocks can access it by slot
+    pub resolved: usize,
+    /// Blocks after forever and stop blocks, which never run
+    pub removed: usize,
+}
+
+impl Optimizations {
+    pub fn is_empty(&self) -> bool {
+        *self == Optimizations::default()
+    }
+}
+
+impl AddAssign for Optimizations {
+    fn add_assign(&mut self, other: Self) {
+        self.folded += other.folded;
+        self.coerced += other.coerced;
+        self.resolved += other.resolved;
+        self.removed += other.removed;
+    }
+}
+
+impl Display for Optimizations {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "folded {} operators, converted {} literals to numbers, resolved {} variable references, \
+             removed {} unreachable blocks",
+            self.folded, self.coerced, self.resolved, self.removed
+        )
+    }
+}
+
+/// Inputs that blocks convert to numbers
+const NUMBER_INPUTS: &[(&str, &[&str])] = &[
+    ("operator_add", &["NUM1", "NUM2"]),
+    ("operator_subtract", &["NUM1", "NUM2"]),
+    ("operator_multiply", &["NUM1", "NUM2"]),
+    ("operator_divide", &["NUM1", "NUM2"]),
+    ("operator_lt", &["OPERAND1", "OPERAND2"]),
+    ("operator_gt", &["OPERAND1", "OPERAND2"]),
+    ("data_changevariableby", &["VALUE"]),
+    ("control_repeat", &["TIMES"]),
+    ("control_wait", &["DURATION"]),
+    ("motion_movesteps", &["STEPS"]),
+    ("motion_gotoxy", &["X", "Y"]),
+    ("motion_changexby", &["DX"]),
+    ("motion_changeyby", &["DY"]),
+    ("motion_setx", &["X"]),
+    ("motion_sety", &["Y"]),
+];
+
+/// Optimizes the script under hat. Only arithmetic is folded because inputs cannot hold
+/// booleans.
+pub fn optimize(
+    hat: BlockID,
+    blocks: &mut HashMap<BlockID, file::Block>,
+    variables: &Variables,
+) -> Optimizations {
+    let mut optimizations = Optimizations::default();
+
+    for id in stack_blocks(hat, blocks) {
+        let info = &blocks[&id];
+        if info.next.is_some() && is_cap(info) {
+            let next = info.next;
+            optimizations.removed += reachable(next, blocks).len();
+            blocks.get_mut(&id).unwrap().next = None;
+        }
+    }
+
+    let ids = reachable(Some(hat), blocks);
+    for id in &ids {
+        let info = blocks.get_mut(id).unwrap();
+        let keys = match NUMBER_INPUTS
+            .iter()
+            .find(|(opcode, _)| *opcode == info.opcode)
+        {
+            Some((_, keys)) => keys,
+            None => continue,
+        };
+        for key in keys.iter() {
+            if let Some(input) = info.inputs.get_mut(*key) {
+                if coerce(input) {
+                    optimizations.coerced += 1;
+                }
+            }
+        }
+    }
+
+    // Inputs are found after the blocks that use them, so they are folded first
+    for id in ids.iter().rev() {
+        let keys: Vec<String> = blocks[id].inputs.keys().cloned().collect();
+        for key in keys {
+            let number = match input_block(&blocks[id].inputs[&key]) {
+                Some(input_id) => match blocks.get(&input_id).and_then(fold) {
+                    Some(number) => number,
+                    None => continue,
+                },
+                None => continue,
+            };
+            blocks
+                .get_mut(id)
+                .unwrap()
+                .inputs
+                .insert(key, serde_json::json!([1, [4, number]]));
+            optimizations.folded += 1;
+        }
+    }
+
+    for id in reachable(Some(hat), blocks) {
+        let info = blocks.get_mut(&id).unwrap();
+        if info.opcode.starts_with("data_") {
+            for (key, field) in info.fields.iter_mut() {
+                if key != "VARIABLE" && key != "LIST" {
+                    continue;
+                }
+                if let [Some(name), Some(variable_id), ..] = field.as_mut_slice() {
+                    if resolve(variables, name, variable_id) {
+                        optimizations.resolved += 1;
+                    }
+                }
+            }
+        }
+        for input in info.inputs.values_mut() {
+            let arr = match input
+                .as_array_mut()
+                .and_then(|arr| arr.get_mut(1)?.as_array_mut())
+            {
+                Some(arr) => arr,
+                None => continue,
+            };
+            let is_variable = matches!(arr.first().and_then(|t| t.as_i64()), Some(12) | Some(13));
+            if let (
+                true,
+                [_, serde_json::Value::String(name), serde_json::Value::String(variable_id), ..],
+            ) = (is_variable, arr.as_mut_slice())
+            {
+                if resolve(variables, name, variable_id) {
+                    optimizations.resolved += 1;
+                }
+            }
+        }
+    }
+
+    optimizations
+}
+
+/// Replaces an ID that does not refer to a variable with the ID of the only variable with the
+/// name. Returns whether the ID changed.
+fn resolve(variables: &Variables, name: &str, variable_id: &mut String) -> bool {
+    if variables.slot(variable_id).is_some() {
+        return false;
+    }
+    match variables.key_of_name(name) {
+        Some(key) => {
+            *variable_id = key.to_string();
+            true
+        }
+        None => false,
+    }
+}
+
+/// Forever and stop blocks that end the script
+fn is_cap(info: &file::Block) -> bool {
+    match info.opcode.as_str() {
+        "control_forever" => true,
+        "control_stop" => matches!(
+            info.fields
+                .get("STOP_OPTION")
+                .map(|f| get_field_value(f, 0)),
+            Some(Ok("all")) | Some(Ok("this script"))
+        ),
+        _ => false,
+    }
+}
+
+/// Block that an input refers to
+fn input_block(input: &serde_json::Value) -> Option<BlockID> {
+    input.as_array()?.get(1)?.as_str()?.try_into().ok()
+}
+
+/// Stack blocks under hat, including hat
+fn stack_blocks(hat: BlockID, blocks: &HashMap<BlockID, file::Block>) -> Vec<BlockID> {
+    let mut result: Vec<BlockID> = Vec::new();
+    let mut visited: HashSet<BlockID> = HashSet::new();
+    let mut stack: Vec<BlockID> = vec![hat];
+    while let Some(id) = stack.pop() {
+        let info = match blocks.get(&id) {
+            Some(info) if visited.insert(id) => info,
+            _ => continue,
+        };
+        result.push(id);
+        stack.extend(info.next);
+        for (key, input) in &info.inputs {
+            if key.starts_with("SUBSTACK") {
+                stack.extend(input_block(input));
+            }
+        }
+    }
+    result
+}
+
+/// Blocks in stacks and inputs under first, in the order they are found
+fn reachable(first: Option<BlockID>, blocks: &HashMap<BlockID, file::Block>) -> Vec<BlockID> {
+    let mut result: Vec<BlockID> = Vec::new();
+    let mut visited: HashSet<BlockID> = HashSet::new();
+    let mut stack: Vec<BlockID> = first.into_iter().collect();
+    while let Some(id) = stack.pop() {
+        let info = match blocks.get(&id) {
+            Some(info) if visited.insert(id) => info,
+            _ => continue,
+        };
+        result.push(id);
+        stack.extend(info.next);
+        stack.extend(info.inputs.values().filter_map(input_block));
+    }
+    result
+}
+
+/// Replaces text with a number if the text converts to a finite number. Returns whether the
+/// input changed.
+fn coerce(input: &mut serde_json::Value) -> bool {
+    let arr = match input
+        .as_array_mut()
+        .and_then(|arr| arr.get_mut(1)?.as_array_mut())
+    {
+        Some(arr) => arr,
+        None => return false,
+    };
+    let is_text = matches!(arr.first().and_then(|t| t.as_i64()), Some(10) | Some(11));
+    let number = match arr.get(1) {
+        Some(serde_json::Value::String(s)) if is_text => {
+            match Value::String(s.clone()).try_into() {
+                Ok(number) if f64::is_finite(number) => number,
+                _ => return false,
+            }
+        }
+        _ => return false,
+    };
+    *arr = vec![serde_json::json!(4), serde_json::json!(number)];
+    true
+}
+
+/// Value of an arithmetic operator whose inputs are literals. Follows the blocks in the operator
+/// module.
+fn fold(info: &file::Block) -> Option<f64> {
+    let number = |key: &str| -> Option<f64> {
+        let arr = info.inputs.get(key)?.as_array()?.get(1)?.as_array()?;
+        let value = arr.get(1)?;
+        let value = match arr.first()?.as_i64()? {
+            4..=8 => Value::Number(value::parse_number(value).ok()?),
+            10 | 11 => Value::String(value.as_str()?.to_string()),
+            _ => return None,
+        };
+        value.try_into().ok()
+    };
+    let a = number("NUM1")?;
+    let b = number("NUM2")?;
+    let result = match info.opcode.as_str() {
+        "operator_add" => a + b,
+        "operator_subtract" => a - b,
+        "operator_multiply" => a * b,
+        "operator_divide" => a / b,
+        _ => return None,
+    };
+    if result.is_finite() {
+        Some(result)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{file_block, global, id};
+
+    #[test]
+    fn test_optimize() {
+        let global = global(&["n"]);
+
+        // when flag clicked; set n to (1 + "2") * 3; forever { change x by n / "x" }; show
+        // The reporter of n refers to n by an ID that does not exist
+        let mut blocks: HashMap<BlockID, file::Block> = HashMap::new();
+        blocks.insert(
+            id("hat"),
+            file_block("event_whenflagclicked", Some("set"), serde_json::json!({})),
+        );
+        let mut set = file_block(
+            "data_setvariableto",
+            Some("forever"),
+            serde_json::json!({"VALUE": [3, "multiply", [10, ""]]}),
+        );
+        set.fields.insert(
+            "VARIABLE".to_string(),
+            vec![Some("n".to_string()), Some("n_id".to_string())],
+        );
+        blocks.insert(id("set"), set);
+        blocks.insert(
+            id("multiply"),
+            file_block(
+                "operator_multiply",
+                None,
+                serde_json::json!({"NUM1": [3, "add", [4, ""]], "NUM2": [1, [4, "3"]]}),
+            ),
+        );
+        blocks.insert(
+            id("add"),
+            file_block(
+                "operator_add",
+                None,
+                serde_json::json!({"NUM1": [1, [4, "1"]], "NUM2": [1, [10, "2"]]}),
+            ),
+        );
+        blocks.insert(
+            id("forever"),
+            file_block(
+                "control_forever",
+                Some("show"),
+                serde_json::json!({"SUBSTACK": [2, "move"]}),
+            ),
+        );
+        blocks.insert(
+            id("move"),
+            file_block(
+                "motion_changexby",
+                None,
+                serde_json::json!({"DX": [3, "divide", [4, ""]]}),
+            ),
+        );
+        blocks.insert(
+            id("divide"),
+            file_block(
+                "operator_divide",
+                None,
+                serde_json::json!({"NUM1": [3, [12, "n", "stale_id"], [4, ""]], "NUM2": [1, [10, "x"]]}),
+            ),
+        );
+        blocks.insert(
+            id("show"),
+            file_block("looks_show", None, serde_json::json!({})),
+        );
+
+        let optimizations = optimize(id("hat"), &mut blocks, &global.variables);
+        assert_eq!(
+            optimizations,
+            Optimizations {
+                folded: 2,
+                coerced: 1,
+                resolved: 1,
+                removed: 1,
+            }
+        );
+        assert_eq!(
+            blocks[&id("set")].inputs["VALUE"],
+            serde_json::json!([1, [4, 9.0]])
+        );
+        // "x" is not a number, so the error is left to the block
+        assert_eq!(
+            blocks[&id("move")].inputs["DX"],
+            serde_json::json!([3, "divide", [4, ""]])
+        );
+        assert_eq!(blocks[&id("forever")].next, None);
+        assert!(blocks.contains_key(&id("show")));
+
+        assert_eq!(
+            blocks[&id("divide")].inputs["NUM1"],
+            serde_json::json!([3, [12, "n", "n_id"], [4, ""]])
+        );
+
+        assert!(optimize(id("hat"), &mut blocks, &global.variables).is_empty());
+    }
+}
@@ -1,6 +1,6 @@
 use super::*;
-use palette::{Hsv, IntoColor};
-use palette::{Mix, Srgb};
+use palette::Srgb;
+use scratch_runtime::pen::{hsv_to_rgb, rgb_to_hsv, set_hue, set_shade};
 
 pub fn get_block(
     name: &str,
@@ -291,28 +291,6 @@ impl SetPenShadeToNumber {
             shade: Box::new(EmptyInput {}),
         }
     }
-
-    fn set_shade(color: &Hsv, shade: f32) -> Hsv {
-        // https://github.com/LLK/scratch-vm/blob/c6962cb390ba2835d64eb21c0456707b51642084/src/extensions/scratch3_pen/index.js#L718
-        let mut new_shade = shade % 200.0;
-        if new_shade < 0.0 {
-            new_shade += 200.0
-        }
-
-        // https://github.com/LLK/scratch-vm/blob/c6962cb390ba2835d64eb21c0456707b51642084/src/extensions/scratch3_pen/index.js#L750
-        let constrained_shade = if new_shade > 100.0 {
-            200.0 - new_shade
-        } else {
-            new_shade
-        };
-
-        let bright = Hsv::new(color.hue, 1.0, 1.0);
-        if constrained_shade < 50.0 {
-            Hsv::new(0.0, 0.0, 0.0).mix(&bright, (10.0 + shade) / 60.0)
-        } else {
-            bright.mix(&Hsv::new(0.0, 0.0, 1.0), (shade - 50.0) / 60.0)
-        }
-    }
 }
 
 #[async_trait]
@@ -350,30 +328,12 @@ impl Block for SetPenShadeToNumber {
         let mut runtime = self.runtime.sprite.write().await;
         let color = runtime.pen().color();
         let hsv = rgb_to_hsv(color);
-        let new_color = SetPenShadeToNumber::set_shade(&hsv, shade as f32);
+        let new_color = set_shade(&hsv, shade as f32);
         runtime.pen().set_color(hsv_to_rgb(&new_color));
         Next::continue_(self.next)
     }
 }
 
-fn rgb_to_hsv(rgb: &Srgb<u8>) -> Hsv {
-    Srgb::<f32>::new(
-        rgb.red as f32 / 255.0,
-        rgb.green as f32 / 255.0,
-        rgb.blue as f32 / 255.0,
-    )
-    .into_hsv()
-}
-
-fn hsv_to_rgb(hsv: &Hsv) -> Srgb<u8> {
-    let rgb_float: Srgb = hsv.into_rgb().into_encoding();
-    Srgb::new(
-        (rgb_float.red * 255.0) as u8,
-        (rgb_float.green * 255.0) as u8,
-        (rgb_float.blue * 255.0) as u8,
-    )
-}
-
 #[derive(Debug)]
 pub struct SetPenHueToNumber {
     id: BlockID,
@@ -391,15 +351,6 @@ impl SetPenHueToNumber {
             hue: Box::new(EmptyInput {}),
         }
     }
-
-    fn set_hue(color: &Hsv, hue: f32) -> Hsv {
-        #[allow(clippy::float_cmp)]
-        if hue == 200.0 {
-            Hsv::new(360.0, 0.0, 0.0)
-        } else {
-            Hsv::new(hue / 200.0 * 360.0, color.saturation, color.value)
-        }
-    }
 }
 
 #[async_trait]
@@ -435,43 +386,8 @@ impl Block for SetPenHueToNumber {
     async fn execute(&mut self) -> Result<Next> {
         let hue: f64 = self.hue.value().await?.try_into()?;
         let mut runtime = self.runtime.sprite.write().await;
-        let new_color = SetPenHueToNumber::set_hue(&rgb_to_hsv(&runtime.pen().color()), hue as f32);
+        let new_color = set_hue(&rgb_to_hsv(runtime.pen().color()), hue as f32);
         runtime.pen().set_color(hsv_to_rgb(&new_color));
         Next::continue_(self.next)
     }
 }
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use rstest::rstest;
-
-    #[rstest(
-        color,
-        shade,
-        expected,
-        case(Hsv::new(0.0, 0.0, 0.0), 0.0, Hsv::new(0.0, 0.16666667, 0.16666667),),
-        case(Hsv::new(0.0, 0.0, 1.0), 0.0, Hsv::new(0.0, 0.16666667, 0.16666667),),
-        case(Hsv::new(0.0, 0.0, 0.0), 100.0, Hsv::new(0.0, 0.16666669, 1.0),),
-        case(Hsv::new(0.0, 0.0, 1.0), 100.0, Hsv::new(0.0, 0.16666669, 1.0),),
-        case(Hsv::new(0.0, 0.0, 0.0), 50.0, Hsv::new(0.0, 1.0, 1.0),),
-        case(Hsv::new(240.0, 1.0, 1.0), 50.0, Hsv::new(240.0, 1.0, 1.0),)
-    )]
-    fn test_set_shade(color: Hsv, shade: f32, expected: Hsv) {
-        assert_eq!(SetPenShadeToNumber::set_shade(&color, shade), expected);
-    }
-
-    #[rstest(
-        color,
-        hue,
-        expected,
-        case(Hsv::new(0.0, 0.0, 0.0), 0.0, Hsv::new(0.0, 0.0, 0.0),),
-        case(Hsv::new(0.0, 1.0, 1.0), 0.0, Hsv::new(0.0, 1.0, 1.0),),
-        case(Hsv::new(0.0, 0.0, 0.0), 50.0, Hsv::new(90.0, 0.0, 0.0),),
-        case(Hsv::new(0.0, 0.0, 0.0), 100.0, Hsv::new(180.0, 0.0, 0.0),),
-        case(Hsv::new(0.0, 0.0, 0.0), 200.0, Hsv::new(360.0, 0.0, 0.0),)
-    )]
-    fn test_set_hue(color: Hsv, hue: f32, expected: Hsv) {
-        assert_eq!(SetPenHueToNumber::set_hue(&color, hue), expected);
-    }
-}
@@ -1,7 +1,6 @@
 use super::*;
 use crate::broadcaster::BroadcastMsg;
 use crate::coordinate::{canvas_const, CanvasCoordinate};
-use crate::sprite::SpriteID;
 use graphics::types::Rectangle;
 use graphics::Context;
 use graphics_buffer::{buffer_glyphs_from_path, BufferGlyphs, RenderBuffer};
@@ -57,6 +56,11 @@ impl Display for KeyOption {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self {
             KeyOption::Any => write!(f, "any"),
+            KeyOption::Key(Key::Space) => write!(f, "space"),
+            KeyOption::Key(Key::Left) => write!(f, "left arrow"),
+            KeyOption::Key(Key::Right) => write!(f, "right arrow"),
+            KeyOption::Key(Key::Up) => write!(f, "up arrow"),
+            KeyOption::Key(Key::Down) => write!(f, "down arrow"),
             KeyOption::Key(k) => match serde_json::to_string(k) {
                 Ok(s) => write!(f, "{}", &s),
                 Err(e) => {
@@ -169,7 +173,7 @@ impl Block for KeyOptions {
     }
 
     async fn value(&self) -> Result<Value> {
-        Ok(Value::KeyOption(self.key))
+        Ok(self.key.to_string().into())
     }
 }
 
@@ -475,7 +479,11 @@ impl Block for TouchingObject {
                 }
             }
             TouchingObjectOption::Edge => TouchingObject::sprite_on_edge(&sprite_rectangle.into()),
-            TouchingObjectOption::Sprite(id) => {
+            TouchingObjectOption::Sprite(name) => {
+                let id = match self.runtime.global.sprite_id(&name) {
+                    Some(id) => id,
+                    None => return Ok(false.into()),
+                };
                 self.runtime
                     .global
                     .broadcaster
@@ -538,15 +546,16 @@ impl Block for TouchingObjectMenu {
     }
 
     async fn value(&self) -> Result<Value> {
-        Ok(Value::TouchingObjectOption(self.option))
+        Ok(self.option.to_string().into())
     }
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Clone, Eq, PartialEq)]
 pub enum TouchingObjectOption {
     MousePointer,
     Edge,
-    Sprite(SpriteID),
+    /// Sprite name
+    Sprite(String),
 }
 
 impl FromStr for TouchingObjectOption {
@@ -556,7 +565,7 @@ impl FromStr for TouchingObjectOption {
         Ok(match s {
             "_mouse_" => Self::MousePointer,
             "_edge_" => Self::Edge,
-            _ => Self::Sprite(SpriteID::from_sprite_name(s)),
+            _ => Self::Sprite(s.to_string()),
         })
     }
 }
@@ -566,7 +575,7 @@ impl Display for TouchingObjectOption {
         f.write_str(match self {
             TouchingObjectOption::MousePointer => "_mouse_",
             TouchingObjectOption::Edge => "_edge_",
-            TouchingObjectOption::Sprite(s) => return Display::fmt(s, f),
+            TouchingObjectOption::Sprite(name) => name,
         })
     }
 }
@@ -1,10 +1,35 @@
 use super::*;
 use palette::Srgb;
 use std::convert::TryFrom;
-use std::fmt::{Debug, Display, Formatter};
-use std::iter::repeat;
+use std::fmt::Debug;
 use std::str::FromStr;
 
+pub use scratch_runtime::value::*;
+
+pub fn get_block(opcode: &str, runtime: Runtime) -> Option<Box<dyn Block + Send + Sync>> {
+    Some(match opcode {
+        "math_number"
+        | "math_positive_number"
+        | "math_whole_number"
+        | "math_integer"
+        | "math_angle" => Box::new(ValueNumber { number: 0.0 }),
+        "colour_picker" => Box::new(ValueColor {
+            color: Srgb::new(0, 0, 0),
+        }),
+        "text" | "event_broadcast_menu" => Box::new(ValueString {
+            string: String::new(),
+        }),
+        "data_variable" => Box::new(Variable::new(String::new(), runtime)),
+        "data_listcontents" => Box::new(ListContents::new(String::new(), runtime)),
+        _ => return None,
+    })
+}
+
+/// Variables and lists do not have block IDs, so the variable ID is used instead.
+fn variable_block_id(id: &str) -> BlockID {
+    BlockID::try_from(id).unwrap_or_default()
+}
+
 #[derive(Debug)]
 pub struct Variable {
     id: String,
@@ -20,22 +45,9 @@ impl Variable {
 #[async_trait]
 impl Block for Variable {
     fn block_info(&self) -> BlockInfo {
-        // Start from first dash or zero
-        let start_index = self.id.find('-').map_or(0, |n| n + 1);
-        let bytes: Vec<u8> = self
-            .id
-            .bytes()
-            .skip(start_index)
-            .take(self.id.bytes().len() - start_index - 1) // Truncate last dash
-            .chain(repeat(b' ')) // Ensure length
-            .take(20)
-            .collect();
-
-        let mut b: [u8; 20] = [0; 20];
-        b.copy_from_slice(&bytes);
         BlockInfo {
             name: "Variable",
-            id: BlockID::try_from(std::str::from_utf8(&b).unwrap()).unwrap(),
+            id: variable_block_id(&self.id),
         }
     }
 
@@ -45,6 +57,50 @@ impl Block for Variable {
 
     fn set_input(&mut self, _: &str, _: Box<dyn Block + Send + Sync>) {}
 
+    fn set_field(&mut self, key: &str, field: &[Option<String>]) -> Result<()> {
+        if key == "VARIABLE" {
+            self.id = get_field_value(field, 1)?.to_string();
+        }
+        Ok(())
+    }
+
+    async fn value(&self) -> Result<Value> {
+        self.runtime.global.variables.get(&self.id).await
+    }
+}
+
+#[derive(Debug)]
+pub struct ListContents {
+    id: String,
+    runtime: Runtime,
+}
+
+impl ListContents {
+    pub fn new(id: String, runtime: Runtime) -> Self {
+        Self { id, runtime }
+    }
+}
+
+#[async_trait]
+impl Block for ListContents {
+    fn block_info(&self) -> BlockInfo {
+        BlockInfo {
+            name: "ListContents",
+            id: variable_block_id(&self.id),
+        }
+    }
+
+    fn block_inputs(&self) -> BlockInputsPartial {
+        BlockInputsPartial::new(self.block_info(), vec![], vec![], vec![])
+    }
+
+    fn set_field(&mut self, key: &str, field: &[Option<String>]) -> Result<()> {
+        if key == "LIST" {
+            self.id = get_field_value(field, 1)?.to_string();
+        }
+        Ok(())
+    }
+
     async fn value(&self) -> Result<Value> {
         self.runtime.global.variables.get(&self.id).await
     }
@@ -52,32 +108,51 @@ impl Block for Variable {
 
 pub fn value_block_from_input_arr(
     arr: &[serde_json::Value],
+    runtime: Runtime,
 ) -> Result<Box<dyn Block + Send + Sync>> {
     // https://en.scratch-wiki.info/wiki/Scratch_File_Format#Blocks
     let err = || Error::msg("invalid input");
     let value_type = arr.get(0).ok_or_else(err)?.as_i64().ok_or_else(err)?;
     let value = arr.get(1).ok_or_else(err)?;
+    let string = || match value {
+        serde_json::Value::String(s) => s.clone(),
+        _ => value.to_string(),
+    };
     Ok(match value_type {
-        4 | 5 | 6 | 7 | 8 => {
-            let number = if let Some(f) = value.as_f64() {
-                f
-            } else {
-                f64::from_str(value.as_str().unwrap())?
-            };
-            Box::new(ValueNumber { number })
-        }
-        9 => Box::new(ValueColor::new(value.as_str().unwrap())?),
-        10 | 11 => Box::new(ValueString {
-            string: if let serde_json::Value::String(s) = value {
-                s.clone()
-            } else {
-                value.to_string()
-            },
+        // math_number, math_positive_number, math_whole_number, math_integer, math_angle
+        4..=8 => Box::new(ValueNumber {
+            number: parse_number(value)?,
         }),
+        // colour_picker
+        9 => Box::new(ValueColor::new(value.as_str().ok_or_else(err)?)?),
+        // text
+        10 => Box::new(ValueString { string: string() }),
+        // event_broadcast_menu
+        11 => Box::new(ValueString { string: string() }),
+        // data_variable
+        12 => {
+            let id = arr.get(2).ok_or_else(err)?.as_str().ok_or_else(err)?;
+            Box::new(Variable::new(id.to_string(), runtime))
+        }
+        // data_listcontents
+        13 => {
+            let id = arr.get(2).ok_or_else(err)?.as_str().ok_or_else(err)?;
+            Box::new(ListContents::new(id.to_string(), runtime))
+        }
         _ => return Err(Error::msg(format!("unknown value_type: {}", value_type))),
     })
 }
 
+/// Number fields are stored as either numbers or strings. An empty field is zero.
+pub fn parse_number(value: &serde_json::Value) -> Result<f64> {
+    match value {
+        serde_json::Value::Number(n) => n.as_f64().ok_or_else(|| Error::msg("invalid number")),
+        serde_json::Value::String(s) if s.trim().is_empty() => Ok(0.0),
+        serde_json::Value::String(s) => Ok(f64::from_str(s.trim())?),
+        _ => Err(Error::msg(format!("invalid number: {}", value))),
+    }
+}
+
 #[derive(Debug)]
 pub struct ValueNumber {
     number: f64,
@@ -101,6 +176,13 @@ impl Block for ValueNumber {
         )
     }
 
+    fn set_field(&mut self, key: &str, field: &[Option<String>]) -> Result<()> {
+        if key == "NUM" {
+            self.number = parse_number(&get_field_value(field, 0)?.into())?;
+        }
+        Ok(())
+    }
+
     async fn value(&self) -> Result<Value> {
         Ok(Value::Number(self.number))
     }
@@ -129,6 +211,13 @@ impl Block for ValueString {
         )
     }
 
+    fn set_field(&mut self, key: &str, field: &[Option<String>]) -> Result<()> {
+        if key == "TEXT" || key == "BROADCAST_OPTION" {
+            self.string = get_field_value(field, 0)?.to_string();
+        }
+        Ok(())
+    }
+
     async fn value(&self) -> Result<Value> {
         Ok(Value::String(self.string.clone()))
     }
@@ -165,144 +254,25 @@ impl Block for ValueColor {
         )
     }
 
-    async fn value(&self) -> Result<Value> {
-        Ok(Value::Color(self.color))
-    }
-}
-
-#[derive(Debug, Clone, PartialEq)]
-pub enum Value {
-    Bool(bool),
-    Number(f64),
-    String(String),
-    Color(Srgb<u8>),
-    TouchingObjectOption(sensing::TouchingObjectOption),
-    KeyOption(sensing::KeyOption),
-    StopOption(control::StopOption),
-    GoToOption(motion::GoToOption),
-}
-
-impl From<serde_json::Value> for Value {
-    fn from(v: serde_json::Value) -> Self {
-        match v {
-            serde_json::Value::Bool(b) => Value::Bool(b),
-            serde_json::Value::Number(f) => Value::Number(f.as_f64().unwrap()),
-            serde_json::Value::String(s) => Value::String(s),
-            _ => unimplemented!("{}", v),
+    fn set_field(&mut self, key: &str, field: &[Option<String>]) -> Result<()> {
+        if key == "COLOUR" {
+            self.color = str_to_color(get_field_value(field, 0)?)?;
         }
+        Ok(())
     }
-}
-
-impl From<bool> for Value {
-    fn from(b: bool) -> Self {
-        Self::Bool(b)
-    }
-}
-
-impl From<f64> for Value {
-    fn from(f: f64) -> Self {
-        Self::Number(f)
-    }
-}
-
-impl From<String> for Value {
-    fn from(s: String) -> Self {
-        Self::String(s)
-    }
-}
-
-impl From<&str> for Value {
-    fn from(s: &str) -> Self {
-        Self::String(s.to_string())
-    }
-}
-
-impl From<Srgb<u8>> for Value {
-    fn from(c: Srgb<u8>) -> Self {
-        Self::Color(c)
-    }
-}
-
-impl TryInto<bool> for Value {
-    type Error = Error;
-
-    fn try_into(self) -> Result<bool> {
-        if let Self::Bool(b) = self {
-            Ok(b)
-        } else {
-            Err(Error::msg(format!("value is not bool: {}", self)))
-        }
-    }
-}
-
-impl TryInto<f64> for Value {
-    type Error = Error;
-
-    fn try_into(self) -> Result<f64> {
-        (&self).try_into()
-    }
-}
-
-impl TryInto<f64> for &Value {
-    type Error = Error;
-
-    fn try_into(self) -> Result<f64> {
-        Ok(match self {
-            Value::Number(f) => {
-                if f.is_nan() {
-                    0.0
-                } else {
-                    *f
-                }
-            }
-            Value::String(s) => s.parse()?,
-            _ => {
-                return Err(Error::msg(format!(
-                    "expected String or Number but got: {:?}",
-                    self
-                )))
-            }
-        })
-    }
-}
 
-fn str_to_color(s: &str) -> Result<Srgb<u8>> {
-    if s.len() != 7 || s.bytes().next() != Some(b'#') {
-        return Err(Error::msg(format!("string is invalid: {}", s)));
-    }
-
-    Ok(Srgb::new(
-        u8::from_str_radix(&s[1..3], 16)?,
-        u8::from_str_radix(&s[3..5], 16)?,
-        u8::from_str_radix(&s[5..7], 16)?,
-    ))
-}
-
-impl TryInto<Srgb<u8>> for Value {
-    type Error = Error;
-
-    fn try_into(self) -> Result<Srgb<u8>> {
-        Ok(match self {
-            Self::String(s) => str_to_color(&s)?,
-            Self::Color(c) => c,
-            _ => return Err(Error::msg(format!("cannot convert {} into color", self))),
-        })
+    async fn value(&self) -> Result<Value> {
+        Ok(Value::Color(self.color))
     }
 }
 
-impl Display for Value {
-    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let o: &dyn Display = match self {
-            Self::Bool(b) => b,
-            Self::Number(n) => n,
-            Self::String(s) => s,
-            Self::Color(c) => return write!(f, "{:?}", c),
-            Self::TouchingObjectOption(o) => o,
-            Self::KeyOption(o) => o,
-            Self::StopOption(o) => o,
-            Self::GoToOption(o) => o,
-        };
-        write!(f, "{}", o)
+/// Initial value of a variable or list item in project.json.
+pub fn from_json(v: serde_json::Value) -> Value {
+    match v {
+        serde_json::Value::Bool(b) => Value::Bool(b),
+        serde_json::Value::Number(f) => Value::Number(f.as_f64().unwrap()),
+        serde_json::Value::String(s) => Value::String(s),
+        _ => unimplemented!("{}", v),
     }
 }
 
@@ -313,11 +283,10 @@ macro_rules! impl_try_from_value {
             type Error = Error;
 
             fn try_from(value: Value) -> Result<Self> {
-                Ok(match value {
-                    Value::String(s) => Self::from_str(&s)?,
-                    Value::$value_name(o) => o,
-                    _ => return Err(Error::msg(format!("cannot convert value: {}", value))),
-                })
+                match value {
+                    Value::String(s) => Ok(Self::from_str(&s)?),
+                    _ => Err(Error::msg(format!("cannot convert value: {}", value))),
+                }
             }
         }
     };
@@ -329,32 +298,21 @@ mod tests {
     use rstest::rstest;
 
     #[rstest(
-        s,
+        value,
         expected,
         expect_err,
-        case("", Srgb::new(0, 0, 0), true),
-        case("#", Srgb::new(0, 0, 0), true),
-        case("#000000", Srgb::new(0, 0, 0), false),
-        case("#ffffff", Srgb::new(255, 255, 255), false),
-        case("#ffffffa", Srgb::new(0, 0, 0), true)
+        case(serde_json::json!(1.5), 1.5, false),
+        case(serde_json::json!("1.5"), 1.5, false),
+        case(serde_json::json!(" 2 "), 2.0, false),
+        case(serde_json::json!(""), 0.0, false),
+        case(serde_json::json!("a"), 0.0, true),
+        case(serde_json::json!(null), 0.0, true)
     )]
-    fn test_str_to_color(s: &'static str, expected: Srgb<u8>, expect_err: bool) {
-        let result = str_to_color(s);
+    fn test_parse_number(value: serde_json::Value, expected: f64, expect_err: bool) {
+        let result = parse_number(&value);
         assert_eq!(result.is_err(), expect_err);
         if !expect_err {
             assert_eq!(result.unwrap(), expected);
         }
     }
-
-    #[rstest(
-        value,
-        expected,
-        case (Value::String("a".into()), "a"),
-        case (Value::Number(1.0), "1"),
-        case (Value::Number(1.1), "1.1"),
-        case (Value::Bool(false), "false"),
-    )]
-    fn test_to_string(value: Value, expected: &'static str) {
-        assert_eq!(value.to_string(), expected);
-    }
 }
@@ -0,0 +1,327 @@
+use super::*;
+use crate::file::BlockID;
+use crate::fileviewer::{ScriptSource, SpriteBlocks};
+use crate::thread::BlockInputs;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+/// Records the blocks that threads executed, by the name of the target so that clones count for
+/// the sprite they were cloned from.
+#[derive(Debug, Default)]
+pub struct Coverage {
+    executed: Mutex<HashMap<String, HashSet<BlockID>>>,
+}
+
+impl Coverage {
+    pub fn record(&self, sprite_name: &str, block_id: BlockID) {
+        let mut executed = self.executed.lock().unwrap();
+        match executed.get_mut(sprite_name) {
+            Some(blocks) => {
+                blocks.insert(block_id);
+            }
+            None => {
+                executed.insert(sprite_name.to_string(), std::iter::once(block_id).collect());
+            }
+        }
+    }
+
+    pub fn executed(&self) -> HashMap<String, HashSet<BlockID>> {
+        self.executed.lock().unwrap().clone()
+    }
+}
+
+/// Executed blocks of each script. Reporters count as executed when the block that uses them
+/// was, because blocks evaluate all of their inputs. Blocks that are not under a hat never run
+/// and are left out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CoverageReport {
+    pub sprites: Vec<SpriteCoverage>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpriteCoverage {
+    pub name: String,
+    pub scripts: Vec<ScriptCoverage>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptCoverage {
+    pub hat: BlockID,
+    pub source: ScriptSource,
+    /// Blocks of the script in the order of the source
+    pub blocks: Vec<BlockID>,
+    pub unexecuted: HashSet<BlockID>,
+}
+
+impl CoverageReport {
+    pub fn new(sprites: &[SpriteBlocks], executed: &HashMap<String, HashSet<BlockID>>) -> Self {
+        let empty: HashSet<BlockID> = HashSet::new();
+        Self {
+            sprites: sprites
+                .iter()
+                .map(|sprite| SpriteCoverage {
+                    name: sprite.name.clone(),
+                    scripts: sprite
+                        .block_inputs
+                        .iter()
+                        .map(|hat| {
+                            ScriptCoverage::new(hat, executed.get(&sprite.name).unwrap_or(&empty))
+                        })
+                        .collect(),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn text(&self) -> String {
+        let mut result = String::new();
+        for sprite in &self.sprites {
+            let (executed, total) = sprite.count();
+            writeln!(
+                result,
+                "{}: {}/{} blocks ({})",
+                sprite.name,
+                executed,
+                total,
+                percent(executed, total)
+            )
+            .unwrap();
+            for script in &sprite.scripts {
+                let (executed, total) = script.count();
+                writeln!(
+                    result,
+                    "    {} {}: {}/{} blocks ({})",
+                    script.hat_name(),
+                    script.hat,
+                    executed,
+                    total,
+                    percent(executed, total)
+                )
+                .unwrap();
+                for block_id in script.unexecuted_blocks() {
+                    writeln!(result, "        not executed: {}", block_id).unwrap();
+                }
+            }
+        }
+        result
+    }
+
+    pub fn json(&self) -> serde_json::Value {
+        let count = |(executed, total): (usize, usize)| serde_json::json!({"executed": executed, "total": total});
+        serde_json::json!({
+            "sprites": self.sprites.iter().map(|sprite| serde_json::json!({
+                "name": sprite.name,
+                "blocks": count(sprite.count()),
+                "scripts": sprite.scripts.iter().map(|script| serde_json::json!({
+                    "hat": script.hat,
+                    "blocks": count(script.count()),
+                    "unexecuted": script.unexecuted_blocks(),
+                })).collect::<Vec<serde_json::Value>>(),
+            })).collect::<Vec<serde_json::Value>>(),
+        })
+    }
+
+    /// Page with the source of every script. Lines of blocks that were not executed are
+    /// highlighted.
+    pub fn html(&self) -> String {
+        let mut result = String::from(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>Coverage</title>\n\
+             <style>\n\
+             pre { background: #f4f4f4; padding: 8px; }\n\
+             .executed { color: #1a7f37; }\n\
+             .unexecuted { background: #ffd7d5; color: #a40e26; }\n\
+             </style>\n</head>\n<body>\n",
+        );
+        for sprite in &self.sprites {
+            let (executed, total) = sprite.count();
+            writeln!(
+                result,
+                "<h2>{} ({}/{} blocks, {})</h2>",
+                escape(&sprite.name),
+                executed,
+                total,
+                percent(executed, total)
+            )
+            .unwrap();
+            for script in &sprite.scripts {
+                result += "<pre>";
+                for (line, block_id) in script.source.text.lines().zip(&script.source.lines) {
+                    match block_id {
+                        Some(id) if script.unexecuted.contains(id) => {
+                            write!(result, "<span class=\"unexecuted\">{}</span>", escape(line))
+                        }
+                        Some(_) => {
+                            write!(result, "<span class=\"executed\">{}</span>", escape(line))
+                        }
+                        None => write!(result, "{}", escape(line)),
+                    }
+                    .unwrap();
+                    result.push('\n');
+                }
+                result += "</pre>\n";
+            }
+        }
+        result += "</body>\n</html>\n";
+        result
+    }
+}
+
+impl SpriteCoverage {
+    fn count(&self) -> (usize, usize) {
+        self.scripts
+            .iter()
+            .map(ScriptCoverage::count)
+            .fold((0, 0), |a, b| (a.0 + b.0, a.1 + b.1))
+    }
+}
+
+impl ScriptCoverage {
+    fn new(hat: &BlockInputs, executed: &HashSet<BlockID>) -> Self {
+        let mut script = Self {
+            hat: hat.info.id,
+            source: ScriptSource::new(hat),
+            blocks: Vec::new(),
+            unexecuted: HashSet::new(),
+        };
+        script.add_stack(hat, executed);
+        script
+    }
+
+    fn add_stack(&mut self, block: &BlockInputs, executed: &HashSet<BlockID>) {
+        let is_executed = executed.contains(&block.info.id);
+        self.add_block(block, is_executed);
+
+        let mut stacks: Vec<&BlockInputs> = block.stacks.values().collect();
+        stacks.sort_unstable_by_key(|stack| stack.info.id);
+        for stack in stacks {
+            self.add_stack(stack, executed);
+        }
+    }
+
+    /// Adds the block and its reporters.
+    fn add_block(&mut self, block: &BlockInputs, is_executed: bool) {
+        // Values typed into inputs are not blocks
+        if block.info.id != BlockID::pseudo_id() {
+            self.blocks.push(block.info.id);
+            if !is_executed {
+                self.unexecuted.insert(block.info.id);
+            }
+        }
+
+        let mut inputs: Vec<&BlockInputs> = block.inputs.values().collect();
+        inputs.sort_unstable_by_key(|input| input.info.id);
+        for input in inputs {
+            self.add_block(input, is_executed);
+        }
+    }
+
+    fn hat_name(&self) -> &str {
+        self.source.text.lines().next().unwrap_or_default()
+    }
+
+    fn count(&self) -> (usize, usize) {
+        (self.blocks.len() - self.unexecuted.len(), self.blocks.len())
+    }
+
+    fn unexecuted_blocks(&self) -> Vec<BlockID> {
+        self.blocks
+            .iter()
+            .filter(|id| self.unexecuted.contains(id))
+            .copied()
+            .collect()
+    }
+}
+
+fn percent(executed: usize, total: usize) -> String {
+    if total == 0 {
+        "-".to_string()
+    } else {
+        format!("{:.1}%", executed as f64 / total as f64 * 100.0)
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::LoadMode;
+    use crate::fileviewer::block_inputs;
+    use crate::testing::{file_block, id};
+
+    #[tokio::test]
+    async fn test_coverage_report() {
+        let mut target = file::Target {
+            name: "Sprite1".to_string(),
+            ..file::Target::default()
+        };
+        for (block_id, block) in [
+            (
+                "hat",
+                file_block("event_whenflagclicked", Some("if"), serde_json::json!({})),
+            ),
+            (
+                "if",
+                file_block(
+                    "control_if",
+                    Some("show"),
+                    serde_json::json!({"CONDITION": [2, "gt"], "SUBSTACK": [2, "hide"]}),
+                ),
+            ),
+            (
+                "gt",
+                file_block(
+                    "operator_gt",
+                    None,
+                    serde_json::json!({"OPERAND1": [1, [10, "1"]], "OPERAND2": [1, [10, "2"]]}),
+                ),
+            ),
+            (
+                "hide",
+                file_block("looks_hide", None, serde_json::json!({})),
+            ),
+            (
+                "show",
+                file_block("looks_show", None, serde_json::json!({})),
+            ),
+        ] {
+            target.blocks.insert(id(block_id), block);
+        }
+        let sprites = block_inputs(&[target], LoadMode::Strict).await.unwrap();
+
+        let coverage = Coverage::default();
+        for block_id in &["hat", "if", "show"] {
+            coverage.record("Sprite1", id(block_id));
+        }
+        coverage.record("Sprite1-clone", id("hide"));
+        let report = CoverageReport::new(&sprites, &coverage.executed());
+
+        let script = &report.sprites[0].scripts[0];
+        assert_eq!(script.count(), (4, 5));
+        assert_eq!(script.unexecuted_blocks(), vec![id("hide")]);
+        assert!(report.text().starts_with("Sprite1: 4/5 blocks (80.0%)\n"));
+        assert!(report.text().ends_with("        not executed: hide\n"));
+        assert_eq!(
+            report.json()["sprites"][0]["scripts"][0],
+            serde_json::json!({
+                "hat": "hat",
+                "blocks": {"executed": 4, "total": 5},
+                "unexecuted": ["hide"],
+            })
+        );
+
+        let html = report.html();
+        let hide_line = script.source.line(&id("hide")).unwrap();
+        let hide_text = script.source.text.lines().nth(hide_line - 1).unwrap();
+        assert!(html.contains(&format!(
+            "<span class=\"unexecuted\">{}</span>",
+            escape(hide_text)
+        )));
+        assert_eq!(html.matches("class=\"unexecuted\"").count(), 1);
+    }
+}
@@ -0,0 +1,589 @@
+use super::*;
+use crate::debugger::StepMode;
+use crate::file::{BlockID, LoadMode, ScratchFile};
+use crate::fileviewer::{block_inputs, ScriptSource};
+use crate::sprite::SpriteID;
+use crate::vm::{ThreadID, VM};
+use serde::Deserialize;
+use serde_json::json;
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::str::FromStr;
+use std::sync::mpsc;
+
+/// How the Debug Adapter Protocol client connects.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum Transport {
+    Stdio,
+    /// Accepts one connection on localhost
+    Tcp(u16),
+}
+
+impl FromStr for Transport {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s == "stdio" {
+            return Ok(Transport::Stdio);
+        }
+        s.parse()
+            .map(Transport::Tcp)
+            .map_err(|_| Error::msg(format!("expected stdio or a port number: {}", s)))
+    }
+}
+
+/// Changes to the VM's state made by the client.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ExecutionChange {
+    Continued,
+    Paused,
+}
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    seq: i64,
+    command: String,
+    #[serde(default)]
+    arguments: serde_json::Value,
+}
+
+#[derive(Debug)]
+struct Script {
+    sprite_name: String,
+    thread_id: usize,
+    source: ScriptSource,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Scope {
+    /// Variables, which all belong to the stage
+    Stage,
+    /// Position, size and other properties of the sprite
+    SpriteProperties(SpriteID),
+}
+
+/// Serves the Debug Adapter Protocol. Requests are read on separate threads and handled when the
+/// interface polls them, like VM events.
+///
+/// Scripts are sources, referenced by sourceReference, with one stack block per line. DAP
+/// threads, frames and variable references are numbered from 1 in the order they are first
+/// reported.
+#[derive(Debug)]
+pub struct DapServer {
+    request_receiver: mpsc::Receiver<Request>,
+    message_sender: mpsc::Sender<serde_json::Value>,
+    seq: i64,
+    scripts: Vec<Script>,
+    /// Breakpoints of each script
+    breakpoints: HashMap<usize, Vec<BlockID>>,
+    /// Breakpoints set on the command line, which the client cannot remove
+    fixed_breakpoints: HashSet<BlockID>,
+    thread_ids: Vec<ThreadID>,
+    /// Thread of each frame since the VM paused
+    frames: Vec<ThreadID>,
+    scopes: Vec<Scope>,
+}
+
+impl DapServer {
+    pub async fn start(
+        transport: Transport,
+        scratch_file: &ScratchFile,
+        load_mode: LoadMode,
+        fixed_breakpoints: &[BlockID],
+    ) -> Result<Self> {
+        let mut scripts: Vec<Script> = Vec::new();
+        for sprite in block_inputs(&scratch_file.project.targets, load_mode).await? {
+            for (thread_id, inputs) in sprite.block_inputs.iter().enumerate() {
+                scripts.push(Script {
+                    sprite_name: sprite.name.clone(),
+                    thread_id,
+                    source: ScriptSource::new(inputs),
+                });
+            }
+        }
+
+        let (request_sender, request_receiver) = mpsc::channel();
+        let (message_sender, message_receiver) = mpsc::channel();
+        match transport {
+            Transport::Stdio => {
+                std::thread::spawn(move || {
+                    read_requests(&mut BufReader::new(std::io::stdin()), &request_sender)
+                });
+                std::thread::spawn(move || write_messages(std::io::stdout(), message_receiver));
+            }
+            Transport::Tcp(port) => {
+                let listener = TcpListener::bind(("127.0.0.1", port))?;
+                log::info!("waiting for debug adapter client on port {}", port);
+                std::thread::spawn(move || {
+                    let stream = match listener.accept() {
+                        Ok((stream, _)) => stream,
+                        Err(e) => {
+                            log::error!("could not accept debug adapter client: {}", e);
+                            return;
+                        }
+                    };
+                    match stream.try_clone() {
+                        Ok(writer) => {
+                            std::thread::spawn(move || write_messages(writer, message_receiver));
+                        }
+                        Err(e) => {
+                            log::error!("{}", e);
+                            return;
+                        }
+                    }
+                    read_requests(&mut BufReader::new(stream), &request_sender);
+                });
+            }
+        }
+
+        Ok(Self {
+            request_receiver,
+            message_sender,
+            seq: 0,
+            scripts,
+            breakpoints: HashMap::new(),
+            fixed_breakpoints: fixed_breakpoints.iter().copied().collect(),
+            thread_ids: Vec::new(),
+            frames: Vec::new(),
+            scopes: Vec::new(),
+        })
+    }
+
+    /// Handles the requests that arrived since the last call. Returns the last change to the
+    /// VM's state.
+    pub async fn handle_requests(&mut self, vm: &VM) -> Option<ExecutionChange> {
+        let mut change: Option<ExecutionChange> = None;
+        while let Ok(request) = self.request_receiver.try_recv() {
+            let result = self.handle(vm, &request, &mut change).await;
+            let response = match result {
+                Ok(body) => json!({
+                    "type": "response",
+                    "request_seq": request.seq,
+                    "success": true,
+                    "command": request.command,
+                    "body": body,
+                }),
+                Err(e) => json!({
+                    "type": "response",
+                    "request_seq": request.seq,
+                    "success": false,
+                    "command": request.command,
+                    "message": e.to_string(),
+                }),
+            };
+            self.send(response);
+
+            if request.command == "initialize" {
+                self.send_event("initialized", json!({}));
+            }
+        }
+        change
+    }
+
+    async fn handle(
+        &mut self,
+        vm: &VM,
+        request: &Request,
+        change: &mut Option<ExecutionChange>,
+    ) -> Result<serde_json::Value> {
+        let arguments = &request.arguments;
+        Ok(match request.command.as_str() {
+            "initialize" => json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsStepBack": true,
+            }),
+            // The project is loaded from the command line
+            "launch" | "attach" | "configurationDone" | "disconnect" => json!({}),
+            "threads" => {
+                let mut threads: Vec<serde_json::Value> = Vec::new();
+                for thread in vm.threads().await {
+                    threads.push(json!({
+                        "id": self.dap_thread_id(thread.thread_id),
+                        "name": format!("{} thread {}", thread.sprite_name, thread.thread_id.thread_id),
+                    }));
+                }
+                json!({ "threads": threads })
+            }
+            "stackTrace" => {
+                let thread_id = self.thread_id(&arguments["threadId"])?;
+                let thread = vm
+                    .threads()
+                    .await
+                    .into_iter()
+                    .find(|t| t.thread_id == thread_id)
+                    .ok_or_else(|| Error::msg(format!("thread not found: {:?}", thread_id)))?;
+                let script = self.script(&thread.sprite_name, thread_id.thread_id);
+
+                let mut frames: Vec<serde_json::Value> = Vec::new();
+                let blocks =
+                    std::iter::once(&thread.current_block).chain(thread.call_stack.iter().rev());
+                for block in blocks {
+                    self.frames.push(thread_id);
+                    let mut frame = json!({
+                        "id": self.frames.len(),
+                        "name": block.opcode,
+                        "line": 0,
+                        "column": 1,
+                    });
+                    if let Some(index) = script {
+                        let script = &self.scripts[index];
+                        frame["line"] = json!(script.source.line(&block.id).unwrap_or(0));
+                        frame["source"] = DapServer::source(script, index);
+                    }
+                    frames.push(frame);
+                }
+                json!({ "stackFrames": frames, "totalFrames": frames.len() })
+            }
+            "scopes" => {
+                let thread_id = arguments["frameId"]
+                    .as_u64()
+                    .and_then(|id| self.frames.get((id as usize).wrapping_sub(1)))
+                    .copied()
+                    .ok_or_else(|| Error::msg("invalid frameId"))?;
+                json!({ "scopes": [
+                    {
+                        "name": "Stage",
+                        "variablesReference": self.scope_reference(Scope::Stage),
+                        "expensive": false,
+                    },
+                    {
+                        "name": "Sprite properties",
+                        "variablesReference": self.scope_reference(Scope::SpriteProperties(thread_id.sprite_id)),
+                        "expensive": false,
+                    },
+                ]})
+            }
+            "variables" => {
+                let scope = arguments["variablesReference"]
+                    .as_u64()
+                    .and_then(|id| self.scopes.get((id as usize).wrapping_sub(1)))
+                    .copied()
+                    .ok_or_else(|| Error::msg("invalid variablesReference"))?;
+                let variables: Vec<(String, String)> = match scope {
+                    Scope::Stage => vm
+                        .variables()
+                        .await
+                        .into_iter()
+                        .map(|(name, value)| (name, value.to_string()))
+                        .collect(),
+                    Scope::SpriteProperties(sprite_id) => vm
+                        .sprite_properties(sprite_id)
+                        .await?
+                        .into_iter()
+                        .map(|(name, value)| (name.to_string(), value))
+                        .collect(),
+                };
+                let variables: Vec<serde_json::Value> = variables
+                    .into_iter()
+                    .map(|(name, value)| json!({ "name": name, "value": value, "variablesReference": 0 }))
+                    .collect();
+                json!({ "variables": variables })
+            }
+            "source" => {
+                let reference = match arguments["source"]["sourceReference"].as_u64() {
+                    Some(reference) => Some(reference),
+                    None => arguments["sourceReference"].as_u64(),
+                };
+                let index = self.script_index(reference)?;
+                json!({ "content": self.scripts[index].source.text })
+            }
+            "setBreakpoints" => {
+                let index = self.script_index(arguments["source"]["sourceReference"].as_u64())?;
+                for block_id in self.breakpoints.remove(&index).unwrap_or_default() {
+                    if !self.fixed_breakpoints.contains(&block_id) {
+                        vm.remove_breakpoint(block_id).await;
+                    }
+                }
+
+                let lines: Vec<usize> = arguments["breakpoints"]
+                    .as_array()
+                    .map(|breakpoints| {
+                        breakpoints
+                            .iter()
+                            .filter_map(|b| b["line"].as_u64())
+                            .map(|line| line as usize)
+                            .collect()
+                    })
+                    .unwrap_or_default();
+                let mut breakpoints: Vec<serde_json::Value> = Vec::new();
+                let mut block_ids: Vec<BlockID> = Vec::new();
+                for line in lines {
+                    let block_id = self.scripts[index]
+                        .source
+                        .lines
+                        .get(line.wrapping_sub(1))
+                        .copied()
+                        .flatten();
+                    match block_id {
+                        Some(block_id) => {
+                            vm.add_breakpoint(block_id).await;
+                            block_ids.push(block_id);
+                            breakpoints.push(json!({ "verified": true, "line": line }));
+                        }
+                        None => breakpoints.push(json!({
+                            "verified": false,
+                            "line": line,
+                            "message": "no block on this line",
+                        })),
+                    }
+                }
+                self.breakpoints.insert(index, block_ids);
+                json!({ "breakpoints": breakpoints })
+            }
+            "continue" => {
+                vm.continue_().await;
+                self.clear_frames();
+                *change = Some(ExecutionChange::Continued);
+                json!({ "allThreadsContinued": true })
+            }
+            "pause" => {
+                vm.pause().await;
+                *change = Some(ExecutionChange::Paused);
+                let thread_id = match self.thread_ids.first() {
+                    Some(&thread_id) => Some(thread_id),
+                    None => vm.threads().await.first().map(|t| t.thread_id),
+                };
+                self.send_stopped("pause", thread_id, "");
+                json!({})
+            }
+            "next" | "stepIn" | "stepOut" => {
+                let mode = match request.command.as_str() {
+                    "next" => StepMode::Over,
+                    "stepIn" => StepMode::Block,
+                    _ => StepMode::Out,
+                };
+                let thread_id = self.thread_id(&arguments["threadId"])?;
+                self.clear_frames();
+                vm.step_single_thread(thread_id, mode).await;
+                json!({})
+            }
+            "stepBack" => {
+                self.clear_frames();
+                vm.step_back().await;
+                json!({})
+            }
+            command => return Err(Error::msg(format!("unsupported command: {}", command))),
+        })
+    }
+
+    /// Tells the client that the VM paused.
+    pub fn stopped(&mut self, reason: &str, thread_id: ThreadID, text: &str) {
+        self.send_stopped(reason, Some(thread_id), text);
+    }
+
+    /// The thread is left out if the VM has no threads.
+    fn send_stopped(&mut self, reason: &str, thread_id: Option<ThreadID>, text: &str) {
+        self.clear_frames();
+        let mut body = json!({
+            "reason": reason,
+            "text": text,
+            "allThreadsStopped": true,
+        });
+        if let Some(thread_id) = thread_id {
+            body["threadId"] = json!(self.dap_thread_id(thread_id));
+        }
+        self.send_event("stopped", body);
+    }
+
+    /// Tells the client that the VM was continued from the interface.
+    pub fn continued(&mut self) {
+        self.clear_frames();
+        self.send_event(
+            "continued",
+            json!({ "threadId": 1, "allThreadsContinued": true }),
+        );
+    }
+
+    /// Tells the client that the VM stopped and the debug session ended.
+    pub fn terminated(&mut self) {
+        self.clear_frames();
+        self.send_event("terminated", json!({}));
+    }
+
+    fn clear_frames(&mut self) {
+        self.frames.clear();
+        self.scopes.clear();
+    }
+
+    fn dap_thread_id(&mut self, thread_id: ThreadID) -> usize {
+        match self.thread_ids.iter().position(|&id| id == thread_id) {
+            Some(index) => index + 1,
+            None => {
+                self.thread_ids.push(thread_id);
+                self.thread_ids.len()
+            }
+        }
+    }
+
+    fn thread_id(&self, id: &serde_json::Value) -> Result<ThreadID> {
+        id.as_u64()
+            .and_then(|id| self.thread_ids.get((id as usize).wrapping_sub(1)))
+            .copied()
+            .ok_or_else(|| Error::msg(format!("invalid threadId: {}", id)))
+    }
+
+    fn scope_reference(&mut self, scope: Scope) -> usize {
+        match self.scopes.iter().position(|&s| s == scope) {
+            Some(index) => index + 1,
+            None => {
+                self.scopes.push(scope);
+                self.scopes.len()
+            }
+        }
+    }
+
+    /// Clones run the scripts of the sprite they were cloned from, which has the same name.
+    fn script(&self, sprite_name: &str, thread_id: usize) -> Option<usize> {
+        self.scripts
+            .iter()
+            .position(|s| s.sprite_name == sprite_name && s.thread_id == thread_id)
+    }
+
+    fn script_index(&self, source_reference: Option<u64>) -> Result<usize> {
+        source_reference
+            .map(|reference| (reference as usize).wrapping_sub(1))
+            .filter(|&index| index < self.scripts.len())
+            .ok_or_else(|| Error::msg("unknown source"))
+    }
+
+    fn source(script: &Script, index: usize) -> serde_json::Value {
+        json!({
+            "name": format!("{} thread {}", script.sprite_name, script.thread_id),
+            "sourceReference": index + 1,
+        })
+    }
+
+    fn send_event(&mut self, event: &str, body: serde_json::Value) {
+        self.send(json!({
+            "type": "event",
+            "event": event,
+            "body": body,
+        }));
+    }
+
+    fn send(&mut self, mut message: serde_json::Value) {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        if self.message_sender.send(message).is_err() {
+            log::warn!("debug adapter client disconnected");
+        }
+    }
+}
+
+fn read_requests<R: BufRead>(reader: &mut R, sender: &mpsc::Sender<Request>) {
+    loop {
+        let request = match read_message(reader) {
+            Ok(Some(message)) => serde_json::from_value(message),
+            Ok(None) => return,
+            Err(e) => {
+                log::error!("could not read debug adapter message: {}", e);
+                return;
+            }
+        };
+        match request {
+            Ok(request) => {
+                if sender.send(request).is_err() {
+                    return;
+                }
+            }
+            Err(e) => log::error!("invalid debug adapter request: {}", e),
+        }
+    }
+}
+
+fn write_messages<W: Write>(mut writer: W, receiver: mpsc::Receiver<serde_json::Value>) {
+    for message in receiver {
+        if let Err(e) = write_message(&mut writer, &message) {
+            log::error!("could not write debug adapter message: {}", e);
+            return;
+        }
+    }
+}
+
+/// Reads a message with a Content-Length header. Returns None at the end of the stream.
+fn read_message<R: BufRead>(reader: &mut R) -> Result<Option<serde_json::Value>> {
+    let mut content_length: Option<usize> = None;
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(length) = line.strip_prefix("Content-Length:") {
+            content_length = Some(length.trim().parse()?);
+        }
+    }
+
+    let content_length = content_length.ok_or_else(|| Error::msg("missing Content-Length"))?;
+    let mut content = vec![0; content_length];
+    reader.read_exact(&mut content)?;
+    Ok(Some(serde_json::from_slice(&content)?))
+}
+
+fn write_message<W: Write>(writer: &mut W, message: &serde_json::Value) -> Result<()> {
+    let content = message.to_string();
+    write!(
+        writer,
+        "Content-Length: {}\r\n\r\n{}",
+        content.len(),
+        content
+    )?;
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_transport() {
+        assert_eq!(Transport::from_str("stdio").unwrap(), Transport::Stdio);
+        assert_eq!(Transport::from_str("4711").unwrap(), Transport::Tcp(4711));
+        assert!(Transport::from_str("tcp").is_err());
+    }
+
+    #[test]
+    fn test_message() {
+        let message = json!({ "seq": 1, "type": "request", "command": "threads" });
+        let mut buffer: Vec<u8> = Vec::new();
+        write_message(&mut buffer, &message).unwrap();
+        write_message(&mut buffer, &message).unwrap();
+
+        let mut reader = Cursor::new(buffer);
+        assert_eq!(read_message(&mut reader).unwrap(), Some(message.clone()));
+        assert_eq!(read_message(&mut reader).unwrap(), Some(message));
+        assert_eq!(read_message(&mut reader).unwrap(), None);
+
+        let mut reader = Cursor::new(b"Content-Type: json\r\n\r\n{}".to_vec());
+        assert!(read_message(&mut reader).is_err());
+    }
+
+    #[test]
+    fn test_read_requests() {
+        let mut buffer: Vec<u8> = Vec::new();
+        write_message(
+            &mut buffer,
+            &json!({ "seq": 3, "type": "request", "command": "next", "arguments": { "threadId": 1 } }),
+        )
+        .unwrap();
+        write_message(
+            &mut buffer,
+            &json!({ "seq": 4, "type": "request", "command": "threads" }),
+        )
+        .unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        read_requests(&mut Cursor::new(buffer), &sender);
+        let requests: Vec<Request> = receiver.try_iter().collect();
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[0].seq, 3);
+        assert_eq!(requests[0].command, "next");
+        assert_eq!(requests[0].arguments["threadId"], 1);
+        assert_eq!(requests[1].arguments, serde_json::Value::Null);
+    }
+}
@@ -0,0 +1,1031 @@
+use super::*;
+use crate::blocks::Block;
+use crate::dap::Transport;
+use crate::file::BlockID;
+use crate::runtime::Global;
+use crate::sprite_map::SpriteMap;
+use crate::vm::{DebugInfo, ThreadID, VMEvent};
+use std::convert::TryInto;
+use std::path::PathBuf;
+
+/// Breakpoints and watchpoints that are set when the VM starts.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DebugOptions {
+    pub breakpoints: Vec<BlockID>,
+    /// Names or IDs of variables
+    pub watched_variables: Vec<String>,
+    /// IDs of reporter blocks
+    pub conditions: Vec<BlockID>,
+    /// Serve the Debug Adapter Protocol
+    pub dap: Option<Transport>,
+    /// File that snapshots are saved to and loaded from
+    pub snapshot: Option<PathBuf>,
+    /// Snapshot that the VM starts from
+    pub restore: Option<PathBuf>,
+    /// File that the folded stacks of the profile are written to
+    pub profile: Option<PathBuf>,
+    /// File that executed blocks are recorded to as JSON Lines
+    pub trace: Option<PathBuf>,
+    /// File that the coverage report is written to
+    pub coverage: Option<PathBuf>,
+    /// Run scripts as they are in the file instead of optimizing them
+    pub no_optimize: bool,
+}
+
+impl DebugOptions {
+    /// Whether the VM can pause on its own or is controlled by a debug adapter
+    pub fn is_debugging(&self) -> bool {
+        !self.breakpoints.is_empty()
+            || !self.watched_variables.is_empty()
+            || !self.conditions.is_empty()
+            || self.dap.is_some()
+    }
+}
+
+/// Decides when the VM pauses. Kept when the VM is stopped.
+#[derive(Debug)]
+pub struct Debugger {
+    global: Arc<Global>,
+    breakpoints: HashSet<BlockID>,
+    conditions: Vec<Condition>,
+}
+
+impl Debugger {
+    pub fn new(global: Arc<Global>) -> Self {
+        Self {
+            global,
+            breakpoints: HashSet::new(),
+            conditions: Vec::new(),
+        }
+    }
+
+    pub fn add_breakpoint(&mut self, block_id: BlockID) {
+        self.breakpoints.insert(block_id);
+    }
+
+    pub fn remove_breakpoint(&mut self, block_id: &BlockID) {
+        self.breakpoints.remove(block_id);
+    }
+
+    /// Replaces the conditions. Writes to unwatched variables are only recorded while there are
+    /// conditions because the last write is reported with the condition.
+    pub async fn set_conditions(&mut self, conditions: Vec<Condition>) {
+        self.global
+            .variables
+            .watch_all(!conditions.is_empty())
+            .await;
+        self.conditions = conditions;
+    }
+
+    /// Returns the reasons to pause after the thread finished a step. Breakpoints are only checked
+    /// if the thread is still running.
+    pub async fn after_step(
+        &mut self,
+        sprites: &SpriteMap,
+        thread_id: ThreadID,
+        running: bool,
+    ) -> Result<Vec<VMEvent>> {
+        let mut events: Vec<VMEvent> = Vec::new();
+
+        if running && !self.breakpoints.is_empty() {
+            let block_info = sprites.block_info(thread_id).await?;
+            if self.breakpoints.contains(&block_info.id) {
+                events.push(VMEvent::Breakpoint(DebugInfo {
+                    thread_id,
+                    block_info,
+                }));
+            }
+        }
+
+        let writes = self.global.variables.take_writes().await;
+        let last_write = writes.last().cloned();
+        for write in writes {
+            if write.watched {
+                events.push(VMEvent::Watchpoint(write));
+            }
+        }
+
+        // Conditions can depend on more than variables, so they are evaluated after every step
+        for condition in &mut self.conditions {
+            let value = condition.evaluate().await;
+            if value && !condition.value {
+                events.push(VMEvent::Condition {
+                    block_id: condition.block_id,
+                    thread_id: last_write
+                        .as_ref()
+                        .map_or(thread_id, |write| write.writer.thread_id),
+                    write: last_write.clone(),
+                });
+            }
+            condition.value = value;
+        }
+
+        Ok(events)
+    }
+}
+
+/// A reporter block that pauses the VM when its value becomes true.
+#[derive(Debug)]
+pub struct Condition {
+    block_id: BlockID,
+    block: Box<dyn Block + Send + Sync>,
+    /// Value after the last step
+    value: bool,
+}
+
+impl Condition {
+    pub async fn new(block_id: BlockID, block: Box<dyn Block + Send + Sync>) -> Self {
+        let mut condition = Self {
+            block_id,
+            block,
+            value: false,
+        };
+        condition.value = condition.evaluate().await;
+        condition
+    }
+
+    async fn evaluate(&self) -> bool {
+        match self.block.value().await.and_then(|v| v.try_into()) {
+            Ok(value) => value,
+            Err(e) => {
+                log::warn!("condition {} could not be evaluated: {}", self.block_id, e);
+                false
+            }
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum StepMode {
+    /// Executes one block
+    Block,
+    /// Executes the block and the substack it runs, so the thread stops at the block after it
+    /// or at the start of the next loop iteration
+    Over,
+    /// Runs until the innermost loop block returns
+    Out,
+}
+
+/// A thread that is stepped while the other threads stay paused.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct ThreadStep {
+    pub thread_id: ThreadID,
+    mode: StepMode,
+    /// Block that the thread was about to execute when the step started
+    block_id: BlockID,
+    /// Length of the loop stack when the step started
+    depth: usize,
+}
+
+impl ThreadStep {
+    pub async fn new(sprites: &SpriteMap, thread_id: ThreadID, mode: StepMode) -> Result<Self> {
+        let position = sprites.position(thread_id).await?;
+        Ok(Self {
+            thread_id,
+            mode,
+            block_id: position.curr_block,
+            depth: position.loop_stack.len(),
+        })
+    }
+
+    /// Whether the thread should pause after it executed a block. The step is finished if the
+    /// thread no longer exists.
+    pub async fn is_finished(&self, sprites: &SpriteMap) -> bool {
+        let position = match sprites.position(self.thread_id).await {
+            Ok(position) => position,
+            Err(_) => return true,
+        };
+        let depth = position.loop_stack.len();
+        match self.mode {
+            StepMode::Block => true,
+            // Loop blocks return to themselves at the same depth after each iteration
+            StepMode::Over => {
+                depth < self.depth || (depth == self.depth && position.curr_block != self.block_id)
+            }
+            StepMode::Out => depth < self.depth,
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ThreadStatus {
+    Running,
+    /// Executing a block that waits, such as wait or glide
+    Waiting,
+    Done,
+    /// Stopped by a stop block, an error or the debugger
+    Stopped,
+}
+
+impl Display for ThreadStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let s = match self {
+            ThreadStatus::Running => "running",
+            ThreadStatus::Waiting => "waiting",
+            ThreadStatus::Done => "done",
+            ThreadStatus::Stopped => "stopped",
+        };
+        write!(f, "{}", s)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockRef {
+    pub id: BlockID,
+    pub opcode: String,
+}
+
+impl Display for BlockRef {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} ({})", self.opcode, self.id)
+    }
+}
+
+/// State of a thread, reported by VM::threads.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThreadInspection {
+    pub thread_id: ThreadID,
+    pub sprite_name: String,
+    pub hat: BlockRef,
+    pub current_block: BlockRef,
+    /// Loop blocks that the thread returns to, outermost first
+    pub call_stack: Vec<BlockRef>,
+    pub status: ThreadStatus,
+}
+
+impl Display for ThreadInspection {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "{} thread {} {}: {}",
+            self.sprite_name, self.thread_id.thread_id, self.status, self.hat
+        )?;
+        writeln!(f, "    at {}", self.current_block)?;
+        for block in self.call_stack.iter().rev() {
+            writeln!(f, "    in {}", block)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blocks::value::Value;
+    use crate::blocks::{block_tree, BlockInfo};
+    use crate::coordinate::SpriteCoordinate;
+    use crate::runtime::VariableWrite;
+    use crate::snapshot::{SavedValue, Snapshot};
+    use crate::sprite::{Sprite, SpriteID};
+    use crate::sprite_runtime::SpriteRuntime;
+    use crate::testing::{self, file_block, id};
+
+    fn global() -> Arc<Global> {
+        global_with_history(true)
+    }
+
+    fn global_with_history(history: bool) -> Arc<Global> {
+        let mut global = testing::global(&["score", "lives"]);
+        global.set_history(history);
+        Arc::new(global)
+    }
+
+    fn writer() -> DebugInfo {
+        DebugInfo {
+            thread_id: ThreadID {
+                sprite_id: SpriteID::new(1),
+                thread_id: 2,
+            },
+            block_info: BlockInfo {
+                name: "SetVariable",
+                id: id("writer"),
+            },
+        }
+    }
+
+    fn thread_id() -> ThreadID {
+        ThreadID {
+            sprite_id: SpriteID::new(0),
+            thread_id: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_watchpoint() {
+        let global = global();
+        let sprites = SpriteMap::new(HashMap::new(), &[], global.clone());
+        let mut debugger = Debugger::new(global.clone());
+        let variables = &global.variables;
+
+        assert!(variables.watch("unknown").await.is_err());
+        variables.watch("score").await.unwrap();
+
+        variables
+            .set("lives_id", 1.0.into(), writer())
+            .await
+            .unwrap();
+        // Writing the same value is not a change
+        variables
+            .set("score_id", 0.0.into(), writer())
+            .await
+            .unwrap();
+        assert!(debugger
+            .after_step(&sprites, thread_id(), true)
+            .await
+            .unwrap()
+            .is_empty());
+
+        variables
+            .set_with("score_id", |_| 5.0.into(), writer())
+            .await
+            .unwrap();
+        let events = debugger
+            .after_step(&sprites, thread_id(), true)
+            .await
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        match &events[0] {
+            VMEvent::Watchpoint(write) => assert_eq!(
+                write,
+                &VariableWrite {
+                    watched: true,
+                    variable_name: "score".to_string(),
+                    old_value: 0.0.into(),
+                    new_value: 5.0.into(),
+                    writer: writer(),
+                }
+            ),
+            event => panic!("{:?}", event),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_condition() {
+        let global = global();
+        let sprites = SpriteMap::new(HashMap::new(), &[], global.clone());
+        let mut debugger = Debugger::new(global.clone());
+
+        // score > 10
+        let condition_id = id("condition");
+        let mut infos: HashMap<BlockID, file::Block> = HashMap::new();
+        infos.insert(
+            condition_id,
+            file::Block {
+                top_level: true,
+                ..file_block(
+                    "operator_gt",
+                    None,
+                    serde_json::json!({
+                        "OPERAND1": [3, [12, "score", "score_id"], [10, ""]],
+                        "OPERAND2": [1, [10, "10"]],
+                    }),
+                )
+            },
+        );
+        let runtime = testing::runtime(global.clone());
+        let (_, mut blocks) = block_tree(condition_id, runtime, &infos).unwrap();
+        let condition = Condition::new(condition_id, blocks.remove(&condition_id).unwrap()).await;
+        debugger.set_conditions(vec![condition]).await;
+
+        let variables = &global.variables;
+        variables
+            .set("score_id", 5.0.into(), writer())
+            .await
+            .unwrap();
+        assert!(debugger
+            .after_step(&sprites, thread_id(), true)
+            .await
+            .unwrap()
+            .is_empty());
+
+        variables
+            .set("score_id", 20.0.into(), writer())
+            .await
+            .unwrap();
+        let events = debugger
+            .after_step(&sprites, thread_id(), true)
+            .await
+            .unwrap();
+        assert_eq!(events.len(), 1);
+        match &events[0] {
+            VMEvent::Condition {
+                block_id,
+                write: Some(write),
+                ..
+            } => {
+                assert_eq!(block_id, &condition_id);
+                assert!(!write.watched);
+                assert_eq!(write.new_value, 20.0.into());
+            }
+            event => panic!("{:?}", event),
+        }
+
+        // Only pauses when the condition becomes true
+        variables
+            .set("score_id", 30.0.into(), writer())
+            .await
+            .unwrap();
+        assert!(debugger
+            .after_step(&sprites, thread_id(), true)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Unwatched writes are not recorded without conditions
+        debugger.set_conditions(Vec::new()).await;
+        variables
+            .set("score_id", 40.0.into(), writer())
+            .await
+            .unwrap();
+        assert!(variables.take_writes().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_condition_without_write() {
+        let global = global();
+        let sprites = SpriteMap::new(HashMap::new(), &[], global.clone());
+        let mut debugger = Debugger::new(global.clone());
+
+        // x position > 10
+        let condition_id = id("condition");
+        let mut infos: HashMap<BlockID, file::Block> = HashMap::new();
+        infos.insert(
+            condition_id,
+            file::Block {
+                top_level: true,
+                ..file_block(
+                    "operator_gt",
+                    None,
+                    serde_json::json!({
+                        "OPERAND1": [3, "x", [10, ""]],
+                        "OPERAND2": [1, [10, "10"]],
+                    }),
+                )
+            },
+        );
+        infos.insert(
+            id("x"),
+            file_block("motion_xposition", None, serde_json::json!({})),
+        );
+        let runtime = testing::runtime(global.clone());
+        let sprite_runtime = runtime.sprite.clone();
+        let (_, mut blocks) = block_tree(condition_id, runtime, &infos).unwrap();
+        let condition = Condition::new(condition_id, blocks.remove(&condition_id).unwrap()).await;
+        debugger.set_conditions(vec![condition]).await;
+
+        sprite_runtime
+            .write()
+            .await
+            .set_center(SpriteCoordinate { x: 20.0, y: 0.0 });
+        let events = debugger
+            .after_step(&sprites, thread_id(), false)
+            .await
+            .unwrap();
+        match events.as_slice() {
+            [VMEvent::Condition {
+                block_id,
+                thread_id: event_thread_id,
+                write: None,
+            }] => {
+                assert_eq!(block_id, &condition_id);
+                assert_eq!(event_thread_id, &thread_id());
+            }
+            events => panic!("{:?}", events),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_breakpoint() {
+        let sprites = sprites().await;
+        let mut debugger = Debugger::new(global());
+        debugger.add_breakpoint(id("show"));
+
+        // hat -> loop
+        sprites.step(thread_id()).await.unwrap();
+        assert!(debugger
+            .after_step(&sprites, thread_id(), true)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // loop -> show
+        sprites.step(thread_id()).await.unwrap();
+        let events = debugger
+            .after_step(&sprites, thread_id(), true)
+            .await
+            .unwrap();
+        match events.as_slice() {
+            [VMEvent::Breakpoint(info)] => {
+                assert_eq!(info.thread_id, thread_id());
+                assert_eq!(info.block_info.id, id("show"));
+            }
+            events => panic!("{:?}", events),
+        }
+        // A finished thread does not pause
+        assert!(debugger
+            .after_step(&sprites, thread_id(), false)
+            .await
+            .unwrap()
+            .is_empty());
+
+        debugger.remove_breakpoint(&id("show"));
+        sprites.step(thread_id()).await.unwrap();
+        sprites.step(thread_id()).await.unwrap();
+        assert_eq!(
+            sprites.position(thread_id()).await.unwrap().curr_block,
+            id("show")
+        );
+        assert!(debugger
+            .after_step(&sprites, thread_id(), true)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    /// Sprite1 with a script that starts with the hat block "hat"
+    async fn sprite_map(
+        global: Arc<Global>,
+        first_block: &str,
+        blocks: Vec<(&str, file::Block)>,
+    ) -> SpriteMap {
+        let mut target = file::Target {
+            name: "Sprite1".to_string(),
+            ..file::Target::default()
+        };
+        target.blocks.insert(
+            id("hat"),
+            file_block(
+                "event_whenflagclicked",
+                Some(first_block),
+                serde_json::json!({}),
+            ),
+        );
+        for (block_id, block) in blocks {
+            target.blocks.insert(id(block_id), block);
+        }
+
+        let sprite_id = SpriteID::new(0);
+        let sprite = Sprite::new(
+            sprite_id,
+            SpriteRuntime::new(&target),
+            global.clone(),
+            target.clone(),
+        )
+        .await
+        .unwrap();
+        let mut sprites: HashMap<SpriteID, Sprite> = HashMap::new();
+        sprites.insert(sprite_id, sprite);
+        SpriteMap::new(sprites, &[target], global)
+    }
+
+    /// when flag clicked, forever show
+    async fn sprites() -> SpriteMap {
+        sprite_map(
+            global(),
+            "loop",
+            vec![
+                (
+                    "loop",
+                    file_block(
+                        "control_forever",
+                        None,
+                        serde_json::json!({"SUBSTACK": [2, "show"]}),
+                    ),
+                ),
+                (
+                    "show",
+                    file_block("looks_show", None, serde_json::json!({})),
+                ),
+            ],
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_inspect_threads() {
+        let sprites = sprites().await;
+        let block_ref = |s: &str, opcode: &str| BlockRef {
+            id: id(s),
+            opcode: opcode.to_string(),
+        };
+        let hat = block_ref("hat", "event_whenflagclicked");
+
+        assert_eq!(
+            sprites.inspect().await,
+            vec![ThreadInspection {
+                thread_id: thread_id(),
+                sprite_name: "Sprite1".to_string(),
+                hat: hat.clone(),
+                current_block: hat.clone(),
+                call_stack: Vec::new(),
+                status: ThreadStatus::Running,
+            }]
+        );
+
+        sprites.step(thread_id()).await.unwrap();
+        sprites.step(thread_id()).await.unwrap();
+        let threads = sprites.inspect().await;
+        assert_eq!(threads[0].current_block, block_ref("show", "looks_show"));
+        assert_eq!(
+            threads[0].call_stack,
+            vec![block_ref("loop", "control_forever")]
+        );
+        assert_eq!(
+            threads[0].to_string(),
+            "Sprite1 thread 0 running: event_whenflagclicked (hat)\n    at looks_show (show)\n    in control_forever (loop)\n"
+        );
+
+        sprites.stop(thread_id()).await;
+        assert_eq!(sprites.inspect().await[0].status, ThreadStatus::Stopped);
+        assert_eq!(sprites.step(thread_id()).await.unwrap(), None);
+        assert_eq!(sprites.inspect().await[0].status, ThreadStatus::Stopped);
+    }
+
+    #[tokio::test]
+    async fn test_stop_other_threads() {
+        let sprites = sprite_map(
+            global(),
+            "show",
+            vec![
+                (
+                    "show",
+                    file_block("looks_show", None, serde_json::json!({})),
+                ),
+                (
+                    "hat2",
+                    file_block("event_whenflagclicked", Some("hide"), serde_json::json!({})),
+                ),
+                (
+                    "hide",
+                    file_block("looks_hide", None, serde_json::json!({})),
+                ),
+            ],
+        )
+        .await;
+        let other = ThreadID {
+            sprite_id: SpriteID::new(0),
+            thread_id: 1,
+        };
+        let status = || async {
+            let threads = sprites.inspect().await;
+            (threads[0].status, threads[1].status)
+        };
+
+        sprites.stop_other_threads(thread_id()).await;
+        assert_eq!(sprites.step(other).await.unwrap(), None);
+        assert_eq!(
+            status().await,
+            (ThreadStatus::Running, ThreadStatus::Stopped)
+        );
+        sprites.step(thread_id()).await.unwrap();
+        assert_eq!(
+            sprites.position(thread_id()).await.unwrap().curr_block,
+            id("show")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_thread_step() {
+        let sprites = sprites().await;
+        let current_block = || async { sprites.position(thread_id()).await.unwrap().curr_block };
+
+        let step = ThreadStep::new(&sprites, thread_id(), StepMode::Block)
+            .await
+            .unwrap();
+        sprites.step(thread_id()).await.unwrap();
+        assert!(step.is_finished(&sprites).await);
+        assert_eq!(current_block().await, id("loop"));
+
+        // Runs the substack of forever
+        let step = ThreadStep::new(&sprites, thread_id(), StepMode::Over)
+            .await
+            .unwrap();
+        sprites.step(thread_id()).await.unwrap();
+        assert!(!step.is_finished(&sprites).await);
+        assert_eq!(current_block().await, id("show"));
+
+        let step = ThreadStep::new(&sprites, thread_id(), StepMode::Out)
+            .await
+            .unwrap();
+        sprites.step(thread_id()).await.unwrap();
+        assert!(step.is_finished(&sprites).await);
+        assert_eq!(current_block().await, id("loop"));
+
+        // Returning to forever after an iteration does not finish stepping over it
+        let step = ThreadStep::new(&sprites, thread_id(), StepMode::Over)
+            .await
+            .unwrap();
+        sprites.step(thread_id()).await.unwrap();
+        assert!(!step.is_finished(&sprites).await);
+        sprites.step(thread_id()).await.unwrap();
+        assert_eq!(current_block().await, id("loop"));
+        assert!(!step.is_finished(&sprites).await);
+
+        // The thread of a deleted sprite is finished
+        sprites.remove(SpriteID::new(0)).await;
+        assert!(step.is_finished(&sprites).await);
+    }
+
+    /// Sprite1 with a script that changes x by 10 and then sets score to "5"
+    async fn move_and_set_score(global: Arc<Global>) -> SpriteMap {
+        let mut set_score = file_block(
+            "data_setvariableto",
+            None,
+            serde_json::json!({"VALUE": [1, [10, "5"]]}),
+        );
+        set_score.fields.insert(
+            "VARIABLE".to_string(),
+            vec![Some("score".to_string()), Some("score_id".to_string())],
+        );
+        sprite_map(
+            global,
+            "move",
+            vec![
+                (
+                    "move",
+                    file_block(
+                        "motion_changexby",
+                        Some("set"),
+                        serde_json::json!({"DX": [1, [4, "10"]]}),
+                    ),
+                ),
+                ("set", set_score),
+            ],
+        )
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_step_back() {
+        let global = global();
+        let sprites = move_and_set_score(global.clone()).await;
+        let sprite_id = SpriteID::new(0);
+        let x = || async {
+            let properties = sprites.properties(&sprite_id).await.unwrap();
+            properties[0].1.clone()
+        };
+        let score = || async { global.variables.get("score_id").await.unwrap() };
+
+        assert!(sprites.step_back().await.unwrap().is_empty());
+        for _ in 0..3 {
+            sprites.step(thread_id()).await.unwrap();
+        }
+        assert_eq!(x().await, "10");
+        assert_eq!(score().await, Value::String("5".to_string()));
+        assert_eq!(sprites.inspect().await[0].status, ThreadStatus::Done);
+
+        assert_eq!(sprites.step_back().await.unwrap(), vec![thread_id()]);
+        assert_eq!(score().await, 0.0.into());
+        assert_eq!(sprites.inspect().await[0].status, ThreadStatus::Running);
+        assert_eq!(
+            sprites.position(thread_id()).await.unwrap().curr_block,
+            id("set")
+        );
+
+        sprites.step_back().await.unwrap();
+        assert_eq!(x().await, "0");
+        assert_eq!(
+            sprites.position(thread_id()).await.unwrap().curr_block,
+            id("move")
+        );
+
+        // Steps again after stepping back
+        sprites.step(thread_id()).await.unwrap();
+        assert_eq!(x().await, "10");
+    }
+
+    #[tokio::test]
+    async fn test_step_back_without_history() {
+        let global = global();
+        let sprites = move_and_set_score(global_with_history(false)).await;
+        sprites.step(thread_id()).await.unwrap();
+        assert!(sprites.step_back().await.unwrap().is_empty());
+
+        // Only the writes of the thread are taken
+        let other = writer();
+        let mut own = writer();
+        own.thread_id = thread_id();
+        global
+            .variables
+            .set("score_id", 1.0.into(), own)
+            .await
+            .unwrap();
+        global
+            .variables
+            .set("score_id", 2.0.into(), other)
+            .await
+            .unwrap();
+        assert_eq!(
+            global.variables.take_undo(thread_id()).await,
+            vec![("score_id".to_string(), 0.0.into())]
+        );
+        assert!(global.variables.take_undo(thread_id()).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_delete_clone() {
+        let sprites = move_and_set_score(global()).await;
+        let sprite_id = SpriteID::new(0);
+        let clone_id = sprites.clone_sprite(sprite_id).await.unwrap();
+        let clone_thread = ThreadID {
+            sprite_id: clone_id,
+            thread_id: 0,
+        };
+        sprites.step(thread_id()).await.unwrap();
+        sprites.remove(clone_id).await;
+        assert_eq!(sprites.inspect().await.len(), 1);
+        assert!(sprites.properties(&clone_id).await.is_err());
+        // Threads of the deleted clone are no longer scheduled
+        assert_eq!(sprites.step(clone_thread).await.unwrap(), None);
+
+        // Undoing the deletion puts the clone back in front of its original
+        let mut thread_ids = sprites.step_back().await.unwrap();
+        thread_ids.sort_unstable();
+        assert_eq!(thread_ids, vec![thread_id(), clone_thread]);
+        assert_eq!(sprites.inspect().await.len(), 2);
+        assert_eq!(
+            sprites.snapshot().await.unwrap().draw_order,
+            vec![sprite_id, clone_id]
+        );
+        assert!(sprites.properties(&clone_id).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_delete_clone_without_history() {
+        let sprites = move_and_set_score(global_with_history(false)).await;
+        let sprite_id = SpriteID::new(0);
+        let clone_id = sprites.clone_sprite(sprite_id).await.unwrap();
+        let snapshot = sprites.snapshot().await.unwrap();
+        sprites.remove(clone_id).await;
+        assert!(sprites.step_back().await.unwrap().is_empty());
+
+        // The slot of the deleted clone is used with a new ID
+        let new_clone_id = sprites.clone_sprite(sprite_id).await.unwrap();
+        assert_ne!(new_clone_id, clone_id);
+        assert_eq!(new_clone_id.handle().to_bits() as u32, 1);
+        assert!(sprites.properties(&clone_id).await.is_err());
+        let clone_thread = ThreadID {
+            sprite_id: clone_id,
+            thread_id: 0,
+        };
+        assert_eq!(sprites.step(clone_thread).await.unwrap(), None);
+
+        // The clone of the snapshot gets another ID because its slot was used again
+        let thread_ids = sprites.restore(&snapshot).await.unwrap();
+        assert_eq!(thread_ids.len(), 2);
+        assert!(!thread_ids.iter().any(|t| t.sprite_id == clone_id));
+        assert!(sprites.properties(&new_clone_id).await.is_err());
+        let draw_order = sprites.snapshot().await.unwrap().draw_order;
+        assert_eq!(draw_order[0], sprite_id);
+        assert_eq!(draw_order[1], thread_ids[1].sprite_id);
+    }
+
+    #[tokio::test]
+    async fn test_snapshot() {
+        let global = global();
+        let sprites = move_and_set_score(global.clone()).await;
+        let sprite_id = SpriteID::new(0);
+        let x = || async {
+            let properties = sprites.properties(&sprite_id).await.unwrap();
+            properties[0].1.clone()
+        };
+        let score = || async { global.variables.get("score_id").await.unwrap() };
+
+        sprites.step(thread_id()).await.unwrap();
+        sprites.step(thread_id()).await.unwrap();
+        let clone_id = sprites.clone_sprite(sprite_id).await.unwrap();
+        let snapshot = sprites.snapshot().await.unwrap();
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let snapshot: Snapshot = serde_json::from_str(&json).unwrap();
+
+        sprites.step(thread_id()).await.unwrap();
+        sprites.remove(clone_id).await;
+        assert_eq!(score().await, Value::String("5".to_string()));
+        assert_eq!(sprites.inspect().await.len(), 1);
+
+        // Nothing changes if a part of the snapshot cannot be restored
+        let mut invalid = snapshot.clone();
+        invalid
+            .variables
+            .push(("unknown".to_string(), SavedValue::Number(1.0)));
+        assert!(sprites.restore(&invalid).await.is_err());
+        let mut invalid = snapshot.clone();
+        invalid.sprites[1].threads[0].position.curr_block = id("unknown");
+        assert!(sprites.restore(&invalid).await.is_err());
+        // The clone is restored from the sprite that it was cloned from
+        assert_eq!(snapshot.sprites[1].original_id, sprite_id);
+        let mut invalid = snapshot.clone();
+        invalid.sprites[1].original_id = clone_id;
+        assert!(sprites.restore(&invalid).await.is_err());
+        assert_eq!(score().await, Value::String("5".to_string()));
+        assert_eq!(sprites.inspect().await.len(), 1);
+
+        let mut thread_ids = sprites.restore(&snapshot).await.unwrap();
+        thread_ids.sort_unstable();
+        assert_eq!(
+            thread_ids,
+            vec![
+                thread_id(),
+                ThreadID {
+                    sprite_id: clone_id,
+                    thread_id: 0
+                }
+            ]
+        );
+        assert_eq!(x().await, "10");
+        assert_eq!(score().await, 0.0.into());
+        assert_eq!(
+            sprites.position(thread_id()).await.unwrap().curr_block,
+            id("set")
+        );
+        assert_eq!(sprites.inspect().await.len(), 2);
+        // The history before the snapshot is gone
+        assert!(sprites.step_back().await.unwrap().is_empty());
+
+        // Clones that are not in the snapshot are deleted
+        let snapshot = Snapshot {
+            sprites: vec![snapshot.sprites[0].clone()],
+            draw_order: vec![sprite_id],
+            ..snapshot
+        };
+        sprites.restore(&snapshot).await.unwrap();
+        assert_eq!(sprites.inspect().await.len(), 1);
+
+        let mut snapshot = snapshot;
+        snapshot.sprites[0].name = "Sprite2".to_string();
+        assert!(sprites.restore(&snapshot).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_snapshot_repeat() {
+        let sprites = sprite_map(
+            global(),
+            "repeat",
+            vec![
+                (
+                    "repeat",
+                    file_block(
+                        "control_repeat",
+                        None,
+                        serde_json::json!({"TIMES": [1, [4, "3"]], "SUBSTACK": [2, "move"]}),
+                    ),
+                ),
+                (
+                    "move",
+                    file_block(
+                        "motion_changexby",
+                        None,
+                        serde_json::json!({"DX": [1, [4, "10"]]}),
+                    ),
+                ),
+            ],
+        )
+        .await;
+        let x = || async {
+            let properties = sprites.properties(&SpriteID::new(0)).await.unwrap();
+            properties[0].1.clone()
+        };
+        let run = || async { while sprites.step(thread_id()).await.unwrap().is_some() {} };
+
+        for _ in 0..3 {
+            sprites.step(thread_id()).await.unwrap();
+        }
+        assert_eq!(x().await, "10");
+        let snapshot = sprites.snapshot().await.unwrap();
+        assert_eq!(snapshot.sprites[0].threads[0].blocks[0].1.count, 1);
+        run().await;
+        assert_eq!(x().await, "30");
+
+        // The repeat block continues with the saved iteration
+        sprites.restore(&snapshot).await.unwrap();
+        assert_eq!(x().await, "10");
+        run().await;
+        assert_eq!(x().await, "30");
+    }
+
+    #[tokio::test]
+    async fn test_inspect_waiting() {
+        let sprites = sprite_map(
+            global(),
+            "wait",
+            vec![(
+                "wait",
+                file_block(
+                    "control_wait",
+                    None,
+                    serde_json::json!({"DURATION": [1, [5, "0.2"]]}),
+                ),
+            )],
+        )
+        .await;
+        sprites.step(thread_id()).await.unwrap();
+
+        let (_, threads) = tokio::join!(sprites.step(thread_id()), async {
+            tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            sprites.inspect().await
+        });
+        assert_eq!(threads[0].status, ThreadStatus::Waiting);
+        assert_eq!(threads[0].current_block.id, id("wait"));
+        assert_eq!(sprites.inspect().await[0].status, ThreadStatus::Done);
+    }
+}
@@ -1,5 +1,6 @@
 use super::*;
-use crate::file::BlockID;
+use crate::file::{BlockID, LoadReport};
+use crate::vm::ThreadID;
 
 #[derive(Debug, thiserror::Error)]
 pub enum ScratchError {
@@ -27,10 +28,45 @@ pub enum ScratchError {
         error: Error,
     },
 
+    #[error("asset integrity check failed:\n{report}")]
+    AssetIntegrity { report: LoadReport },
+
+    #[error("decompressed size exceeds limit of {limit} bytes")]
+    DecompressedSizeLimit { limit: u64 },
+
+    #[error("file contains {count} assets which exceeds limit of {limit}")]
+    AssetCountLimit { count: usize, limit: usize },
+
+    #[error("project contains {count} blocks which exceeds limit of {limit}")]
+    BlockCountLimit { count: usize, limit: usize },
+
+    #[error("project.json nesting depth exceeds limit of {limit}")]
+    JsonDepthLimit { limit: usize },
+
+    #[error("image \"{file_name}\" is {width}x{height} which exceeds limit of {limit}")]
+    ImageDimensionLimit {
+        file_name: String,
+        width: f64,
+        height: f64,
+        limit: f64,
+    },
+
+    #[error("size of image \"{file_name}\" cannot be read: {error}")]
+    ImageSize { file_name: String, error: Error },
+
     #[error("block \"{id}\" of type {name} returned error during execution: {error}")]
     Block {
         id: BlockID,
         name: &'static str,
         error: Error,
     },
+
+    #[error("thread {} of sprite {} stopped: {error}", thread_id.thread_id, thread_id.sprite_id)]
+    Thread { thread_id: ThreadID, error: Error },
+
+    #[error("error while handling broadcast: {error}")]
+    Broadcast { error: Error },
+
+    #[error("cannot export project:\n{}", reasons.join("\n"))]
+    Export { reasons: Vec<String> },
 }
@@ -14,6 +14,133 @@ pub struct ScratchFile {
 
     /// Filename to file contents
     pub images: HashMap<String, Image>,
+
+    pub load_report: LoadReport,
+}
+
+#[derive(PartialEq, Clone, Default, Debug)]
+pub struct LoadOptions {
+    pub mode: LoadMode,
+    pub limits: LoadLimits,
+}
+
+/// Limits that protect against files that would exhaust memory.
+#[derive(PartialEq, Clone, Debug)]
+pub struct LoadLimits {
+    /// Total size in bytes of all decompressed files, including project.json
+    pub max_decompressed_size: u64,
+    /// Number of files other than project.json
+    pub max_asset_count: usize,
+    /// Number of blocks in all targets
+    pub max_block_count: usize,
+    /// Nesting depth of arrays and objects in project.json. serde_json also stops at 128.
+    pub max_json_depth: usize,
+    /// Width and height of images in pixels. Images whose size cannot be read are rejected.
+    pub max_image_dimension: f64,
+}
+
+impl Default for LoadLimits {
+    fn default() -> Self {
+        Self {
+            max_decompressed_size: 256 * 1024 * 1024,
+            max_asset_count: 4096,
+            max_block_count: 500_000,
+            max_json_depth: 64,
+            max_image_dimension: 4096.0,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Copy, Clone, Debug, Default)]
+pub enum LoadMode {
+    /// Problems in the file are returned as errors.
+    Strict,
+    /// Problems are recorded in LoadReport and loading continues.
+    #[default]
+    Lenient,
+}
+
+/// Problems found while loading a file.
+#[derive(PartialEq, Eq, Clone, Default, Debug)]
+pub struct LoadReport {
+    /// Assets whose contents do not hash to the MD5 in their file name
+    pub mismatched_assets: Vec<AssetMismatch>,
+    /// Assets that are referenced by a target but are not in the file
+    pub missing_assets: Vec<AssetReference>,
+    /// Files that are not referenced by any target
+    pub orphaned_files: Vec<String>,
+    /// Assets that are in the file but cannot be decoded
+    pub undecodable_assets: Vec<AssetError>,
+}
+
+impl LoadReport {
+    pub fn is_ok(&self) -> bool {
+        self.mismatched_assets.is_empty()
+            && self.missing_assets.is_empty()
+            && self.orphaned_files.is_empty()
+            && self.undecodable_assets.is_empty()
+    }
+}
+
+impl Display for LoadReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.is_ok() {
+            return f.write_str("no problems found");
+        }
+
+        for mismatch in &self.mismatched_assets {
+            writeln!(
+                f,
+                "mismatched asset: {} (expected md5 {}, actual md5 {})",
+                mismatch.asset, mismatch.expected_md5, mismatch.actual_md5
+            )?;
+        }
+        for asset in &self.missing_assets {
+            writeln!(f, "missing asset: {}", asset)?;
+        }
+        for file_name in &self.orphaned_files {
+            writeln!(f, "orphaned file: {}", file_name)?;
+        }
+        for asset_error in &self.undecodable_assets {
+            writeln!(
+                f,
+                "undecodable asset: {} ({})",
+                asset_error.asset, asset_error.error
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct AssetMismatch {
+    pub asset: AssetReference,
+    pub expected_md5: String,
+    pub actual_md5: String,
+}
+
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct AssetError {
+    pub asset: AssetReference,
+    pub error: String,
+}
+
+/// Costume or sound of a target
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct AssetReference {
+    pub target_name: String,
+    pub asset_name: String,
+    pub md5ext: String,
+}
+
+impl Display for AssetReference {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} (\"{}\" of target \"{}\")",
+            self.md5ext, self.asset_name, self.target_name
+        )
+    }
 }
 
 #[derive(PartialEq, Clone, Default, Debug, Serialize, Deserialize)]
@@ -31,9 +158,13 @@ pub struct Target {
     pub is_stage: bool,
     pub name: String,
     pub variables: HashMap<String, Variable>,
+    #[serde(default)]
+    pub lists: HashMap<String, List>,
     pub blocks: HashMap<BlockID, Block>,
     pub costumes: Vec<Costume>,
     #[serde(default)]
+    pub sounds: Vec<Sound>,
+    #[serde(default)]
     pub layer_order: usize,
     #[serde(default)]
     pub x: f64,
@@ -51,8 +182,10 @@ impl Default for Target {
             is_stage: false,
             name: String::new(),
             variables: HashMap::new(),
+            lists: HashMap::new(),
             blocks: HashMap::new(),
             costumes: Vec::new(),
+            sounds: Vec::new(),
             layer_order: 0,
             x: 0.0,
             y: 0.0,
@@ -67,8 +200,10 @@ impl Hash for Target {
         self.is_stage.hash(state);
         self.name.hash(state);
         sorted_entries(&self.variables).hash(state);
+        sorted_entries(&self.lists).hash(state);
         sorted_entries(&self.blocks).hash(state);
         self.costumes.hash(state);
+        self.sounds.hash(state);
         self.x.to_bits().hash(state);
         self.y.to_bits().hash(state);
         self.size.to_bits().hash(state);
@@ -104,6 +239,27 @@ impl PartialEq for Variable {
     }
 }
 
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+pub struct List {
+    pub name: String,
+    pub values: Vec<Value>,
+}
+
+impl Hash for List {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.name.hash(state);
+        for value in &self.values {
+            hash_value(value, state);
+        }
+    }
+}
+
+impl PartialEq for List {
+    fn eq(&self, other: &Self) -> bool {
+        compare(self, other)
+    }
+}
+
 fn compare<A, B>(a: A, b: B) -> bool
 where
     A: Hash,
@@ -116,7 +272,7 @@ where
     hasher_a.finish() == hasher_b.finish()
 }
 
-#[derive(Clone, Default, Debug, Serialize, Deserialize)]
+#[derive(Clone, Default, Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Block {
     pub opcode: String,
@@ -126,6 +282,90 @@ pub struct Block {
     pub top_level: bool,
 }
 
+impl Block {
+    /// Expands a compact primitive such as `[12, name, id, x, y]` into a block. Loose variable and
+    /// list reporters are stored in this form.
+    /// https://en.scratch-wiki.info/wiki/Scratch_File_Format#Blocks
+    pub fn from_primitive(arr: &[Value]) -> Result<Self> {
+        let err = || Error::msg(format!("invalid primitive: {:?}", arr));
+        let field_value = |index: usize| -> Result<Option<String>> {
+            Ok(Some(match arr.get(index).ok_or_else(err)? {
+                Value::String(s) => s.clone(),
+                v => v.to_string(),
+            }))
+        };
+
+        let primitive_type = arr.first().and_then(|v| v.as_i64()).ok_or_else(err)?;
+        let (opcode, field_name, field) = match primitive_type {
+            4 => ("math_number", "NUM", vec![field_value(1)?]),
+            5 => ("math_positive_number", "NUM", vec![field_value(1)?]),
+            6 => ("math_whole_number", "NUM", vec![field_value(1)?]),
+            7 => ("math_integer", "NUM", vec![field_value(1)?]),
+            8 => ("math_angle", "NUM", vec![field_value(1)?]),
+            9 => ("colour_picker", "COLOUR", vec![field_value(1)?]),
+            10 => ("text", "TEXT", vec![field_value(1)?]),
+            11 => (
+                "event_broadcast_menu",
+                "BROADCAST_OPTION",
+                vec![field_value(1)?, field_value(2)?],
+            ),
+            12 => (
+                "data_variable",
+                "VARIABLE",
+                vec![field_value(1)?, field_value(2)?],
+            ),
+            13 => (
+                "data_listcontents",
+                "LIST",
+                vec![field_value(1)?, field_value(2)?],
+            ),
+            _ => return Err(err()),
+        };
+
+        let mut fields: HashMap<String, Vec<Option<String>>> = HashMap::new();
+        fields.insert(field_name.to_string(), field);
+        Ok(Self {
+            opcode: opcode.to_string(),
+            next: None,
+            inputs: HashMap::new(),
+            fields,
+            top_level: true,
+        })
+    }
+}
+
+impl<'de> Deserialize<'de> for Block {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, <D as Deserializer<'de>>::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct BlockObject {
+            opcode: String,
+            next: Option<BlockID>,
+            inputs: HashMap<String, Value>,
+            fields: HashMap<String, Vec<Option<String>>>,
+            top_level: bool,
+        }
+
+        match Value::deserialize(deserializer)? {
+            Value::Array(arr) => Block::from_primitive(&arr).map_err(serde::de::Error::custom),
+            value => {
+                let b: BlockObject =
+                    serde_json::from_value(value).map_err(serde::de::Error::custom)?;
+                Ok(Self {
+                    opcode: b.opcode,
+                    next: b.next,
+                    inputs: b.inputs,
+                    fields: b.fields,
+                    top_level: b.top_level,
+                })
+            }
+        }
+    }
+}
+
 impl Hash for Block {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.opcode.hash(state);
@@ -204,6 +444,16 @@ impl PartialEq for Costume {
     }
 }
 
+#[derive(PartialEq, Eq, Clone, Default, Debug, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Sound {
+    pub name: String,
+    pub md5ext: Option<String>,
+    pub asset_id: String,
+    #[serde(default)]
+    pub data_format: String,
+}
+
 #[derive(PartialEq, Clone, Default, Debug, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Monitor {
@@ -236,24 +486,71 @@ pub struct Meta {
     pub agent: String,
 }
 
+/// Variants are named after the file extensions.
+#[allow(clippy::upper_case_acronyms)]
 #[derive(PartialEq, Eq, Clone)]
 pub enum Image {
     SVG(Vec<u8>),
     PNG(Vec<u8>),
+    JPEG(Vec<u8>),
+    /// Only the first frame is used.
+    GIF(Vec<u8>),
+    BMP(Vec<u8>),
+}
+
+impl Image {
+    /// Returns None if the file extension is not a supported image format.
+    pub fn new(file_name: &str, data: Vec<u8>) -> Option<Self> {
+        let (_, extension) = file_name.rsplit_once('.')?;
+        Some(match extension.to_ascii_lowercase().as_str() {
+            "svg" => Image::SVG(data),
+            "png" => Image::PNG(data),
+            "jpg" | "jpeg" => Image::JPEG(data),
+            "gif" => Image::GIF(data),
+            "bmp" => Image::BMP(data),
+            _ => return None,
+        })
+    }
+
+    pub fn is_image_file_name(file_name: &str) -> bool {
+        Image::new(file_name, Vec::new()).is_some()
+    }
+
+    /// Width and height in pixels, read without decoding the image.
+    pub fn size(&self) -> Result<(f64, f64)> {
+        let format = match self {
+            Image::SVG(data) => return scratch_runtime::image::svg_size(data),
+            Image::PNG(_) => image::ImageFormat::Png,
+            Image::JPEG(_) => image::ImageFormat::Jpeg,
+            Image::GIF(_) => image::ImageFormat::Gif,
+            Image::BMP(_) => image::ImageFormat::Bmp,
+        };
+        let (width, height) = scratch_runtime::image::bitmap_size(self.bytes(), format)?;
+        Ok((width as f64, height as f64))
+    }
+
+    /// Contents of the file.
+    pub fn bytes(&self) -> &[u8] {
+        match self {
+            Image::SVG(data)
+            | Image::PNG(data)
+            | Image::JPEG(data)
+            | Image::GIF(data)
+            | Image::BMP(data) => data,
+        }
+    }
 }
 
 impl Debug for Image {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        let vec_len = match self {
-            Image::SVG(v) => {
-                write!(f, "SVG(")?;
-                v.len()
-            }
-            Image::PNG(v) => {
-                write!(f, "PNG(")?;
-                v.len()
-            }
+        let (name, vec_len) = match self {
+            Image::SVG(v) => ("SVG", v.len()),
+            Image::PNG(v) => ("PNG", v.len()),
+            Image::JPEG(v) => ("JPEG", v.len()),
+            Image::GIF(v) => ("GIF", v.len()),
+            Image::BMP(v) => ("BMP", v.len()),
         };
+        write!(f, "{}(", name)?;
 
         if vec_len > 0 {
             write!(f, "[...]")?;
@@ -265,15 +562,66 @@ impl Debug for Image {
     }
 }
 
-#[derive(Copy, Clone, PartialOrd, Ord, PartialEq, Eq, Default, Hash)]
+/// Interned block ID of any length. Copying, comparing and hashing only touch a pointer, and
+/// reading the string does not lock the interner.
+///
+/// Interned strings are kept until the process exits. A load adds at most the length of
+/// project.json, which LoadLimits::max_decompressed_size limits, and IDs that were already loaded,
+/// such as those of a project that is loaded again, are not added again.
+#[derive(Copy, Clone)]
 pub struct BlockID {
-    id: [u8; 20],
+    id: &'static str,
 }
 
+/// Shared by all pseudo IDs, so that they have the same address
+static PSEUDO_ID: &str = "";
+
 impl BlockID {
     /// Indicates block that did not come from the .sb3 file.
     pub fn pseudo_id() -> BlockID {
-        BlockID::try_from("                    ").unwrap()
+        BlockID { id: PSEUDO_ID }
+    }
+}
+
+impl Default for BlockID {
+    fn default() -> Self {
+        BlockID::pseudo_id()
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref BLOCK_ID_INTERNER: std::sync::RwLock<HashSet<&'static str>> =
+        std::sync::RwLock::new(HashSet::new());
+}
+
+/// Each string is interned once, so equal IDs have the same address.
+impl PartialEq for BlockID {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self.id, other.id)
+    }
+}
+
+impl Eq for BlockID {}
+
+impl Hash for BlockID {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (self.id.as_ptr() as usize).hash(state)
+    }
+}
+
+impl PartialOrd for BlockID {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for BlockID {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        if self == other {
+            std::cmp::Ordering::Equal
+        } else {
+            self.id.cmp(other.id)
+        }
     }
 }
 
@@ -287,7 +635,7 @@ impl Debug for BlockID {
 
 impl Display for BlockID {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        f.write_str(std::str::from_utf8(&self.id[..10]).map_err(|_| std::fmt::Error {})?)
+        f.write_str(self.id)
     }
 }
 
@@ -295,14 +643,22 @@ impl TryFrom<&str> for BlockID {
     type Error = Error;
 
     fn try_from(s: &str) -> Result<Self> {
-        let mut id: [u8; 20] = [0; 20];
-        let s_bytes = s.as_bytes();
-        if s_bytes.len() == id.len() {
-            id.copy_from_slice(s_bytes);
-            Ok(Self { id })
-        } else {
-            Err(Error::msg("invalid string"))
+        if s.is_empty() {
+            return Err(Error::msg("invalid string"));
+        }
+
+        if let Some(&id) = BLOCK_ID_INTERNER.read().unwrap().get(s) {
+            return Ok(Self { id });
         }
+
+        let mut interner = BLOCK_ID_INTERNER.write().unwrap();
+        // Another thread may have interned the string before the write lock was taken
+        if let Some(&id) = interner.get(s) {
+            return Ok(Self { id });
+        }
+        let id: &'static str = Box::leak(s.into());
+        interner.insert(id);
+        Ok(Self { id })
     }
 }
 
@@ -314,7 +670,7 @@ impl Serialize for BlockID {
     where
         S: Serializer,
     {
-        serializer.serialize_str(&self.to_string())
+        serializer.serialize_str(self.id)
     }
 }
 
@@ -345,43 +701,221 @@ impl<'de> Deserialize<'de> for BlockID {
 }
 
 impl ScratchFile {
-    pub fn parse<R>(file: R) -> Result<ScratchFile>
+    pub fn parse_with_options<R>(file: R, options: &LoadOptions) -> Result<ScratchFile>
     where
         R: std::io::Read + std::io::Seek,
     {
-        use std::io::Read;
-
+        let limits = &options.limits;
         let mut archive = zip::ZipArchive::new(file)?;
-        let project: Project = serde_json::from_reader(archive.by_name("project.json")?)?;
 
-        let mut image_names: Vec<String> = Vec::new();
-        for name in archive.file_names() {
-            if name.ends_with(".svg") | name.ends_with(".png") {
-                image_names.push(name.to_string());
+        let asset_count = archive.len().saturating_sub(1);
+        if asset_count > limits.max_asset_count {
+            return Err(ScratchError::AssetCountLimit {
+                count: asset_count,
+                limit: limits.max_asset_count,
             }
+            .into());
         }
 
+        let mut remaining_size = limits.max_decompressed_size;
+
+        let mut project_json: Vec<u8> = Vec::new();
+        copy_limited(
+            &mut archive.by_name("project.json")?,
+            &mut project_json,
+            &mut remaining_size,
+            limits,
+        )?;
+        check_json_depth(&project_json, limits.max_json_depth)?;
+        let project: Project = serde_json::from_slice(&project_json)?;
+        drop(project_json);
+
+        let block_count: usize = project.targets.iter().map(|t| t.blocks.len()).sum();
+        if block_count > limits.max_block_count {
+            return Err(ScratchError::BlockCountLimit {
+                count: block_count,
+                limit: limits.max_block_count,
+            }
+            .into());
+        }
+
+        let file_names: Vec<String> = archive
+            .file_names()
+            .filter(|name| *name != "project.json")
+            .map(|name| name.to_string())
+            .collect();
+
         let mut images: HashMap<String, Image> = HashMap::new();
-        for name in &image_names {
-            let mut b: Vec<u8> = Vec::new();
-            archive.by_name(name).unwrap().read_to_end(&mut b)?;
-            let image = if name.ends_with(".svg") {
-                Image::SVG(b)
-            } else if name.ends_with(".png") {
-                Image::PNG(b)
+        // Filename to MD5 of file contents
+        let mut hashes: HashMap<String, String> = HashMap::with_capacity(file_names.len());
+        for name in &file_names {
+            let mut zip_file = archive.by_name(name)?;
+            if Image::is_image_file_name(name) {
+                let mut b: Vec<u8> = Vec::new();
+                copy_limited(&mut zip_file, &mut b, &mut remaining_size, limits)?;
+                hashes.insert(name.clone(), format!("{:x}", md5::compute(&b)));
+                let image = Image::new(name, b)
+                    .ok_or_else(|| Error::msg(format!("unrecognized file extension: {}", name)))?;
+                check_image_size(name, &image, limits.max_image_dimension)?;
+                images.insert(name.clone(), image);
             } else {
-                return Err(Error::msg("unrecognized file extension"));
-            };
-            images.insert(name.clone(), image);
+                let mut context = md5::Context::new();
+                copy_limited(&mut zip_file, &mut context, &mut remaining_size, limits)?;
+                hashes.insert(name.clone(), format!("{:x}", context.compute()));
+            }
+        }
+
+        let load_report = ScratchFile::verify_assets(&project, &hashes);
+        if options.mode == LoadMode::Strict && !load_report.is_ok() {
+            return Err(ScratchError::AssetIntegrity {
+                report: load_report,
+            }
+            .into());
+        }
+
+        Ok(Self {
+            project,
+            images,
+            load_report,
+        })
+    }
+
+    /// Checks that each costume and sound is in the file and that its contents hash to the MD5 in
+    /// its file name.
+    fn verify_assets(project: &Project, hashes: &HashMap<String, String>) -> LoadReport {
+        let mut report = LoadReport::default();
+        let mut referenced: HashSet<&str> = HashSet::new();
+
+        for target in &project.targets {
+            let costumes = target
+                .costumes
+                .iter()
+                .map(|c| (&c.name, &c.md5ext, &c.asset_id));
+            let sounds = target
+                .sounds
+                .iter()
+                .map(|s| (&s.name, &s.md5ext, &s.asset_id));
+
+            for (asset_name, md5ext, asset_id) in costumes.chain(sounds) {
+                // Pre-made Scratch backdrops are not included in the file
+                let md5ext = match md5ext {
+                    Some(md5ext) => md5ext,
+                    None => continue,
+                };
+
+                let asset = AssetReference {
+                    target_name: target.name.clone(),
+                    asset_name: asset_name.clone(),
+                    md5ext: md5ext.clone(),
+                };
+
+                match hashes.get(md5ext) {
+                    Some(actual_md5) => {
+                        referenced.insert(md5ext);
+                        let expected_md5 = md5ext.split('.').next().unwrap_or(asset_id);
+                        if !actual_md5.eq_ignore_ascii_case(expected_md5) {
+                            report.mismatched_assets.push(AssetMismatch {
+                                asset,
+                                expected_md5: expected_md5.to_string(),
+                                actual_md5: actual_md5.clone(),
+                            });
+                        }
+                    }
+                    None => report.missing_assets.push(asset),
+                }
+            }
+        }
+
+        report.orphaned_files = hashes
+            .keys()
+            .filter(|name| !referenced.contains(name.as_str()))
+            .cloned()
+            .collect();
+        report.orphaned_files.sort_unstable();
+        report
+    }
+}
+
+/// Copies all of reader into writer, failing if the total decompressed size would exceed the limit.
+/// Sizes in zip headers are not trusted.
+fn copy_limited<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    remaining_size: &mut u64,
+    limits: &LoadLimits,
+) -> Result<()>
+where
+    R: std::io::Read,
+    W: std::io::Write,
+{
+    use std::io::Read;
+
+    // Read one more byte than allowed to detect files that are too large
+    let copied = std::io::copy(&mut reader.take(*remaining_size + 1), writer)?;
+    if copied > *remaining_size {
+        return Err(ScratchError::DecompressedSizeLimit {
+            limit: limits.max_decompressed_size,
+        }
+        .into());
+    }
+    *remaining_size -= copied;
+    Ok(())
+}
+
+/// Checks nesting depth without parsing, so that deeply nested input cannot exhaust the stack.
+fn check_json_depth(json: &[u8], limit: usize) -> Result<()> {
+    let mut depth: usize = 0;
+    let mut in_string = false;
+    let mut escaped = false;
+    for &b in json {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if b == b'\\' {
+                escaped = true;
+            } else if b == b'"' {
+                in_string = false;
+            }
+            continue;
+        }
+
+        match b {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                depth += 1;
+                if depth > limit {
+                    return Err(ScratchError::JsonDepthLimit { limit }.into());
+                }
+            }
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => {}
         }
+    }
+    Ok(())
+}
 
-        Ok(Self { project, images })
+fn check_image_size(file_name: &str, image: &Image, limit: f64) -> Result<()> {
+    let (width, height) = image.size().map_err(|error| ScratchError::ImageSize {
+        file_name: file_name.to_string(),
+        error,
+    })?;
+    if width > limit || height > limit {
+        return Err(ScratchError::ImageDimensionLimit {
+            file_name: file_name.to_string(),
+            width,
+            height,
+            limit,
+        }
+        .into());
     }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::testing::{png, sb3};
+    use std::io::Cursor;
 
     #[test]
     fn test_savefile() {
@@ -393,11 +927,279 @@ mod tests {
             .join("test_saves")
             .join("say.sb3");
         let file = std::fs::File::open(dir).unwrap();
-        let savefile = ScratchFile::parse(&file).unwrap();
+        let savefile = ScratchFile::parse_with_options(&file, &LoadOptions::default()).unwrap();
         let target = &savefile.project.targets[1];
         assert_eq!(target.name, "Sprite1");
     }
 
+    #[test]
+    fn test_image_new() {
+        assert!(matches!(Image::new("a.svg", vec![]), Some(Image::SVG(_))));
+        assert!(matches!(Image::new("a.png", vec![]), Some(Image::PNG(_))));
+        assert!(matches!(Image::new("a.jpg", vec![]), Some(Image::JPEG(_))));
+        assert!(matches!(Image::new("a.JPEG", vec![]), Some(Image::JPEG(_))));
+        assert!(matches!(Image::new("a.gif", vec![]), Some(Image::GIF(_))));
+        assert!(matches!(Image::new("a.bmp", vec![]), Some(Image::BMP(_))));
+        assert!(Image::new("a.wav", vec![]).is_none());
+        assert!(Image::new("project.json", vec![]).is_none());
+        assert!(Image::new("svg", vec![]).is_none());
+    }
+
+    #[test]
+    fn test_block_deserialize() {
+        {
+            let block: Block = serde_json::from_str(
+                r#"{"opcode":"motion_movesteps","next":null,"inputs":{},"fields":{},"topLevel":true}"#,
+            )
+            .unwrap();
+            assert_eq!(block.opcode, "motion_movesteps");
+            assert!(block.top_level);
+        }
+        {
+            let block: Block = serde_json::from_str(
+                r#"[12, "my variable", "`jEk@4|i[#Fk?(8x)AV.-my variable", 10, 20]"#,
+            )
+            .unwrap();
+            assert_eq!(block.opcode, "data_variable");
+            assert_eq!(
+                block.fields["VARIABLE"],
+                vec![
+                    Some("my variable".to_string()),
+                    Some("`jEk@4|i[#Fk?(8x)AV.-my variable".to_string())
+                ]
+            );
+            assert!(block.top_level);
+        }
+        {
+            let block: Block = serde_json::from_str(r#"[13, "list", "id", 0, 0]"#).unwrap();
+            assert_eq!(block.opcode, "data_listcontents");
+        }
+        {
+            let block: Block = serde_json::from_str(r#"[4, 10]"#).unwrap();
+            assert_eq!(block.opcode, "math_number");
+            assert_eq!(block.fields["NUM"], vec![Some("10".to_string())]);
+        }
+        {
+            assert!(serde_json::from_str::<Block>(r#"[3, "a"]"#).is_err());
+            assert!(serde_json::from_str::<Block>(r#"[12, "a"]"#).is_err());
+            assert!(serde_json::from_str::<Block>(r#"[]"#).is_err());
+        }
+    }
+
+    mod verify_assets {
+        use super::*;
+
+        #[test]
+        fn test_valid() {
+            let image = png(1, 1);
+            let md5ext = format!("{:x}.png", md5::compute(&image));
+            let file = sb3(&md5ext, &[(&md5ext, &image)]);
+            let scratch_file =
+                ScratchFile::parse_with_options(file, &LoadOptions::default()).unwrap();
+            assert_eq!(scratch_file.load_report, LoadReport::default());
+        }
+
+        #[test]
+        fn test_problems() {
+            let md5ext = format!("{:x}.png", md5::compute(png(1, 1)));
+            let corrupted = png(2, 2);
+            let file = sb3(&md5ext, &[(&md5ext, &corrupted), ("a.wav", b"sound")]);
+            let scratch_file =
+                ScratchFile::parse_with_options(file, &LoadOptions::default()).unwrap();
+            let report = scratch_file.load_report;
+            assert_eq!(report.mismatched_assets.len(), 1);
+            assert_eq!(report.mismatched_assets[0].asset.md5ext, md5ext);
+            assert_eq!(
+                report.mismatched_assets[0].actual_md5,
+                format!("{:x}", md5::compute(&corrupted))
+            );
+            assert!(report.missing_assets.is_empty());
+            assert_eq!(report.orphaned_files, vec!["a.wav".to_string()]);
+        }
+
+        #[test]
+        fn test_missing() {
+            let file = sb3("0123456789abcdef0123456789abcdef.svg", &[]);
+            let scratch_file =
+                ScratchFile::parse_with_options(file, &LoadOptions::default()).unwrap();
+            assert_eq!(scratch_file.load_report.missing_assets.len(), 1);
+            assert_eq!(
+                scratch_file.load_report.missing_assets[0].asset_name,
+                "backdrop1"
+            );
+        }
+
+        #[test]
+        fn test_strict() {
+            let options = LoadOptions {
+                mode: LoadMode::Strict,
+                ..LoadOptions::default()
+            };
+            {
+                let image = png(1, 1);
+                let md5ext = format!("{:x}.png", md5::compute(&image));
+                let file = sb3(&md5ext, &[(&md5ext, &image)]);
+                assert!(ScratchFile::parse_with_options(file, &options).is_ok());
+            }
+            {
+                let file = sb3("0123456789abcdef0123456789abcdef.svg", &[]);
+                assert!(ScratchFile::parse_with_options(file, &options).is_err());
+            }
+        }
+    }
+
+    mod limits {
+        use super::*;
+
+        fn parse_with_limits(file: Cursor<Vec<u8>>, limits: LoadLimits) -> Result<ScratchFile> {
+            ScratchFile::parse_with_options(
+                file,
+                &LoadOptions {
+                    mode: LoadMode::Lenient,
+                    limits,
+                },
+            )
+        }
+
+        fn limit_error(result: Result<ScratchFile>) -> ScratchError {
+            result.unwrap_err().downcast::<ScratchError>().unwrap()
+        }
+
+        #[test]
+        fn test_decompressed_size() {
+            let large = vec![0u8; 1024 * 1024];
+            let file = sb3("a.wav", &[("a.wav", &large)]);
+            {
+                let limits = LoadLimits {
+                    max_decompressed_size: 1024 * 1024 - 1,
+                    ..LoadLimits::default()
+                };
+                assert!(matches!(
+                    limit_error(parse_with_limits(file.clone(), limits)),
+                    ScratchError::DecompressedSizeLimit { .. }
+                ));
+            }
+            {
+                let limits = LoadLimits {
+                    max_decompressed_size: 2 * 1024 * 1024,
+                    ..LoadLimits::default()
+                };
+                assert!(parse_with_limits(file, limits).is_ok());
+            }
+        }
+
+        #[test]
+        fn test_asset_count() {
+            let file = sb3("a.png", &[("a.png", b""), ("b.png", b"")]);
+            let limits = LoadLimits {
+                max_asset_count: 1,
+                ..LoadLimits::default()
+            };
+            assert!(matches!(
+                limit_error(parse_with_limits(file, limits)),
+                ScratchError::AssetCountLimit { count: 2, limit: 1 }
+            ));
+        }
+
+        #[test]
+        fn test_block_count() {
+            let dir = std::path::Path::new(file!())
+                .parent()
+                .unwrap()
+                .parent()
+                .unwrap()
+                .join("test_saves")
+                .join("say.sb3");
+            let file = Cursor::new(std::fs::read(dir).unwrap());
+            let limits = LoadLimits {
+                max_block_count: 0,
+                ..LoadLimits::default()
+            };
+            assert!(matches!(
+                limit_error(parse_with_limits(file, limits)),
+                ScratchError::BlockCountLimit { .. }
+            ));
+        }
+
+        #[test]
+        fn test_check_json_depth() {
+            assert!(check_json_depth(b"{}", 1).is_ok());
+            assert!(check_json_depth(b"[[]]", 1).is_err());
+            assert!(check_json_depth(b"[[]]", 2).is_ok());
+            assert!(check_json_depth(b"[], [], []", 1).is_ok());
+            assert!(check_json_depth(br#"["[[[", "\"[["]"#, 1).is_ok());
+            assert!(check_json_depth(&[b'['; 10000], 64).is_err());
+        }
+
+        #[test]
+        fn test_image_dimension() {
+            let svg =
+                br#"<svg xmlns="http://www.w3.org/2000/svg" width="10000" height="10"></svg>"#;
+            let file = sb3("a.svg", &[("a.svg", svg)]);
+            {
+                assert!(matches!(
+                    limit_error(parse_with_limits(file.clone(), LoadLimits::default())),
+                    ScratchError::ImageDimensionLimit { .. }
+                ));
+            }
+            {
+                let limits = LoadLimits {
+                    max_image_dimension: 10000.0,
+                    ..LoadLimits::default()
+                };
+                assert!(parse_with_limits(file, limits).is_ok());
+            }
+            {
+                let image = png(1, 5000);
+                let file = sb3("a.png", &[("a.png", &image)]);
+                assert!(matches!(
+                    limit_error(parse_with_limits(file, LoadLimits::default())),
+                    ScratchError::ImageDimensionLimit {
+                        width,
+                        height,
+                        ..
+                    } if width == 1.0 && height == 5000.0
+                ));
+            }
+        }
+
+        #[test]
+        fn test_svg_in_comment() {
+            let svg = br#"<!-- <svg width="1" height="1"> -->
+                <svg xmlns="http://www.w3.org/2000/svg" width="10000" height="10"></svg>"#;
+            let file = sb3("a.svg", &[("a.svg", svg)]);
+            assert!(matches!(
+                limit_error(parse_with_limits(file, LoadLimits::default())),
+                ScratchError::ImageDimensionLimit { .. }
+            ));
+        }
+
+        #[test]
+        fn test_unreadable_size() {
+            let md5ext = format!("{:x}.svg", md5::compute(b"corrupted"));
+            let file = sb3(&md5ext, &[(&md5ext, b"corrupted")]);
+            assert!(matches!(
+                limit_error(parse_with_limits(file.clone(), LoadLimits::default())),
+                ScratchError::ImageSize { .. }
+            ));
+
+            let options = LoadOptions {
+                mode: LoadMode::Strict,
+                ..LoadOptions::default()
+            };
+            assert!(matches!(
+                limit_error(ScratchFile::parse_with_options(file, &options)),
+                ScratchError::ImageSize { .. }
+            ));
+
+            let file = sb3("a.png", &[("a.png", b"corrupted")]);
+            assert!(matches!(
+                limit_error(parse_with_limits(file, LoadLimits::default())),
+                ScratchError::ImageSize { .. }
+            ));
+        }
+    }
+
     mod block_id {
         use super::*;
 
@@ -407,13 +1209,46 @@ mod tests {
                 assert!(BlockID::try_from("").is_err());
             }
             {
-                assert!(BlockID::try_from("a").is_err());
+                let id = BlockID::try_from("a").unwrap();
+                assert_eq!(&id.to_string(), "a");
             }
             {
                 let s = "G@pZX]3ynBGB)L`_LJk8";
                 let id = BlockID::try_from(s).unwrap();
-                assert_eq!(&id.to_string(), "G@pZX]3ynB");
+                assert_eq!(&id.to_string(), s);
+                assert_eq!(id, BlockID::try_from(s).unwrap());
+                assert_ne!(id, BlockID::try_from("G@pZX]3ynBGB)L`_LJk9").unwrap());
             }
+            {
+                let s = "a very long block id from another editor";
+                assert_eq!(&BlockID::try_from(s).unwrap().to_string(), s);
+            }
+        }
+
+        #[test]
+        fn test_interned() {
+            let a = BlockID::try_from("interned").unwrap();
+            let b = BlockID::try_from(String::from("interned").as_str()).unwrap();
+            assert!(std::ptr::eq(a.id, b.id));
+            assert_eq!(BlockID::pseudo_id(), BlockID::default());
+            assert_ne!(BlockID::pseudo_id(), a);
+        }
+
+        #[test]
+        fn test_ord() {
+            let a = BlockID::try_from("b").unwrap();
+            let b = BlockID::try_from("a").unwrap();
+            assert!(b < a);
+            assert_eq!(a.cmp(&a), std::cmp::Ordering::Equal);
+        }
+
+        #[test]
+        fn test_serde() {
+            let id = BlockID::try_from("1234").unwrap();
+            let s = serde_json::to_string(&id).unwrap();
+            assert_eq!(s, r#""1234""#);
+            assert_eq!(serde_json::from_str::<BlockID>(&s).unwrap(), id);
+            assert!(serde_json::from_str::<BlockID>(r#""""#).is_err());
         }
     }
 }
@@ -1,18 +1,22 @@
 use super::*;
+use crate::blocks::is_supported;
 use crate::broadcaster::Broadcaster;
-use crate::file::ScratchFile;
-use crate::runtime::Global;
+use crate::file::{BlockID, LoadMode, LoadOptions, LoadReport, ScratchFile};
+use crate::runtime::{Global, Runtime};
 use crate::sprite::{Sprite, SpriteID};
 use crate::sprite_runtime::SpriteRuntime;
 use crate::thread::BlockInputs;
+use crate::vm::ThreadID;
 use colored::Colorize;
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::{BufReader, BufWriter, Write};
 use std::path::Path;
 
-pub async fn fileviewer(file_path: &Path) -> Result<()> {
-    let scratch_file = ScratchFile::parse(BufReader::new(File::open(file_path)?))?;
-    let block_inputs = block_inputs(&scratch_file.project.targets).await?;
+pub async fn fileviewer(file_path: &Path, load_options: &LoadOptions) -> Result<()> {
+    let scratch_file =
+        ScratchFile::parse_with_options(BufReader::new(File::open(file_path)?), load_options)?;
+    let block_inputs = block_inputs(&scratch_file.project.targets, load_options.mode).await?;
 
     let mut w = BufWriter::new(std::io::stdout());
     output_block_inputs(&mut w, &block_inputs)?;
@@ -22,30 +26,143 @@ pub async fn fileviewer(file_path: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Prints the problems with the assets. Problems are errors in strict mode.
+pub fn verify(file_path: &Path, load_options: &LoadOptions) -> Result<()> {
+    let report = load_report(BufReader::new(File::open(file_path)?), load_options)?;
+    println!("{}", report);
+    Ok(())
+}
+
+fn load_report<R>(file: R, load_options: &LoadOptions) -> Result<LoadReport>
+where
+    R: std::io::Read + std::io::Seek,
+{
+    Ok(ScratchFile::parse_with_options(file, load_options)?.load_report)
+}
+
+pub fn compat(file_path: &Path, load_options: &LoadOptions) -> Result<()> {
+    let scratch_file =
+        ScratchFile::parse_with_options(BufReader::new(File::open(file_path)?), load_options)?;
+    print!("{}", CompatReport::new(&scratch_file.project.targets));
+    Ok(())
+}
+
+/// Blocks with opcodes that the VM does not support.
+#[derive(Debug, Default, PartialEq)]
+struct CompatReport {
+    /// Opcode to the blocks that use it
+    opcodes: BTreeMap<String, Vec<BlockUse>>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct BlockUse {
+    sprite_name: String,
+    block_id: BlockID,
+}
+
+impl CompatReport {
+    fn new(targets: &[file::Target]) -> Self {
+        let global = Arc::new(Global::new(
+            &HashMap::new(),
+            &HashMap::new(),
+            &Vec::new(),
+            SpriteID::sprite_ids(targets),
+            Broadcaster::new(),
+            LoadMode::Strict,
+        ));
+
+        let mut opcodes: BTreeMap<String, Vec<BlockUse>> = BTreeMap::new();
+        for (index, target) in targets.iter().enumerate() {
+            let runtime = Runtime::new(
+                Arc::new(RwLock::new(SpriteRuntime::new(target))),
+                global.clone(),
+                ThreadID {
+                    sprite_id: SpriteID::new(index),
+                    thread_id: 0,
+                },
+                Arc::from(target.name.as_str()),
+            );
+
+            let mut block_ids: Vec<&BlockID> = target.blocks.keys().collect();
+            block_ids.sort_unstable();
+            for block_id in block_ids {
+                let info = &target.blocks[block_id];
+                if !is_supported(runtime.clone(), info) {
+                    opcodes
+                        .entry(info.opcode.clone())
+                        .or_default()
+                        .push(BlockUse {
+                            sprite_name: target.name.clone(),
+                            block_id: *block_id,
+                        });
+                }
+            }
+        }
+        Self { opcodes }
+    }
+}
+
+impl Display for CompatReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        if self.opcodes.is_empty() {
+            return writeln!(f, "all opcodes are supported");
+        }
+
+        // Most used first
+        let mut opcodes: Vec<(&String, &Vec<BlockUse>)> = self.opcodes.iter().collect();
+        opcodes.sort_by(|a, b| b.1.len().cmp(&a.1.len()).then(a.0.cmp(b.0)));
+
+        for (opcode, uses) in opcodes {
+            writeln!(f, "{} ({} blocks)", opcode, uses.len())?;
+            let mut sprite_uses: Vec<(&str, Vec<String>)> = Vec::new();
+            for block_use in uses {
+                match sprite_uses.last_mut() {
+                    Some((name, ids)) if *name == block_use.sprite_name => {
+                        ids.push(block_use.block_id.to_string())
+                    }
+                    _ => sprite_uses
+                        .push((&block_use.sprite_name, vec![block_use.block_id.to_string()])),
+                }
+            }
+            for (name, ids) in sprite_uses {
+                writeln!(f, "    {}: {}", name, ids.join(", "))?;
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
-struct SpriteBlocks {
-    name: String,
-    id: SpriteID,
-    block_inputs: Vec<BlockInputs>,
+pub struct SpriteBlocks {
+    pub name: String,
+    pub id: SpriteID,
+    /// Script of each thread
+    pub block_inputs: Vec<BlockInputs>,
 }
 
-async fn block_inputs(targets: &[file::Target]) -> Result<Vec<SpriteBlocks>> {
+pub async fn block_inputs(
+    targets: &[file::Target],
+    load_mode: LoadMode,
+) -> Result<Vec<SpriteBlocks>> {
     let global = Arc::new(Global::new(
+        &HashMap::new(),
         &HashMap::new(),
         &Vec::new(),
+        SpriteID::sprite_ids(targets),
         Broadcaster::new(),
+        load_mode,
     ));
 
     let mut block_inputs: Vec<SpriteBlocks> = Vec::with_capacity(targets.len());
 
-    for target in targets {
+    for (index, target) in targets.iter().enumerate() {
         let sprite_runtime = SpriteRuntime::new(&target);
-        let sprite_id = SpriteID::from_sprite_name(&target.name);
+        let sprite_id = SpriteID::new(index);
         let sprite = Sprite::new(sprite_id, sprite_runtime, global.clone(), target.clone()).await?;
         block_inputs.push(SpriteBlocks {
             name: target.name.clone(),
             id: sprite_id,
-            block_inputs: sprite.block_inputs().await,
+            block_inputs: sprite.block_inputs().await?,
         });
     }
     Ok(block_inputs)
@@ -101,16 +218,108 @@ where
     Ok(())
 }
 
+/// Text of a script with one stack block per line, similar to scratchblocks.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptSource {
+    pub text: String,
+    /// Block of each line. Lines that close or split a C block have no block.
+    pub lines: Vec<Option<BlockID>>,
+}
+
+impl ScriptSource {
+    pub fn new(hat: &BlockInputs) -> Self {
+        let mut source = Self {
+            text: String::new(),
+            lines: Vec::new(),
+        };
+        source.push_stack(hat, 0);
+        source
+    }
+
+    /// Returns the 1-based line number of the block.
+    pub fn line(&self, block_id: &BlockID) -> Option<usize> {
+        self.lines
+            .iter()
+            .position(|id| id.as_ref() == Some(block_id))
+            .map(|index| index + 1)
+    }
+
+    fn push_stack(&mut self, block: &BlockInputs, indent: usize) {
+        let mut line = format!("{}{}", "    ".repeat(indent), block.info.name);
+        for (name, value) in sorted(&block.fields) {
+            line += &format!(" {}: {}", name, value);
+        }
+        for (name, input) in sorted(&block.inputs) {
+            line += &format!(" {}: {}", name, ScriptSource::reporter(input));
+        }
+        self.push_line(line, Some(block.info.id));
+
+        let mut substacks: Vec<(&str, &BlockInputs)> = sorted(&block.stacks)
+            .into_iter()
+            .filter(|(name, _)| *name != "next")
+            .collect();
+        // The else branch of if-else comes last
+        substacks.sort_by_key(|(name, _)| *name == "substack_false");
+        for (index, (_, substack)) in substacks.iter().enumerate() {
+            if index > 0 {
+                self.push_line(format!("{}else", "    ".repeat(indent)), None);
+            }
+            self.push_stack(substack, indent + 1);
+        }
+        if !substacks.is_empty() {
+            self.push_line(format!("{}end", "    ".repeat(indent)), None);
+        }
+
+        if let Some(next) = block.stacks.get("next") {
+            self.push_stack(next, indent);
+        }
+    }
+
+    fn reporter(block: &BlockInputs) -> String {
+        match block.info.name {
+            "Number" => format!("({})", block.fields.get("number").map_or("", |s| s)),
+            "String" => format!("[{}]", block.fields.get("string").map_or("", |s| s)),
+            _ => {
+                let mut text = block.info.name.to_string();
+                for (name, value) in sorted(&block.fields) {
+                    text += &format!(" {}: {}", name, value);
+                }
+                for (name, input) in sorted(&block.inputs) {
+                    text += &format!(" {}: {}", name, ScriptSource::reporter(input));
+                }
+                format!("({})", text)
+            }
+        }
+    }
+
+    fn push_line(&mut self, line: String, block_id: Option<BlockID>) {
+        self.text += &line;
+        self.text.push('\n');
+        self.lines.push(block_id);
+    }
+}
+
+fn sorted<'a, T>(map: &'a HashMap<&'static str, T>) -> Vec<(&'static str, &'a T)> {
+    let mut entries: Vec<(&'static str, &T)> = map.iter().map(|(&k, v)| (k, v)).collect();
+    entries.sort_unstable_by_key(|(k, _)| *k);
+    entries
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::blocks::BlockInfo;
     use crate::file::ScratchFile;
+    use crate::testing::{file_block, id, png, sb3};
     use std::io::Cursor;
 
     #[tokio::test]
     async fn test_block_inputs() {
         {
-            assert!(block_inputs(&Vec::new()).await.unwrap().is_empty());
+            assert!(block_inputs(&Vec::new(), LoadMode::Strict)
+                .await
+                .unwrap()
+                .is_empty());
         }
         {
             let dir = std::path::Path::new(file!())
@@ -121,11 +330,14 @@ mod test {
                 .join("test_saves")
                 .join("say.sb3");
             let file = std::fs::File::open(dir).unwrap();
-            let scratch_file = ScratchFile::parse(&file).unwrap();
-            assert!(!block_inputs(&scratch_file.project.targets)
-                .await
-                .unwrap()
-                .is_empty());
+            let scratch_file =
+                ScratchFile::parse_with_options(&file, &LoadOptions::default()).unwrap();
+            assert!(
+                !block_inputs(&scratch_file.project.targets, LoadMode::Strict)
+                    .await
+                    .unwrap()
+                    .is_empty()
+            );
         }
     }
 
@@ -145,12 +357,128 @@ mod test {
                 .join("test_saves")
                 .join("say.sb3");
             let file = std::fs::File::open(dir).unwrap();
-            let scratch_file = ScratchFile::parse(&file).unwrap();
-            let block_inputs = block_inputs(&scratch_file.project.targets).await.unwrap();
+            let scratch_file =
+                ScratchFile::parse_with_options(&file, &LoadOptions::default()).unwrap();
+            let block_inputs = block_inputs(&scratch_file.project.targets, LoadMode::Strict)
+                .await
+                .unwrap();
 
             let mut result: Cursor<Vec<u8>> = Cursor::new(Vec::new());
             output_block_inputs(&mut result, &block_inputs).unwrap();
             assert!(!result.get_ref().is_empty());
         }
     }
+
+    #[test]
+    fn test_verify() {
+        let options = |mode: LoadMode| LoadOptions {
+            mode,
+            ..LoadOptions::default()
+        };
+        let image = png(1, 1);
+        let md5ext = format!("{:x}.png", md5::compute(&image));
+        let valid = || sb3(&md5ext, &[(&md5ext, &image)]);
+        let mismatched = || sb3(&md5ext, &[(&md5ext, &png(2, 2))]);
+        let missing = || sb3(&md5ext, &[]);
+
+        assert!(load_report(valid(), &options(LoadMode::Strict))
+            .unwrap()
+            .is_ok());
+        for file in [mismatched(), missing()] {
+            assert!(!load_report(file, &options(LoadMode::Lenient))
+                .unwrap()
+                .is_ok());
+        }
+        for file in [mismatched(), missing()] {
+            let error = load_report(file, &options(LoadMode::Strict)).unwrap_err();
+            assert!(matches!(
+                error.downcast_ref::<ScratchError>(),
+                Some(ScratchError::AssetIntegrity { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_compat_report() {
+        {
+            assert_eq!(CompatReport::new(&[]), CompatReport::default());
+        }
+        {
+            let block = |opcode: &str| file_block(opcode, None, serde_json::json!({}));
+
+            let mut target = file::Target {
+                name: "Sprite1".to_string(),
+                ..file::Target::default()
+            };
+            target.blocks.insert(id("a"), block("motion_movesteps"));
+            target.blocks.insert(id("b"), block("music_restForBeats"));
+            target.blocks.insert(id("c"), block("music_restForBeats"));
+            target.blocks.insert(id("d"), block("unknown"));
+
+            let report = CompatReport::new(&[target]);
+            assert_eq!(report.opcodes.len(), 2);
+            assert_eq!(
+                report.opcodes["music_restForBeats"],
+                vec![
+                    BlockUse {
+                        sprite_name: "Sprite1".to_string(),
+                        block_id: id("b"),
+                    },
+                    BlockUse {
+                        sprite_name: "Sprite1".to_string(),
+                        block_id: id("c"),
+                    },
+                ]
+            );
+            assert_eq!(
+                report.to_string(),
+                "music_restForBeats (2 blocks)\n    Sprite1: b, c\nunknown (1 blocks)\n    Sprite1: d\n"
+            );
+        }
+    }
+
+    #[test]
+    fn test_script_source() {
+        let block = |name: &'static str, block_id: &str| BlockInputs {
+            info: BlockInfo {
+                name,
+                id: id(block_id),
+            },
+            fields: HashMap::new(),
+            inputs: HashMap::new(),
+            stacks: HashMap::new(),
+        };
+
+        let mut say = block("Say", "say");
+        say.inputs.insert(
+            "message",
+            BlockInputs {
+                fields: vec![("string", "\"Hello!\"".to_string())]
+                    .into_iter()
+                    .collect(),
+                ..block("String", "literal")
+            },
+        );
+        let mut if_else = block("IfElse", "if");
+        if_else
+            .stacks
+            .insert("substack_true", block("Show", "show"));
+        if_else
+            .stacks
+            .insert("substack_false", block("Hide", "hide"));
+        if_else.stacks.insert("next", say);
+        let mut hat = block("WhenFlagClicked", "hat");
+        hat.stacks.insert("next", if_else);
+
+        let source = ScriptSource::new(&hat);
+        assert_eq!(
+            source.text,
+            "WhenFlagClicked\nIfElse\n    Show\nelse\n    Hide\nend\nSay message: [\"Hello!\"]\n"
+        );
+        assert_eq!(source.line(&id("hat")), Some(1));
+        assert_eq!(source.line(&id("hide")), Some(5));
+        assert_eq!(source.line(&id("say")), Some(7));
+        assert_eq!(source.lines[3], None);
+        assert_eq!(source.line(&id("unknown")), None);
+    }
 }
@@ -0,0 +1,182 @@
+use crate::blocks::value::Value;
+use crate::sprite::{Sprite, SpriteID};
+use crate::sprite_runtime::SpriteState;
+use crate::thread::{ThreadPosition, ThreadState};
+use crate::vm::ThreadID;
+use std::collections::VecDeque;
+use std::sync::Arc;
+
+/// Changes made by the VM that can be undone, oldest first. Only the last CAPACITY steps are
+/// kept.
+#[derive(Debug, Default)]
+pub struct History {
+    changes: VecDeque<Change>,
+    steps: usize,
+}
+
+#[derive(Debug)]
+pub enum Change {
+    /// A thread executed a block
+    Step {
+        thread_id: ThreadID,
+        before: Box<SavedThread>,
+        /// Keys and previous values of the variables that the block changed
+        variables: Vec<(String, Value)>,
+    },
+    CloneCreated(SpriteID),
+    CloneDeleted {
+        sprite_id: SpriteID,
+        sprite: Arc<Sprite>,
+        /// Index in the draw order
+        layer: usize,
+    },
+}
+
+/// A thread and its sprite before a step.
+#[derive(Debug, Clone)]
+pub struct SavedThread {
+    pub sprite: SpriteState,
+    pub position: ThreadPosition,
+    pub state: ThreadState,
+    /// Number of saved steps of the sprite that started before this one
+    pub step: usize,
+    /// Whether other steps of the sprite ran during this one
+    pub overlapped: bool,
+}
+
+impl History {
+    pub const CAPACITY: usize = 10_000;
+
+    /// Adds the change. Returns the oldest changes if there are more than CAPACITY steps.
+    pub fn push(&mut self, change: Change) -> Vec<Change> {
+        if matches!(change, Change::Step { .. }) {
+            self.steps += 1;
+        }
+        self.changes.push_back(change);
+
+        let mut dropped: Vec<Change> = Vec::new();
+        while self.steps > History::CAPACITY {
+            match self.changes.pop_front() {
+                Some(change) => {
+                    if matches!(change, Change::Step { .. }) {
+                        self.steps -= 1;
+                    }
+                    dropped.push(change);
+                }
+                None => break,
+            }
+        }
+        dropped
+    }
+
+    pub fn into_changes(self) -> Vec<Change> {
+        self.changes.into()
+    }
+
+    /// Removes the changes up to and including the last step, newest first. Returns nothing if
+    /// there are no steps.
+    pub fn pop_step(&mut self) -> Vec<Change> {
+        if self.steps == 0 {
+            return Vec::new();
+        }
+
+        let mut changes: Vec<Change> = Vec::new();
+        while let Some(change) = self.changes.pop_back() {
+            let is_step = matches!(change, Change::Step { .. });
+            changes.push(change);
+            if is_step {
+                self.steps -= 1;
+                break;
+            }
+        }
+        changes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file;
+    use crate::sprite_runtime::SpriteRuntime;
+    use crate::testing;
+
+    fn step(thread_id: usize) -> Change {
+        Change::Step {
+            thread_id: ThreadID {
+                sprite_id: SpriteID::new(0),
+                thread_id,
+            },
+            before: Box::new(SavedThread {
+                sprite: SpriteRuntime::new(&file::Target::default()).state(),
+                position: ThreadPosition::default(),
+                state: ThreadState::Running,
+                step: 0,
+                overlapped: false,
+            }),
+            variables: Vec::new(),
+        }
+    }
+
+    async fn clone_deleted(id: usize) -> Change {
+        let global = testing::global(&[]);
+        let target = file::Target::default();
+        let sprite = Sprite::new(
+            SpriteID::new(id),
+            SpriteRuntime::new(&target),
+            Arc::new(global),
+            target,
+        )
+        .await
+        .unwrap();
+        Change::CloneDeleted {
+            sprite_id: SpriteID::new(id),
+            sprite: Arc::new(sprite),
+            layer: 1,
+        }
+    }
+
+    fn thread_ids(changes: &[Change]) -> Vec<Option<usize>> {
+        changes
+            .iter()
+            .map(|change| match change {
+                Change::Step { thread_id, .. } => Some(thread_id.thread_id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_pop_step() {
+        let mut history = History::default();
+        history.push(Change::CloneCreated(SpriteID::new(1)));
+        assert!(history.pop_step().is_empty());
+
+        history.push(step(0));
+        history.push(clone_deleted(1).await);
+        history.push(step(1));
+        history.push(Change::CloneCreated(SpriteID::new(2)));
+
+        assert_eq!(thread_ids(&history.pop_step()), vec![None, Some(1)]);
+        assert_eq!(thread_ids(&history.pop_step()), vec![None, Some(0)]);
+        assert!(history.pop_step().is_empty());
+    }
+
+    #[test]
+    fn test_capacity() {
+        let mut history = History::default();
+        let mut dropped: Vec<Change> = Vec::new();
+        for thread_id in 0..History::CAPACITY + 2 {
+            dropped.extend(history.push(step(thread_id)));
+        }
+        assert_eq!(thread_ids(&dropped), vec![Some(0), Some(1)]);
+        assert_eq!(history.steps, History::CAPACITY);
+        assert_eq!(
+            thread_ids(&history.pop_step()),
+            vec![Some(History::CAPACITY + 1)]
+        );
+        assert!(matches!(
+            history.changes.front(),
+            Some(Change::Step { thread_id, .. }) if thread_id.thread_id == 2
+        ));
+    }
+}
@@ -2,18 +2,23 @@ use super::*;
 use crate::app::WINDOW_SIZE;
 use crate::broadcaster::Broadcaster;
 use crate::coordinate::{canvas_const, CanvasCoordinate};
+use crate::dap::{DapServer, ExecutionChange};
+use crate::debugger::{DebugOptions, StepMode, ThreadStatus};
 use crate::event_sender::EventSender;
-use crate::file::ScratchFile;
-use crate::vm::VM;
+use crate::file::{LoadMode, ScratchFile};
+use crate::fileviewer::{block_inputs, SpriteBlocks};
+use crate::vm::{BlockError, ThreadID, VMEvent, VM};
 use conrod_core::image::Id;
 use conrod_core::position::Relative;
 use conrod_core::widget::button::Flat;
-use conrod_core::widget::Button;
+use conrod_core::widget::{Button, DropDownList, Rectangle, Text};
 use conrod_core::{Borderable, Color, Colorable, Labelable, UiCell};
 use conrod_core::{Positionable, Sizeable, Widget};
 use graphics::Context;
 use graphics::{rectangle, Transformed};
 use piston_window::{G2d, G2dTextureContext, Glyphs, Input};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
 
 pub struct Interface {
     ids: Ids,
@@ -22,6 +27,18 @@ pub struct Interface {
     vm: VM,
     pause_state: PauseState,
     event_sender: EventSender,
+    /// Errors that have yet to be skipped or stopped, oldest first
+    block_errors: VecDeque<BlockError>,
+    /// Thread that the step buttons other than Step apply to
+    selected_thread: Option<ThreadID>,
+    dap: Option<DapServer>,
+    /// File that the Save and Load buttons use
+    snapshot_path: Option<PathBuf>,
+    /// File that the profile is written to when the window closes
+    profile_path: Option<PathBuf>,
+    /// File that the coverage report is written to when the window closes and the scripts that
+    /// it covers
+    coverage: Option<(PathBuf, Vec<SpriteBlocks>)>,
 }
 
 widget_ids! {
@@ -30,6 +47,19 @@ widget_ids! {
         stop_button,
         pause_continue_button,
         step_button,
+        step_back_button,
+        step_thread_button,
+        step_over_button,
+        step_out_button,
+        save_snapshot_button,
+        load_snapshot_button,
+        error_overlay,
+        error_text,
+        skip_block_button,
+        stop_thread_button,
+        thread_panel,
+        thread_list,
+        thread_text,
     }
 }
 
@@ -46,9 +76,47 @@ impl Interface {
         ids: Ids,
         green_flag_image: Id,
         stop_image: Id,
+        load_mode: LoadMode,
+        debug_options: &DebugOptions,
     ) -> Result<Self> {
+        let dap = match debug_options.dap {
+            Some(transport) => Some(
+                DapServer::start(
+                    transport,
+                    &scratch_file,
+                    load_mode,
+                    &debug_options.breakpoints,
+                )
+                .await?,
+            ),
+            None => None,
+        };
+        let coverage = match &debug_options.coverage {
+            Some(path) => Some((
+                path.clone(),
+                block_inputs(&scratch_file.project.targets, load_mode).await?,
+            )),
+            None => None,
+        };
         let broadcaster = Broadcaster::new();
-        let vm = VM::new(texture_context, scratch_file, broadcaster.clone()).await?;
+        let vm = VM::new(
+            texture_context,
+            scratch_file,
+            broadcaster.clone(),
+            load_mode,
+            debug_options,
+        )
+        .await?;
+        for &block_id in &debug_options.breakpoints {
+            vm.add_breakpoint(block_id).await;
+        }
+        for name in &debug_options.watched_variables {
+            vm.watch_variable(name).await?;
+        }
+        vm.set_conditions(&debug_options.conditions).await?;
+        if let Some(path) = &debug_options.restore {
+            vm.restore(path).await?;
+        }
         Ok(Self {
             ids,
             green_flag_image,
@@ -56,10 +124,74 @@ impl Interface {
             vm,
             pause_state: PauseState::Paused,
             event_sender: EventSender::new(broadcaster),
+            block_errors: VecDeque::new(),
+            selected_thread: None,
+            dap,
+            snapshot_path: debug_options.snapshot.clone(),
+            profile_path: debug_options.profile.clone(),
+            coverage,
         })
     }
 
     pub async fn widgets(&mut self, ui_cell: &mut UiCell<'_>) {
+        while let Some(event) = self.vm.try_recv_event() {
+            match event {
+                VMEvent::Error(error) => {
+                    log::error!("{}", error);
+                    if let Some(block_error) = self.vm.block_error(&error).await {
+                        self.stopped("exception", block_error.thread_id, &block_error.message);
+                        self.block_errors.push_back(block_error);
+                        self.pause_state = PauseState::Paused;
+                    }
+                }
+                VMEvent::Breakpoint(debug_info) => {
+                    log::info!("breakpoint: {}", debug_info);
+                    self.stopped("breakpoint", debug_info.thread_id, "");
+                    self.pause_state = PauseState::Paused;
+                }
+                VMEvent::Watchpoint(write) => {
+                    log::info!("watchpoint: {}", write);
+                    self.stopped(
+                        "data breakpoint",
+                        write.writer.thread_id,
+                        &write.to_string(),
+                    );
+                    self.pause_state = PauseState::Paused;
+                }
+                VMEvent::Condition {
+                    block_id,
+                    thread_id,
+                    write,
+                } => {
+                    let text = match write {
+                        Some(write) => write.to_string(),
+                        None => String::new(),
+                    };
+                    log::info!("condition {} is true: {}", block_id, text);
+                    self.stopped("breakpoint", thread_id, &text);
+                    self.pause_state = PauseState::Paused;
+                }
+                VMEvent::StepFinished(thread_id) | VMEvent::SteppedBack(thread_id) => {
+                    self.stopped("step", thread_id, "");
+                }
+                VMEvent::Stopped(error) => {
+                    log::error!("VM stopped: {}", error);
+                    self.pause_state = PauseState::Paused;
+                    if let Some(dap) = &mut self.dap {
+                        dap.terminated();
+                    }
+                }
+            }
+        }
+
+        if let Some(dap) = &mut self.dap {
+            match dap.handle_requests(&self.vm).await {
+                Some(ExecutionChange::Continued) => self.pause_state = PauseState::Running,
+                Some(ExecutionChange::Paused) => self.pause_state = PauseState::Paused,
+                None => {}
+            }
+        }
+
         let green_flag_event = Button::image(self.green_flag_image)
             .top_left_with_margins(10.0, 25.0)
             .w_h(30.0, 30.0)
@@ -68,7 +200,7 @@ impl Interface {
 
         if green_flag_event.was_clicked() {
             self.vm.continue_().await;
-            self.pause_state = PauseState::Running;
+            self.continued();
         }
 
         let stop_flag_event = Button::image(self.stop_image)
@@ -81,6 +213,10 @@ impl Interface {
             self.vm.stop().await;
         }
 
+        if let Some(path) = self.snapshot_path.clone() {
+            self.snapshot_buttons(ui_cell, &path).await;
+        }
+
         let pause_button_text = match self.pause_state {
             PauseState::Paused => "Continue",
             PauseState::Running => "Pause",
@@ -91,29 +227,209 @@ impl Interface {
             match self.pause_state {
                 PauseState::Paused => {
                     self.vm.continue_().await;
-                    self.pause_state = PauseState::Paused;
+                    self.continued();
                 }
                 PauseState::Running => {
                     self.vm.pause().await;
                     self.pause_state = PauseState::Paused;
+                    if let Some(thread_id) = self.selected_thread {
+                        self.stopped("pause", thread_id, "");
+                    }
                 }
             }
         }
 
-        let step_event = Interface::button(155.0, "Step").set(self.ids.step_button, ui_cell);
+        let step_back_event =
+            Interface::button(101.0, "Step back").set(self.ids.step_back_button, ui_cell);
+        if step_back_event.was_clicked() && self.pause_state == PauseState::Paused {
+            self.vm.step_back().await;
+        }
+
+        let step_event = Interface::button(183.0, "Step").set(self.ids.step_button, ui_cell);
         if step_event.was_clicked() {
             self.vm.step().await;
         }
+
+        let step_buttons = [
+            (
+                265.0,
+                "Step thread",
+                StepMode::Block,
+                self.ids.step_thread_button,
+            ),
+            (
+                347.0,
+                "Step over",
+                StepMode::Over,
+                self.ids.step_over_button,
+            ),
+            (429.0, "Step out", StepMode::Out, self.ids.step_out_button),
+        ];
+        for &(left, label, mode, id) in &step_buttons {
+            let event = Interface::button(left, label).set(id, ui_cell);
+            if let (true, Some(thread_id)) = (event.was_clicked(), self.selected_thread) {
+                self.vm.step_single_thread(thread_id, mode).await;
+            }
+        }
+
+        if self.block_errors.is_empty() {
+            if self.pause_state == PauseState::Paused {
+                self.thread_panel(ui_cell).await;
+            }
+        } else {
+            self.error_overlay(ui_cell).await;
+        }
+    }
+
+    async fn snapshot_buttons(&mut self, ui_cell: &mut UiCell<'_>, path: &Path) {
+        let save_event = Interface::button_style("Save")
+            .w(76.0)
+            .top_left_with_margins(10.0, 347.0)
+            .set(self.ids.save_snapshot_button, ui_cell);
+        if save_event.was_clicked() {
+            match self.vm.snapshot(path).await {
+                Ok(_) => log::info!("saved snapshot: {}", path.display()),
+                Err(e) => log::error!("could not save snapshot: {}", e),
+            }
+        }
+
+        let load_event = Interface::button_style("Load")
+            .w(76.0)
+            .top_left_with_margins(10.0, 429.0)
+            .set(self.ids.load_snapshot_button, ui_cell);
+        if load_event.was_clicked() {
+            match self.vm.restore(path).await {
+                Ok(_) => {
+                    self.pause_state = PauseState::Paused;
+                    self.block_errors.clear();
+                    self.selected_thread = None;
+                }
+                Err(e) => log::error!("could not load snapshot: {}", e),
+            }
+        }
+    }
+
+    /// Selects the thread that caused the VM to pause and tells the debug adapter client.
+    fn stopped(&mut self, reason: &str, thread_id: ThreadID, text: &str) {
+        self.selected_thread = Some(thread_id);
+        if let Some(dap) = &mut self.dap {
+            dap.stopped(reason, thread_id, text);
+        }
+    }
+
+    fn continued(&mut self) {
+        self.pause_state = PauseState::Running;
+        if let Some(dap) = &mut self.dap {
+            dap.continued();
+        }
+    }
+
+    async fn thread_panel(&mut self, ui_cell: &mut UiCell<'_>) {
+        const MAX_THREADS: usize = 6;
+
+        let threads = self.vm.threads().await;
+        let mut selected = self
+            .selected_thread
+            .and_then(|id| threads.iter().position(|t| t.thread_id == id));
+        if selected.is_none() {
+            selected = threads
+                .iter()
+                .position(|t| t.status == ThreadStatus::Running);
+            self.selected_thread = selected.map(|index| threads[index].thread_id);
+        }
+
+        let mut text: String = threads
+            .iter()
+            .take(MAX_THREADS)
+            .map(|thread| thread.to_string())
+            .collect();
+        if threads.len() > MAX_THREADS {
+            text += &format!("... and {} more", threads.len() - MAX_THREADS);
+        }
+
+        Rectangle::fill_with([240.0, 360.0], Color::Rgba(0.95, 0.95, 0.97, 0.9))
+            .top_left_with_margins(50.0, 260.0)
+            .set(self.ids.thread_panel, ui_cell);
+
+        Text::new(&text)
+            .font_size(11)
+            .color(Color::Hsla(0.0, 0.0, 0.15, 1.0))
+            .w(230.0)
+            .wrap_by_character()
+            .top_left_with_margins_on(self.ids.thread_panel, 35.0, 5.0)
+            .set(self.ids.thread_text, ui_cell);
+
+        // Drawn after the text so the open list covers it
+        let items: Vec<String> = threads
+            .iter()
+            .map(|t| format!("{} thread {}", t.sprite_name, t.thread_id.thread_id))
+            .collect();
+        let selected_event = DropDownList::new(&items, selected)
+            .w_h(230.0, 24.0)
+            .max_visible_items(10)
+            .label_font_size(12)
+            .top_left_with_margins_on(self.ids.thread_panel, 5.0, 5.0)
+            .set(self.ids.thread_list, ui_cell);
+        if let Some(index) = selected_event {
+            self.selected_thread = Some(threads[index].thread_id);
+        }
+    }
+
+    async fn error_overlay(&mut self, ui_cell: &mut UiCell<'_>) {
+        let block_error = match self.block_errors.front() {
+            Some(block_error) => block_error,
+            None => return,
+        };
+
+        Rectangle::fill_with([400.0, 180.0], Color::Rgba(1.0, 0.92, 0.92, 0.95))
+            .top_left_with_margins(140.0, 60.0)
+            .set(self.ids.error_overlay, ui_cell);
+
+        let text = format!(
+            "Sprite: {}\nOpcode: {}\nBlock ID: {}\n{}",
+            block_error.sprite_name, block_error.opcode, block_error.block_id, block_error.message
+        );
+        Text::new(&text)
+            .font_size(14)
+            .color(Color::Hsla(0.0, 0.0, 0.15, 1.0))
+            .w(380.0)
+            .wrap_by_character()
+            .top_left_with_margins_on(self.ids.error_overlay, 10.0, 10.0)
+            .set(self.ids.error_text, ui_cell);
+
+        let thread_id = block_error.thread_id;
+        let skip_event = Interface::button_style("Skip block")
+            .bottom_left_with_margins_on(self.ids.error_overlay, 10.0, 10.0)
+            .set(self.ids.skip_block_button, ui_cell);
+        if skip_event.was_clicked() {
+            self.vm.skip_block(thread_id).await;
+            self.block_errors.pop_front();
+            return;
+        }
+
+        let stop_event = Interface::button_style("Stop thread")
+            .bottom_left_with_margins_on(self.ids.error_overlay, 10.0, 140.0)
+            .set(self.ids.stop_thread_button, ui_cell);
+        if stop_event.was_clicked() {
+            self.vm.stop_thread(thread_id).await;
+            self.block_errors.pop_front();
+        }
     }
 
     fn button(left: f64, label: &str) -> Button<Flat> {
+        Interface::button_style(label)
+            .w(76.0)
+            .label_font_size(13)
+            .top_left_with_margins(425.0, left)
+    }
+
+    fn button_style(label: &str) -> Button<'_, Flat> {
         Button::new()
             .color(Color::Hsla(0.0, 0.0, 0.9, 1.0))
             .hover_color(Color::Hsla(0.0, 0.0, 0.87, 1.0))
             .press_color(Color::Hsla(0.0, 0.0, 0.83, 1.0))
             .border(1.5)
             .border_color(Color::Hsla(0.0, 0.0, 0.5, 1.0))
-            .top_left_with_margins(425.0, left)
             .label(label)
             .label_font_size(15)
             .label_hsl(0.15, 0.15, 0.15)
@@ -147,6 +463,35 @@ impl Interface {
         Ok(())
     }
 
+    /// Writes the reports of the run.
+    pub async fn close(&self) -> Result<()> {
+        self.vm.flush_trace()?;
+        if let (Some(path), Some(profile)) = (&self.profile_path, self.vm.profile().await) {
+            std::fs::write(path, profile.folded())?;
+            let mut table_path = path.clone().into_os_string();
+            table_path.push(".txt");
+            std::fs::write(&table_path, profile.table())?;
+            log::info!(
+                "wrote profile to {} and {}",
+                path.display(),
+                Path::new(&table_path).display()
+            );
+        }
+        if let Some((path, sprites)) = &self.coverage {
+            if let Some(report) = self.vm.coverage(sprites) {
+                std::fs::write(path, report.text())?;
+                let mut json_path = path.clone().into_os_string();
+                json_path.push(".json");
+                std::fs::write(&json_path, serde_json::to_string_pretty(&report.json())?)?;
+                let mut html_path = path.clone().into_os_string();
+                html_path.push(".html");
+                std::fs::write(&html_path, report.html())?;
+                log::info!("wrote coverage to {}", path.display());
+            }
+        }
+        Ok(())
+    }
+
     pub async fn input(&mut self, input: Input) -> Result<()> {
         self.event_sender.input(input).await
     }
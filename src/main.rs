@@ -1,31 +1,42 @@
 #![feature(async_closure)]
 #![feature(str_split_once)]
-#![feature(maybe_uninit_uninit_array)]
+#![cfg_attr(test, feature(test))]
 
 #[macro_use]
 extern crate conrod_core;
 
 mod app;
+mod arena;
 mod blocks;
 mod broadcaster;
-mod coordinate;
+mod coverage;
+mod dap;
+mod debugger;
 mod error;
 mod event_sender;
 mod file;
 mod fileviewer;
+mod history;
 mod interface;
-mod pen;
+mod profiler;
 mod runtime;
+mod snapshot;
 mod sprite;
 mod sprite_map;
 mod sprite_runtime;
+#[cfg(test)]
+mod testing;
 mod thread;
+mod tracer;
+mod transpiler;
 mod vm;
 
 use anyhow::{Error, Result};
 use async_lock::RwLock;
 use error::*;
+use scratch_runtime::{coordinate, pen};
 use std::collections::{HashMap, HashSet};
+use std::convert::TryInto;
 use std::fmt::{Display, Formatter};
 use std::sync::Arc;
 use tokio::spawn;
@@ -36,13 +47,60 @@ use tokio::task::JoinHandle;
 struct Options {
     command: Command,
     file_path: String,
+    /// Refuse to load files with missing or corrupted assets or unsupported blocks
+    #[clap(long)]
+    strict: bool,
+    /// Pause when a thread is about to execute the block
+    #[clap(long = "break", value_name = "block-id")]
+    breakpoints: Vec<String>,
+    /// Pause when the variable changes
+    #[clap(long = "watch", value_name = "variable")]
+    watched_variables: Vec<String>,
+    /// Pause when the value of the reporter block becomes true
+    #[clap(long = "break-when", value_name = "block-id")]
+    conditions: Vec<String>,
+    /// Serve the Debug Adapter Protocol on stdio or a local TCP port
+    #[clap(long, value_name = "stdio|port")]
+    dap: Option<String>,
+    /// File that the Save and Load buttons write and read snapshots to
+    #[clap(long, value_name = "file")]
+    snapshot: Option<std::path::PathBuf>,
+    /// Start from a snapshot of the same project
+    #[clap(long, value_name = "file")]
+    restore: Option<std::path::PathBuf>,
+    /// Measure blocks and write folded stacks to the file and a table to the file with .txt
+    /// appended when the window closes
+    #[clap(long, value_name = "file")]
+    profile: Option<std::path::PathBuf>,
+    /// Record every executed block, broadcast and clone event to the file as JSON Lines
+    #[clap(long, value_name = "file")]
+    trace: Option<std::path::PathBuf>,
+    /// Record executed blocks and write a report to the file, with .json and .html appended for
+    /// the other formats, when the window closes
+    #[clap(long, value_name = "file")]
+    coverage: Option<std::path::PathBuf>,
+    /// Run scripts without folding constants or removing unreachable blocks
+    #[clap(long)]
+    no_optimize: bool,
+    /// Directory that export-rust writes a crate with main.rs and costume files to
+    #[clap(long, value_name = "dir")]
+    out: Option<std::path::PathBuf>,
+    /// Export a project that runs without a window and only prints what sprites say
+    #[clap(long)]
+    headless: bool,
 }
 
 #[derive(strum::EnumString)]
-#[strum(serialize_all = "snake_case")]
+#[strum(serialize_all = "kebab-case")]
 enum Command {
     Vm,
     Viewer,
+    /// Checks assets against their MD5 hashes. Problems are errors with --strict.
+    Verify,
+    /// Lists blocks with opcodes that are not supported
+    Compat,
+    /// Translates scripts into Rust source that runs against the scratch_runtime crate
+    ExportRust,
 }
 
 fn main() {
@@ -52,6 +110,21 @@ fn main() {
 
     let options = Options::parse();
     let path = std::path::Path::new(&options.file_path);
+    let load_options = file::LoadOptions {
+        mode: if options.strict {
+            file::LoadMode::Strict
+        } else {
+            file::LoadMode::Lenient
+        },
+        ..file::LoadOptions::default()
+    };
+    let debug_options = match debug_options(&options) {
+        Ok(debug_options) => debug_options,
+        Err(e) => {
+            log::error!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
     tokio::runtime::Builder::new_multi_thread()
         .enable_all()
@@ -59,8 +132,16 @@ fn main() {
         .unwrap()
         .block_on(async {
             let result = match options.command {
-                Command::Vm => app::app(path).await,
-                Command::Viewer => fileviewer::fileviewer(path).await,
+                Command::Vm => app::app(path, &load_options, &debug_options).await,
+                Command::Viewer => fileviewer::fileviewer(path, &load_options).await,
+                Command::Verify => fileviewer::verify(path, &load_options),
+                Command::Compat => fileviewer::compat(path, &load_options),
+                Command::ExportRust => match &options.out {
+                    Some(out) => {
+                        transpiler::export_rust(path, &load_options, out, options.headless)
+                    }
+                    None => Err(Error::msg("export-rust requires --out")),
+                },
             };
             let exit_code = match result {
                 Ok(_) => 0,
@@ -72,3 +153,53 @@ fn main() {
             std::process::exit(exit_code);
         });
 }
+
+/// Returns an error if a block ID or --dap is invalid.
+fn debug_options(options: &Options) -> Result<debugger::DebugOptions> {
+    let block_ids = |ids: &[String]| -> Result<Vec<file::BlockID>> {
+        ids.iter()
+            .map(|s| s.as_str().try_into())
+            .collect::<Result<_>>()
+            .map_err(|e| Error::msg(format!("invalid block ID: {:?}", e)))
+    };
+    Ok(debugger::DebugOptions {
+        breakpoints: block_ids(&options.breakpoints)?,
+        watched_variables: options.watched_variables.clone(),
+        conditions: block_ids(&options.conditions)?,
+        dap: match &options.dap {
+            Some(s) => Some(
+                s.parse()
+                    .map_err(|e| Error::msg(format!("invalid --dap: {}", e)))?,
+            ),
+            None => None,
+        },
+        snapshot: options.snapshot.clone(),
+        restore: options.restore.clone(),
+        profile: options.profile.clone(),
+        trace: options.trace.clone(),
+        coverage: options.coverage.clone(),
+        no_optimize: options.no_optimize,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Clap;
+
+    #[test]
+    fn test_debug_options() {
+        let options = Options::try_parse_from([
+            "scratch", "vm", "a.sb3", "--break", "block1", "--break", "block2",
+        ])
+        .unwrap();
+        let result = debug_options(&options).unwrap();
+        assert_eq!(
+            result.breakpoints,
+            vec![testing::id("block1"), testing::id("block2")]
+        );
+        assert!(result.is_debugging());
+
+        assert!(Options::try_parse_from(["scratch", "vm", "a.sb3", "--break", ""]).is_err());
+    }
+}
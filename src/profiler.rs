@@ -0,0 +1,298 @@
+use super::*;
+use crate::blocks::value::Value;
+use crate::blocks::{Block, BlockInfo, BlockInputsPartial, Next};
+use crate::debugger::BlockRef;
+use crate::file::BlockID;
+use crate::sprite::SpriteID;
+use async_trait::async_trait;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Counts the executions of blocks and their wall time, which includes the time of the reporters
+/// that a block evaluated.
+#[derive(Debug, Default)]
+pub struct Profiler {
+    samples: Mutex<HashMap<Stack, Sample>>,
+}
+
+/// Where a block was executed.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Stack {
+    /// Clones have the name of the sprite that they were cloned from
+    pub sprite_name: Arc<str>,
+    /// Clones have the ID of the sprite that they were cloned from
+    pub sprite_id: SpriteID,
+    pub hat: BlockID,
+    /// The stack block followed by the reporters that the last block is an input of
+    pub blocks: Vec<BlockID>,
+}
+
+#[derive(Debug, Copy, Clone, Default, PartialEq)]
+pub struct Sample {
+    pub count: u64,
+    pub time: Duration,
+}
+
+impl Profiler {
+    pub fn record(&self, stack: &Stack, time: Duration) {
+        let mut samples = self.samples.lock().unwrap();
+        // Avoids cloning the stack unless it is new
+        if !samples.contains_key(stack) {
+            samples.insert(stack.clone(), Sample::default());
+        }
+        let sample = samples.get_mut(stack).unwrap();
+        sample.count += 1;
+        sample.time += time;
+    }
+
+    pub fn samples(&self) -> Vec<(Stack, Sample)> {
+        self.samples
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(stack, sample)| (stack.clone(), *sample))
+            .collect()
+    }
+}
+
+/// Measures the value calls of a reporter.
+#[derive(Debug)]
+pub struct ProfiledReporter {
+    block: Box<dyn Block + Send + Sync>,
+    profiler: Arc<Profiler>,
+    stack: Stack,
+}
+
+impl ProfiledReporter {
+    pub fn new(block: Box<dyn Block + Send + Sync>, profiler: Arc<Profiler>, stack: Stack) -> Self {
+        Self {
+            block,
+            profiler,
+            stack,
+        }
+    }
+}
+
+#[async_trait]
+impl Block for ProfiledReporter {
+    fn block_info(&self) -> BlockInfo {
+        self.block.block_info()
+    }
+
+    fn block_inputs(&self) -> BlockInputsPartial {
+        self.block.block_inputs()
+    }
+
+    async fn value(&self) -> Result<Value> {
+        let start = Instant::now();
+        let result = self.block.value().await;
+        self.profiler.record(&self.stack, start.elapsed());
+        result
+    }
+
+    async fn execute(&mut self) -> Result<Next> {
+        self.block.execute().await
+    }
+}
+
+/// Measurements of a run with the names of sprites and blocks. Clones are merged into the sprite
+/// that they were cloned from.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Profile {
+    pub rows: Vec<ProfileRow>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ProfileRow {
+    pub sprite_name: String,
+    pub hat: BlockRef,
+    /// The stack block followed by the reporters that the last block is an input of
+    pub blocks: Vec<BlockRef>,
+    pub count: u64,
+    /// Includes the time of the reporters
+    pub time: Duration,
+}
+
+impl Profile {
+    pub fn new(mut rows: Vec<ProfileRow>) -> Self {
+        rows.sort_unstable_by(|a, b| {
+            (&a.sprite_name, &a.hat.id, Profile::ids(a)).cmp(&(
+                &b.sprite_name,
+                &b.hat.id,
+                Profile::ids(b),
+            ))
+        });
+        rows.dedup_by(|row, first| {
+            let same = row.sprite_name == first.sprite_name
+                && row.hat.id == first.hat.id
+                && Profile::ids(row) == Profile::ids(first);
+            if same {
+                first.count += row.count;
+                first.time += row.time;
+            }
+            same
+        });
+        Self { rows }
+    }
+
+    fn ids(row: &ProfileRow) -> Vec<BlockID> {
+        row.blocks.iter().map(|b| b.id).collect()
+    }
+
+    /// Time of the row without the time of the reporters that it evaluated.
+    fn self_time(&self, row: &ProfileRow) -> Duration {
+        let children: Duration = self
+            .rows
+            .iter()
+            .filter(|child| {
+                child.sprite_name == row.sprite_name
+                    && child.hat.id == row.hat.id
+                    && child.blocks.len() == row.blocks.len() + 1
+                    && child.blocks.starts_with(&row.blocks)
+            })
+            .map(|child| child.time)
+            .sum();
+        row.time.checked_sub(children).unwrap_or_default()
+    }
+
+    /// Folded stacks of sprite, hat and blocks with their self time in microseconds, which
+    /// inferno and flamegraph.pl can render.
+    pub fn folded(&self) -> String {
+        let frame = |s: String| s.replace(';', ":").replace('\n', " ");
+        let mut result = String::new();
+        for row in &self.rows {
+            let mut frames: Vec<String> = vec![frame(row.sprite_name.clone())];
+            frames.push(frame(row.hat.to_string()));
+            frames.extend(row.blocks.iter().map(|b| frame(b.to_string())));
+            writeln!(
+                result,
+                "{} {}",
+                frames.join(";"),
+                self.self_time(row).as_micros()
+            )
+            .unwrap();
+        }
+        result
+    }
+
+    /// Blocks and opcodes sorted by self time, slowest first.
+    pub fn table(&self) -> String {
+        struct Line {
+            name: String,
+            count: u64,
+            self_time: Duration,
+            time: Duration,
+        }
+
+        let mut blocks: Vec<Line> = Vec::with_capacity(self.rows.len());
+        let mut opcodes: HashMap<&str, Line> = HashMap::new();
+        for row in &self.rows {
+            let block = row.blocks.last().unwrap_or(&row.hat);
+            let self_time = self.self_time(row);
+            blocks.push(Line {
+                name: format!("{} {}", row.sprite_name, block),
+                count: row.count,
+                self_time,
+                time: row.time,
+            });
+
+            let opcode = opcodes.entry(&block.opcode).or_insert_with(|| Line {
+                name: block.opcode.clone(),
+                count: 0,
+                self_time: Duration::default(),
+                time: Duration::default(),
+            });
+            opcode.count += row.count;
+            opcode.self_time += self_time;
+            opcode.time += row.time;
+        }
+
+        let write_lines = |result: &mut String, title: &str, mut lines: Vec<Line>| {
+            lines.sort_by(|a, b| b.self_time.cmp(&a.self_time).then(a.name.cmp(&b.name)));
+            writeln!(
+                result,
+                "{:>10} {:>10} {:>10}  {}",
+                "self ms", "total ms", "count", title
+            )
+            .unwrap();
+            for line in lines {
+                writeln!(
+                    result,
+                    "{:>10.3} {:>10.3} {:>10}  {}",
+                    line.self_time.as_secs_f64() * 1000.0,
+                    line.time.as_secs_f64() * 1000.0,
+                    line.count,
+                    line.name
+                )
+                .unwrap();
+            }
+        };
+
+        let mut result = String::new();
+        write_lines(&mut result, "block", blocks);
+        result.push('\n');
+        write_lines(
+            &mut result,
+            "opcode",
+            opcodes.drain().map(|(_, line)| line).collect(),
+        );
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::id;
+
+    fn block_ref(block_id: &str, opcode: &str) -> BlockRef {
+        BlockRef {
+            id: id(block_id),
+            opcode: opcode.to_string(),
+        }
+    }
+
+    fn row(sprite_name: &str, blocks: Vec<BlockRef>, count: u64, millis: u64) -> ProfileRow {
+        ProfileRow {
+            sprite_name: sprite_name.to_string(),
+            hat: block_ref("hat", "event_whenflagclicked"),
+            blocks,
+            count,
+            time: Duration::from_millis(millis),
+        }
+    }
+
+    #[test]
+    fn test_profile() {
+        let say = || block_ref("say", "looks_say");
+        let join = || block_ref("a;b", "operator_join");
+        let profile = Profile::new(vec![
+            row("Sprite1", vec![say()], 2, 10),
+            row("Sprite1", vec![say(), join()], 2, 4),
+            // Clone
+            row("Sprite1", vec![say()], 1, 5),
+            row("Stage", vec![block_ref("wait", "control_wait")], 1, 1000),
+        ]);
+        assert_eq!(profile.rows.len(), 3);
+
+        assert_eq!(
+            profile.folded(),
+            "Sprite1;event_whenflagclicked (hat);looks_say (say) 11000\n\
+             Sprite1;event_whenflagclicked (hat);looks_say (say);operator_join (a:b) 4000\n\
+             Stage;event_whenflagclicked (hat);control_wait (wait) 1000000\n"
+        );
+
+        let table = profile.table();
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(
+            lines[1],
+            "  1000.000   1000.000          1  Stage control_wait (wait)"
+        );
+        assert_eq!(
+            lines[2],
+            "    11.000     15.000          3  Sprite1 looks_say (say)"
+        );
+        assert_eq!(lines[6], "  1000.000   1000.000          1  control_wait");
+    }
+}
@@ -1,10 +1,14 @@
 use super::*;
-use crate::blocks::value::Value;
+use crate::blocks::value::{self, Value};
 use crate::broadcaster::Broadcaster;
 use crate::coordinate::CanvasCoordinate;
-use crate::file::Monitor;
+use crate::coverage::Coverage;
+use crate::file::{LoadMode, Monitor};
+use crate::profiler::Profiler;
+use crate::sprite::SpriteID;
 use crate::sprite_runtime::SpriteRuntime;
-use crate::vm::ThreadID;
+use crate::tracer::Tracer;
+use crate::vm::{DebugInfo, ThreadID};
 use graphics::character::CharacterCache;
 use graphics::types::FontSize;
 use graphics::{rectangle, text};
@@ -16,6 +20,10 @@ pub struct Runtime {
     pub sprite: Arc<RwLock<SpriteRuntime>>,
     pub global: Arc<Global>,
     thread_id: ThreadID,
+    /// Name of the target, which clones share
+    sprite_name: Arc<str>,
+    /// Sprite that the blocks belong to, which is the sprite that a clone was cloned from
+    original_id: SpriteID,
 }
 
 impl Runtime {
@@ -23,43 +31,101 @@ impl Runtime {
         sprite: Arc<RwLock<SpriteRuntime>>,
         global: Arc<Global>,
         thread_id: ThreadID,
+        sprite_name: Arc<str>,
     ) -> Self {
         Self {
             sprite,
             global,
             thread_id,
+            sprite_name,
+            original_id: thread_id.sprite_id,
         }
     }
+
+    /// Blocks of clones belong to the sprite with original_id.
+    pub fn with_original_id(self, original_id: SpriteID) -> Self {
+        Self {
+            original_id,
+            ..self
+        }
+    }
+
     pub fn thread_id(&self) -> ThreadID {
         self.thread_id
     }
+
+    pub fn sprite_name(&self) -> &Arc<str> {
+        &self.sprite_name
+    }
+
+    pub fn original_id(&self) -> SpriteID {
+        self.original_id
+    }
 }
 
 #[derive(Debug)]
 pub struct Global {
     pub variables: Variables,
     pub broadcaster: Broadcaster,
+    /// Whether blocks with unsupported opcodes are replaced by placeholders
+    pub load_mode: LoadMode,
+    /// Measures blocks if profiling is enabled
+    pub profiler: Option<Arc<Profiler>>,
+    /// Records executed blocks if tracing is enabled
+    pub tracer: Option<Arc<Tracer>>,
+    /// Collects executed block IDs if coverage is enabled
+    pub coverage: Option<Arc<Coverage>>,
+    /// Whether scripts are optimized before their threads start
+    pub optimize: bool,
+    /// Whether steps are recorded so that they can be undone
+    history: bool,
+    sprite_ids: HashMap<String, SpriteID>,
 }
 
 impl Global {
     pub fn new(
         scratch_file_variables: &HashMap<String, file::Variable>,
+        scratch_file_lists: &HashMap<String, file::List>,
         monitors: &[Monitor],
+        sprite_ids: HashMap<String, SpriteID>,
         broadcaster: Broadcaster,
+        load_mode: LoadMode,
     ) -> Self {
         Self {
-            variables: Variables::new(scratch_file_variables, monitors),
+            variables: Variables::new(scratch_file_variables, scratch_file_lists, monitors),
             broadcaster,
+            load_mode,
+            profiler: None,
+            tracer: None,
+            coverage: None,
+            optimize: false,
+            history: false,
+            sprite_ids,
         }
     }
 
+    pub fn history(&self) -> bool {
+        self.history
+    }
+
+    /// Records steps and the variables they change so that they can be undone.
+    pub fn set_history(&mut self, history: bool) {
+        self.history = history;
+        self.variables.record_undo = history;
+    }
+
+    /// Looks up a sprite by the name used in menus. Menus can name sprites that do not exist.
+    pub fn sprite_id(&self, sprite_name: &str) -> Option<SpriteID> {
+        self.sprite_ids.get(sprite_name).copied()
+    }
+
     pub async fn draw(
         &self,
         context: &Context,
         graphics: &mut G2d<'_>,
         character_cache: &mut Glyphs,
     ) -> Result<()> {
-        for variable in self.variables.variables.read().await.values() {
+        for variable in self.variables.variables.read().await.iter() {
             if variable.monitored {
                 Global::draw_monitor(
                     &context.trans(variable.position.x, variable.position.y),
@@ -146,18 +212,64 @@ impl Global {
 
 #[derive(Debug)]
 pub struct Variables {
-    variables: RwLock<HashMap<String, Variable>>,
+    /// Sorted by key
+    variables: RwLock<Vec<Variable>>,
+    /// Index of each variable by key. Variables are not added or removed after loading.
+    slots: HashMap<String, usize>,
+    /// Key of each variable by name, or None if several variables have the name
+    keys_by_name: HashMap<String, Option<String>>,
+    watch: RwLock<Watch>,
+    /// Keys and previous values of changed variables by writer, oldest first
+    undo: RwLock<HashMap<ThreadID, Vec<(String, Value)>>>,
+    /// Whether previous values are kept in undo
+    record_undo: bool,
+}
+
+#[derive(Debug, Default)]
+struct Watch {
+    /// IDs of variables whose changes are recorded
+    variable_ids: HashSet<String>,
+    /// Record changes of every variable
+    all: bool,
+    writes: Vec<VariableWrite>,
+}
+
+/// A change to the value of a variable.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VariableWrite {
+    /// The variable is watched rather than only recorded because of watch_all
+    pub watched: bool,
+    pub variable_name: String,
+    pub old_value: Value,
+    pub new_value: Value,
+    /// Thread and block that made the write
+    pub writer: DebugInfo,
+}
+
+impl Display for VariableWrite {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "variable \"{}\" changed from {} to {} ({})",
+            self.variable_name, self.old_value, self.new_value, self.writer
+        )
+    }
 }
 
 impl Variables {
-    fn new(scratch_file_variables: &HashMap<String, file::Variable>, monitors: &[Monitor]) -> Self {
-        let mut variables: HashMap<String, Variable> = HashMap::new();
+    fn new(
+        scratch_file_variables: &HashMap<String, file::Variable>,
+        scratch_file_lists: &HashMap<String, file::List>,
+        monitors: &[Monitor],
+    ) -> Self {
+        let mut variables: Vec<Variable> = Vec::new();
         for (key, v) in scratch_file_variables {
             let monitor = monitors.iter().find(|m| &m.id == key);
             let variable = match monitor {
                 Some(monitor) => Variable {
+                    key: key.clone(),
                     name: v.id.clone(),
-                    value: v.value.clone().into(),
+                    value: value::from_json(v.value.clone()),
                     monitored: monitor.visible,
                     position: CanvasCoordinate {
                         x: monitor.x,
@@ -165,66 +277,234 @@ impl Variables {
                     },
                 },
                 None => Variable {
+                    key: key.clone(),
                     name: v.id.clone(),
-                    value: v.value.clone().into(),
+                    value: value::from_json(v.value.clone()),
                     monitored: false,
                     position: CanvasCoordinate { x: 0.0, y: 0.0 },
                 },
             };
-            variables.insert(key.clone(), variable);
+            variables.push(variable);
+        }
+
+        // Lists cannot be modified yet, so they are stored as their contents
+        for (key, list) in scratch_file_lists {
+            variables.push(Variable {
+                key: key.clone(),
+                name: list.name.clone(),
+                value: Value::String(list_contents(&list.values)),
+                monitored: false,
+                position: CanvasCoordinate { x: 0.0, y: 0.0 },
+            });
+        }
+        variables.sort_by(|a, b| a.key.cmp(&b.key));
+        variables.dedup_by(|a, b| a.key == b.key);
+        let slots = variables
+            .iter()
+            .enumerate()
+            .map(|(slot, v)| (v.key.clone(), slot))
+            .collect();
+        let mut keys_by_name: HashMap<String, Option<String>> = HashMap::new();
+        for v in &variables {
+            keys_by_name
+                .entry(v.name.clone())
+                .and_modify(|key| *key = None)
+                .or_insert_with(|| Some(v.key.clone()));
         }
 
         Self {
             variables: RwLock::new(variables),
+            slots,
+            keys_by_name,
+            watch: RwLock::default(),
+            undo: RwLock::default(),
+            record_undo: false,
         }
     }
 
+    /// Returns the index of the variable, which does not change while the VM runs.
+    pub fn slot(&self, key: &str) -> Option<usize> {
+        self.slots.get(key).copied()
+    }
+
+    /// Returns the key of the only variable with the name.
+    pub fn key_of_name(&self, name: &str) -> Option<&str> {
+        self.keys_by_name.get(name)?.as_deref()
+    }
+
+    fn slot_of_key(&self, key: &str) -> Result<usize> {
+        self.slot(key)
+            .ok_or_else(|| Error::msg(format!("key does not exist: {}", key)))
+    }
+
     pub async fn get(&self, key: &str) -> Result<Value> {
-        match self.variables.read().await.get(key) {
+        self.get_slot(self.slot_of_key(key)?).await
+    }
+
+    pub async fn get_slot(&self, slot: usize) -> Result<Value> {
+        match self.variables.read().await.get(slot) {
             Some(v) => Ok(v.value.clone()),
-            None => Err(Error::msg(format!("key does not exist: {}", key))),
+            None => Err(Error::msg(format!("slot does not exist: {}", slot))),
         }
     }
 
-    pub async fn set(&self, key: &str, value: Value) -> Result<()> {
-        let mut variables = self.variables.write().await;
-        let variable = match variables.get_mut(key) {
-            Some(v) => v,
-            None => return Err(Error::msg(format!("key does not exist: {}", key))),
-        };
+    pub async fn set(&self, key: &str, value: Value, writer: DebugInfo) -> Result<()> {
+        self.set_with(key, |_| value, writer).await
+    }
 
-        variable.value = value;
-        Ok(())
+    pub async fn set_with<F>(&self, key: &str, function: F, writer: DebugInfo) -> Result<()>
+    where
+        F: FnOnce(&Value) -> Value,
+    {
+        self.set_slot_with(self.slot_of_key(key)?, function, writer)
+            .await
     }
 
-    pub async fn set_with<F>(&self, key: &str, function: F) -> Result<()>
+    pub async fn set_slot_with<F>(&self, slot: usize, function: F, writer: DebugInfo) -> Result<()>
     where
         F: FnOnce(&Value) -> Value,
     {
         let mut variables = self.variables.write().await;
-        let mut variable = match variables.get_mut(key) {
+        let variable = match variables.get_mut(slot) {
             Some(v) => v,
-            None => return Err(Error::msg(format!("key does not exist: {}", key))),
+            None => return Err(Error::msg(format!("slot does not exist: {}", slot))),
         };
 
-        variable.value = function(&variable.value);
+        let new_value = function(&variable.value);
+        let old_value = std::mem::replace(&mut variable.value, new_value);
+        if old_value != variable.value {
+            if self.record_undo {
+                self.undo
+                    .write()
+                    .await
+                    .entry(writer.thread_id)
+                    .or_default()
+                    .push((variable.key.clone(), old_value.clone()));
+            }
+            let mut watch = self.watch.write().await;
+            let watched = watch.variable_ids.contains(&variable.key);
+            if watched || watch.all {
+                watch.writes.push(VariableWrite {
+                    watched,
+                    variable_name: variable.name.clone(),
+                    old_value,
+                    new_value: variable.value.clone(),
+                    writer,
+                });
+            }
+        }
         Ok(())
     }
 
-    pub async fn set_monitored(&self, key: &str, monitored: bool) -> Result<()> {
+    /// Returns the names and values of all variables, sorted by name.
+    pub async fn list(&self) -> Vec<(String, Value)> {
+        let mut variables: Vec<(String, Value)> = self
+            .variables
+            .read()
+            .await
+            .iter()
+            .map(|v| (v.name.clone(), v.value.clone()))
+            .collect();
+        variables.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+        variables
+    }
+
+    /// Returns the keys and values of all variables, sorted by key.
+    pub async fn values(&self) -> Vec<(String, Value)> {
+        self.variables
+            .read()
+            .await
+            .iter()
+            .map(|v| (v.key.clone(), v.value.clone()))
+            .collect()
+    }
+
+    /// Sets the values returned by values without recording the changes. Nothing is set if a key
+    /// does not exist.
+    pub async fn set_values(&self, values: Vec<(String, Value)>) -> Result<()> {
+        let slots = values
+            .iter()
+            .map(|(key, _)| self.slot_of_key(key))
+            .collect::<Result<Vec<usize>>>()?;
         let mut variables = self.variables.write().await;
-        match variables.get_mut(key) {
-            Some(v) => {
-                v.monitored = monitored;
-                Ok(())
+        for (slot, (_, value)) in slots.into_iter().zip(values) {
+            variables[slot].value = value;
+        }
+        self.undo.write().await.clear();
+        Ok(())
+    }
+
+    /// Returns the previous values of the variables that the thread changed since the last call.
+    pub async fn take_undo(&self, thread_id: ThreadID) -> Vec<(String, Value)> {
+        self.undo
+            .write()
+            .await
+            .remove(&thread_id)
+            .unwrap_or_default()
+    }
+
+    /// Sets the values returned by take_undo without recording the changes.
+    pub async fn undo(&self, values: Vec<(String, Value)>) {
+        let mut variables = self.variables.write().await;
+        for (key, value) in values.into_iter().rev() {
+            match self.slot(&key) {
+                Some(slot) => variables[slot].value = value,
+                None => log::warn!("key does not exist: {}", key),
             }
-            None => Err(Error::msg(format!("key does not exist: {}", key))),
         }
     }
+
+    /// Records changes to the variable with the name or ID.
+    pub async fn watch(&self, name_or_id: &str) -> Result<()> {
+        let key = self
+            .variables
+            .read()
+            .await
+            .iter()
+            .find(|v| v.key == name_or_id || v.name == name_or_id)
+            .map(|v| v.key.clone())
+            .ok_or_else(|| Error::msg(format!("variable does not exist: {}", name_or_id)))?;
+        self.watch.write().await.variable_ids.insert(key);
+        Ok(())
+    }
+
+    /// Records changes to every variable, not only watched variables.
+    pub async fn watch_all(&self, all: bool) {
+        self.watch.write().await.all = all;
+    }
+
+    /// Returns the recorded changes since the last call.
+    pub async fn take_writes(&self) -> Vec<VariableWrite> {
+        std::mem::take(&mut self.watch.write().await.writes)
+    }
+
+    pub async fn set_monitored(&self, key: &str, monitored: bool) -> Result<()> {
+        let slot = self.slot_of_key(key)?;
+        self.variables.write().await[slot].monitored = monitored;
+        Ok(())
+    }
+}
+
+/// Items are joined by spaces unless every item is a single character.
+fn list_contents(values: &[serde_json::Value]) -> String {
+    let items: Vec<String> = values
+        .iter()
+        .map(|v| match v {
+            serde_json::Value::String(s) => s.clone(),
+            _ => v.to_string(),
+        })
+        .collect();
+
+    if items.iter().all(|s| s.chars().count() == 1) {
+        items.concat()
+    } else {
+        items.join(" ")
+    }
 }
 
 #[derive(Debug, Clone)]
 pub struct Variable {
+    key: String,
     name: String,
     value: Value,
     monitored: bool,
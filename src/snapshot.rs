@@ -0,0 +1,115 @@
+use super::*;
+use crate::blocks::value::Value;
+use crate::blocks::BlockState;
+use crate::file::BlockID;
+use crate::sprite::SpriteID;
+use crate::sprite_runtime::SpriteRuntimeSnapshot;
+use crate::thread::{ThreadPosition, ThreadState};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::path::Path;
+
+/// State of a running project, which can be restored by a VM that loaded the same project.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Keys and values of the stage variables
+    pub variables: Vec<(String, SavedValue)>,
+    /// Sprites and clones that have not been deleted, sorted by ID
+    pub sprites: Vec<SavedSprite>,
+    /// Back to front
+    pub draw_order: Vec<SpriteID>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SavedSprite {
+    pub sprite_id: SpriteID,
+    /// Name of the target. Clones have the name of the sprite they were cloned from.
+    pub name: String,
+    /// Sprite that a clone was cloned from, which is the sprite itself if it is not a clone
+    pub original_id: SpriteID,
+    pub runtime: SpriteRuntimeSnapshot,
+    pub threads: Vec<SavedThreadPosition>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SavedThreadPosition {
+    pub position: ThreadPosition,
+    pub state: ThreadState,
+    /// Blocks of the thread that keep a state, such as the iterations of a repeat block
+    #[serde(default)]
+    pub blocks: Vec<(BlockID, BlockState)>,
+}
+
+/// Value of a variable. Infinity and NaN are saved as strings because JSON has no such numbers.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum SavedValue {
+    Bool(bool),
+    Number(f64),
+    NonFiniteNumber(String),
+    String(String),
+}
+
+impl Snapshot {
+    pub fn save(&self, path: &Path) -> Result<()> {
+        serde_json::to_writer(BufWriter::new(File::create(path)?), self)?;
+        Ok(())
+    }
+
+    pub fn load(path: &Path) -> Result<Self> {
+        Ok(serde_json::from_reader(BufReader::new(File::open(path)?))?)
+    }
+}
+
+impl TryFrom<&Value> for SavedValue {
+    type Error = Error;
+
+    fn try_from(value: &Value) -> Result<Self> {
+        Ok(match value {
+            Value::Bool(b) => SavedValue::Bool(*b),
+            Value::Number(n) if n.is_finite() => SavedValue::Number(*n),
+            Value::Number(n) => SavedValue::NonFiniteNumber(n.to_string()),
+            Value::String(s) => SavedValue::String(s.clone()),
+            _ => return Err(Error::msg(format!("cannot save variable value: {}", value))),
+        })
+    }
+}
+
+impl TryFrom<&SavedValue> for Value {
+    type Error = Error;
+
+    fn try_from(value: &SavedValue) -> Result<Self> {
+        Ok(match value {
+            SavedValue::Bool(b) => Value::Bool(*b),
+            SavedValue::Number(n) => Value::Number(*n),
+            SavedValue::NonFiniteNumber(s) => Value::Number(s.parse()?),
+            SavedValue::String(s) => Value::String(s.clone()),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_saved_value() {
+        for value in &[
+            Value::Bool(true),
+            Value::Number(1.5),
+            Value::Number(f64::INFINITY),
+            Value::Number(f64::NEG_INFINITY),
+            Value::String("a".to_string()),
+        ] {
+            let saved = SavedValue::try_from(value).unwrap();
+            let json = serde_json::to_string(&saved).unwrap();
+            let saved: SavedValue = serde_json::from_str(&json).unwrap();
+            assert_eq!(&Value::try_from(&saved).unwrap(), value);
+        }
+
+        let saved = SavedValue::try_from(&Value::Number(f64::NAN)).unwrap();
+        assert!(matches!(Value::try_from(&saved).unwrap(), Value::Number(n) if n.is_nan()));
+        assert!(SavedValue::try_from(&Value::Color(palette::Srgb::new(0, 0, 0))).is_err());
+    }
+}
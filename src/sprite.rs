@@ -1,21 +1,33 @@
 use super::*;
+use crate::arena::Handle;
+use crate::blocks::optimizer::{self, Optimizations};
 use crate::blocks::*;
 use crate::coordinate::SpriteRectangle;
+use crate::debugger::{BlockRef, ThreadInspection, ThreadStatus};
 use crate::file::{BlockID, Image, Target};
+use crate::history::SavedThread;
 use crate::runtime::{Global, Runtime};
+use crate::snapshot::{SavedSprite, SavedThreadPosition};
 use crate::sprite_runtime::{GraphicsCostumeTexture, SpriteRuntime};
-use crate::thread::{BlockInputs, Thread};
+use crate::thread::{BlockInputs, Thread, ThreadPosition, ThreadState};
 use crate::vm::ThreadID;
 use graphics::character::CharacterCache;
 use graphics::Context;
 use piston_window::G2dTextureContext;
-use std::collections::hash_map::DefaultHasher;
+use serde::{Deserialize, Serialize};
 use std::fmt::{Debug, Display, Formatter};
-use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
 
 #[derive(Debug)]
 pub struct Sprite {
     threads: Vec<RwLock<Thread>>,
+    /// Position of each thread, which can be read while the thread is locked
+    positions: Vec<Arc<Mutex<ThreadPosition>>>,
+    /// Number of saved steps that started
+    saved_steps: AtomicUsize,
+    /// Number of saved steps that have not finished
+    active_saved_steps: AtomicUsize,
     runtime: Runtime,
     target: Target,
 }
@@ -26,12 +38,32 @@ impl Sprite {
         sprite_runtime: SpriteRuntime,
         global: Arc<Global>,
         target: Target,
+    ) -> Result<Self> {
+        Sprite::with_original(sprite_id, sprite_id, sprite_runtime, global, target).await
+    }
+
+    /// Clones are built with the ID of the sprite that they were cloned from.
+    async fn with_original(
+        sprite_id: SpriteID,
+        original_id: SpriteID,
+        sprite_runtime: SpriteRuntime,
+        global: Arc<Global>,
+        mut target: Target,
     ) -> Result<Self> {
         let mut threads: Vec<RwLock<Thread>> = Vec::new();
+        let mut positions: Vec<Arc<Mutex<ThreadPosition>>> = Vec::new();
+        let mut optimizations = Optimizations::default();
 
+        // Clones start from the target that was already optimized
+        let optimize = global.optimize && !sprite_runtime.is_a_clone();
         let sprite_runtime_ref = Arc::new(RwLock::new(sprite_runtime));
+        let sprite_name: Arc<str> = Arc::from(target.name.as_str());
 
         for hat_id in find_hats(&target.blocks) {
+            if optimize {
+                optimizations += optimizer::optimize(hat_id, &mut target.blocks, &global.variables);
+            }
+
             let runtime = Runtime::new(
                 sprite_runtime_ref.clone(),
                 global.clone(),
@@ -39,14 +71,24 @@ impl Sprite {
                     sprite_id,
                     thread_id: threads.len(),
                 },
-            );
+                sprite_name.clone(),
+            )
+            .with_original_id(original_id);
 
             let thread = Thread::start(hat_id, runtime, &target.blocks)?;
+            positions.push(thread.shared_position());
             threads.push(RwLock::new(thread));
         }
 
+        if !optimizations.is_empty() {
+            log::info!("optimized {}: {}", target.name, optimizations);
+        }
+
         Ok(Self {
             threads,
+            positions,
+            saved_steps: AtomicUsize::new(0),
+            active_saved_steps: AtomicUsize::new(0),
             runtime: Runtime::new(
                 sprite_runtime_ref,
                 global,
@@ -54,7 +96,9 @@ impl Sprite {
                     sprite_id,
                     thread_id: 0,
                 },
-            ),
+                sprite_name,
+            )
+            .with_original_id(original_id),
             target,
         })
     }
@@ -64,12 +108,13 @@ impl Sprite {
         texture_context: &mut G2dTextureContext,
         costumes: &[file::Costume],
         images: &HashMap<String, Image>,
-    ) -> Result<()> {
+    ) -> Result<Vec<(usize, Error)>> {
+        let load_mode = self.runtime.global.load_mode;
         self.runtime
             .sprite
             .write()
             .await
-            .add_costumes(texture_context, costumes, images)
+            .add_costumes(texture_context, costumes, images, load_mode)
             .await
     }
 
@@ -79,7 +124,18 @@ impl Sprite {
 
     pub async fn block_info(&self, thread_id: usize) -> Result<BlockInfo> {
         if let Some(thread) = self.threads.get(thread_id) {
-            Ok(thread.read().await.block_info())
+            thread.read().await.block_info()
+        } else {
+            Err(Error::msg(format!(
+                "thread_id does not exist: {}",
+                thread_id
+            )))
+        }
+    }
+
+    pub async fn position(&self, thread_id: usize) -> Result<ThreadPosition> {
+        if let Some(thread) = self.threads.get(thread_id) {
+            Ok(thread.read().await.position())
         } else {
             Err(Error::msg(format!(
                 "thread_id does not exist: {}",
@@ -88,8 +144,239 @@ impl Sprite {
         }
     }
 
-    pub async fn step(&self, thread_id: usize) -> Result<()> {
-        self.threads[thread_id].write().await.step().await
+    /// Returns the block that the thread executes next and the state of the thread.
+    pub async fn curr_block(&self, thread_id: usize) -> Result<(BlockID, ThreadState)> {
+        if let Some(thread) = self.threads.get(thread_id) {
+            let thread = thread.read().await;
+            Ok((thread.curr_block(), thread.state()))
+        } else {
+            Err(Error::msg(format!(
+                "thread_id does not exist: {}",
+                thread_id
+            )))
+        }
+    }
+
+    pub async fn step(&self, thread_id: usize) -> Result<ThreadState> {
+        match self.threads.get(thread_id) {
+            Some(thread) => {
+                let mut thread = thread.write().await;
+                thread.step().await?;
+                Ok(thread.state())
+            }
+            None => Err(Error::msg(format!(
+                "thread_id does not exist: {}",
+                thread_id
+            ))),
+        }
+    }
+
+    pub async fn skip_block(&self, thread_id: usize) -> Result<()> {
+        match self.threads.get(thread_id) {
+            Some(thread) => thread.write().await.skip_block(),
+            None => Err(Error::msg(format!(
+                "thread_id does not exist: {}",
+                thread_id
+            ))),
+        }
+    }
+
+    pub async fn save(&self, thread_id: usize) -> Result<SavedThread> {
+        match self.threads.get(thread_id) {
+            Some(thread) => {
+                let thread = thread.read().await;
+                let overlapped = self.active_saved_steps.fetch_add(1, Ordering::SeqCst) > 0;
+                Ok(SavedThread {
+                    sprite: self.runtime.sprite.read().await.state(),
+                    position: thread.position(),
+                    state: thread.state(),
+                    step: self.saved_steps.fetch_add(1, Ordering::SeqCst),
+                    overlapped,
+                })
+            }
+            None => Err(Error::msg(format!(
+                "thread_id does not exist: {}",
+                thread_id
+            ))),
+        }
+    }
+
+    /// Called after the thread stepped to finish saving the state before the step. The pen lines
+    /// of steps that overlapped with other steps of the sprite, such as waits, are kept when the
+    /// step is undone so that the lines of the other threads are not removed.
+    pub async fn complete_save(&self, saved: &mut SavedThread) {
+        self.active_saved_steps.fetch_sub(1, Ordering::SeqCst);
+        if self.saved_steps.load(Ordering::SeqCst) != saved.step + 1 {
+            saved.overlapped = true;
+        }
+        self.runtime
+            .sprite
+            .read()
+            .await
+            .complete_state(&mut saved.sprite, saved.overlapped);
+    }
+
+    pub async fn restore(&self, thread_id: usize, saved: &SavedThread) -> Result<()> {
+        match self.threads.get(thread_id) {
+            Some(thread) => {
+                self.runtime.sprite.write().await.set_state(&saved.sprite)?;
+                thread.write().await.restore(&saved.position, saved.state);
+                Ok(())
+            }
+            None => Err(Error::msg(format!(
+                "thread_id does not exist: {}",
+                thread_id
+            ))),
+        }
+    }
+
+    pub async fn snapshot(&self, sprite_id: SpriteID) -> SavedSprite {
+        let mut threads: Vec<SavedThreadPosition> = Vec::with_capacity(self.threads.len());
+        for thread in &self.threads {
+            let thread = thread.read().await;
+            threads.push(SavedThreadPosition {
+                position: thread.position(),
+                state: thread.state(),
+                blocks: thread.block_states(),
+            });
+        }
+        SavedSprite {
+            sprite_id,
+            name: self.target.name.clone(),
+            original_id: self.runtime.original_id(),
+            runtime: self.runtime.sprite.read().await.snapshot(),
+            threads,
+        }
+    }
+
+    /// Returns an error if the saved sprite cannot be restored to this sprite or its clones.
+    pub async fn check_snapshot(&self, saved: &SavedSprite) -> Result<()> {
+        if saved.threads.len() != self.threads.len() {
+            return Err(Error::msg(format!(
+                "sprite {} has {} threads but the snapshot has {}",
+                self.target.name,
+                self.threads.len(),
+                saved.threads.len()
+            )));
+        }
+        for (thread, saved) in self.threads.iter().zip(&saved.threads) {
+            let thread = thread.read().await;
+            if thread.position().hat != saved.position.hat {
+                return Err(Error::msg(format!(
+                    "sprite {} has no script with hat {}",
+                    self.target.name, saved.position.hat
+                )));
+            }
+            let blocks = saved
+                .position
+                .loop_stack
+                .iter()
+                .chain(std::iter::once(&saved.position.curr_block))
+                .chain(saved.blocks.iter().map(|(id, _)| id));
+            for block_id in blocks {
+                if !thread.has_block(block_id) {
+                    return Err(Error::msg(format!(
+                        "script {} of sprite {} has no block {}",
+                        saved.position.hat, self.target.name, block_id
+                    )));
+                }
+            }
+        }
+        self.runtime
+            .sprite
+            .read()
+            .await
+            .check_snapshot(&saved.runtime)
+    }
+
+    pub async fn restore_snapshot(&self, saved: &SavedSprite) -> Result<()> {
+        self.check_snapshot(saved).await?;
+        self.runtime
+            .sprite
+            .write()
+            .await
+            .restore_snapshot(&saved.runtime)?;
+        for (thread, saved) in self.threads.iter().zip(&saved.threads) {
+            let mut thread = thread.write().await;
+            thread.restore(&saved.position, saved.state);
+            thread.set_block_states(&saved.blocks);
+        }
+        Ok(())
+    }
+
+    pub async fn stop(&self, thread_id: usize) -> Result<()> {
+        match self.threads.get(thread_id) {
+            Some(thread) => {
+                thread.write().await.stop();
+                Ok(())
+            }
+            None => Err(Error::msg(format!(
+                "thread_id does not exist: {}",
+                thread_id
+            ))),
+        }
+    }
+
+    pub async fn inspect(&self, sprite_id: SpriteID) -> Vec<ThreadInspection> {
+        let mut result: Vec<ThreadInspection> = Vec::with_capacity(self.threads.len());
+        for (index, thread) in self.threads.iter().enumerate() {
+            // Threads are locked while a block is executing, which only takes long when the
+            // block waits
+            let (position, status) = match thread.try_read() {
+                Some(thread) => {
+                    let status = match thread.state() {
+                        ThreadState::Running => ThreadStatus::Running,
+                        ThreadState::Done => ThreadStatus::Done,
+                        ThreadState::Error | ThreadState::Stopped => ThreadStatus::Stopped,
+                    };
+                    (thread.position(), status)
+                }
+                None => (
+                    self.positions[index].lock().unwrap().clone(),
+                    ThreadStatus::Waiting,
+                ),
+            };
+
+            result.push(ThreadInspection {
+                thread_id: ThreadID {
+                    sprite_id,
+                    thread_id: index,
+                },
+                sprite_name: self.target.name.clone(),
+                hat: self.block_ref(position.hat),
+                current_block: self.block_ref(position.curr_block),
+                call_stack: position
+                    .loop_stack
+                    .iter()
+                    .map(|&id| self.block_ref(id))
+                    .collect(),
+                status,
+            });
+        }
+        result
+    }
+
+    fn block_ref(&self, id: BlockID) -> BlockRef {
+        BlockRef {
+            id,
+            opcode: self.opcode(&id).unwrap_or_default().to_string(),
+        }
+    }
+
+    /// Builds the reporter block with the runtime of this sprite.
+    pub fn reporter(&self, block_id: &BlockID) -> Result<Box<dyn Block + Send + Sync>> {
+        let (_, mut blocks) = block_tree(*block_id, self.runtime.clone(), &self.target.blocks)?;
+        blocks
+            .remove(block_id)
+            .ok_or_else(|| Error::msg(format!("could not find block: {}", block_id)))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.target.name
+    }
+
+    pub fn opcode(&self, block_id: &BlockID) -> Option<&str> {
+        self.target.blocks.get(block_id).map(|b| b.opcode.as_str())
     }
 
     pub async fn draw<G, C>(
@@ -109,18 +396,19 @@ impl Sprite {
             .draw(context, graphics, character_cache)
     }
 
-    pub async fn block_inputs(&self) -> Vec<BlockInputs> {
+    pub async fn block_inputs(&self) -> Result<Vec<BlockInputs>> {
         let mut result: Vec<BlockInputs> = Vec::with_capacity(self.threads.len());
         for thread in &self.threads {
-            result.push(thread.read().await.block_inputs());
+            result.push(thread.read().await.block_inputs()?);
         }
-        result
+        Ok(result)
     }
 
     pub async fn clone_sprite(&self, new_sprite_id: SpriteID) -> Result<Sprite> {
         let sprite_runtime = self.runtime.sprite.read().await.clone_sprite_runtime();
-        Sprite::new(
+        Sprite::with_original(
             new_sprite_id,
+            self.runtime.original_id(),
             sprite_runtime,
             self.runtime.global.clone(),
             self.target.clone(),
@@ -131,15 +419,16 @@ impl Sprite {
     pub async fn rectangle(&self) -> SpriteRectangle {
         self.runtime.sprite.read().await.rectangle()
     }
+
+    pub async fn properties(&self) -> Vec<(&'static str, String)> {
+        self.runtime.sprite.read().await.properties()
+    }
 }
 
 pub fn find_hats(block_infos: &HashMap<BlockID, file::Block>) -> Vec<BlockID> {
     let mut hats: Vec<BlockID> = Vec::new();
     for (id, block_info) in block_infos {
-        // Blocks without event watcher (has rounded top in editor) are ignored
-        if (block_info.opcode == "control_start_as_clone" || block_info.opcode.contains("_when"))
-            && block_info.top_level
-        {
+        if is_hat(block_info) {
             hats.push(*id);
         }
     }
@@ -148,17 +437,45 @@ pub fn find_hats(block_infos: &HashMap<BlockID, file::Block>) -> Vec<BlockID> {
     hats
 }
 
-#[derive(Copy, Clone, PartialOrd, Ord, PartialEq, Eq, Hash)]
+pub fn is_hat(block_info: &file::Block) -> bool {
+    // Blocks without event watcher (has rounded top in editor) are ignored
+    (block_info.opcode == "control_start_as_clone" || block_info.opcode.contains("_when"))
+        && block_info.top_level
+}
+
+/// Unique ID of a target or clone. Targets are numbered in the order of the project's targets
+/// when it is loaded. Clones use the handle of their slot in the sprite arena, so the ID of a
+/// deleted clone is not used again. IDs do not change when a sprite is renamed.
+#[derive(Copy, Clone, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(transparent)]
 pub struct SpriteID {
-    hash: u64,
+    id: u64,
 }
 
 impl SpriteID {
-    pub fn from_sprite_name(sprite_name: &str) -> Self {
-        let mut hasher = DefaultHasher::new();
-        sprite_name.hash(&mut hasher);
+    pub fn new(id: usize) -> Self {
+        Self { id: id as u64 }
+    }
+
+    pub fn handle(self) -> Handle {
+        Handle::from_bits(self.id)
+    }
+
+    /// Maps sprite names to IDs. The stage is not included because menus only refer to sprites.
+    pub fn sprite_ids(targets: &[Target]) -> HashMap<String, SpriteID> {
+        targets
+            .iter()
+            .enumerate()
+            .filter(|(_, target)| !target.is_stage)
+            .map(|(index, target)| (target.name.clone(), SpriteID::new(index)))
+            .collect()
+    }
+}
+
+impl From<Handle> for SpriteID {
+    fn from(handle: Handle) -> Self {
         Self {
-            hash: hasher.finish(),
+            id: handle.to_bits(),
         }
     }
 }
@@ -173,6 +490,35 @@ impl Debug for SpriteID {
 
 impl Display for SpriteID {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{:x}", self.hash as u32)
+        write!(f, "{}", self.id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn test_sprite_ids() {
+        let target = |name: &str, is_stage: bool| Target {
+            name: name.to_string(),
+            is_stage,
+            ..Target::default()
+        };
+        let targets = [
+            target("Stage", true),
+            target("A", false),
+            target("B", false),
+        ];
+        let mut expected: HashMap<String, SpriteID> = HashMap::new();
+        expected.insert("A".to_string(), SpriteID::new(1));
+        expected.insert("B".to_string(), SpriteID::new(2));
+        assert_eq!(SpriteID::sprite_ids(&targets), expected);
+
+        let global = testing::global_with_sprites(&targets);
+        assert_eq!(global.sprite_id("B"), Some(SpriteID::new(2)));
+        assert_eq!(global.sprite_id("Stage"), None);
+        assert_eq!(global.sprite_id("C"), None);
     }
 }
@@ -1,27 +1,68 @@
 use super::*;
-use crate::blocks::BlockInfo;
+use crate::arena::Arena;
+use crate::blocks::value::Value;
+use crate::blocks::{Block, BlockInfo};
 use crate::broadcaster::LayerChange;
 use crate::coordinate::SpriteRectangle;
-use crate::file::Target;
+use crate::debugger::{ThreadInspection, ThreadStatus};
+use crate::file::{BlockID, Target};
+use crate::history::{Change, History};
 use crate::runtime::Global;
+use crate::snapshot::{SavedValue, Snapshot};
 use crate::sprite::{Sprite, SpriteID};
+use crate::thread::{ThreadPosition, ThreadState};
 use crate::vm::ThreadID;
 use graphics::Context;
 use graphics_buffer::{BufferGlyphs, RenderBuffer};
 use piston_window::{G2d, Glyphs};
-use std::mem::MaybeUninit;
+use std::convert::TryFrom;
 
-/// I needed a map that can to add cloned sprites while other sprites are still running.
+/// I needed a map that can to add cloned sprites while other sprites are still running. Sprites
+/// are shared, so the lock is only held to look them up.
 #[derive(Debug)]
 pub struct SpriteMap {
-    sprite_groups: SpriteGroups,
+    sprites: RwLock<Sprites>,
     draw_order: RwLock<DrawOrder>,
-    removed_sprites: RwLock<HashSet<SpriteID>>,
     stopped_threads: RwLock<HashSet<ThreadID>>,
     global: Arc<Global>,
+    history: RwLock<History>,
 }
 
-type SpriteGroups = [RwLock<HashMap<SpriteID, Sprite>>; 64];
+/// Sprites and clones that have not been deleted, in the arena slots of their IDs.
+#[derive(Debug, Default)]
+struct Sprites {
+    arena: Arena<Arc<Sprite>>,
+}
+
+impl Sprites {
+    fn get(&self, sprite_id: &SpriteID) -> Option<Arc<Sprite>> {
+        self.arena.get(sprite_id.handle()).cloned()
+    }
+
+    /// Puts the sprite in the slot of its ID.
+    fn insert(&mut self, sprite_id: SpriteID, sprite: Arc<Sprite>) -> Result<()> {
+        let handle = sprite_id.handle();
+        if self.arena.reserve_at(handle) && self.arena.fill(handle, sprite).is_ok() {
+            Ok(())
+        } else {
+            Err(Error::msg(format!(
+                "sprite_id is not available: {}",
+                sprite_id
+            )))
+        }
+    }
+
+    fn remove(&mut self, sprite_id: &SpriteID) -> Option<Arc<Sprite>> {
+        self.arena.remove(sprite_id.handle())
+    }
+
+    fn all(&self) -> Vec<(SpriteID, Arc<Sprite>)> {
+        self.arena
+            .iter()
+            .map(|(handle, sprite)| (SpriteID::from(handle), sprite.clone()))
+            .collect()
+    }
+}
 
 impl SpriteMap {
     pub fn new(
@@ -29,49 +70,412 @@ impl SpriteMap {
         targets: &[Target],
         global: Arc<Global>,
     ) -> Self {
-        let mut sprite_groups: [MaybeUninit<RwLock<HashMap<SpriteID, Sprite>>>; 64] =
-            MaybeUninit::uninit_array();
-
-        sprite_groups[0] = MaybeUninit::new(RwLock::new(sprites));
-
-        for group in &mut sprite_groups[1..] {
-            *group = MaybeUninit::new(RwLock::default());
+        let mut arena = Sprites::default();
+        for (sprite_id, sprite) in sprites {
+            arena.insert(sprite_id, Arc::new(sprite)).unwrap();
         }
 
         Self {
-            // The unsafe enables the use of MaybeUninit to initialize a big array
-            sprite_groups: unsafe { std::mem::transmute(sprite_groups) },
+            sprites: RwLock::new(arena),
             draw_order: RwLock::new(DrawOrder::new(targets)),
-            removed_sprites: RwLock::default(),
             stopped_threads: RwLock::default(),
             global,
+            history: RwLock::default(),
         }
     }
 
+    /// Records a change. Deleted clones that can no longer be restored free their slots.
+    async fn record(&self, change: Change) {
+        let dropped = self.history.write().await.push(change);
+        self.release_deleted(dropped).await;
+    }
+
+    async fn release_deleted(&self, changes: Vec<Change>) {
+        let mut sprites = self.sprites.write().await;
+        for change in changes {
+            if let Change::CloneDeleted { sprite_id, .. } = change {
+                sprites.arena.release(sprite_id.handle());
+            }
+        }
+    }
+
+    async fn sprite(&self, sprite_id: &SpriteID) -> Option<Arc<Sprite>> {
+        self.sprites.read().await.get(sprite_id)
+    }
+
     pub async fn step(&self, thread_id: ThreadID) -> Result<Option<ThreadID>> {
-        if self.stopped_threads.write().await.remove(&thread_id)
-            || self
-                .removed_sprites
+        let sprite = match self.sprite(&thread_id.sprite_id).await {
+            Some(sprite) => sprite,
+            // Threads of deleted clones are still scheduled
+            None if self
+                .sprites
                 .read()
                 .await
-                .contains(&thread_id.sprite_id)
-        {
+                .arena
+                .is_removed(thread_id.sprite_id.handle()) =>
+            {
+                return Ok(None)
+            }
+            None => return Err(Error::msg("thread_id is invalid")),
+        };
+        if self.stopped_threads.write().await.remove(&thread_id) {
+            sprite.stop(thread_id.thread_id).await?;
             return Ok(None);
         }
 
-        for group in &self.sprite_groups {
-            if let Some(sprite) = group.read().await.get(&thread_id.sprite_id) {
-                return sprite
-                    .step(thread_id.thread_id)
-                    .await
-                    .map(|_| Some(thread_id));
+        let before = if self.global.history() {
+            Some(sprite.save(thread_id.thread_id).await?)
+        } else {
+            None
+        };
+        let curr_block = if self.global.coverage.is_some() || self.global.tracer.is_some() {
+            Some(sprite.curr_block(thread_id.thread_id).await?)
+        } else {
+            None
+        };
+        if let Some(tracer) = &self.global.tracer {
+            tracer.begin(thread_id);
+        }
+        let result = sprite.step(thread_id.thread_id).await;
+        if let (Some(coverage), Some((block_id, ThreadState::Running))) =
+            (&self.global.coverage, curr_block)
+        {
+            coverage.record(sprite.name(), block_id);
+        }
+        if let (Some(tracer), Some((block_id, ThreadState::Running))) =
+            (&self.global.tracer, curr_block)
+        {
+            let opcode = sprite.opcode(&block_id).unwrap_or_default();
+            if let Err(e) = tracer.block(thread_id, sprite.name(), opcode, block_id) {
+                log::error!("could not write trace: {}", e);
+            }
+        }
+        if let Some(mut before) = before {
+            sprite.complete_save(&mut before).await;
+            let variables = self.global.variables.take_undo(thread_id).await;
+            self.record(Change::Step {
+                thread_id,
+                before: Box::new(before),
+                variables,
+            })
+            .await;
+        }
+
+        // Threads that are not running are no longer scheduled
+        result.map(|state| match state {
+            ThreadState::Running => Some(thread_id),
+            ThreadState::Done | ThreadState::Error | ThreadState::Stopped => None,
+        })
+    }
+
+    pub async fn skip_block(&self, thread_id: ThreadID) -> Result<()> {
+        match self.sprite(&thread_id.sprite_id).await {
+            Some(sprite) => sprite.skip_block(thread_id.thread_id).await,
+            None => Err(Error::msg("thread_id is invalid")),
+        }
+    }
+
+    /// Returns the sprite name and the opcode of the block.
+    pub async fn block_source(
+        &self,
+        sprite_id: SpriteID,
+        block_id: &BlockID,
+    ) -> Result<(String, String)> {
+        let sprite = self
+            .sprite(&sprite_id)
+            .await
+            .ok_or_else(|| Error::msg(format!("sprite_id not found: {}", sprite_id)))?;
+        let opcode = sprite
+            .opcode(block_id)
+            .ok_or_else(|| Error::msg(format!("could not find block: {}", block_id)))?;
+        Ok((sprite.name().to_string(), opcode.to_string()))
+    }
+
+    /// Builds the reporter block with the runtime of the first sprite that contains it.
+    pub async fn reporter(&self, block_id: &BlockID) -> Result<Box<dyn Block + Send + Sync>> {
+        let sprite = self
+            .sprites
+            .read()
+            .await
+            .all()
+            .into_iter()
+            .filter(|(_, sprite)| sprite.opcode(block_id).is_some())
+            .min_by_key(|(sprite_id, _)| *sprite_id)
+            .map(|(_, sprite)| sprite)
+            .ok_or_else(|| Error::msg(format!("could not find block: {}", block_id)))?;
+        sprite.reporter(block_id)
+    }
+
+    /// Returns the threads of all sprites and clones that have not been deleted.
+    pub async fn inspect(&self) -> Vec<ThreadInspection> {
+        let sprites = self.sprites.read().await.all();
+        let stopped_threads = self.stopped_threads.read().await;
+        let mut result: Vec<ThreadInspection> = Vec::new();
+        for (sprite_id, sprite) in sprites {
+            for mut inspection in sprite.inspect(sprite_id).await {
+                // Stopped threads are marked when they are scheduled next
+                if stopped_threads.contains(&inspection.thread_id) {
+                    inspection.status = ThreadStatus::Stopped;
+                }
+                result.push(inspection);
             }
         }
-        Err(Error::msg("thread_id is invalid"))
+        result.sort_unstable_by_key(|inspection| inspection.thread_id);
+        result
     }
 
+    /// Deletes the clone. While steps are recorded, it is kept in the history and its slot stays
+    /// reserved until its deletion can no longer be undone.
     pub async fn remove(&self, sprite_id: SpriteID) {
-        self.removed_sprites.write().await.insert(sprite_id);
+        let history = self.global.history();
+        let sprite = {
+            let mut sprites = self.sprites.write().await;
+            match history {
+                true => sprites.arena.take(sprite_id.handle()),
+                false => sprites.arena.remove(sprite_id.handle()),
+            }
+        };
+        let sprite = match sprite {
+            Some(sprite) => sprite,
+            None => return,
+        };
+        let layer = self.draw_order.write().await.remove(sprite_id);
+        self.stopped_threads
+            .write()
+            .await
+            .retain(|thread_id| thread_id.sprite_id != sprite_id);
+        if history {
+            self.record(Change::CloneDeleted {
+                sprite_id,
+                sprite,
+                layer,
+            })
+            .await;
+        }
+    }
+
+    /// Undoes the last step and the clones created or deleted after it. Returns the threads that
+    /// need to be scheduled again.
+    pub async fn step_back(&self) -> Result<Vec<ThreadID>> {
+        let mut thread_ids: Vec<ThreadID> = Vec::new();
+        for change in self.history.write().await.pop_step() {
+            match change {
+                Change::Step {
+                    thread_id,
+                    before,
+                    variables,
+                } => {
+                    self.global.variables.undo(variables).await;
+                    self.stopped_threads.write().await.remove(&thread_id);
+                    if let Some(sprite) = self.sprite(&thread_id.sprite_id).await {
+                        sprite.restore(thread_id.thread_id, &before).await?;
+                    }
+                    thread_ids.push(thread_id);
+                }
+                Change::CloneCreated(sprite_id) => {
+                    self.sprites.write().await.remove(&sprite_id);
+                    self.draw_order.write().await.remove(sprite_id);
+                }
+                Change::CloneDeleted {
+                    sprite_id,
+                    sprite,
+                    layer,
+                } => {
+                    for thread_id in 0..sprite.number_of_threads() {
+                        thread_ids.push(ThreadID {
+                            sprite_id,
+                            thread_id,
+                        });
+                    }
+                    self.sprites.write().await.insert(sprite_id, sprite)?;
+                    self.draw_order.write().await.insert(layer, sprite_id);
+                }
+            }
+        }
+        Ok(thread_ids)
+    }
+
+    /// Saves the sprites and clones that have not been deleted. Threads that are executing a block
+    /// are saved when the block finishes.
+    pub async fn snapshot(&self) -> Result<Snapshot> {
+        let mut variables: Vec<(String, SavedValue)> = Vec::new();
+        for (key, value) in self.global.variables.values().await {
+            variables.push((key, SavedValue::try_from(&value)?));
+        }
+
+        let all_sprites = self.sprites.read().await.all();
+        let stopped_threads = self.stopped_threads.read().await;
+        let mut sprites = Vec::new();
+        for (sprite_id, sprite) in all_sprites {
+            let mut saved = sprite.snapshot(sprite_id).await;
+            for (thread_id, thread) in saved.threads.iter_mut().enumerate() {
+                // Stopped threads are marked when they are scheduled next
+                let id = ThreadID {
+                    sprite_id,
+                    thread_id,
+                };
+                if stopped_threads.contains(&id) && thread.state != ThreadState::Done {
+                    thread.state = ThreadState::Stopped;
+                }
+            }
+            sprites.push(saved);
+        }
+        sprites.sort_unstable_by_key(|saved| saved.sprite_id);
+
+        Ok(Snapshot {
+            variables,
+            sprites,
+            draw_order: self.draw_order.read().await.iter().copied().collect(),
+        })
+    }
+
+    /// Replaces the state of all sprites with the snapshot. Clones that are missing are created
+    /// and clones that are not in the snapshot are deleted. Missing clones get a new ID if their
+    /// slot was used again. Nothing changes if the snapshot does not match the project. The
+    /// history is cleared. Returns the threads that need to be scheduled.
+    pub async fn restore(&self, snapshot: &Snapshot) -> Result<Vec<ThreadID>> {
+        let mut variables: Vec<(String, Value)> = Vec::with_capacity(snapshot.variables.len());
+        for (key, value) in &snapshot.variables {
+            if self.global.variables.slot(key).is_none() {
+                return Err(Error::msg(format!("key does not exist: {}", key)));
+            }
+            variables.push((key.clone(), Value::try_from(value)?));
+        }
+
+        // Saved clones that are missing and the sprites they are cloned from
+        let mut missing: Vec<(SpriteID, SpriteID)> = Vec::new();
+        let mut saved_ids: HashSet<SpriteID> = HashSet::new();
+        for saved in &snapshot.sprites {
+            if !saved_ids.insert(saved.sprite_id) {
+                return Err(Error::msg(format!(
+                    "sprite {} is saved twice",
+                    saved.sprite_id
+                )));
+            }
+            match self.sprite(&saved.sprite_id).await {
+                Some(sprite) if sprite.name() == saved.name => sprite.check_snapshot(saved).await?,
+                Some(sprite) => {
+                    return Err(Error::msg(format!(
+                        "sprite {} is {} but the snapshot has {}",
+                        saved.sprite_id,
+                        sprite.name(),
+                        saved.name
+                    )))
+                }
+                None => {
+                    let original = saved.original_id;
+                    match self.sprite(&original).await {
+                        Some(sprite) if sprite.name() == saved.name => {
+                            sprite.check_snapshot(saved).await?
+                        }
+                        _ => return Err(Error::msg("original_id is invalid")),
+                    }
+                    missing.push((saved.sprite_id, original));
+                }
+            }
+        }
+        let draw_order_ids: HashSet<SpriteID> = snapshot.draw_order.iter().copied().collect();
+        if draw_order_ids != saved_ids || snapshot.draw_order.len() != saved_ids.len() {
+            return Err(Error::msg("draw order does not match the saved sprites"));
+        }
+
+        // IDs of the saved sprites in this map
+        let clones = self.create_clones(&missing).await?;
+        let ids: HashMap<SpriteID, SpriteID> = missing
+            .iter()
+            .zip(&clones)
+            .map(|((saved_id, _), (sprite_id, _))| (*saved_id, *sprite_id))
+            .collect();
+        let id = |saved_id: SpriteID| ids.get(&saved_id).copied().unwrap_or(saved_id);
+        {
+            let mut sprites = self.sprites.write().await;
+            for (sprite_id, clone) in clones {
+                sprites.insert(sprite_id, clone)?;
+            }
+        }
+
+        self.global.variables.set_values(variables).await?;
+        let mut thread_ids: Vec<ThreadID> = Vec::new();
+        for saved in &snapshot.sprites {
+            let sprite_id = id(saved.sprite_id);
+            if let Some(sprite) = self.sprite(&sprite_id).await {
+                sprite.restore_snapshot(saved).await?;
+            }
+            for (thread_id, thread) in saved.threads.iter().enumerate() {
+                if thread.state == ThreadState::Running {
+                    thread_ids.push(ThreadID {
+                        sprite_id,
+                        thread_id,
+                    });
+                }
+            }
+        }
+
+        let restored_ids: HashSet<SpriteID> = saved_ids.into_iter().map(id).collect();
+        {
+            let mut sprites = self.sprites.write().await;
+            let mut draw_order = self.draw_order.write().await;
+            for (sprite_id, _) in sprites.all() {
+                if !restored_ids.contains(&sprite_id) {
+                    sprites.remove(&sprite_id);
+                    draw_order.remove(sprite_id);
+                }
+            }
+            let saved_order: Vec<SpriteID> = snapshot.draw_order.iter().map(|s| id(*s)).collect();
+            draw_order.restore(&saved_order);
+        }
+
+        self.stopped_threads.write().await.clear();
+        let history = std::mem::take(&mut *self.history.write().await);
+        self.release_deleted(history.into_changes()).await;
+        Ok(thread_ids)
+    }
+
+    /// Clones the sprites for the saved IDs, which are used unless their slots were used again.
+    /// The clones are not added to the map. No IDs are reserved if a sprite cannot be cloned.
+    async fn create_clones(
+        &self,
+        missing: &[(SpriteID, SpriteID)],
+    ) -> Result<Vec<(SpriteID, Arc<Sprite>)>> {
+        let mut sprite_ids: Vec<SpriteID> = Vec::with_capacity(missing.len());
+        {
+            let mut sprites = self.sprites.write().await;
+            for (saved_id, _) in missing {
+                if sprites.arena.reserve_at(saved_id.handle()) {
+                    sprite_ids.push(*saved_id);
+                } else {
+                    sprite_ids.push(SpriteID::from(sprites.arena.reserve()));
+                }
+            }
+        }
+
+        let mut clones: Vec<(SpriteID, Arc<Sprite>)> = Vec::with_capacity(missing.len());
+        for ((_, original), sprite_id) in missing.iter().zip(&sprite_ids) {
+            match self.clone_of(*original, *sprite_id).await {
+                Ok(clone) => clones.push((*sprite_id, clone)),
+                Err(e) => {
+                    let mut sprites = self.sprites.write().await;
+                    for sprite_id in &sprite_ids {
+                        sprites.arena.release(sprite_id.handle());
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        Ok(clones)
+    }
+
+    /// Sprites from back to front.
+    async fn sprites_in_draw_order(&self) -> Result<Vec<(SpriteID, Arc<Sprite>)>> {
+        let sprites = self.sprites.read().await;
+        let draw_order = self.draw_order.read().await;
+        draw_order
+            .iter()
+            .map(|id| match sprites.get(id) {
+                Some(sprite) => Ok((*id, sprite)),
+                None => Err(Error::msg(format!("id not found: {}", id))),
+            })
+            .collect()
     }
 
     pub async fn draw(
@@ -82,19 +486,8 @@ impl SpriteMap {
     ) -> Result<()> {
         self.global.draw(context, graphics, character_cache).await?;
 
-        let removed_sprites = self.removed_sprites.read().await;
-        for id in self.draw_order.read().await.iter() {
-            if !removed_sprites.contains(id) {
-                let mut found = false;
-                for group in &self.sprite_groups {
-                    if let Some(sprite) = group.read().await.get(id) {
-                        sprite.draw(context, graphics, character_cache).await?;
-                        found = true;
-                        break;
-                    }
-                }
-                assert!(found);
-            }
+        for (_, sprite) in self.sprites_in_draw_order().await? {
+            sprite.draw(context, graphics, character_cache).await?;
         }
         Ok(())
     }
@@ -106,18 +499,9 @@ impl SpriteMap {
         character_cache: &mut BufferGlyphs<'_>,
         removed_sprite: &SpriteID,
     ) -> Result<()> {
-        let removed_sprites = self.removed_sprites.read().await;
-        for id in self.draw_order.read().await.iter() {
-            if !removed_sprites.contains(id) && id != removed_sprite {
-                let mut found = false;
-                for group in &self.sprite_groups {
-                    if let Some(sprite) = group.read().await.get(id) {
-                        sprite.draw(context, graphics, character_cache).await?;
-                        found = true;
-                        break;
-                    }
-                }
-                assert!(found);
+        for (id, sprite) in self.sprites_in_draw_order().await? {
+            if &id != removed_sprite {
+                sprite.draw(context, graphics, character_cache).await?;
             }
         }
         Ok(())
@@ -125,95 +509,101 @@ impl SpriteMap {
 
     pub async fn all_thread_ids(&self) -> Vec<ThreadID> {
         let mut result: Vec<ThreadID> = Vec::new();
-        for group in &self.sprite_groups {
-            for (sprite_id, sprite) in group.read().await.iter() {
-                for thread_id in 0..sprite.number_of_threads() {
-                    result.push(ThreadID {
-                        sprite_id: *sprite_id,
-                        thread_id,
-                    });
-                }
+        for (sprite_id, sprite) in self.sprites.read().await.all() {
+            for thread_id in 0..sprite.number_of_threads() {
+                result.push(ThreadID {
+                    sprite_id,
+                    thread_id,
+                });
             }
         }
         result
     }
 
     pub async fn block_info(&self, thread_id: ThreadID) -> Result<BlockInfo> {
-        for group in &self.sprite_groups {
-            if let Some(sprite) = group.read().await.get(&thread_id.sprite_id) {
-                return sprite.block_info(thread_id.thread_id).await;
-            }
+        match self.sprite(&thread_id.sprite_id).await {
+            Some(sprite) => sprite.block_info(thread_id.thread_id).await,
+            None => Err(Error::msg(format!("thread_id not found: {:?}", thread_id))),
         }
+    }
 
-        Err(Error::msg(format!("thread_id not found: {:?}", thread_id)))
+    pub async fn position(&self, thread_id: ThreadID) -> Result<ThreadPosition> {
+        match self.sprite(&thread_id.sprite_id).await {
+            Some(sprite) => sprite.position(thread_id.thread_id).await,
+            None => Err(Error::msg(format!("thread_id not found: {:?}", thread_id))),
+        }
     }
 
     pub async fn clone_sprite(&self, sprite_id: SpriteID) -> Result<SpriteID> {
-        let new_sprite_id = SpriteID::from_sprite_name(&(format!("{}", sprite_id) + "clone"));
-        let cloned_sprite = SpriteMap::get_cloned_sprite(&self.sprite_groups, sprite_id).await?;
-        SpriteMap::insert_sprite(&self.sprite_groups, new_sprite_id, cloned_sprite).await?;
+        let new_sprite_id = SpriteID::from(self.sprites.write().await.arena.reserve());
+        self.create_clone(sprite_id, new_sprite_id).await?;
 
         let mut draw_order = self.draw_order.write().await;
         let index = draw_order.iter().position(|s| s == &sprite_id).unwrap();
         draw_order.insert(index + 1, new_sprite_id);
+        if self.global.history() {
+            self.record(Change::CloneCreated(new_sprite_id)).await;
+        }
         Ok(new_sprite_id)
     }
 
-    pub async fn get_cloned_sprite(
-        sprite_groups: &SpriteGroups,
-        sprite_id: SpriteID,
-    ) -> Result<Sprite> {
-        let new_sprite_id = SpriteID::from_sprite_name(&(format!("{}", sprite_id) + "clone"));
-        for group in sprite_groups {
-            if let Some(sprite) = group.read().await.get(&sprite_id) {
-                return Ok(sprite.clone_sprite(new_sprite_id).await?);
+    /// Puts a clone of the sprite in the reserved slot of new_sprite_id, which is released if the
+    /// sprite cannot be cloned.
+    async fn create_clone(&self, sprite_id: SpriteID, new_sprite_id: SpriteID) -> Result<()> {
+        let result = self.clone_of(sprite_id, new_sprite_id).await;
+        let mut sprites = self.sprites.write().await;
+        match result {
+            Ok(clone) => sprites.insert(new_sprite_id, clone),
+            Err(e) => {
+                sprites.arena.release(new_sprite_id.handle());
+                Err(e)
             }
         }
-        Err(Error::msg("sprite_id is invalid"))
     }
 
-    pub async fn insert_sprite(
-        sprite_groups: &SpriteGroups,
-        new_sprite_id: SpriteID,
-        sprite: Sprite,
-    ) -> Result<()> {
-        for group_cell in sprite_groups {
-            if let Some(mut group) = group_cell.try_write() {
-                group
-                    .insert(new_sprite_id, sprite)
-                    .expect("cloned sprite exists");
-                return Ok(());
-            }
+    async fn clone_of(&self, sprite_id: SpriteID, new_sprite_id: SpriteID) -> Result<Arc<Sprite>> {
+        match self.sprite(&sprite_id).await {
+            Some(sprite) => Ok(Arc::new(sprite.clone_sprite(new_sprite_id).await?)),
+            None => Err(Error::msg("sprite_id is invalid")),
         }
-        Err(Error::msg("could not acquire a lock from any sprite group"))
     }
 
     pub async fn number_of_threads(&self, sprite_id: &SpriteID) -> Result<usize> {
-        for group in &self.sprite_groups {
-            if let Some(sprite) = group.read().await.get(&sprite_id) {
-                return Ok(sprite.number_of_threads());
-            }
+        match self.sprite(sprite_id).await {
+            Some(sprite) => Ok(sprite.number_of_threads()),
+            None => Err(Error::msg(format!("sprite_id not found: {}", sprite_id))),
         }
-
-        Err(Error::msg(format!("sprite_id not found: {}", sprite_id)))
     }
 
     pub async fn stop(&self, thread_id: ThreadID) {
         self.stopped_threads.write().await.insert(thread_id);
     }
 
+    /// Stops the threads of the sprite except thread_id.
+    pub async fn stop_other_threads(&self, thread_id: ThreadID) {
+        for id in self.all_thread_ids().await {
+            if id.sprite_id == thread_id.sprite_id && id.thread_id != thread_id.thread_id {
+                self.stop(id).await;
+            }
+        }
+    }
+
     pub async fn change_layer(&self, id: SpriteID, change: LayerChange) -> Result<()> {
         self.draw_order.write().await.change_layer(id, change)
     }
 
     pub async fn sprite_rectangle(&self, id: &SpriteID) -> Result<SpriteRectangle> {
-        for group in &self.sprite_groups {
-            if let Some(sprite) = group.read().await.get(id) {
-                return Ok(sprite.rectangle().await);
-            }
+        match self.sprite(id).await {
+            Some(sprite) => Ok(sprite.rectangle().await),
+            None => Err(Error::msg(format!("id not found: {}", id))),
         }
+    }
 
-        Err(Error::msg(format!("id not found: {}", id)))
+    pub async fn properties(&self, id: &SpriteID) -> Result<Vec<(&'static str, String)>> {
+        match self.sprite(id).await {
+            Some(sprite) => Ok(sprite.properties().await),
+            None => Err(Error::msg(format!("id not found: {}", id))),
+        }
     }
 }
 
@@ -227,7 +617,8 @@ impl DrawOrder {
     fn new(targets: &[Target]) -> Self {
         let mut id_layer_order: Vec<(SpriteID, usize)> = targets
             .iter()
-            .map(|t| (SpriteID::from_sprite_name(&t.name), t.layer_order))
+            .enumerate()
+            .map(|(index, t)| (SpriteID::new(index), t.layer_order))
             .collect();
 
         id_layer_order.sort_unstable_by(|a, b| a.1.cmp(&b.1));
@@ -255,6 +646,29 @@ impl DrawOrder {
     }
 
     fn insert(&mut self, index: usize, id: SpriteID) {
-        self.ids.insert(index, id)
+        self.ids.insert(index.min(self.ids.len()), id)
+    }
+
+    /// Returns the index that the sprite was at.
+    fn remove(&mut self, id: SpriteID) -> usize {
+        match self.ids.iter().position(|sprite_id| sprite_id == &id) {
+            Some(index) => {
+                self.ids.remove(index);
+                index
+            }
+            None => self.ids.len(),
+        }
+    }
+
+    /// Puts the sprites in the order of the IDs. Sprites that are not in the IDs go to the back.
+    fn restore(&mut self, ids: &[SpriteID]) {
+        let mut restored: Vec<SpriteID> = self
+            .ids
+            .iter()
+            .filter(|id| !ids.contains(id))
+            .copied()
+            .collect();
+        restored.extend_from_slice(ids);
+        self.ids = restored;
     }
 }
@@ -1,8 +1,8 @@
 use super::*;
 use crate::coordinate::Scale;
 use crate::coordinate::{CanvasCoordinate, Size, SpriteCoordinate, SpriteRectangle};
-use crate::file::{BlockID, Image, Target};
-use crate::pen::Pen;
+use crate::file::{BlockID, Image, LoadMode, Target};
+use crate::pen::{Pen, PenMark};
 use flo_curves::{bezier, BezierCurve, Coord2};
 use gfx_device_gl::Resources;
 use gfx_graphics::{CreateTexture, Format};
@@ -12,12 +12,12 @@ use graphics::types::{FontSize, Rectangle};
 use graphics::{line, CircleArc, Context};
 use graphics::{Graphics, Transformed};
 use graphics_buffer::{BufferGlyphs, RenderBuffer};
-use image::codecs::png::PngDecoder;
-use image::{DynamicImage, ImageBuffer, ImageDecoder, RgbaImage};
+use image::ImageFormat;
 use piston_window::{G2d, G2dTextureContext, Glyphs};
+use serde::{Deserialize, Serialize};
 use std::f64::consts::TAU;
 use std::fs::File;
-use std::io::{Cursor, Read};
+use std::io::Read;
 
 #[derive(Debug)]
 pub struct SpriteRuntime {
@@ -33,6 +33,30 @@ pub struct SpriteRuntime {
     hide: HideStatus,
 }
 
+/// State of a sprite that a step can change, except for costumes that were added.
+#[derive(Debug, Clone)]
+pub struct SpriteState {
+    position: SpriteCoordinate,
+    scale: Scale,
+    current_costume: usize,
+    costume_transparency: f64,
+    text: Text,
+    pen: PenMark,
+    hide: HideStatus,
+}
+
+/// Everything that the blocks can change about a sprite, which is saved in snapshots.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SpriteRuntimeSnapshot {
+    position: SpriteCoordinate,
+    scale: Scale,
+    current_costume: usize,
+    costume_transparency: f64,
+    text: Text,
+    pen: Pen,
+    hide: HideStatus,
+}
+
 #[allow(dead_code)]
 impl SpriteRuntime {
     pub fn new(target: &Target) -> Self {
@@ -69,9 +93,10 @@ impl SpriteRuntime {
         texture_context: &mut G2dTextureContext,
         costumes: &[file::Costume],
         images: &HashMap<String, Image>,
-    ) -> Result<()> {
+        load_mode: LoadMode,
+    ) -> Result<Vec<(usize, Error)>> {
         self.costumes
-            .add_costumes(texture_context, costumes, images)
+            .add_costumes(texture_context, costumes, images, load_mode)
             .await
     }
 
@@ -335,6 +360,98 @@ impl SpriteRuntime {
         self.costume_transparency = transparency;
     }
 
+    pub fn state(&self) -> SpriteState {
+        SpriteState {
+            position: self.position,
+            scale: self.scale,
+            current_costume: self.costumes.current_costume,
+            costume_transparency: self.costume_transparency,
+            text: self.text.clone(),
+            pen: self.pen.mark(),
+            hide: self.hide,
+        }
+    }
+
+    /// Keeps the pen lines that are needed to restore the state if the pen was cleared since. If
+    /// other threads could have drawn since the state was taken, the pen is marked again instead.
+    pub fn complete_state(&self, state: &mut SpriteState, overlapped: bool) {
+        if overlapped {
+            state.pen = self.pen.mark();
+        } else {
+            self.pen.complete_mark(&mut state.pen);
+        }
+    }
+
+    pub fn set_state(&mut self, state: &SpriteState) -> Result<()> {
+        self.pen.rewind(&state.pen)?;
+        self.position = state.position;
+        self.scale = state.scale;
+        self.costumes.current_costume = state.current_costume;
+        self.costume_transparency = state.costume_transparency;
+        self.text = state.text.clone();
+        self.hide = state.hide;
+        Ok(())
+    }
+
+    pub fn snapshot(&self) -> SpriteRuntimeSnapshot {
+        SpriteRuntimeSnapshot {
+            position: self.position,
+            scale: self.scale,
+            current_costume: self.costumes.current_costume,
+            costume_transparency: self.costume_transparency,
+            text: self.text.clone(),
+            pen: self.pen.clone(),
+            hide: self.hide,
+        }
+    }
+
+    /// Returns an error if the snapshot cannot be restored.
+    pub fn check_snapshot(&self, snapshot: &SpriteRuntimeSnapshot) -> Result<()> {
+        if snapshot.current_costume >= self.costumes.costumes.len().max(1) {
+            return Err(Error::msg(format!(
+                "costume index does not exist: {}",
+                snapshot.current_costume
+            )));
+        }
+        if !snapshot.pen.has_lines() {
+            return Err(Error::msg("pen has no lines"));
+        }
+        Ok(())
+    }
+
+    pub fn restore_snapshot(&mut self, snapshot: &SpriteRuntimeSnapshot) -> Result<()> {
+        self.check_snapshot(snapshot)?;
+        self.position = snapshot.position;
+        self.scale = snapshot.scale;
+        self.costumes.current_costume = snapshot.current_costume;
+        self.costume_transparency = snapshot.costume_transparency;
+        self.text = snapshot.text.clone();
+        self.pen = snapshot.pen.clone();
+        self.hide = snapshot.hide;
+        Ok(())
+    }
+
+    /// Returns the properties shown by the debugger.
+    pub fn properties(&self) -> Vec<(&'static str, String)> {
+        let costume = self
+            .costumes
+            .current_costume()
+            .map(|c| c.name.clone())
+            .unwrap_or_default();
+        vec![
+            ("x", self.position.x.to_string()),
+            ("y", self.position.y.to_string()),
+            ("size", (self.scale.x * 100.0).to_string()),
+            ("costume", costume),
+            ("visible", matches!(self.hide, HideStatus::Show).to_string()),
+            (
+                "ghost",
+                ((1.0 - self.costume_transparency) * 100.0).to_string(),
+            ),
+            ("clone", self.is_a_clone.to_string()),
+        ]
+    }
+
     pub fn clone_sprite_runtime(&self) -> SpriteRuntime {
         SpriteRuntime {
             sprite_name: self.sprite_name.clone() + "-clone",
@@ -370,7 +487,8 @@ impl GraphicsCostumeTexture<BufferGlyphs<'_>> for RenderBuffer {
     }
 }
 
-#[derive(Debug, Clone)]
+/// Images are immutable so that clones can share them.
+#[derive(Debug)]
 pub struct Costume {
     image_size: Size,
     scale: f64,
@@ -388,7 +506,10 @@ impl Costume {
     ) -> Result<Self> {
         let (gfx_texture, render_buffer_texture, width, height) = match image_file {
             Image::SVG(b) => Costume::svg_texture(b, texture_context)?,
-            Image::PNG(b) => Costume::png_texture(b, texture_context)?,
+            Image::PNG(b) => Costume::bitmap_texture(b, ImageFormat::Png, texture_context)?,
+            Image::JPEG(b) => Costume::bitmap_texture(b, ImageFormat::Jpeg, texture_context)?,
+            Image::GIF(b) => Costume::bitmap_texture(b, ImageFormat::Gif, texture_context)?,
+            Image::BMP(b) => Costume::bitmap_texture(b, ImageFormat::Bmp, texture_context)?,
         };
 
         Ok(Self {
@@ -416,20 +537,9 @@ impl Costume {
         data: &[u8],
         texture_context: &mut G2dTextureContext,
     ) -> Result<(Texture<Resources>, RenderBuffer, u32, u32)> {
-        let mut options = usvg::Options::default();
-        options.fontdb.load_system_fonts();
-
-        let tree = usvg::Tree::from_data(data, &options)?;
-        let size = tree.svg_node().size.to_screen_size();
-        let mut pixmap = tiny_skia::Pixmap::new(size.width() * 2, size.height() * 2).unwrap();
-
-        let width = pixmap.width();
-        let height = pixmap.height();
-
-        resvg::render(&tree, usvg::FitTo::Zoom(2.0), pixmap.as_mut())
-            .ok_or_else(|| Error::msg("svg error"))?;
-        let image: RgbaImage = ImageBuffer::from_raw(width, height, pixmap.take())
-            .ok_or_else(|| Error::msg("svg error"))?;
+        let image = scratch_runtime::image::rasterize_svg(data)?;
+        let width = image.width();
+        let height = image.height();
         Ok((
             CreateTexture::create(
                 texture_context,
@@ -450,17 +560,14 @@ impl Costume {
         ))
     }
 
-    fn png_texture(
+    fn bitmap_texture(
         data: &[u8],
+        format: ImageFormat,
         texture_context: &mut G2dTextureContext,
     ) -> Result<(Texture<Resources>, RenderBuffer, u32, u32)> {
-        let decoder = PngDecoder::new(Cursor::new(data))?;
-        let x = decoder.dimensions().0;
-        let y = decoder.dimensions().1;
-        let dynamic_image = DynamicImage::from_decoder(decoder)?;
-        let image = dynamic_image
-            .as_rgba8()
-            .ok_or_else(|| Error::msg("not in RGBA color space"))?;
+        let image = scratch_runtime::image::decode_bitmap(data, format)?;
+        let x = image.width();
+        let y = image.height();
         Ok((
             CreateTexture::create(
                 texture_context,
@@ -489,7 +596,7 @@ impl Costume {
         let mut buffer: Vec<u8> = Vec::new();
         file.read_to_end(&mut buffer)?;
         let (gfx_texture, render_buffer_texture, width, height) =
-            Costume::png_texture(&buffer, texture_context)?;
+            Costume::bitmap_texture(&buffer, ImageFormat::Png, texture_context)?;
         Ok(Self {
             image_size: Size {
                 width: width as f64,
@@ -509,32 +616,54 @@ impl Costume {
 
 #[derive(Debug, Clone, Default)]
 pub struct Costumes {
-    costumes: Vec<Costume>,
+    /// Shared by a target and its clones
+    costumes: Arc<Vec<Costume>>,
     current_costume: usize,
 }
 
 impl Costumes {
+    /// Returns the indexes of costumes whose images could not be decoded and were replaced by
+    /// blank images. In strict mode the error is returned instead.
     async fn add_costumes(
         &mut self,
         texture_context: &mut G2dTextureContext,
         costume_data: &[file::Costume],
         images: &HashMap<String, Image>,
-    ) -> Result<()> {
-        self.costumes.reserve(costume_data.len());
-        for costume in costume_data {
+        load_mode: LoadMode,
+    ) -> Result<Vec<(usize, Error)>> {
+        let costumes = Arc::get_mut(&mut self.costumes)
+            .ok_or_else(|| Error::msg("costumes cannot be added after the sprite is cloned"))?;
+        costumes.reserve(costume_data.len());
+        let mut undecodable: Vec<(usize, Error)> = Vec::new();
+        for (index, costume) in costume_data.iter().enumerate() {
             let costume = if let Some(md5ext) = &costume.md5ext {
                 match images.get(md5ext) {
-                    Some(file) => Costume::new(texture_context, &costume, file).await?,
-                    None => return Err(Error::msg(format!("image not found: {}", md5ext))),
+                    Some(file) => match Costume::new(texture_context, costume, file).await {
+                        Ok(c) => c,
+                        Err(e) if load_mode == LoadMode::Lenient => {
+                            log::warn!(
+                                "cannot decode image, using blank image instead: {}",
+                                md5ext
+                            );
+                            undecodable.push((index, e));
+                            Costume::new_blank(texture_context, costume)?
+                        }
+                        Err(e) => return Err(e),
+                    },
+                    None => {
+                        // Missing images are reported by ScratchFile::parse_with_options
+                        log::warn!("image not found, using blank image instead: {}", md5ext);
+                        Costume::new_blank(texture_context, costume)?
+                    }
                 }
             } else {
                 // Pre-made Scratch backdrops are not included in the .sb3 file. A blank image is
                 // used as a placeholder.
-                Costume::new_blank(texture_context, &costume)?
+                Costume::new_blank(texture_context, costume)?
             };
-            self.costumes.push(costume);
+            costumes.push(costume);
         }
-        Ok(())
+        Ok(undecodable)
     }
 
     fn current_costume(&self) -> Option<&Costume> {
@@ -563,16 +692,17 @@ impl Costumes {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Serialize, Deserialize)]
 pub enum HideStatus {
     Hide,
     Show,
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 /// Text can only be hidden by the thread that posted it. It can be replaced with new text by any
 /// thread.
 pub struct Text {
+    #[serde(with = "text_id")]
     pub id: BlockID,
     pub text: Option<String>,
 }
@@ -584,3 +714,45 @@ impl Text {
         }
     }
 }
+
+/// Saves the pseudo ID of text that no block posted as null, because block IDs cannot be empty.
+mod text_id {
+    use crate::file::BlockID;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S>(id: &BlockID, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        if *id == BlockID::pseudo_id() {
+            None
+        } else {
+            Some(id)
+        }
+        .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<BlockID, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Ok(Option::<BlockID>::deserialize(deserializer)?.unwrap_or_else(BlockID::pseudo_id))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clone_shares_costumes() {
+        let mut sprite = SpriteRuntime::new(&Target::default());
+        sprite.costumes.current_costume = 1;
+        let clone = sprite.clone_sprite_runtime();
+        assert!(Arc::ptr_eq(
+            &sprite.costumes.costumes,
+            &clone.costumes.costumes
+        ));
+        assert_eq!(clone.costumes.current_costume, 1);
+    }
+}
@@ -0,0 +1,130 @@
+//! Helpers for building blocks, globals and project files in unit tests.
+
+use crate::broadcaster::Broadcaster;
+use crate::file::{self, BlockID, LoadMode};
+use crate::runtime::{Global, Runtime};
+use crate::sprite::SpriteID;
+use crate::sprite_runtime::SpriteRuntime;
+use crate::vm::ThreadID;
+use async_lock::RwLock;
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io::{Cursor, Write};
+use std::sync::Arc;
+
+pub fn id(s: &str) -> BlockID {
+    s.try_into().unwrap()
+}
+
+/// Blocks with opcodes that contain "_when" are hats, which are top level.
+pub fn file_block(opcode: &str, next: Option<&str>, inputs: serde_json::Value) -> file::Block {
+    file::Block {
+        opcode: opcode.to_string(),
+        next: next.map(id),
+        inputs: serde_json::from_value(inputs).unwrap(),
+        top_level: opcode.contains("_when"),
+        ..file::Block::default()
+    }
+}
+
+/// Stage variables with the names, IDs of the names with "_id" appended, and values of 0.
+pub fn variables(names: &[&str]) -> HashMap<String, file::Variable> {
+    names
+        .iter()
+        .map(|name| {
+            let variable = file::Variable {
+                id: name.to_string(),
+                value: serde_json::json!(0),
+                ..file::Variable::default()
+            };
+            (format!("{}_id", name), variable)
+        })
+        .collect()
+}
+
+/// Global with the stage variables and no lists, broadcasts or sprites.
+pub fn global(variable_names: &[&str]) -> Global {
+    Global::new(
+        &variables(variable_names),
+        &HashMap::new(),
+        &[],
+        HashMap::new(),
+        Broadcaster::new(),
+        LoadMode::Strict,
+    )
+}
+
+/// Global with the sprites of the targets and no variables, lists or broadcasts.
+pub fn global_with_sprites(targets: &[file::Target]) -> Global {
+    Global::new(
+        &HashMap::new(),
+        &HashMap::new(),
+        &[],
+        SpriteID::sprite_ids(targets),
+        Broadcaster::new(),
+        LoadMode::Strict,
+    )
+}
+
+/// Runtime of thread 0 of Sprite1, which has the ID 0.
+pub fn runtime(global: Arc<Global>) -> Runtime {
+    Runtime::new(
+        Arc::new(RwLock::new(SpriteRuntime::new(&file::Target::default()))),
+        global,
+        ThreadID {
+            sprite_id: SpriteID::new(0),
+            thread_id: 0,
+        },
+        Arc::from("Sprite1"),
+    )
+}
+
+/// Project with a stage whose backdrop has the md5ext, and the files.
+pub fn sb3(costume_md5ext: &str, files: &[(&str, &[u8])]) -> Cursor<Vec<u8>> {
+    let project = format!(
+        r#"{{
+            "targets": [{{
+                "isStage": true,
+                "name": "Stage",
+                "variables": {{}},
+                "blocks": {{}},
+                "costumes": [{{
+                    "name": "backdrop1",
+                    "assetId": "{}",
+                    "md5ext": "{}",
+                    "rotationCenterX": 0,
+                    "rotationCenterY": 0
+                }}]
+            }}],
+            "monitors": [],
+            "extensions": [],
+            "meta": {{ "semver": "3.0.0", "vm": "0.2.0", "agent": "" }}
+        }}"#,
+        costume_md5ext.split('.').next().unwrap(),
+        costume_md5ext
+    );
+
+    let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    writer
+        .start_file("project.json", zip::write::FileOptions::default())
+        .unwrap();
+    writer.write_all(project.as_bytes()).unwrap();
+    for (name, contents) in files {
+        writer
+            .start_file(*name, zip::write::FileOptions::default())
+            .unwrap();
+        writer.write_all(contents).unwrap();
+    }
+    let mut cursor = writer.finish().unwrap();
+    cursor.set_position(0);
+    cursor
+}
+
+/// PNG of transparent pixels.
+pub fn png(width: u32, height: u32) -> Vec<u8> {
+    let mut data: Vec<u8> = Vec::new();
+    image::DynamicImage::new_rgba8(width, height)
+        .write_to(&mut data, image::ImageFormat::Png)
+        .unwrap();
+    data
+}
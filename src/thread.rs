@@ -1,14 +1,39 @@
 use super::*;
-use crate::blocks::{block_tree, Block, BlockInfo, BlockInputsPartial, Next};
+use crate::blocks::{block_tree, Block, BlockInfo, BlockInputsPartial, BlockState, Next};
 use crate::file::BlockID;
+use crate::profiler::Stack;
 use crate::runtime::Runtime;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use std::time::Instant;
 
 #[derive(Debug)]
 pub struct Thread {
     blocks: HashMap<BlockID, Box<dyn Block + Send + Sync>>,
-    curr_block: BlockID,
-    loop_stack: Vec<BlockID>,
-    done: bool,
+    /// Shared so that the position can be inspected while the thread is locked by a block that
+    /// waits
+    position: Arc<Mutex<ThreadPosition>>,
+    state: ThreadState,
+    runtime: Runtime,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ThreadState {
+    Running,
+    Done,
+    /// A block returned an error. The thread does not run again unless the block is skipped.
+    Error,
+    /// Stopped by a stop block or the debugger
+    Stopped,
+}
+
+/// Where a thread is in its script.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ThreadPosition {
+    pub hat: BlockID,
+    pub curr_block: BlockID,
+    /// Loop blocks that the thread returns to, outermost first
+    pub loop_stack: Vec<BlockID>,
 }
 
 impl Thread {
@@ -17,51 +42,166 @@ impl Thread {
         runtime: Runtime,
         file_blocks: &HashMap<BlockID, file::Block>,
     ) -> Result<Self> {
-        let (_, blocks) = block_tree(hat, runtime, file_blocks)?;
+        let (_, blocks) = block_tree(hat, runtime.clone(), file_blocks)?;
         Ok(Thread {
             blocks,
-            curr_block: hat,
-            loop_stack: Vec::new(),
-            done: false,
+            position: Arc::new(Mutex::new(ThreadPosition {
+                hat,
+                curr_block: hat,
+                loop_stack: Vec::new(),
+            })),
+            state: ThreadState::Running,
+            runtime,
         })
     }
 
     pub async fn step(&mut self) -> Result<()> {
-        if self.done {
+        if self.state != ThreadState::Running {
             return Ok(());
         }
 
-        let block = self.blocks.get_mut(&self.curr_block).unwrap();
-        let execute_result = block.execute().await.map_err(|error| ScratchError::Block {
-            id: block.block_info().id,
-            name: block.block_info().name,
-            error,
-        })?;
-        match execute_result {
-            Next::None => match self.loop_stack.pop() {
-                None => {
-                    self.done = true;
-                    return Ok(());
+        let curr_block = self.curr_block();
+        let block = match self.blocks.get_mut(&curr_block) {
+            Some(block) => block,
+            None => {
+                self.state = ThreadState::Error;
+                return Err(Error::msg(format!("could not find block: {}", curr_block)));
+            }
+        };
+        let start = Instant::now();
+        let result = block.execute().await;
+        if let Some(profiler) = &self.runtime.global.profiler {
+            let stack = Stack {
+                sprite_name: self.runtime.sprite_name().clone(),
+                sprite_id: self.runtime.original_id(),
+                hat: self.position.lock().unwrap().hat,
+                blocks: vec![curr_block],
+            };
+            profiler.record(&stack, start.elapsed());
+        }
+        let execute_result = match result {
+            Ok(next) => next,
+            Err(error) => {
+                self.state = ThreadState::Error;
+                return Err(ScratchError::Block {
+                    id: block.block_info().id,
+                    name: block.block_info().name,
+                    error,
                 }
-                Some(b) => self.curr_block = b,
+                .into());
+            }
+        };
+        self.go_to(execute_result);
+        Ok(())
+    }
+
+    pub fn state(&self) -> ThreadState {
+        self.state
+    }
+
+    pub fn stop(&mut self) {
+        if self.state != ThreadState::Done {
+            self.state = ThreadState::Stopped;
+        }
+    }
+
+    pub fn curr_block(&self) -> BlockID {
+        self.position.lock().unwrap().curr_block
+    }
+
+    pub fn position(&self) -> ThreadPosition {
+        self.position.lock().unwrap().clone()
+    }
+
+    /// Returns the position that stays up to date while the thread runs.
+    pub fn shared_position(&self) -> Arc<Mutex<ThreadPosition>> {
+        self.position.clone()
+    }
+
+    /// Returns the blocks whose state differs from the state they start with, sorted by ID.
+    pub fn block_states(&self) -> Vec<(BlockID, BlockState)> {
+        let mut states: Vec<(BlockID, BlockState)> = self
+            .blocks
+            .iter()
+            .map(|(id, block)| (*id, block.state()))
+            .filter(|(_, state)| *state != BlockState::default())
+            .collect();
+        states.sort_unstable_by_key(|(id, _)| *id);
+        states
+    }
+
+    pub fn has_block(&self, block_id: &BlockID) -> bool {
+        self.blocks.contains_key(block_id)
+    }
+
+    /// Sets the states returned by block_states. Other blocks get the state they start with.
+    pub fn set_block_states(&mut self, states: &[(BlockID, BlockState)]) {
+        for block in self.blocks.values_mut() {
+            block.set_state(BlockState::default());
+        }
+        for (id, state) in states {
+            if let Some(block) = self.blocks.get_mut(id) {
+                block.set_state(*state);
+            }
+        }
+    }
+
+    /// Moves the thread back to a position. The state of the blocks, such as the iterations of a
+    /// repeat block, is not restored.
+    pub fn restore(&mut self, position: &ThreadPosition, state: ThreadState) {
+        let mut current = self.position.lock().unwrap();
+        current.curr_block = position.curr_block;
+        current.loop_stack = position.loop_stack.clone();
+        self.state = state;
+    }
+
+    /// Continues after the current block as if it had finished, which resumes a thread that
+    /// stopped because of an error.
+    pub fn skip_block(&mut self) -> Result<()> {
+        let next = self
+            .current_block()?
+            .block_inputs()
+            .stacks
+            .get("next")
+            .copied();
+        self.state = ThreadState::Running;
+        self.go_to(Next::continue_(next)?);
+        Ok(())
+    }
+
+    fn go_to(&mut self, next: Next) {
+        let mut position = self.position.lock().unwrap();
+        match next {
+            Next::None => match position.loop_stack.pop() {
+                None => self.state = ThreadState::Done,
+                Some(b) => position.curr_block = b,
             },
-            Next::Continue(b) => self.curr_block = b,
+            Next::Continue(b) => position.curr_block = b,
             Next::Loop(b) => {
-                self.loop_stack.push(self.curr_block);
-                self.curr_block = b;
+                let curr_block = position.curr_block;
+                position.loop_stack.push(curr_block);
+                position.curr_block = b;
             }
         }
+    }
 
-        Ok(())
+    pub fn block_inputs(&self) -> Result<BlockInputs> {
+        Ok(BlockInputs::new(
+            self.current_block()?.block_inputs(),
+            &self.blocks,
+        ))
     }
 
-    pub fn block_inputs(&self) -> BlockInputs {
-        let block_inputs = self.blocks.get(&self.curr_block).unwrap().block_inputs();
-        BlockInputs::new(block_inputs, &self.blocks)
+    pub fn block_info(&self) -> Result<BlockInfo> {
+        Ok(self.current_block()?.block_info())
     }
 
-    pub fn block_info(&self) -> BlockInfo {
-        self.blocks.get(&self.curr_block).unwrap().block_info()
+    fn current_block(&self) -> Result<&(dyn Block + Send + Sync)> {
+        let curr_block = self.curr_block();
+        match self.blocks.get(&curr_block) {
+            Some(block) => Ok(block.as_ref()),
+            None => Err(Error::msg(format!("could not find block: {}", curr_block))),
+        }
     }
 }
 
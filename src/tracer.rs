@@ -0,0 +1,294 @@
+use super::*;
+use crate::blocks::value::Value;
+use crate::blocks::{Block, BlockInfo, BlockInputsPartial, Next};
+use crate::file::BlockID;
+use crate::sprite::SpriteID;
+use crate::vm::ThreadID;
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Writes a JSON object per line for every executed block, broadcast and clone event. Ticks count
+/// the records, so traces of the same run can be diffed.
+pub struct Tracer {
+    writer: Mutex<TraceWriter>,
+    /// Values of the inputs of the block that each thread is executing
+    inputs: Mutex<HashMap<ThreadID, BTreeMap<String, serde_json::Value>>>,
+}
+
+struct TraceWriter {
+    writer: Box<dyn Write + Send>,
+    tick: u64,
+}
+
+impl Tracer {
+    pub fn new(writer: Box<dyn Write + Send>) -> Self {
+        Self {
+            writer: Mutex::new(TraceWriter { writer, tick: 0 }),
+            inputs: Mutex::default(),
+        }
+    }
+
+    pub fn create(path: &Path) -> Result<Self> {
+        Ok(Tracer::new(Box::new(BufWriter::new(File::create(path)?))))
+    }
+
+    /// Called before a thread executes a block.
+    pub fn begin(&self, thread_id: ThreadID) {
+        self.inputs.lock().unwrap().remove(&thread_id);
+    }
+
+    pub fn input(&self, thread_id: ThreadID, input_name: &str, value: &Value) {
+        self.inputs
+            .lock()
+            .unwrap()
+            .entry(thread_id)
+            .or_default()
+            .insert(input_name.to_string(), json(value));
+    }
+
+    /// Records a block that the thread executed with the inputs that it evaluated.
+    pub fn block(
+        &self,
+        thread_id: ThreadID,
+        sprite_name: &str,
+        opcode: &str,
+        block_id: BlockID,
+    ) -> Result<()> {
+        let inputs = self
+            .inputs
+            .lock()
+            .unwrap()
+            .remove(&thread_id)
+            .unwrap_or_default();
+        self.write(serde_json::json!({
+            "event": "block",
+            "thread": thread_id,
+            "sprite": sprite_name,
+            "opcode": opcode,
+            "block_id": block_id,
+            "inputs": inputs,
+        }))
+    }
+
+    pub fn broadcast(&self, message: &str) -> Result<()> {
+        self.write(serde_json::json!({
+            "event": "broadcast",
+            "message": message,
+        }))
+    }
+
+    pub fn clone_created(&self, sprite_id: SpriteID, clone_id: SpriteID) -> Result<()> {
+        self.write(serde_json::json!({
+            "event": "clone",
+            "sprite_id": sprite_id,
+            "clone_id": clone_id,
+        }))
+    }
+
+    pub fn clone_deleted(&self, clone_id: SpriteID) -> Result<()> {
+        self.write(serde_json::json!({
+            "event": "delete_clone",
+            "clone_id": clone_id,
+        }))
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        Ok(self.writer.lock().unwrap().writer.flush()?)
+    }
+
+    fn write(&self, mut record: serde_json::Value) -> Result<()> {
+        let mut writer = self.writer.lock().unwrap();
+        record["tick"] = writer.tick.into();
+        writer.tick += 1;
+        serde_json::to_writer(&mut writer.writer, &record)?;
+        writer.writer.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+impl Debug for Tracer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tracer").finish()
+    }
+}
+
+/// Numbers that JSON cannot represent and values that are not numbers, strings or booleans are
+/// written as strings.
+fn json(value: &Value) -> serde_json::Value {
+    match value {
+        Value::Bool(b) => (*b).into(),
+        Value::Number(n) if n.is_finite() => (*n).into(),
+        Value::String(s) => s.clone().into(),
+        _ => value.to_string().into(),
+    }
+}
+
+/// Records the values of an input of a stack block.
+#[derive(Debug)]
+pub struct TracedInput {
+    block: Box<dyn Block + Send + Sync>,
+    input_name: String,
+    tracer: Arc<Tracer>,
+    thread_id: ThreadID,
+}
+
+impl TracedInput {
+    pub fn new(
+        block: Box<dyn Block + Send + Sync>,
+        input_name: &str,
+        tracer: Arc<Tracer>,
+        thread_id: ThreadID,
+    ) -> Self {
+        Self {
+            block,
+            input_name: input_name.to_string(),
+            tracer,
+            thread_id,
+        }
+    }
+}
+
+#[async_trait]
+impl Block for TracedInput {
+    fn block_info(&self) -> BlockInfo {
+        self.block.block_info()
+    }
+
+    fn block_inputs(&self) -> BlockInputsPartial {
+        self.block.block_inputs()
+    }
+
+    async fn value(&self) -> Result<Value> {
+        let value = self.block.value().await?;
+        self.tracer.input(self.thread_id, &self.input_name, &value);
+        Ok(value)
+    }
+
+    async fn execute(&mut self) -> Result<Next> {
+        self.block.execute().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{self, file_block, global, id};
+    use crate::thread::Thread;
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_tracer() {
+        let buffer = Buffer::default();
+        let tracer = Tracer::new(Box::new(buffer.clone()));
+        let thread_id = ThreadID {
+            sprite_id: SpriteID::new(1),
+            thread_id: 0,
+        };
+        let block_id = id("say");
+
+        tracer.input(thread_id, "MESSAGE", &Value::String("old".to_string()));
+        tracer.begin(thread_id);
+        tracer.input(thread_id, "MESSAGE", &Value::String("hi".to_string()));
+        tracer.input(thread_id, "SECS", &Value::Number(f64::INFINITY));
+        tracer
+            .block(thread_id, "Sprite1", "looks_sayforsecs", block_id)
+            .unwrap();
+        tracer.broadcast("start").unwrap();
+        tracer
+            .clone_created(SpriteID::new(1), SpriteID::new(3))
+            .unwrap();
+        tracer.clone_deleted(SpriteID::new(3)).unwrap();
+        tracer
+            .block(thread_id, "Sprite1", "looks_show", block_id)
+            .unwrap();
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let records: Vec<serde_json::Value> = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(
+            records,
+            vec![
+                serde_json::json!({
+                    "tick": 0,
+                    "event": "block",
+                    "thread": {"sprite_id": 1, "thread_id": 0},
+                    "sprite": "Sprite1",
+                    "opcode": "looks_sayforsecs",
+                    "block_id": "say",
+                    "inputs": {"MESSAGE": "hi", "SECS": "inf"},
+                }),
+                serde_json::json!({"tick": 1, "event": "broadcast", "message": "start"}),
+                serde_json::json!({"tick": 2, "event": "clone", "sprite_id": 1, "clone_id": 3}),
+                serde_json::json!({"tick": 3, "event": "delete_clone", "clone_id": 3}),
+                serde_json::json!({
+                    "tick": 4,
+                    "event": "block",
+                    "thread": {"sprite_id": 1, "thread_id": 0},
+                    "sprite": "Sprite1",
+                    "opcode": "looks_show",
+                    "block_id": "say",
+                    "inputs": {},
+                }),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_traced_inputs() {
+        let mut infos: HashMap<BlockID, file::Block> = HashMap::new();
+        infos.insert(
+            id("move"),
+            file_block(
+                "motion_changexby",
+                None,
+                serde_json::json!({"DX": [3, "add", [4, "1"]]}),
+            ),
+        );
+        infos.insert(
+            id("add"),
+            file_block(
+                "operator_add",
+                None,
+                serde_json::json!({"NUM1": [1, [4, "2"]], "NUM2": [1, [4, "3"]]}),
+            ),
+        );
+
+        let buffer = Buffer::default();
+        let tracer = Arc::new(Tracer::new(Box::new(buffer.clone())));
+        let mut global = global(&[]);
+        global.tracer = Some(tracer.clone());
+        let runtime = testing::runtime(Arc::new(global));
+        let thread_id = runtime.thread_id();
+
+        let mut thread = Thread::start(id("move"), runtime, &infos).unwrap();
+        tracer.begin(thread_id);
+        thread.step().await.unwrap();
+        tracer
+            .block(thread_id, "Sprite1", "motion_changexby", id("move"))
+            .unwrap();
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let record: serde_json::Value = serde_json::from_str(output.trim()).unwrap();
+        // Inputs of reporters are not recorded
+        assert_eq!(record["inputs"], serde_json::json!({"DX": 5.0}));
+    }
+}
@@ -0,0 +1,827 @@
+//! Translates a project's scripts into a Rust crate for the `export-rust` command.
+//!
+//! The generated main.rs depends on the `scratch_runtime` crate of this workspace, which is copied
+//! into the exported crate so that it builds on any machine. That crate provides `run`, `Project`, `Target`, `Costume`, `Hat`, `Script`, `Sprite` and `Value`, and the
+//! value conversions and operators that the VM uses for the same blocks. Each script becomes an
+//! async function that takes the sprite running it. Like threads in the VM, loops yield once per
+//! iteration. Costume files are written next to main.rs and embedded with include_bytes.
+
+use super::*;
+use crate::blocks::{get_field_value, value};
+use crate::file::{BlockID, Image, ScratchFile};
+use crate::sprite::find_hats;
+use std::collections::BTreeSet;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+pub fn export_rust(
+    file_path: &Path,
+    load_options: &file::LoadOptions,
+    out: &Path,
+    headless: bool,
+) -> Result<()> {
+    let scratch_file =
+        ScratchFile::parse_with_options(BufReader::new(File::open(file_path)?), load_options)?;
+    let name = file_path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+    write_crate(
+        &scratch_file.project,
+        &scratch_file.images,
+        &package_name(&name),
+        headless,
+        out,
+    )?;
+    println!("wrote {}", out.display());
+    Ok(())
+}
+
+/// Sources of the scratch_runtime crate, relative to its directory
+const RUNTIME_FILES: &[(&str, &str)] = &[
+    ("Cargo.toml", include_str!("../scratch_runtime/Cargo.toml")),
+    ("src/lib.rs", include_str!("../scratch_runtime/src/lib.rs")),
+    (
+        "src/coordinate.rs",
+        include_str!("../scratch_runtime/src/coordinate.rs"),
+    ),
+    (
+        "src/image.rs",
+        include_str!("../scratch_runtime/src/image.rs"),
+    ),
+    ("src/pen.rs", include_str!("../scratch_runtime/src/pen.rs")),
+    (
+        "src/project.rs",
+        include_str!("../scratch_runtime/src/project.rs"),
+    ),
+    (
+        "src/sprite.rs",
+        include_str!("../scratch_runtime/src/sprite.rs"),
+    ),
+    (
+        "src/value.rs",
+        include_str!("../scratch_runtime/src/value.rs"),
+    ),
+    (
+        "src/window.rs",
+        include_str!("../scratch_runtime/src/window.rs"),
+    ),
+];
+
+/// Writes Cargo.toml, src/main.rs, the costume files and a copy of scratch_runtime to out.
+fn write_crate(
+    project: &file::Project,
+    images: &HashMap<String, Image>,
+    name: &str,
+    headless: bool,
+    out: &Path,
+) -> Result<()> {
+    let source = transpile(project)?;
+    let assets = out.join("src").join("assets");
+    std::fs::create_dir_all(&assets)?;
+    std::fs::write(out.join("Cargo.toml"), cargo_toml(name, headless))?;
+    std::fs::write(out.join("src").join("main.rs"), source)?;
+    for (name, image) in images {
+        std::fs::write(assets.join(name), image.bytes())?;
+    }
+    let runtime = out.join("scratch_runtime");
+    std::fs::create_dir_all(runtime.join("src"))?;
+    for (path, contents) in RUNTIME_FILES {
+        std::fs::write(runtime.join(path), contents)?;
+    }
+    Ok(())
+}
+
+/// Lowercase letters, digits and underscores of the project's file name.
+fn package_name(file_name: &str) -> String {
+    let name: String = file_name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() {
+                c.to_ascii_lowercase()
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    match name.chars().next() {
+        Some(c) if c.is_ascii_alphabetic() => name,
+        _ => format!("scratch_{}", name),
+    }
+}
+
+/// Depends on the copy of scratch_runtime in the crate. Headless projects run without a window,
+/// so they do not need a display or the graphics dependencies.
+fn cargo_toml(name: &str, headless: bool) -> String {
+    let features = if headless {
+        ", default-features = false"
+    } else {
+        ""
+    };
+    format!(
+        "[package]\n\
+         name = \"{}\"\n\
+         version = \"0.1.0\"\n\
+         edition = \"2018\"\n\
+         \n\
+         [dependencies]\n\
+         scratch_runtime = {{ path = \"scratch_runtime\"{} }}\n\
+         \n\
+         # Not part of the workspace that the project was exported into\n\
+         [workspace]\n",
+        name, features
+    )
+}
+
+/// Returns the source of main.rs, or an error listing every block that cannot be translated.
+pub fn transpile(project: &file::Project) -> Result<String> {
+    let stage = project
+        .targets
+        .first()
+        .ok_or_else(|| Error::msg("project has no stage"))?;
+    let mut variable_ids: Vec<&String> = stage.variables.keys().collect();
+    variable_ids.sort_unstable();
+
+    let mut transpiler = Transpiler {
+        blocks: &stage.blocks,
+        slots: variable_ids
+            .iter()
+            .enumerate()
+            .map(|(slot, id)| (id.to_string(), slot))
+            .collect(),
+        unsupported: BTreeSet::new(),
+        code: String::new(),
+        indent: 0,
+    };
+
+    transpiler.line("//! Generated by `scratch export-rust`.");
+    transpiler.line("");
+    transpiler.line("use scratch_runtime::*;");
+    transpiler.line("");
+    transpiler.line("fn main() -> Result<()> {");
+    transpiler.indent += 1;
+    transpiler.line("run(Project {");
+    transpiler.indent += 1;
+    transpiler.line("variables: vec![");
+    for id in &variable_ids {
+        let variable = &stage.variables[*id];
+        let line = format!(
+            "    ({:?}, {}),",
+            variable.id,
+            json_literal(&variable.value)
+        );
+        transpiler.line(&line);
+    }
+    transpiler.line("],");
+    transpiler.line("targets: vec![");
+    transpiler.indent += 1;
+    for (index, target) in project.targets.iter().enumerate() {
+        transpiler.target(index, target);
+    }
+    transpiler.indent -= 1;
+    transpiler.line("],");
+    transpiler.indent -= 1;
+    transpiler.line("})");
+    transpiler.indent -= 1;
+    transpiler.line("}");
+
+    for (index, target) in project.targets.iter().enumerate() {
+        transpiler.blocks = &target.blocks;
+        for (script, hat) in find_hats(&target.blocks).iter().enumerate() {
+            transpiler.script(&format!("target{}_script{}", index, script), *hat);
+        }
+    }
+
+    if transpiler.unsupported.is_empty() {
+        Ok(transpiler.code)
+    } else {
+        Err(ScratchError::Export {
+            reasons: transpiler.unsupported.into_iter().collect(),
+        }
+        .into())
+    }
+}
+
+/// Operator blocks, the runtime functions that implement them and their inputs
+const OPERATORS: &[(&str, &str, &[&str])] = &[
+    ("operator_add", "add", &["NUM1", "NUM2"]),
+    ("operator_subtract", "subtract", &["NUM1", "NUM2"]),
+    ("operator_multiply", "multiply", &["NUM1", "NUM2"]),
+    ("operator_divide", "divide", &["NUM1", "NUM2"]),
+    ("operator_lt", "lt", &["OPERAND1", "OPERAND2"]),
+    ("operator_gt", "gt", &["OPERAND1", "OPERAND2"]),
+    ("operator_equals", "equals", &["OPERAND1", "OPERAND2"]),
+    ("operator_and", "and", &["OPERAND1", "OPERAND2"]),
+    ("operator_or", "or", &["OPERAND1", "OPERAND2"]),
+    ("operator_not", "not", &["OPERAND"]),
+    ("operator_random", "random", &["FROM", "TO"]),
+    ("operator_join", "join", &["STRING1", "STRING2"]),
+];
+
+/// Blocks that are a call to a method of the sprite running the script, and their inputs
+const SPRITE_METHODS: &[(&str, &str, &[&str])] = &[
+    ("motion_movesteps", "move_steps", &["STEPS"]),
+    ("motion_gotoxy", "go_to_xy", &["X", "Y"]),
+    ("motion_changexby", "change_x_by", &["DX"]),
+    ("motion_changeyby", "change_y_by", &["DY"]),
+    ("motion_setx", "set_x", &["X"]),
+    ("motion_sety", "set_y", &["Y"]),
+    (
+        "motion_pointindirection",
+        "point_in_direction",
+        &["DIRECTION"],
+    ),
+    ("motion_xposition", "x_position", &[]),
+    ("motion_yposition", "y_position", &[]),
+    ("motion_direction", "direction", &[]),
+    ("looks_say", "say", &["MESSAGE"]),
+    ("looks_sayforsecs", "say_for_secs", &["MESSAGE", "SECS"]),
+    ("looks_show", "show", &[]),
+    ("looks_hide", "hide", &[]),
+    ("looks_nextcostume", "next_costume", &[]),
+    ("looks_setsizeto", "set_size_to", &["SIZE"]),
+    ("pen_penDown", "pen_down", &[]),
+    ("pen_penUp", "pen_up", &[]),
+    ("pen_clear", "pen_clear", &[]),
+    ("pen_setPenSizeTo", "set_pen_size_to", &["SIZE"]),
+    (
+        "pen_setPenColorToColor",
+        "set_pen_color_to_color",
+        &["COLOR"],
+    ),
+    ("pen_setPenHueToNumber", "set_pen_hue_to_number", &["HUE"]),
+    (
+        "pen_setPenShadeToNumber",
+        "set_pen_shade_to_number",
+        &["SHADE"],
+    ),
+    ("control_wait", "wait", &["DURATION"]),
+    ("event_broadcast", "broadcast", &["BROADCAST_INPUT"]),
+    (
+        "event_broadcastandwait",
+        "broadcast_and_wait",
+        &["BROADCAST_INPUT"],
+    ),
+];
+
+struct Transpiler<'a> {
+    blocks: &'a HashMap<BlockID, file::Block>,
+    /// Stage variable ID to its index in the generated variables
+    slots: HashMap<String, usize>,
+    /// Blocks that cannot be translated
+    unsupported: BTreeSet<String>,
+    code: String,
+    indent: usize,
+}
+
+impl<'a> Transpiler<'a> {
+    fn line(&mut self, line: &str) {
+        if !line.is_empty() {
+            for _ in 0..self.indent {
+                self.code.push_str("    ");
+            }
+            self.code.push_str(line);
+        }
+        self.code.push('\n');
+    }
+
+    fn unsupported(&mut self, id: BlockID, reason: &str) {
+        self.unsupported
+            .insert(format!("block \"{}\": {}", id, reason));
+    }
+
+    fn target(&mut self, index: usize, target: &file::Target) {
+        self.line("Target {");
+        self.indent += 1;
+        self.line(&format!("name: {:?},", target.name));
+        self.line(&format!("is_stage: {},", target.is_stage));
+        self.line(&format!(
+            "x: {}, y: {}, size: {}, visible: {}, layer_order: {},",
+            f64_literal(target.x),
+            f64_literal(target.y),
+            f64_literal(target.size),
+            target.visible,
+            target.layer_order
+        ));
+        self.line("costumes: vec![");
+        for costume in &target.costumes {
+            let image = match &costume.md5ext {
+                Some(md5ext) => format!("include_bytes!(\"assets/{}\")", md5ext),
+                None => "&[]".to_string(),
+            };
+            self.line(&format!(
+                "    Costume {{ name: {:?}, image: {}, rotation_center: ({}, {}), \
+                 bitmap_resolution: {} }},",
+                costume.name,
+                image,
+                f64_literal(costume.rotation_center_x),
+                f64_literal(costume.rotation_center_y),
+                f64_literal(costume.bitmap_resolution)
+            ));
+        }
+        self.line("],");
+        self.line("scripts: vec![");
+        for (script, hat) in find_hats(&target.blocks).iter().enumerate() {
+            let info = &target.blocks[hat];
+            let hat_code = match info.opcode.as_str() {
+                "event_whenflagclicked" => "Hat::FlagClicked".to_string(),
+                "event_whenbroadcastreceived" => {
+                    match info
+                        .fields
+                        .get("BROADCAST_OPTION")
+                        .map(|f| get_field_value(f, 0))
+                    {
+                        Some(Ok(name)) => format!("Hat::BroadcastReceived({:?})", name),
+                        _ => {
+                            self.unsupported(*hat, "invalid BROADCAST_OPTION");
+                            continue;
+                        }
+                    }
+                }
+                opcode => {
+                    self.unsupported(*hat, opcode);
+                    continue;
+                }
+            };
+            self.line(&format!(
+                "    ({}, target{}_script{}),",
+                hat_code, index, script
+            ));
+        }
+        self.line("],");
+        self.indent -= 1;
+        self.line("},");
+    }
+
+    fn script(&mut self, name: &str, hat: BlockID) {
+        self.line("");
+        self.line(&format!("fn {}(s: &Sprite) -> Script<'_> {{", name));
+        self.indent += 1;
+        self.line("Box::pin(async move {");
+        self.indent += 1;
+        let mut visited: HashSet<BlockID> = HashSet::new();
+        let next = self.blocks.get(&hat).and_then(|info| info.next);
+        if !self.stack(next, &mut visited) {
+            self.line("Ok(())");
+        }
+        self.indent -= 1;
+        self.line("})");
+        self.indent -= 1;
+        self.line("}");
+    }
+
+    /// Returns true if the stack never reaches its end.
+    fn stack(&mut self, mut next: Option<BlockID>, visited: &mut HashSet<BlockID>) -> bool {
+        let mut diverges = false;
+        while let Some(id) = next {
+            if !visited.insert(id) {
+                self.unsupported(id, "block is used more than once");
+                break;
+            }
+            let info = match self.blocks.get(&id) {
+                Some(info) => info,
+                None => {
+                    self.unsupported(id, "block does not exist");
+                    break;
+                }
+            };
+            diverges = self.stack_block(id, info, visited);
+            next = info.next;
+        }
+        diverges
+    }
+
+    /// Returns true if the block never finishes.
+    fn stack_block(
+        &mut self,
+        id: BlockID,
+        info: &'a file::Block,
+        visited: &mut HashSet<BlockID>,
+    ) -> bool {
+        match info.opcode.as_str() {
+            "data_setvariableto" | "data_changevariableby" => {
+                let slot = match self.slot(id, info) {
+                    Some(slot) => slot,
+                    None => return false,
+                };
+                let value = self.input(info, "VALUE", visited);
+                if info.opcode == "data_setvariableto" {
+                    self.line(&format!("s.set_variable({}, {}).await;", slot, value));
+                } else {
+                    self.line(&format!("s.change_variable({}, &{}).await?;", slot, value));
+                }
+            }
+            "control_if" | "control_if_else" => {
+                let condition = self.input(info, "CONDITION", visited);
+                self.line(&format!("if truthy(&{})? {{", condition));
+                self.substack(info, "SUBSTACK", false, visited);
+                if info.opcode == "control_if_else" {
+                    self.line("} else {");
+                    self.substack(info, "SUBSTACK2", false, visited);
+                }
+                self.line("}");
+            }
+            "control_repeat" => {
+                let times = self.input(info, "TIMES", visited);
+                self.line(&format!("for _ in 0..repeat_count(&{})? {{", times));
+                self.substack(info, "SUBSTACK", true, visited);
+                self.line("}");
+            }
+            "control_repeat_until" => {
+                let condition = self.input(info, "CONDITION", visited);
+                self.line(&format!("while !truthy(&{})? {{", condition));
+                self.substack(info, "SUBSTACK", true, visited);
+                self.line("}");
+            }
+            "control_forever" => {
+                self.line("loop {");
+                self.substack(info, "SUBSTACK", true, visited);
+                self.line("}");
+                return true;
+            }
+            "control_stop" => {
+                match info
+                    .fields
+                    .get("STOP_OPTION")
+                    .map(|f| get_field_value(f, 0))
+                {
+                    Some(Ok("all")) => {
+                        self.line("s.stop_all().await;");
+                        self.line("return Ok(());");
+                        return true;
+                    }
+                    Some(Ok("this script")) => {
+                        self.line("return Ok(());");
+                        return true;
+                    }
+                    Some(Ok("other scripts in sprite")) => {
+                        self.line("s.stop_other_scripts().await;")
+                    }
+                    _ => self.unsupported(id, "invalid STOP_OPTION"),
+                }
+            }
+            _ => {
+                if let Some(call) = self.sprite_method(id, info, visited) {
+                    self.line(&format!("{};", call));
+                }
+            }
+        }
+        false
+    }
+
+    fn substack(
+        &mut self,
+        info: &'a file::Block,
+        key: &str,
+        yields: bool,
+        visited: &mut HashSet<BlockID>,
+    ) {
+        self.indent += 1;
+        let first = info
+            .inputs
+            .get(key)
+            .and_then(|input| input.as_array()?.get(1)?.as_str()?.try_into().ok());
+        self.stack(first, visited);
+        if yields {
+            self.line("s.yield_now().await;");
+        }
+        self.indent -= 1;
+    }
+
+    /// Expression of type Value for the input
+    fn input(
+        &mut self,
+        info: &'a file::Block,
+        key: &str,
+        visited: &mut HashSet<BlockID>,
+    ) -> String {
+        let input = match info.inputs.get(key).and_then(|input| input.as_array()) {
+            Some(input) => input,
+            None => return "Value::from(\"\")".to_string(),
+        };
+        match input.get(1) {
+            Some(serde_json::Value::String(id)) => match id.as_str().try_into() {
+                Ok(id) => self.reporter(id, visited),
+                Err(_) => "Value::from(\"\")".to_string(),
+            },
+            Some(serde_json::Value::Array(primitive)) => self.primitive(primitive),
+            _ => "Value::from(\"\")".to_string(),
+        }
+    }
+
+    fn primitive(&mut self, primitive: &[serde_json::Value]) -> String {
+        let value = primitive.get(1).unwrap_or(&serde_json::Value::Null);
+        match primitive.first().and_then(|t| t.as_i64()) {
+            Some(4..=8) => match value::parse_number(value) {
+                Ok(number) => format!("Value::from({})", f64_literal(number)),
+                Err(_) => json_literal(value),
+            },
+            Some(12) => match primitive
+                .get(2)
+                .and_then(|id| id.as_str())
+                .and_then(|id| self.slots.get(id))
+            {
+                Some(slot) => format!("s.variable({}).await", slot),
+                None => {
+                    self.unsupported
+                        .insert(format!("variable {} is not a stage variable", value));
+                    "Value::from(\"\")".to_string()
+                }
+            },
+            Some(13) => {
+                self.unsupported.insert(format!("list {}", value));
+                "Value::from(\"\")".to_string()
+            }
+            _ => json_literal(value),
+        }
+    }
+
+    fn reporter(&mut self, id: BlockID, visited: &mut HashSet<BlockID>) -> String {
+        if !visited.insert(id) {
+            self.unsupported(id, "block is used more than once");
+            return "Value::from(\"\")".to_string();
+        }
+        let info = match self.blocks.get(&id) {
+            Some(info) => info,
+            None => {
+                self.unsupported(id, "block does not exist");
+                return "Value::from(\"\")".to_string();
+            }
+        };
+        if info.opcode == "data_variable" {
+            return match self.slot(id, info) {
+                Some(slot) => format!("s.variable({}).await", slot),
+                None => "Value::from(\"\")".to_string(),
+            };
+        }
+        if let Some((_, function, keys)) = OPERATORS.iter().find(|(op, _, _)| *op == info.opcode) {
+            let args: Vec<String> = keys
+                .iter()
+                .map(|key| format!("&{}", self.input(info, key, visited)))
+                .collect();
+            return format!("{}({})?", function, args.join(", "));
+        }
+        match self.sprite_method(id, info, visited) {
+            Some(call) => call,
+            None => "Value::from(\"\")".to_string(),
+        }
+    }
+
+    fn sprite_method(
+        &mut self,
+        id: BlockID,
+        info: &'a file::Block,
+        visited: &mut HashSet<BlockID>,
+    ) -> Option<String> {
+        let (_, method, keys) = match SPRITE_METHODS.iter().find(|(op, _, _)| *op == info.opcode) {
+            Some(method) => method,
+            None => {
+                self.unsupported(id, &info.opcode);
+                return None;
+            }
+        };
+        let args: Vec<String> = keys
+            .iter()
+            .map(|key| format!("&{}", self.input(info, key, visited)))
+            .collect();
+        Some(format!("s.{}({}).await?", method, args.join(", ")))
+    }
+
+    fn slot(&mut self, id: BlockID, info: &file::Block) -> Option<usize> {
+        let slot = info
+            .fields
+            .get("VARIABLE")
+            .and_then(|field| get_field_value(field, 1).ok())
+            .and_then(|variable_id| self.slots.get(variable_id))
+            .copied();
+        if slot.is_none() {
+            self.unsupported(id, "VARIABLE is not a stage variable");
+        }
+        slot
+    }
+}
+
+fn f64_literal(number: f64) -> String {
+    if number.is_nan() {
+        "f64::NAN".to_string()
+    } else if number.is_infinite() && number > 0.0 {
+        "f64::INFINITY".to_string()
+    } else if number.is_infinite() {
+        "f64::NEG_INFINITY".to_string()
+    } else {
+        format!("{:?}", number)
+    }
+}
+
+fn json_literal(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Number(n) => {
+            format!("Value::from({})", f64_literal(n.as_f64().unwrap_or(0.0)))
+        }
+        serde_json::Value::String(s) => format!("Value::from({:?})", s),
+        serde_json::Value::Bool(b) => format!("Value::from({})", b),
+        _ => "Value::from(\"\")".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::{file_block, id, variables};
+
+    fn project(blocks: HashMap<BlockID, file::Block>) -> file::Project {
+        let target: file::Target = serde_json::from_value(serde_json::json!({
+            "isStage": true,
+            "name": "Stage",
+            "variables": {},
+            "blocks": {},
+            "costumes": [],
+        }))
+        .unwrap();
+        file::Project {
+            targets: vec![file::Target {
+                variables: variables(&["n"]),
+                blocks,
+                ..target
+            }],
+            monitors: Vec::new(),
+            extensions: Vec::new(),
+            meta: file::Meta {
+                semver: "3.0.0".to_string(),
+                vm: String::new(),
+                agent: String::new(),
+            },
+        }
+    }
+
+    #[test]
+    fn test_transpile() {
+        // when flag clicked; forever { change n by (n + 1); pen clear }
+        let mut blocks: HashMap<BlockID, file::Block> = HashMap::new();
+        blocks.insert(
+            id("hat"),
+            file_block(
+                "event_whenflagclicked",
+                Some("forever"),
+                serde_json::json!({}),
+            ),
+        );
+        blocks.insert(
+            id("forever"),
+            file_block(
+                "control_forever",
+                None,
+                serde_json::json!({"SUBSTACK": [2, "change"]}),
+            ),
+        );
+        let mut change = file_block(
+            "data_changevariableby",
+            Some("clear"),
+            serde_json::json!({"VALUE": [3, "add", [4, ""]]}),
+        );
+        change.fields.insert(
+            "VARIABLE".to_string(),
+            vec![Some("n".to_string()), Some("n_id".to_string())],
+        );
+        blocks.insert(id("change"), change);
+        blocks.insert(
+            id("add"),
+            file_block(
+                "operator_add",
+                None,
+                serde_json::json!({"NUM1": [3, [12, "n", "n_id"], [4, ""]], "NUM2": [1, [4, "1"]]}),
+            ),
+        );
+        blocks.insert(
+            id("clear"),
+            file_block("pen_clear", None, serde_json::json!({})),
+        );
+
+        let source = transpile(&project(blocks.clone())).unwrap();
+        assert!(source.contains("(\"n\", Value::from(0.0)),"));
+        assert!(source.contains("(Hat::FlagClicked, target0_script0),"));
+        assert!(source.contains(
+            "        loop {\n\
+             \x20           s.change_variable(0, &add(&s.variable(0).await, &Value::from(1.0))?).await?;\n\
+             \x20           s.pen_clear().await?;\n\
+             \x20           s.yield_now().await;\n\
+             \x20       }\n\
+             \x20   })\n"
+        ));
+
+        blocks.insert(
+            id("clear"),
+            file_block("sensing_askandwait", None, serde_json::json!({})),
+        );
+        let error = transpile(&project(blocks)).unwrap_err();
+        assert!(error
+            .to_string()
+            .contains("block \"clear\": sensing_askandwait"));
+    }
+
+    #[test]
+    fn test_write_crate() {
+        let out = std::env::temp_dir().join(format!("scratch-write-{}", std::process::id()));
+        write_crate(&project(HashMap::new()), &HashMap::new(), "a", true, &out).unwrap();
+        let cargo_toml = std::fs::read_to_string(out.join("Cargo.toml")).unwrap();
+        let runtime = out.join("scratch_runtime");
+        let runtime_files: Vec<bool> = RUNTIME_FILES
+            .iter()
+            .map(|(path, _)| runtime.join(path).is_file())
+            .collect();
+        std::fs::remove_dir_all(&out).unwrap();
+        assert!(cargo_toml.contains(
+            r#"scratch_runtime = { path = "scratch_runtime", default-features = false }"#
+        ));
+        assert!(runtime_files.iter().all(|&exists| exists));
+
+        // Every source file of the runtime is copied
+        let sources = Path::new(env!("CARGO_MANIFEST_DIR")).join("scratch_runtime/src");
+        for entry in std::fs::read_dir(sources).unwrap() {
+            let path = format!("src/{}", entry.unwrap().file_name().to_string_lossy());
+            assert!(RUNTIME_FILES.iter().any(|(p, _)| *p == path), "{}", path);
+        }
+    }
+
+    /// Builds and runs the exported crate without a window. The dependencies of scratch_runtime
+    /// must be in the Cargo cache because the build is offline.
+    #[test]
+    #[ignore = "runs cargo to build the exported crate"]
+    fn test_build() {
+        // when flag clicked; set n to 0; repeat 3 { change n by (n + 1) }; say n
+        let mut blocks: HashMap<BlockID, file::Block> = HashMap::new();
+        blocks.insert(
+            id("hat"),
+            file_block("event_whenflagclicked", Some("set"), serde_json::json!({})),
+        );
+        let variable = |opcode: &str, next: Option<&str>, value: serde_json::Value| {
+            let mut block = file_block(opcode, next, serde_json::json!({ "VALUE": value }));
+            block.fields.insert(
+                "VARIABLE".to_string(),
+                vec![Some("n".to_string()), Some("n_id".to_string())],
+            );
+            block
+        };
+        blocks.insert(
+            id("set"),
+            variable(
+                "data_setvariableto",
+                Some("repeat"),
+                serde_json::json!([1, [10, "0"]]),
+            ),
+        );
+        blocks.insert(
+            id("repeat"),
+            file_block(
+                "control_repeat",
+                Some("say"),
+                serde_json::json!({"TIMES": [1, [6, "3"]], "SUBSTACK": [2, "change"]}),
+            ),
+        );
+        blocks.insert(
+            id("change"),
+            variable(
+                "data_changevariableby",
+                None,
+                serde_json::json!([3, "add", [4, ""]]),
+            ),
+        );
+        blocks.insert(
+            id("add"),
+            file_block(
+                "operator_add",
+                None,
+                serde_json::json!({"NUM1": [3, [12, "n", "n_id"], [4, ""]], "NUM2": [1, [4, "1"]]}),
+            ),
+        );
+        blocks.insert(
+            id("say"),
+            file_block(
+                "looks_say",
+                None,
+                serde_json::json!({"MESSAGE": [3, [12, "n", "n_id"], [10, ""]]}),
+            ),
+        );
+
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let out = std::env::temp_dir().join(format!("scratch-export-{}", std::process::id()));
+        write_crate(&project(blocks), &HashMap::new(), "export_test", true, &out).unwrap();
+        // Locked to the versions that the workspace builds with
+        std::fs::copy(root.join("Cargo.lock"), out.join("Cargo.lock")).unwrap();
+        let output =
+            std::process::Command::new(std::env::var("CARGO").unwrap_or_else(|_| "cargo".into()))
+                .args(["run", "--offline", "--quiet"])
+                .current_dir(&out)
+                .env("CARGO_TARGET_DIR", root.join("target").join("export-test"))
+                .output()
+                .unwrap();
+        std::fs::remove_dir_all(&out).unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+        assert_eq!(String::from_utf8_lossy(&output.stdout), "Stage: 7\n");
+    }
+}
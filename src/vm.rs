@@ -1,44 +1,81 @@
 use super::*;
+use crate::blocks::value::Value;
 use crate::blocks::BlockInfo;
 use crate::broadcaster::{BroadcastMsg, Broadcaster, Stop};
 use crate::coordinate::canvas_const;
-use crate::file::ScratchFile;
-use crate::runtime::Global;
+use crate::coverage::{Coverage, CoverageReport};
+use crate::debugger::{
+    BlockRef, Condition, DebugOptions, Debugger, StepMode, ThreadInspection, ThreadStep,
+};
+use crate::file::{AssetError, AssetReference, BlockID, LoadMode, ScratchFile};
+use crate::fileviewer::SpriteBlocks;
+use crate::profiler::{Profile, ProfileRow, Profiler};
+use crate::runtime::{Global, VariableWrite};
+use crate::snapshot::Snapshot;
 use crate::sprite::{Sprite, SpriteID};
 use crate::sprite_map::SpriteMap;
 use crate::sprite_runtime::SpriteRuntime;
+use crate::tracer::Tracer;
 use futures::stream::FuturesUnordered;
-use futures::StreamExt;
+use futures::{FutureExt, StreamExt};
 use graphics::Context;
 use graphics_buffer::{buffer_glyphs_from_path, RenderBuffer};
 use piston_window::{G2d, G2dTextureContext, Glyphs};
+use serde::Serialize;
 use std::fmt::Debug;
+use std::path::Path;
 use tokio::select;
-use tokio::sync::mpsc;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::{mpsc, oneshot};
 
 #[derive(Debug)]
 pub struct VM {
     control_sender: mpsc::Sender<Control>,
+    event_receiver: mpsc::UnboundedReceiver<VMEvent>,
     broadcaster: Broadcaster,
     vm_task: JoinHandle<()>,
     sprites: Arc<SpriteMap>,
+    global: Arc<Global>,
 }
 
 impl VM {
     pub async fn new(
         texture_context: &mut G2dTextureContext,
-        scratch_file: ScratchFile,
+        mut scratch_file: ScratchFile,
         broadcaster: Broadcaster,
+        load_mode: LoadMode,
+        debug_options: &DebugOptions,
     ) -> Result<Self> {
         let (control_sender, control_receiver) = mpsc::channel(1);
+        let (event_sender, event_receiver) = mpsc::unbounded_channel();
 
-        let global = Arc::new(Global::new(
+        let mut global = Global::new(
             &scratch_file.project.targets[0].variables,
+            &scratch_file.project.targets[0].lists,
             &scratch_file.project.monitors,
+            SpriteID::sprite_ids(&scratch_file.project.targets),
             broadcaster.clone(),
-        ));
+            load_mode,
+        );
+        // Optimized scripts skip blocks, so breakpoints and conditions on them would not be hit
+        global.optimize = !debug_options.no_optimize && !debug_options.is_debugging();
+        // Steps can only be undone while debugging
+        global.set_history(debug_options.is_debugging());
+        if debug_options.profile.is_some() {
+            global.profiler = Some(Arc::new(Profiler::default()));
+        }
+        if let Some(path) = &debug_options.trace {
+            global.tracer = Some(Arc::new(Tracer::create(path)?));
+        }
+        if debug_options.coverage.is_some() {
+            global.coverage = Some(Arc::new(Coverage::default()));
+        }
+        let global = Arc::new(global);
 
-        let sprites = VM::sprites(texture_context, &scratch_file, global.clone()).await?;
+        let sprites = VM::sprites(texture_context, &mut scratch_file, global.clone()).await?;
+        if !scratch_file.load_report.is_ok() {
+            log::warn!("{}", scratch_file.load_report);
+        }
 
         let sprite_map = Arc::new(SpriteMap::new(
             sprites,
@@ -50,14 +87,23 @@ impl VM {
             let mut control_receiver = control_receiver;
             let broadcaster = broadcaster.clone();
             let sprite_map = sprite_map.clone();
+            let mut debugger = Debugger::new(global.clone());
+            let tracer = global.tracer.clone();
 
             async move {
                 loop {
-                    if let Err(e) =
-                        VM::run(sprite_map.clone(), &mut control_receiver, &broadcaster).await
+                    if let Err(e) = VM::run(
+                        sprite_map.clone(),
+                        &mut control_receiver,
+                        &broadcaster,
+                        &event_sender,
+                        &mut debugger,
+                        tracer.as_deref(),
+                    )
+                    .await
                     {
-                        log::error!("{}", e);
-                        std::process::exit(1);
+                        VM::send_event(&event_sender, VMEvent::Stopped(e));
+                        return;
                     }
                 }
             }
@@ -65,29 +111,46 @@ impl VM {
 
         Ok(Self {
             control_sender,
+            event_receiver,
             broadcaster,
             vm_task,
             sprites: sprite_map,
+            global,
         })
     }
 
+    /// Images that cannot be decoded are added to the load report.
     async fn sprites(
         texture_context: &mut G2dTextureContext,
-        scratch_file: &ScratchFile,
+        scratch_file: &mut ScratchFile,
         global: Arc<Global>,
     ) -> Result<HashMap<SpriteID, Sprite>> {
         let images = Arc::new(scratch_file.images.clone());
 
         let mut sprites: HashMap<SpriteID, Sprite> =
             HashMap::with_capacity(scratch_file.project.targets.len());
-        for target in &scratch_file.project.targets {
+        for (index, target) in scratch_file.project.targets.iter().enumerate() {
             let sprite_runtime = SpriteRuntime::new(&target);
-            let id = SpriteID::from_sprite_name(&target.name);
+            let id = SpriteID::new(index);
             let mut sprite =
                 Sprite::new(id, sprite_runtime, global.clone(), target.clone()).await?;
-            sprite
+            let undecodable = sprite
                 .add_costumes(texture_context, &target.costumes, &images)
                 .await?;
+            for (costume_index, error) in undecodable {
+                let costume = &target.costumes[costume_index];
+                scratch_file
+                    .load_report
+                    .undecodable_assets
+                    .push(AssetError {
+                        asset: AssetReference {
+                            target_name: target.name.clone(),
+                            asset_name: costume.name.clone(),
+                            md5ext: costume.md5ext.clone().unwrap_or_default(),
+                        },
+                        error: error.to_string(),
+                    });
+            }
             sprites.insert(id, sprite);
         }
         Ok(sprites)
@@ -97,6 +160,9 @@ impl VM {
         sprites: Arc<SpriteMap>,
         control_receiver: &mut mpsc::Receiver<Control>,
         broadcaster: &Broadcaster,
+        event_sender: &mpsc::UnboundedSender<VMEvent>,
+        debugger: &mut Debugger,
+        tracer: Option<&Tracer>,
     ) -> Result<()> {
         let mut broadcast_receiver = broadcaster.subscribe();
         let mut futures = FuturesUnordered::new();
@@ -104,54 +170,173 @@ impl VM {
         let mut paused_threads: Vec<ThreadID> = Vec::new();
         for thread_id in sprites.all_thread_ids().await {
             paused_threads.push(thread_id);
-            log::trace!(
-                "{}",
-                DebugInfo {
-                    thread_id,
-                    block_info: sprites.block_info(thread_id).await?,
-                }
-            );
+            VM::trace_paused(&sprites, thread_id).await;
         }
 
         let mut buffer_glyphs = buffer_glyphs_from_path("assets/Roboto-Regular.ttf")?;
 
         let mut current_state = Control::Pause;
+        let mut thread_step: Option<ThreadStep> = None;
 
         loop {
             select! {
                 futures_result = futures.next() => {
-                    if let Some(step_result) = futures_result {
-                        if let Some(thread_id) = step_result? {
-                            match current_state {
-                                Control::Continue => futures.push(sprites.step(thread_id)),
-                                Control::Step | Control::Pause => {
-                                    paused_threads.push(thread_id);
-                                    log::trace!(
-                                        "{}",
-                                        DebugInfo {
-                                            thread_id,
-                                            block_info: sprites.block_info(thread_id).await?,
-                                        }
-                                    );
-                                    current_state = Control::Pause;
-                                }
-                                _ => unreachable!("{:?}", current_state),
+                    let (stepped_thread, step_result) = match futures_result {
+                        Some(result) => result,
+                        None => continue,
+                    };
+                    let running_thread = match step_result {
+                        Ok(running_thread) => running_thread,
+                        Err(e) => {
+                            if VM::is_block_error(&e) {
+                                current_state = Control::Pause;
                             }
+                            VM::report_error(event_sender, e);
+                            None
                         }
+                    };
+
+                    // Steps that finish or stop their thread can also change variables
+                    let running = running_thread.is_some();
+                    match debugger.after_step(&sprites, stepped_thread, running).await {
+                        Ok(events) => for event in events {
+                            current_state = Control::Pause;
+                            thread_step = None;
+                            VM::send_event(event_sender, event);
+                        },
+                        Err(error) => VM::report_error(
+                            event_sender,
+                            ScratchError::Thread { thread_id: stepped_thread, error },
+                        ),
+                    }
+
+                    let thread_id = match running_thread {
+                        Some(thread_id) => thread_id,
+                        None => {
+                            // The stepped thread finished or was stopped
+                            if matches!(thread_step, Some(step) if step.thread_id == stepped_thread) {
+                                thread_step = None;
+                                VM::send_event(event_sender, VMEvent::StepFinished(stepped_thread));
+                            }
+                            continue;
+                        }
+                    };
+                    let step_finished = match thread_step {
+                        Some(step) if step.thread_id == thread_id => {
+                            let finished = step.is_finished(&sprites).await;
+                            if finished {
+                                thread_step = None;
+                                VM::send_event(event_sender, VMEvent::StepFinished(thread_id));
+                            }
+                            finished
+                        }
+                        _ => true,
+                    };
+
+                    match current_state {
+                        Control::Continue => futures.push(VM::step_thread(&sprites, thread_id)),
+                        Control::Step | Control::Pause if !step_finished => {
+                            futures.push(VM::step_thread(&sprites, thread_id))
+                        }
+                        Control::Step | Control::Pause => {
+                            paused_threads.push(thread_id);
+                            VM::trace_paused(&sprites, thread_id).await;
+                            current_state = Control::Pause;
+                        }
+                        _ => unreachable!("{:?}", current_state),
                     }
                 },
                 c = control_receiver.recv() => {
                     if let Some(control) = c {
                         log::info!("control: {:?}", &control);
-                        current_state = control;
                         match control {
                             Control::Continue | Control::Step => {
+                                current_state = control;
+                                thread_step = None;
                                 for thread_id in paused_threads.drain(..) {
-                                    futures.push(sprites.step(thread_id));
+                                    futures.push(VM::step_thread(&sprites, thread_id));
+                                }
+                            }
+                            Control::StepBack => {
+                                // Running steps hold the locks of their threads
+                                if !futures.is_empty() {
+                                    log::warn!("cannot step back while blocks are running");
+                                    continue;
+                                }
+                                match sprites.step_back().await {
+                                    Ok(thread_ids) => {
+                                        for &thread_id in &thread_ids {
+                                            if !paused_threads.contains(&thread_id) {
+                                                paused_threads.push(thread_id);
+                                            }
+                                        }
+                                        if let Some(&thread_id) = thread_ids.last() {
+                                            VM::send_event(event_sender, VMEvent::SteppedBack(thread_id));
+                                        }
+                                    }
+                                    Err(e) => log::error!("could not step back: {}", e),
+                                }
+                            }
+                            Control::Restore(snapshot, result_sender) => {
+                                // Dropping the running steps releases the locks of their threads.
+                                // Their threads are moved by the snapshot anyway.
+                                futures = FuturesUnordered::new();
+                                thread_step = None;
+                                current_state = Control::Pause;
+                                let result = sprites.restore(&snapshot).await.map(|thread_ids| {
+                                    paused_threads = thread_ids;
+                                });
+                                if result_sender.send(result).is_err() {
+                                    log::info!("snapshot restored without a receiver");
                                 }
                             }
                             Control::Stop => return Ok(()),
-                            Control::Pause => {}
+                            Control::Pause => {
+                                current_state = control;
+                                thread_step = None;
+                            }
+                            Control::StepThread { thread_id, mode } => {
+                                match paused_threads.iter().position(|&id| id == thread_id) {
+                                    // Replaces the previous step, so the thread that was stepped
+                                    // pauses after its next block
+                                    Some(index) => match ThreadStep::new(&sprites, thread_id, mode).await {
+                                        Ok(step) => {
+                                            thread_step = Some(step);
+                                            paused_threads.remove(index);
+                                            futures.push(VM::step_thread(&sprites, thread_id));
+                                        }
+                                        Err(error) => {
+                                            VM::report_error(
+                                                event_sender,
+                                                ScratchError::Thread { thread_id, error },
+                                            );
+                                            VM::send_event(event_sender, VMEvent::StepFinished(thread_id));
+                                        }
+                                    },
+                                    // Threads that finished stay where they are, so the step is
+                                    // finished at once
+                                    None if matches!(current_state, Control::Pause) => {
+                                        VM::send_event(event_sender, VMEvent::StepFinished(thread_id));
+                                    }
+                                    None => log::warn!("thread is not paused: {:?}", thread_id),
+                                }
+                            }
+                            Control::SkipBlock(thread_id) => {
+                                match sprites.skip_block(thread_id).await {
+                                    Ok(_) => match current_state {
+                                        Control::Continue => futures.push(VM::step_thread(&sprites, thread_id)),
+                                        _ => paused_threads.push(thread_id),
+                                    },
+                                    Err(error) => VM::report_error(
+                                        event_sender,
+                                        ScratchError::Thread { thread_id, error },
+                                    ),
+                                }
+                            }
+                            Control::StopThread(thread_id) => sprites.stop(thread_id).await,
+                            Control::AddBreakpoint(block_id) => debugger.add_breakpoint(block_id),
+                            Control::RemoveBreakpoint(block_id) => debugger.remove_breakpoint(&block_id),
+                            Control::SetConditions(conditions) => debugger.set_conditions(conditions).await,
                         }
                     }
                 },
@@ -159,66 +344,96 @@ impl VM {
                     match recv_result {
                         Ok(msg) => {
                             log::info!("broadcast: {:?}", BroadcastMsgDebug(&msg));
-                            match msg {
-                                BroadcastMsg::Clone(from_sprite) => {
-                                    let new_sprite_id = sprites.clone_sprite(from_sprite).await?;
-                                    for thread_id in 0..sprites.number_of_threads(&new_sprite_id).await? {
-                                        let id = ThreadID {
-                                            sprite_id: new_sprite_id,
-                                            thread_id,
-                                        };
-                                        match current_state {
-                                            Control::Continue | Control::Step => {
-                                                futures.push(sprites.step(id))
+                            // Errors only affect the message that caused them. Returns the
+                            // threads of new clones.
+                            let result: Result<Vec<ThreadID>> = async {
+                                let mut new_threads: Vec<ThreadID> = Vec::new();
+                                match msg {
+                                    BroadcastMsg::Start(message) => {
+                                        if let Some(tracer) = tracer {
+                                            if let Err(e) = tracer.broadcast(&message) {
+                                                log::error!("could not write trace: {}", e);
                                             }
-                                            Control::Pause => paused_threads.push(id),
-                                            _ => unreachable!(),
                                         }
                                     }
-                                }
-                                BroadcastMsg::DeleteClone(sprite_id) => {
-                                    sprites.remove(sprite_id).await;
-                                }
-                                BroadcastMsg::Stop(s) => match s {
-                                    Stop::All => {
-                                        for thread_id in sprites.all_thread_ids().await {
-                                            sprites.stop(thread_id).await;
+                                    BroadcastMsg::Clone(from_sprite) => {
+                                        let new_sprite_id = sprites.clone_sprite(from_sprite).await?;
+                                        if let Some(tracer) = tracer {
+                                            if let Err(e) = tracer.clone_created(from_sprite, new_sprite_id) {
+                                                log::error!("could not write trace: {}", e);
+                                            }
+                                        }
+                                        for thread_id in 0..sprites.number_of_threads(&new_sprite_id).await? {
+                                            new_threads.push(ThreadID {
+                                                sprite_id: new_sprite_id,
+                                                thread_id,
+                                            });
                                         }
                                     }
-                                    Stop::ThisThread(thread_id) => {
-                                        sprites.stop(thread_id).await;
+                                    BroadcastMsg::DeleteClone(sprite_id) => {
+                                        sprites.remove(sprite_id).await;
+                                        if let Some(tracer) = tracer {
+                                            if let Err(e) = tracer.clone_deleted(sprite_id) {
+                                                log::error!("could not write trace: {}", e);
+                                            }
+                                        }
                                     }
-                                    Stop::OtherThreads(thread_id) => {
-                                        for id in sprites.all_thread_ids().await {
-                                            if id.sprite_id == thread_id.sprite_id
-                                                && id.thread_id != thread_id.thread_id
-                                            {
+                                    BroadcastMsg::Stop(s) => match s {
+                                        Stop::All => {
+                                            for thread_id in sprites.all_thread_ids().await {
                                                 sprites.stop(thread_id).await;
                                             }
                                         }
+                                        Stop::ThisThread(thread_id) => {
+                                            sprites.stop(thread_id).await;
+                                        }
+                                        Stop::OtherThreads(thread_id) => {
+                                            sprites.stop_other_threads(thread_id).await;
+                                        }
+                                    },
+                                    BroadcastMsg::ChangeLayer { sprite, action } => {
+                                        sprites.change_layer(sprite, action).await?;
+                                    }
+                                    BroadcastMsg::RequestSpriteRectangle(sprite_id) => {
+                                        let rectangle = sprites.sprite_rectangle(&sprite_id).await?;
+                                        broadcaster.send(BroadcastMsg::SpriteRectangle {
+                                            sprite: sprite_id,
+                                            rectangle,
+                                        })?;
                                     }
-                                },
-                                BroadcastMsg::ChangeLayer { sprite, action } => {
-                                    sprites.change_layer(sprite, action).await?;
+                                    BroadcastMsg::RequestCanvasImage(sprite_id) => {
+                                        let mut render_buffer =
+                                            RenderBuffer::new(canvas_const::X_MAX as u32, canvas_const::Y_MAX as u32);
+                                        sprites
+                                            .draw_to_buffer(&mut Context::new(), &mut render_buffer, &mut buffer_glyphs, &sprite_id)
+                                            .await?;
+                                        broadcaster.send(BroadcastMsg::CanvasImage(render_buffer))?;
+                                    }
+                                    _ => {}
                                 }
-                                BroadcastMsg::RequestSpriteRectangle(sprite_id) => {
-                                    let rectangle = sprites.sprite_rectangle(&sprite_id).await?;
-                                    broadcaster.send(BroadcastMsg::SpriteRectangle {
-                                        sprite: sprite_id,
-                                        rectangle,
-                                    })?;
+                                Ok(new_threads)
+                            }
+                            .await;
+                            match result {
+                                Ok(new_threads) => {
+                                    for id in new_threads {
+                                        match current_state {
+                                            Control::Continue | Control::Step => {
+                                                futures.push(VM::step_thread(&sprites, id))
+                                            }
+                                            Control::Pause => paused_threads.push(id),
+                                            _ => unreachable!(),
+                                        }
+                                    }
                                 }
-                                BroadcastMsg::RequestCanvasImage(sprite_id) => {
-                                    let mut render_buffer =
-                                        RenderBuffer::new(canvas_const::X_MAX as u32, canvas_const::Y_MAX as u32);
-                                    sprites
-                                        .draw_to_buffer(&mut Context::new(), &mut render_buffer, &mut buffer_glyphs, &sprite_id)
-                                        .await?;
-                                    broadcaster.send(BroadcastMsg::CanvasImage(render_buffer))?;
+                                Err(error) => {
+                                    VM::report_error(event_sender, ScratchError::Broadcast { error })
                                 }
-                                _ => {}
                             }
                         }
+                        Err(RecvError::Lagged(n)) => {
+                            log::warn!("skipped {} broadcast messages", n);
+                        }
                         Err(e) => {
                             return Err(e.into());
                         }
@@ -229,19 +444,233 @@ impl VM {
     }
 
     pub async fn continue_(&self) {
-        self.control_sender.send(Control::Continue).await.unwrap();
+        self.send_control(Control::Continue).await;
     }
 
     pub async fn pause(&self) {
-        self.control_sender.send(Control::Pause).await.unwrap();
+        self.send_control(Control::Pause).await;
     }
 
     pub async fn step(&self) {
-        self.control_sender.send(Control::Step).await.unwrap();
+        self.send_control(Control::Step).await;
     }
 
     pub async fn stop(&self) {
-        self.control_sender.send(Control::Stop).await.unwrap();
+        self.send_control(Control::Stop).await;
+    }
+
+    /// Undoes the last block that was executed while the VM is paused.
+    pub async fn step_back(&self) {
+        self.send_control(Control::StepBack).await;
+    }
+
+    /// Steps a paused thread while the other threads stay paused.
+    pub async fn step_single_thread(&self, thread_id: ThreadID, mode: StepMode) {
+        self.send_control(Control::StepThread { thread_id, mode })
+            .await;
+    }
+
+    /// Resumes a thread that stopped because of an error after the block that failed.
+    pub async fn skip_block(&self, thread_id: ThreadID) {
+        self.send_control(Control::SkipBlock(thread_id)).await;
+    }
+
+    pub async fn stop_thread(&self, thread_id: ThreadID) {
+        self.send_control(Control::StopThread(thread_id)).await;
+    }
+
+    /// Pauses the VM when any thread is about to execute the block.
+    pub async fn add_breakpoint(&self, block_id: BlockID) {
+        self.send_control(Control::AddBreakpoint(block_id)).await;
+    }
+
+    pub async fn remove_breakpoint(&self, block_id: BlockID) {
+        self.send_control(Control::RemoveBreakpoint(block_id)).await;
+    }
+
+    /// Saves the sprites, clones and variables to a file. Threads that are executing a block are
+    /// saved when the block finishes.
+    pub async fn snapshot(&self, path: &Path) -> Result<()> {
+        self.sprites.snapshot().await?.save(path)
+    }
+
+    /// Loads a snapshot that was saved from the same project and pauses the VM. Blocks that were
+    /// running are interrupted.
+    pub async fn restore(&self, path: &Path) -> Result<()> {
+        let snapshot = Snapshot::load(path)?;
+        let (result_sender, result_receiver) = oneshot::channel();
+        self.send_control(Control::Restore(Box::new(snapshot), result_sender))
+            .await;
+        result_receiver.await?
+    }
+
+    /// Writes the records that are buffered if tracing is enabled.
+    pub fn flush_trace(&self) -> Result<()> {
+        match &self.global.tracer {
+            Some(tracer) => tracer.flush(),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the blocks of the scripts that were executed if coverage is enabled.
+    pub fn coverage(&self, sprites: &[SpriteBlocks]) -> Option<CoverageReport> {
+        let coverage = self.global.coverage.as_ref()?;
+        Some(CoverageReport::new(sprites, &coverage.executed()))
+    }
+
+    /// Returns the measurements of the blocks that were executed if profiling is enabled.
+    pub async fn profile(&self) -> Option<Profile> {
+        let profiler = self.global.profiler.as_ref()?;
+        let mut rows: Vec<ProfileRow> = Vec::new();
+        for (stack, sample) in profiler.samples() {
+            // Clones run the blocks of the sprite that they were cloned from, which is not deleted
+            let sprite_id = stack.sprite_id;
+            let mut blocks: Vec<BlockRef> = Vec::with_capacity(stack.blocks.len());
+            for &id in &stack.blocks {
+                blocks.push(self.block_ref(sprite_id, id).await);
+            }
+            rows.push(ProfileRow {
+                sprite_name: stack.sprite_name.to_string(),
+                hat: self.block_ref(sprite_id, stack.hat).await,
+                blocks,
+                count: sample.count,
+                time: sample.time,
+            });
+        }
+        Some(Profile::new(rows))
+    }
+
+    async fn block_ref(&self, sprite_id: SpriteID, id: BlockID) -> BlockRef {
+        let opcode = match self.sprites.block_source(sprite_id, &id).await {
+            Ok((_, opcode)) => opcode,
+            Err(_) => String::new(),
+        };
+        BlockRef { id, opcode }
+    }
+
+    /// Returns the threads of all sprites and clones.
+    pub async fn threads(&self) -> Vec<ThreadInspection> {
+        self.sprites.inspect().await
+    }
+
+    /// Returns the names and values of the stage variables.
+    pub async fn variables(&self) -> Vec<(String, Value)> {
+        self.global.variables.list().await
+    }
+
+    pub async fn sprite_properties(
+        &self,
+        sprite_id: SpriteID,
+    ) -> Result<Vec<(&'static str, String)>> {
+        self.sprites.properties(&sprite_id).await
+    }
+
+    /// Pauses the VM when the variable with the name or ID changes.
+    pub async fn watch_variable(&self, name_or_id: &str) -> Result<()> {
+        self.global.variables.watch(name_or_id).await
+    }
+
+    /// Pauses the VM when the value of one of the reporter blocks becomes true after a step.
+    /// Replaces the previous conditions.
+    pub async fn set_conditions(&self, block_ids: &[BlockID]) -> Result<()> {
+        let mut conditions: Vec<Condition> = Vec::with_capacity(block_ids.len());
+        for &block_id in block_ids {
+            let block = self.sprites.reporter(&block_id).await?;
+            conditions.push(Condition::new(block_id, block).await);
+        }
+        self.send_control(Control::SetConditions(conditions)).await;
+        Ok(())
+    }
+
+    async fn send_control(&self, control: Control) {
+        if let Err(e) = self.control_sender.send(control).await {
+            log::error!("VM is not running, ignoring control: {:?}", e.0);
+        }
+    }
+
+    /// Returns the next event without waiting.
+    pub fn try_recv_event(&mut self) -> Option<VMEvent> {
+        self.event_receiver.recv().now_or_never().flatten()
+    }
+
+    /// Returns the details of a thread error that was caused by a block.
+    pub async fn block_error(&self, error: &ScratchError) -> Option<BlockError> {
+        let (thread_id, block_id, message) = match error {
+            ScratchError::Thread { thread_id, error } => match error.downcast_ref() {
+                Some(ScratchError::Block { id, error, .. }) => (*thread_id, *id, error.to_string()),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        let (sprite_name, opcode) = match self
+            .sprites
+            .block_source(thread_id.sprite_id, &block_id)
+            .await
+        {
+            Ok(source) => source,
+            Err(e) => {
+                log::error!("{}", e);
+                return None;
+            }
+        };
+        Some(BlockError {
+            thread_id,
+            sprite_name,
+            opcode,
+            block_id,
+            message,
+        })
+    }
+
+    fn is_block_error(error: &ScratchError) -> bool {
+        match error {
+            ScratchError::Thread { error, .. } => {
+                matches!(error.downcast_ref(), Some(ScratchError::Block { .. }))
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns the stepped thread with the result of the step, which is the thread if it is still
+    /// running.
+    async fn step_thread(
+        sprites: &SpriteMap,
+        thread_id: ThreadID,
+    ) -> (
+        ThreadID,
+        std::result::Result<Option<ThreadID>, ScratchError>,
+    ) {
+        let result = sprites
+            .step(thread_id)
+            .await
+            .map_err(|error| ScratchError::Thread { thread_id, error });
+        (thread_id, result)
+    }
+
+    async fn trace_paused(sprites: &SpriteMap, thread_id: ThreadID) {
+        if log::log_enabled!(log::Level::Trace) {
+            match sprites.block_info(thread_id).await {
+                Ok(block_info) => log::trace!(
+                    "{}",
+                    DebugInfo {
+                        thread_id,
+                        block_info
+                    }
+                ),
+                Err(e) => log::trace!("{:?} paused: {}", thread_id, e),
+            }
+        }
+    }
+
+    fn report_error(event_sender: &mpsc::UnboundedSender<VMEvent>, error: ScratchError) {
+        VM::send_event(event_sender, VMEvent::Error(error));
+    }
+
+    fn send_event(event_sender: &mpsc::UnboundedSender<VMEvent>, event: VMEvent) {
+        if let Err(e) = event_sender.send(event) {
+            // The receiver is gone if the VM handle was dropped
+            log::info!("{:?}", e.0);
+        }
     }
 
     pub async fn draw(
@@ -254,21 +683,62 @@ impl VM {
     }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug)]
 enum Control {
     Continue,
     Pause,
     Step,
+    StepBack,
+    StepThread { thread_id: ThreadID, mode: StepMode },
     Stop,
+    SkipBlock(ThreadID),
+    StopThread(ThreadID),
+    AddBreakpoint(BlockID),
+    RemoveBreakpoint(BlockID),
+    SetConditions(Vec<Condition>),
+    Restore(Box<Snapshot>, oneshot::Sender<Result<()>>),
+}
+
+#[derive(Debug)]
+pub enum VMEvent {
+    /// A thread stopped or a broadcast could not be handled
+    Error(ScratchError),
+    /// The thread is about to execute a block with a breakpoint and the VM paused
+    Breakpoint(DebugInfo),
+    /// A watched variable changed and the VM paused
+    Watchpoint(VariableWrite),
+    /// The value of the condition became true after a step of the thread and the VM paused
+    Condition {
+        block_id: BlockID,
+        thread_id: ThreadID,
+        /// Last variable change in the step
+        write: Option<VariableWrite>,
+    },
+    /// A thread that was stepped with VM::step_single_thread paused
+    StepFinished(ThreadID),
+    /// The last block that the thread executed was undone
+    SteppedBack(ThreadID),
+    /// The VM could not continue and no longer runs threads or handles controls
+    Stopped(Error),
+}
+
+/// A block that returned an error, which pauses the VM.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BlockError {
+    pub thread_id: ThreadID,
+    pub sprite_name: String,
+    pub opcode: String,
+    pub block_id: BlockID,
+    pub message: String,
 }
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
 pub struct ThreadID {
     pub sprite_id: SpriteID,
     pub thread_id: usize,
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub struct DebugInfo {
     pub thread_id: ThreadID,
     pub block_info: BlockInfo,